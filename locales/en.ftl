@@ -0,0 +1,399 @@
+# English notices for chaz
+#
+# Copy this file to the `locales_dir` in the config as e.g. `de.ftl` and translate the values to add a language.
+# Notices always start with "!chaz " so they're left out of the context, don't add it to the translations.
+#
+# The command descriptions in `!chaz help` can be translated too, with keys like `help-model` and `help-clear`.
+# The notices that mark points in the conversation, like "context expired" and "import", aren't translated.
+
+## Help
+
+help-header = Available commands:
+help-help = Show this message
+
+## Errors
+
+error = Error: { $message }
+permission-level = Error: { $command } needs power level { $level } in this room
+admin-only = Error: { $command } is only available to admins
+unknown-command = Error: there's no command { $command }, did you mean { $suggestion }?
+rate-limited = Error: { $reason }.
+
+## Moderation
+
+moderated-response = The response was removed by the content filter.
+moderated-message = Error: your message was stopped by the content filter.
+
+## Context
+
+context-cleared = clear: All messages before this will be ignored
+
+## Welcome
+
+welcome = Hi! Mention me or start a message with !chaz to talk to me, and use !chaz help to see the commands. I'm using { $model }. The messages I answer, along with the recent history of this room, are sent to the model's provider to generate the responses.
+
+## Queue
+
+queued = The backend is unavailable, your question is queued ({ $waiting } waiting) and will be answered when it's back.
+queue-expired = Error: the backend didn't come back in time, { $user } please ask again.
+invalid-arguments = Error: { $error }. Usage: !chaz { $command } { $usage }
+
+## Status
+
+status = status:
+    { $status }
+status-backend = Backend: { $backend }
+status-model = Model: { $model }
+status-role = Role: { $role }
+status-context = Context: { $count } messages, ~{ $tokens } tokens
+status-quotas = Quotas left: { $quotas }
+status-no-quotas = no quotas
+status-media = Images and files in the context: { $state }
+status-routed = Last routed to: { $model }
+status-not-routed = nothing yet
+status-degraded = Degraded: { $status }
+usage = usage for { $user }:
+    { $report }
+usage-admin-only = Error: only admins can see the usage of other users
+cost = cost:
+    { $report }
+cost-admin-only = Error: only admins can see the costs of other users
+
+## Listening
+
+listen-enabled = Listening enabled, I'll chime in when it seems useful
+listen-disabled = Listening disabled
+listen-topics-cleared = Listening topics cleared
+listen-topics = Listening for topics: { $topics }
+listen-status = Listening is { $state }
+
+    Room Topics: { $topics }
+
+## Models
+
+models = Current Model: { $model }
+
+    Known Backends:
+    { $backends }
+
+    Known Models:
+    { $models }
+models-locked = Current Model: { $model } (locked)
+
+    Known Backends:
+    { $backends }
+
+    Known Models:
+    { $models }
+
+## Roles
+
+role-list = role list:
+
+    { $roles }
+role-current = Current Role: { $role }
+
+    { $roles }
+role-set = Role set to "{ $role }"
+role-unknown = Role "{ $role }" doesn't exist. Use `!chaz role list` to see the available roles
+roles-room = Room Defined Roles:
+roles-configured = Configured Roles:
+roles-builtin = Builtin Roles:
+model-set = Model set to "{ $model }"
+model-router = Model set to "router", a model will be picked for each request
+model-unknown = Model { $model } is unknown, but may be valid. Please manually verify that it is supported by your desired backend.
+model-locked = Error: the model is locked to "{ $model }" in this room, a room admin can `!chaz model unlock` it
+model-not-allowed = Error: the model "{ $model }" isn't allowed in this space
+router-not-configured = Error: the router isn't configured
+model-lock-admin-only = Error: only room admins can lock or unlock the model
+model-lock-usage = Error: Usage: !chaz model lock <model>
+model-lock-set = Model locked to "{ $model }"
+model-unlocked = Model unlocked
+
+## Backends
+
+backend-added = Successfully added backend { $name }
+login-not-direct = Error: login is only allowed in a direct message, your key would be visible to everyone here. Please delete your message and revoke the key.
+logged-in = Logged in, your requests in this room will now use your own backend. Your key is stored in this bot's private account data for this room, and is only used for your messages. Note that the operator of this bot can read it, and your message containing the key is still in the room history, so consider deleting it. Use `!chaz logout` to remove your key.
+logged-out = Logged out, your key has been removed
+
+## Images
+
+image-not-configured = Error: image generation is not configured
+image-upload-failed = Error: unable to upload image: { $error }
+
+## Rename
+
+rename-forbidden = Error: I don't have permission to rename the room
+topic-forbidden = Error: I don't have permission to set the topic
+
+## Summaries
+
+summary = Summary of { $window }:
+
+    { $summary }
+summary-conversation = the conversation
+summary-since = the last { $duration }
+summary-messages = the last { $count } messages
+summarize-empty = Nothing to summarize in { $window }
+summarize-usage = Error: Usage: !chaz summarize [since <duration>|<n> messages], e.g. since 24h or 50 messages
+
+## Admin
+
+admin-unknown = Error: unknown admin command. Usage: !chaz admin { $usage }
+admin-left = admin: left { $room }
+admin-leave-failed = Error: unable to leave { $room }: { $error }
+admin-posted = admin: posted to { $room }
+admin-post-failed = Error: unable to post to { $room }: { $error }
+admin-not-in-room = Error: not in room { $room }
+admin-blocked = admin: blocked { $user }
+admin-unblocked = admin: unblocked { $user }
+admin-quota-set = admin: daily message quota of { $user } set to { $quota }
+admin-quota-cleared = admin: { $user } uses the configured quota
+admin-quota-invalid = Error: the quota must be a number or none
+admin-reloaded = admin: reloaded the config
+admin-reload-failed = Error: unable to reload the config: { $error }
+admin-migrated = admin: migrated the model of { $count } rooms to tags
+queue-empty = admin: no questions are queued
+queue-report = admin: { $count } questions queued
+queue-entry = { $user } in { $room }, waiting { $seconds }s
+
+## Verification
+
+verify-status = verify status:
+
+    { $status }
+verify-confirmed = verify: confirmed, waiting for the other device
+verify-cancelled = verify: cancelled
+
+## Evaluation
+
+eval-suites = eval suites: { $suites }
+eval-no-suites = eval suites: none configured
+eval-unknown = Error: unknown eval suite { $suite }
+eval-results = eval results for **{ $suite }**
+
+    { $scorecard }
+
+## Names
+
+names = Name list: { $names }
+names-entry = { $user } is { $name }
+names-empty = Name list is empty
+name-set = Name for { $user } set to { $name }
+name-cleared = Name cleared
+name-invalid = Error: names can't contain '='
+
+## Workspaces
+
+workspace-current = Current workspace: { $workspace }
+
+    Workspaces:
+    { $workspaces }
+workspace-already = Already in workspace { $workspace }
+workspace-switched = Switched from workspace { $previous } to { $workspace }
+workspace-invalid = Error: workspace names can't contain ',' or '='
+
+## Accessibility
+
+accessible-on = accessible: on for you
+accessible-off = accessible: off for you
+accessible-on-room = accessible: on for this room
+accessible-off-room = accessible: off for this room
+accessible-usage = Error: Usage: !chaz accessible [on|off] [room]
+
+## Settings
+
+params = set: parameters for this room
+param-set = set: { $parameter } is { $value }
+param-default = set: { $parameter } uses the default
+param-usage = Error: Usage: !chaz set [{ $parameters } <value|none>]
+language = language: { $language }
+language-set = language: set to { $language }
+language-default = language: using the default
+language-not-set = language: not set
+format = format: { $format }
+format-set = format: set to { $format }
+format-default = format: using the default, { $format }
+format-usage = Error: Usage: !chaz format [markdown|plain|notice|none]
+not-set = not set
+
+## Context size
+
+context-expired = after a period of inactivity, starting a new conversation
+context-size = Context is ~{ $tokens } tokens, limit is { $limit }, TTL is { $ttl }
+context-limit-set = Context token limit set to { $limit }
+context-limit-removed = Context token limit removed
+context-usage = Error: invalid arguments. Usage: !chaz context [<tokens>|none|ttl <duration|none>]
+context-ttl-set = Context TTL set to { $ttl }, conversations expire after that long without messages
+context-ttl-disabled = Context TTL disabled
+context-ttl-usage = Error: invalid arguments. Usage: !chaz context ttl <duration|none>, e.g. 12h or 7d
+pruned-messages = Context pruned to the last { $limit } messages
+pruned-duration = Context pruned to the last { $limit }
+prune-usage = Error: invalid arguments. Usage: !chaz prune <N|duration>, e.g. 10 or 2h
+tokens = tokens:
+    { $tokens }
+tokens-model = Model: { $model } ({ $encoding })
+tokens-role = Role: ~{ $tokens } tokens
+tokens-messages = Messages: ~{ $tokens } tokens in { $count } messages
+tokens-media = Images and files: { $count }, counted by the backend
+tokens-total = Total: ~{ $tokens } tokens
+tokens-total-limit = Total: ~{ $tokens } of the { $limit } token limit
+tokens-near-limit = The context is close to the limit and the oldest messages will be dropped, `!chaz clear` to start fresh
+
+## Memories
+
+memory-known = That's already remembered
+memory-saved = Remembered
+memory-forgot = Forgot { $count } memories
+memory-missing = Error: No such memory, see !chaz memories
+memory-forget-usage = Error: the memories are forgotten by their number, see !chaz memories
+memory-empty = Nothing is remembered in this room, add memories with !chaz remember <fact>
+memory-list = Memories:
+    { $memories }
+memory-cleared = Forgot all the memories in this room
+memory-no-suggestions = Nothing new to remember in this conversation
+memory-suggestions = Suggested memories:
+    { $memories }
+    Keep them with !chaz memories keep <n>... or keep all
+memory-keep-nothing = Error: There are no suggestions to keep, see !chaz memories suggest
+memory-keep-usage = Error: Usage: !chaz memories keep <n>... or keep all
+memory-kept = Remembered { $count } memories
+
+## Aliases
+
+aliases-empty = There are no aliases in this room
+aliases = Aliases:
+    { $aliases }
+alias-unknown = Error: { $alias } isn't an alias in this room
+alias-removed = Alias { $alias } removed
+alias-limit = Error: rooms are limited to { $limit } aliases
+alias-added = Alias { $alias } → { $command } added
+alias-usage = Error: missing <command>. Usage: !chaz alias [<alias> <command>|remove <alias>]
+
+## Templates
+
+templates = Your templates:
+    { $templates }
+templates-empty = You have no templates, save one with !chaz template save <name> <prompt>
+template-saved = Template { $name } saved
+template-deleted = Template { $name } deleted
+template-unknown = Error: you have no template named { $name }
+template-arguments = Error: the template needs { $count } arguments
+
+## Schedule
+
+reminder-time = Error: the time has to be a delay like 30m or 2h, or a time of day in UTC like 14:30
+reminder-set = Reminder { $id } set for { $time }
+schedule-added = Scheduled prompt { $id }, next run at { $time }
+schedule-usage = Error: missing fields. Usage: !chaz schedule { $usage }
+schedule-empty = Nothing is scheduled in this room
+schedule = Scheduled in this room:
+schedule-cancel-usage = Error: Usage: !chaz schedule cancel <id>
+schedule-cancelled = Cancelled { $id }
+schedule-unknown = Error: there's no job { $id } of yours in this room
+
+## Checkpoints
+
+checkpoints-empty = No checkpoints in this room
+checkpoints = Checkpoints:
+    { $checkpoints }
+checkpoint-name = Error: checkpoint names can only have letters, numbers, - and _
+checkpoint-saved = Checkpoint { $name } saved, use `!chaz checkpoint restore { $name }` to come back to this point
+checkpoint-nothing = Error: there's no conversation to save yet
+checkpoint-restored = Context restored to checkpoint { $name }, the messages after it are ignored
+checkpoint-unknown = Error: no checkpoint named { $name }
+checkpoint-deleted = Checkpoint { $name } deleted
+
+## Documents
+
+documents-not-configured = Error: document indexing is not configured
+documents-empty = No files are indexed in this room
+documents = Indexed files:
+    { $files }
+document-unknown = Error: { $file } isn't indexed
+document-removed = Removed { $file } from the index
+documents-cleared = Removed all the files from the index
+documents-nothing-new = No new files to index, upload them to the room first
+documents-indexed = Indexed:
+    { $files }
+
+## Snippets
+
+snippets-empty = No snippets in this room, reply to a message with `!chaz snippet save <name>` to save one
+snippets = Snippets:
+    { $snippets }
+snippet-saved = Snippet { $name } saved, use it with `!chaz snippet use { $name }`
+snippet-save-failed = Error: unable to save the snippet, { $error }
+snippet-save-usage = Error: reply to the message to save with `!chaz snippet save <name>`
+snippet-unknown = Error: no snippet named { $name }
+snippet-deleted = Snippet { $name } deleted
+snippet-delete-failed = Error: unable to delete the snippet, { $error }
+
+## Access
+
+access-level = access: users with power level { $level } or more
+access-regex = access: users matching { $regex }
+access-everyone = access: everyone on the allow list
+access-forbidden = Error: only room admins can change who uses chaz in this room
+access-invalid = Error: { $rule } is neither a power level nor a regex
+access-set = access: set to { $rule }, room admins can always use chaz
+
+## JSON
+
+schemas-empty = No JSON schemas are configured
+schemas = JSON schemas:
+    { $schemas }
+schema-unknown = Error: no JSON schema named { $name }, see !chaz json
+schema-usage = Error: missing <prompt>. Usage: !chaz json <schema> <prompt>
+schema-mismatch = Error: the response didn't match the { $schema } schema after { $tries } tries: { $problem }
+
+## Prompt
+
+prompt-set = System prompt set for this room, use `!chaz prompt clear` to go back to the role
+prompt-cleared = System prompt cleared, the role is used again
+prompt-not-set = No system prompt is set for this room
+prompt-room = System prompt (set for this room):
+    { $prompt }
+prompt-role = System prompt (role { $role }):
+    { $prompt }
+prompt-none = No system prompt, set one with `!chaz prompt set <text>` or `!chaz role`
+
+## Preferences
+
+preference-set = Preference { $key } set to { $value }
+preference-unset = Preference { $key } unset
+preferences-empty = You haven't set any preferences, set them with !chaz prefs set <key> <value>
+preferences = Your preferences:
+    { $preferences }
+
+## Debug
+
+debug = debug: { $state }
+debug-forbidden = Error: only room admins can change debug mode
+debug-request = debug: Request to { $backend }, { $messages } messages, { $media } media files
+
+## Leaving
+
+leave-forbidden = Error: only room admins can make chaz leave
+goodbye = Goodbye!
+leave-failed = Error: unable to leave: { $error }
+
+## Verification
+
+verify-request = verify: { $user } wants to verify this device. Check that these match: { $code }
+
+    Then reply with `!chaz verify confirm`, or `!chaz verify cancel` if they don't match.
+verify-done = verify: this device is now verified
+
+## Polls
+
+poll-usage = Error: use !chaz poll <question> | <option> | <option>, with 2 to { $limit } options
+
+## Models
+
+images-unsupported = The model { $model } can't see images, so they're left out of the conversation
+
+## Updates
+
+update-available = Update available: chaz { $version } (running { $current })
@@ -0,0 +1,150 @@
+/// Accessibility mode
+///
+/// Produces responses that work well with screen readers: no emoji decorations, tables turned into lists, and
+/// short paragraphs. Images chaz sends get a generated description as their alt text.
+/// It can be enabled for the whole room or only for the sender, and is stored in the room tags under
+/// `is.chaz.accessibility`.
+use headjack::Tags;
+use matrix_sdk::{ruma::OwnedUserId, Room};
+use openai_api_rs::v1::chat_completion::MessageRole;
+
+use crate::{
+    backends::{ChatContext, Message},
+    get_backend, get_chat_summary_model, moderation,
+};
+
+/// Tag namespace for the settings
+const NAMESPACE: &str = "is.chaz.accessibility";
+
+/// Instructions added to the system prompt
+pub const PROMPT: &str = "Your responses are read with a screen reader. Do not use emoji or decorative symbols. Do not use tables, use lists instead. Keep paragraphs short, a few sentences at most.";
+
+/// Check if accessibility mode is on for the sender, or the whole room
+pub async fn is_enabled(room: &Room, sender: &OwnedUserId) -> bool {
+    let tags = Tags::new(room, NAMESPACE).await;
+    tags.get_value("room").as_deref() == Some("true")
+        || tags.get_value(sender.as_str()).as_deref() == Some("true")
+}
+
+/// Turn accessibility mode on or off for the sender, or the whole room if `user` is None
+pub async fn set_enabled(room: &Room, user: Option<&OwnedUserId>, enabled: bool) {
+    let mut tags = Tags::new(room, NAMESPACE).await;
+    let key = user.map(|user| user.as_str()).unwrap_or("room");
+    if enabled {
+        tags.replace_kv(key, "true");
+    } else {
+        tags.remove_kv(key);
+    }
+    tags.sync().await;
+}
+
+/// Clean up a response for screen readers
+///
+/// Models don't always follow the instructions, so the emoji and tables are removed here as well.
+pub fn format_response(response: &str) -> String {
+    let response = tables_to_lists(response);
+    let mut output = String::new();
+    let mut chars = response.chars().peekable();
+    while let Some(c) = chars.next() {
+        if is_emoji(c) {
+            // Don't leave a double space where the emoji was
+            if chars.peek() == Some(&' ') && (output.is_empty() || output.ends_with(' ')) {
+                chars.next();
+            }
+        } else {
+            output.push(c);
+        }
+    }
+    output
+}
+
+/// Check for emoji and other pictographs that are read out as noise
+fn is_emoji(c: char) -> bool {
+    matches!(c as u32,
+        0x1F000..=0x1FAFF // Emoji and pictographs
+        | 0x2600..=0x27BF // Miscellaneous symbols and dingbats
+        | 0x2B00..=0x2BFF // Arrows and stars
+        | 0xFE0F // Emoji presentation selector
+        | 0x200D // Zero width joiner
+    )
+}
+
+/// Split a markdown table row into its cells
+fn table_cells(line: &str) -> Vec<String> {
+    line.trim()
+        .trim_start_matches('|')
+        .trim_end_matches('|')
+        .split('|')
+        .map(|cell| cell.trim().to_string())
+        .collect()
+}
+
+/// Check if a line is the separator under a markdown table header, e.g. `|---|:---:|`
+fn is_separator(line: &str) -> bool {
+    table_cells(line)
+        .iter()
+        .all(|cell| !cell.is_empty() && cell.chars().all(|c| matches!(c, '-' | ':' | ' ')))
+}
+
+/// Convert markdown tables into lists, with each row as an item
+fn tables_to_lists(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut output = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let is_table = lines[i].trim_start().starts_with('|')
+            && lines.get(i + 1).is_some_and(|next| is_separator(next));
+        if !is_table {
+            output.push(lines[i].to_string());
+            i += 1;
+            continue;
+        }
+        let header = table_cells(lines[i]);
+        i += 2;
+        while i < lines.len() && lines[i].trim_start().starts_with('|') {
+            let item = table_cells(lines[i])
+                .iter()
+                .enumerate()
+                .map(|(column, cell)| match header.get(column) {
+                    Some(name) if !name.is_empty() => format!("{}: {}", name, cell),
+                    _ => cell.clone(),
+                })
+                .collect::<Vec<String>>()
+                .join(", ");
+            output.push(format!("- {}", item));
+            i += 1;
+        }
+    }
+    output.join("\n")
+}
+
+/// Write alt text for an image generated from the prompt
+///
+/// Falls back to the prompt itself if the description can't be generated, or is blocked by the moderation.
+pub async fn alt_text(room: &Room, prompt: &str) -> String {
+    let context = ChatContext {
+        messages: vec![Message::new(
+            MessageRole::user,
+            format!(
+                "Write one or two sentences of alt text for a screen reader, describing an image generated from this prompt. Do not output anything except for the alt text.\n\n{}",
+                prompt
+            ),
+        )],
+        model: get_chat_summary_model(),
+        media: Vec::new(),
+        tools: Vec::new(),
+        schema: None,
+        params: Default::default(),
+        reported_cost: Default::default(),
+        role: None,
+    };
+    match get_backend(room, None).await.execute(&context).await {
+        Ok(description)
+            if !description.trim().is_empty()
+                && !moderation::blocks_output(room, &description).await =>
+        {
+            description.trim().to_string()
+        }
+        _ => prompt.to_string(),
+    }
+}
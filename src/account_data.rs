@@ -0,0 +1,87 @@
+/// Account data
+///
+/// State that belongs to a room but shouldn't be visible to its members is kept in the bot's account data for
+/// that room, so it's stored on the homeserver along with the room. State that isn't tied to a room, like user
+/// preferences, is kept in the bot's global account data.
+use matrix_sdk::{
+    ruma::{
+        api::client::{
+            config::{get_room_account_data, set_room_account_data},
+            error::ErrorKind,
+        },
+        events::{GlobalAccountDataEventType, RoomAccountDataEventType},
+        serde::Raw,
+    },
+    Client, Room,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Largest value that can be stored, account data is limited to 64KiB
+pub const MAX_SIZE: usize = 60_000;
+
+/// Store a value in the room account data
+pub async fn set<T: Serialize>(room: &Room, event_type: &str, value: &T) -> Result<(), String> {
+    let client = room.client();
+    let user_id = client.user_id().ok_or("not logged in")?.to_owned();
+    let data = Raw::new(value).map_err(|e| e.to_string())?.cast();
+    let request = set_room_account_data::v3::Request::new_raw(
+        user_id,
+        room.room_id().to_owned(),
+        RoomAccountDataEventType::from(event_type),
+        data,
+    );
+    client
+        .send(request, None)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Get a value from the room account data
+///
+/// Returns None if nothing is stored, or if it's in a different format.
+pub async fn get<T: DeserializeOwned>(room: &Room, event_type: &str) -> Result<Option<T>, String> {
+    let client = room.client();
+    let user_id = client.user_id().ok_or("not logged in")?.to_owned();
+    let request = get_room_account_data::v3::Request::new(
+        user_id,
+        room.room_id().to_owned(),
+        RoomAccountDataEventType::from(event_type),
+    );
+    match client.send(request, None).await {
+        Ok(response) => Ok(response.account_data.deserialize_as::<T>().ok()),
+        // Nothing has been stored yet
+        Err(e) if e.client_api_error_kind() == Some(&ErrorKind::NotFound) => Ok(None),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Store a value in the global account data
+pub async fn set_global<T: Serialize>(
+    client: &Client,
+    event_type: &str,
+    value: &T,
+) -> Result<(), String> {
+    let data = Raw::new(value).map_err(|e| e.to_string())?.cast();
+    client
+        .account()
+        .set_account_data_raw(GlobalAccountDataEventType::from(event_type), data)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Get a value from the global account data
+///
+/// Returns None if nothing is stored, or if it's in a different format.
+pub async fn get_global<T: DeserializeOwned>(
+    client: &Client,
+    event_type: &str,
+) -> Result<Option<T>, String> {
+    let data = client
+        .account()
+        .fetch_account_data(GlobalAccountDataEventType::from(event_type))
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(data.and_then(|data| data.deserialize_as::<T>().ok()))
+}
@@ -0,0 +1,133 @@
+/// Additional accounts
+///
+/// Chaz can run more accounts in the same process, each with its own login and state, and a persona of its own:
+/// the role and model it uses by default. They share the backends, the commands, and the rest of the config.
+/// The state of each account is kept in `accounts/<name>` in the state directory.
+use std::{path::Path, sync::Mutex};
+
+use headjack::{Bot, BotConfig, Login};
+use lazy_static::lazy_static;
+use matrix_sdk::{
+    ruma::{api::client::filter::FilterDefinition, RoomId, UserId},
+    Client, Room,
+};
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::{auth, get_config, history, invites, register_handlers, session, sync, verification};
+
+/// Configuration for an additional account
+#[derive(Debug, Deserialize, Clone)]
+pub struct AccountConfig {
+    /// Name of the account, used for its state directory
+    pub name: String,
+    /// Homeserver of the account, defaults to the `homeserver_url`
+    pub homeserver_url: Option<String>,
+    pub username: String,
+    pub password: Option<String>,
+    pub access_token: Option<String>,
+    /// Role used by default in the rooms of this account, instead of `role`
+    pub role: Option<String>,
+    /// Model used by default in the rooms of this account, when the room doesn't set one
+    pub model: Option<String>,
+}
+
+lazy_static! {
+    /// The clients of the running accounts, with the name of the account or None for the main one
+    static ref CLIENTS: Mutex<Vec<(Option<String>, Client)>> = Mutex::new(Vec::new());
+}
+
+/// Keep track of the client of an account once it's logged in
+pub fn add(name: Option<String>, client: Client) {
+    CLIENTS.lock().unwrap().push((name, client));
+}
+
+/// Find a room that any of the accounts is in
+pub fn get_room(room_id: &RoomId) -> Option<Room> {
+    CLIENTS
+        .lock()
+        .unwrap()
+        .iter()
+        .find_map(|(_, client)| client.get_room(room_id))
+}
+
+/// Check if a user is one of the accounts, so they don't answer each other
+pub fn is_own_user(user: &UserId) -> bool {
+    CLIENTS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|(_, client)| client.user_id() == Some(user))
+}
+
+/// Get the config of the additional account a room belongs to, None for the main account
+pub fn settings(room: &Room) -> Option<AccountConfig> {
+    let client = room.client();
+    let name = CLIENTS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(_, other)| other.user_id() == client.user_id())
+        .and_then(|(name, _)| name.clone())?;
+    get_config()
+        .accounts
+        .unwrap_or_default()
+        .into_iter()
+        .find(|account| account.name == name)
+}
+
+/// Log in to an additional account and answer its rooms until the sync fails
+pub async fn run(account: AccountConfig, state_dir: &Path, filter: FilterDefinition) {
+    let config = get_config();
+    let homeserver_url = account
+        .homeserver_url
+        .clone()
+        .unwrap_or(config.homeserver_url.clone());
+    let state_dir = state_dir.join("accounts").join(&account.name);
+    let mut bot = Bot::new(BotConfig {
+        // The prefix is the same for every account, so the commands are too
+        command_prefix: Some("!chaz ".to_string()),
+        room_size_limit: config.room_size_limit,
+        login: Login {
+            homeserver_url: homeserver_url.clone(),
+            username: account.username.clone(),
+            password: account.password.clone(),
+        },
+        name: Some(format!("chaz-{}", account.name)),
+        allow_list: Some(".*".to_string()),
+        state_dir: Some(state_dir.to_string_lossy().to_string()),
+    })
+    .await;
+
+    let session_file = state_dir.join("session");
+    session::check(&session_file);
+    if let Err(e) = auth::login(
+        &state_dir,
+        &homeserver_url,
+        false,
+        account.access_token.as_deref(),
+    )
+    .await
+    {
+        error!("Error logging in to {}: {e}", account.name);
+        return;
+    }
+    if let Err(e) = bot.login().await {
+        error!("Error logging in to {}: {e}", account.name);
+        return;
+    }
+    verification::register_handlers(bot.client());
+    invites::join_rooms(bot.client());
+    history::register_handler(bot.client());
+
+    if let Err(e) = sync::initial_sync(bot.client(), &session_file, filter.clone()).await {
+        info!("Error syncing {}: {e}", account.name);
+    }
+    add(Some(account.name.clone()), bot.client().clone());
+    register_handlers(&bot);
+    info!("Account {} is ready as {}", account.name, bot.full_name());
+
+    if let Err(e) = sync::run(bot.client(), &session_file, filter).await {
+        error!("Error running account {}: {e}", account.name);
+    }
+}
@@ -0,0 +1,58 @@
+/// Typing notifications and read receipts
+///
+/// Shows that chaz is working on a response, so a slow backend doesn't look like a stuck bot.
+use std::{future::Future, time::Duration};
+
+use matrix_sdk::{
+    ruma::{
+        api::client::receipt::create_receipt::v3::ReceiptType, events::receipt::ReceiptThread,
+        EventId,
+    },
+    Room,
+};
+use tracing::warn;
+
+use crate::get_config;
+
+/// How often the typing notification is refreshed, it expires after 4 seconds
+const TYPING_REFRESH: Duration = Duration::from_secs(3);
+
+/// Show chaz as typing in the room until the future completes
+pub async fn while_typing<F: Future>(room: &Room, future: F) -> F::Output {
+    if !get_config().typing_notifications.unwrap_or(true) {
+        return future.await;
+    }
+    let typing_room = room.clone();
+    let refresh = tokio::spawn(async move {
+        loop {
+            if let Err(e) = typing_room.typing_notice(true).await {
+                warn!("Unable to send a typing notification: {}", e);
+                return;
+            }
+            tokio::time::sleep(TYPING_REFRESH).await;
+        }
+    });
+    let output = future.await;
+    refresh.abort();
+    if let Err(e) = room.typing_notice(false).await {
+        warn!("Unable to clear the typing notification: {}", e);
+    }
+    output
+}
+
+/// Mark a message as read by chaz
+pub async fn mark_read(room: &Room, event_id: &EventId) {
+    if !get_config().read_receipts.unwrap_or(true) {
+        return;
+    }
+    if let Err(e) = room
+        .send_single_receipt(
+            ReceiptType::Read,
+            ReceiptThread::Unthreaded,
+            event_id.to_owned(),
+        )
+        .await
+    {
+        warn!("Unable to send a read receipt: {}", e);
+    }
+}
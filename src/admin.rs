@@ -0,0 +1,99 @@
+/// Admin state
+///
+/// Users blocked by the admins and per-user quota overrides, set with `!chaz admin`.
+/// They are saved in the state directory.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{session, util::read_state};
+
+#[derive(Serialize, Deserialize, Default)]
+struct AdminState {
+    /// Users that chaz ignores
+    blocked: BTreeSet<String>,
+    /// Daily message quota for specific users, overriding the config
+    daily_messages: BTreeMap<String, u64>,
+}
+
+struct AdminFile {
+    path: PathBuf,
+    state: AdminState,
+}
+
+lazy_static! {
+    static ref ADMIN: Mutex<Option<AdminFile>> = Mutex::new(None);
+}
+
+/// Load the saved state from the state directory
+pub fn init(state_dir: &Path) -> anyhow::Result<()> {
+    let path = state_dir.join("admin.json");
+    let state = read_state(&path)?;
+    *ADMIN.lock().unwrap() = Some(AdminFile { path, state });
+    Ok(())
+}
+
+/// Check if a user is blocked
+pub fn is_blocked(user: &str) -> bool {
+    ADMIN
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|admin| admin.state.blocked.contains(user))
+}
+
+/// Block or unblock a user
+pub fn set_blocked(user: &str, blocked: bool) {
+    update(|state| {
+        if blocked {
+            state.blocked.insert(user.to_string());
+        } else {
+            state.blocked.remove(user);
+        }
+    });
+}
+
+/// Get the daily message quota set for a user
+pub fn daily_messages(user: &str) -> Option<u64> {
+    ADMIN
+        .lock()
+        .unwrap()
+        .as_ref()?
+        .state
+        .daily_messages
+        .get(user)
+        .copied()
+}
+
+/// Set the daily message quota for a user, or go back to the config with None
+pub fn set_daily_messages(user: &str, quota: Option<u64>) {
+    update(|state| match quota {
+        Some(quota) => {
+            state.daily_messages.insert(user.to_string(), quota);
+        }
+        None => {
+            state.daily_messages.remove(user);
+        }
+    });
+}
+
+/// Change the state and save it
+fn update(change: impl FnOnce(&mut AdminState)) {
+    let mut admin = ADMIN.lock().unwrap();
+    let Some(admin) = admin.as_mut() else {
+        return;
+    };
+    change(&mut admin.state);
+    let result = serde_json::to_string(&admin.state)
+        .map_err(anyhow::Error::from)
+        .and_then(|contents| Ok(session::write(&admin.path, &contents)?));
+    if let Err(e) = result {
+        error!("Unable to save the admin state: {}", e);
+    }
+}
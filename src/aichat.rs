@@ -1,15 +1,27 @@
 /// AIChat Backend
 ///
 /// Implements an interface to AIChat to use it as a general backend for LLMs.
-use std::process::Command;
-use tracing::info;
+use std::{
+    process::Output,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
-use crate::{backends::LLMBackend, Backend, ChatContext};
+use async_trait::async_trait;
+use tokio::process::Command;
+use tracing::{error, info, warn};
+
+use crate::{backends::LLMBackend, parse_duration, Backend, ChatContext};
+
+/// How long aichat can run when the backend doesn't set a `timeout`
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
 
 pub struct AiChat {
     binary_location: String,
     config_dir: Option<String>,
     backend: Backend,
+    /// Set once the parameters aichat can't take have been warned about, so it's only logged once
+    warned: AtomicBool,
 }
 
 impl AiChat {
@@ -18,15 +30,42 @@ impl AiChat {
             binary_location: "aichat".to_string(),
             config_dir: backend.config_dir.clone(),
             backend: backend.clone(),
+            warned: AtomicBool::new(false),
+        }
+    }
+
+    /// Run aichat, killing it if it takes longer than the timeout or the request is cancelled
+    async fn run(&self, mut command: Command) -> Result<Output, String> {
+        let timeout = self
+            .backend
+            .timeout
+            .as_deref()
+            .and_then(parse_duration)
+            .unwrap_or(DEFAULT_TIMEOUT);
+        command.kill_on_drop(true);
+        match tokio::time::timeout(timeout, command.output()).await {
+            Ok(Ok(output)) => Ok(output),
+            Ok(Err(e)) => {
+                error!("Unable to run aichat: {}", e);
+                Err(format!("Unable to run aichat: {}", e))
+            }
+            Err(_) => {
+                error!("aichat timed out after {:?}", timeout);
+                Err(format!(
+                    "aichat didn't respond within {} seconds",
+                    timeout.as_secs()
+                ))
+            }
         }
     }
 }
 
+#[async_trait]
 impl LLMBackend for AiChat {
     /// List the models known to the aichat binary
     ///
     /// This may not be a comprehensive list of available models.
-    fn list_models(&self) -> Vec<String> {
+    async fn list_models(&self) -> Vec<String> {
         let mut command = Command::new(self.binary_location.clone());
         command.arg("--list-models");
 
@@ -36,7 +75,7 @@ impl LLMBackend for AiChat {
         }
 
         // Return empty vec if command fails instead of panicking
-        let output = match command.output() {
+        let output = match self.run(command).await {
             Ok(output) => output,
             Err(_) => return Vec::new(),
         };
@@ -52,7 +91,7 @@ impl LLMBackend for AiChat {
     /// Get the default model for the current aichat config
     ///
     /// Query the aichat binary for the known models.
-    fn default_model(&self) -> Option<String> {
+    async fn default_model(&self) -> Option<String> {
         let mut command = Command::new(self.binary_location.clone());
         command.arg("--info");
 
@@ -62,7 +101,7 @@ impl LLMBackend for AiChat {
         }
 
         // Return None if command fails instead of panicking
-        let output = match command.output() {
+        let output = match self.run(command).await {
             Ok(output) => output,
             Err(_) => return None,
         };
@@ -77,14 +116,56 @@ impl LLMBackend for AiChat {
             .and_then(|s| s.split_whitespace().nth(1).map(String::from))
     }
 
+    /// The arguments of the aichat command, with the number of files instead of their paths
+    async fn describe_request(&self, context: &ChatContext) -> Result<serde_json::Value, String> {
+        let model_prefix = self.backend.name.clone().unwrap_or("aichat".to_string());
+        let model = context
+            .model
+            .as_deref()
+            .map(|model| model.trim_start_matches(&format!("{}:", model_prefix)));
+        Ok(serde_json::json!({
+            "command": self.binary_location,
+            "model": model,
+            "temperature": context.params.temperature,
+            "top_p": context.params.top_p,
+            "files": context.media.len(),
+            "prompt": context.string_prompt_with_role(),
+        }))
+    }
+
     async fn execute(&self, context: &ChatContext) -> Result<String, String> {
         let mut command = Command::new(&self.binary_location);
         command.arg("--no-stream");
+        let mut params = context.params.clone();
         if let Some(model) = &context.model {
             let model_prefix = self.backend.name.clone().unwrap_or("aichat".to_string());
 
             let model = model.trim_start_matches(&format!("{}:", model_prefix));
             command.arg("--model").arg(model);
+            params = params.or(self.backend.model_params(model));
+        }
+        // AIChat has no flags for these, but reads its settings from the environment
+        // The other parameters are left to the AIChat config
+        if let Some(temperature) = params.temperature {
+            command.env("AICHAT_TEMPERATURE", temperature.to_string());
+        }
+        if let Some(top_p) = params.top_p {
+            command.env("AICHAT_TOP_P", top_p.to_string());
+        }
+        let ignored: Vec<&str> = [
+            ("max_tokens", params.max_tokens.is_some()),
+            ("frequency_penalty", params.frequency_penalty.is_some()),
+            ("stop", params.stop.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, set)| set.then_some(name))
+        .collect();
+        if !ignored.is_empty() && !self.warned.swap(true, Ordering::Relaxed) {
+            warn!(
+                "The aichat backend {} can't pass {} to aichat, they're ignored",
+                self.backend.get_name(),
+                ignored.join(", ")
+            );
         }
         if let Some(config_dir) = &self.config_dir {
             command.env("AICHAT_CONFIG_DIR", config_dir);
@@ -101,7 +182,7 @@ impl LLMBackend for AiChat {
         command.arg("--").arg(context.string_prompt_with_role());
         info!("Running command: {:?}", command);
 
-        let output = command.output().expect("Failed to execute command");
+        let output = self.run(command).await?;
 
         info!("Output: {:?}", output);
 
@@ -0,0 +1,174 @@
+/// Command aliases
+///
+/// Aliases are shortcuts for commands, like `!c` for `!chaz` or `!sum` for `!chaz summarize`. Operators define
+/// them for every room with `aliases` in the config, and users add their own to a room with `!chaz alias`.
+/// Room aliases are stored in the room account data under `is.chaz.aliases`, and win over the config.
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use lazy_static::lazy_static;
+use matrix_sdk::{
+    ruma::{events::room::message::RoomMessageEventContent, OwnedRoomId, OwnedUserId},
+    Room,
+};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{account_data, commands::Args, error::ChazError, get_config, i18n};
+
+/// Account data type the room aliases are stored in
+const ACCOUNT_DATA_TYPE: &str = "is.chaz.aliases";
+
+/// Maximum number of aliases in a room
+const MAX_ALIASES: usize = 50;
+
+/// The stored aliases of a room
+#[derive(Serialize, Deserialize, Default)]
+struct RoomAliases {
+    aliases: BTreeMap<String, String>,
+}
+
+lazy_static! {
+    /// Room aliases that have been loaded, by room
+    static ref ROOM_ALIASES: Mutex<HashMap<OwnedRoomId, BTreeMap<String, String>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// The aliases that apply in a room
+pub struct Aliases(BTreeMap<String, String>);
+
+impl Aliases {
+    /// Replace an alias at the start of a message with what it stands for
+    ///
+    /// The alias has to be the whole first word, so `!c` doesn't match `!clear`.
+    pub fn expand<'a>(&self, body: &'a str) -> Cow<'a, str> {
+        let body_trimmed = body.trim_start();
+        let first = body_trimmed.split_whitespace().next().unwrap_or_default();
+        match self.0.get(first) {
+            Some(expansion) => Cow::Owned(format!("{}{}", expansion, &body_trimmed[first.len()..])),
+            None => Cow::Borrowed(body),
+        }
+    }
+}
+
+/// Get the aliases of a room from the account data, loading them if needed
+async fn room_aliases(room: &Room) -> Result<BTreeMap<String, String>, String> {
+    if let Some(aliases) = ROOM_ALIASES.lock().unwrap().get(room.room_id()) {
+        return Ok(aliases.clone());
+    }
+    let aliases = account_data::get::<RoomAliases>(room, ACCOUNT_DATA_TYPE)
+        .await?
+        .unwrap_or_default()
+        .aliases;
+    ROOM_ALIASES
+        .lock()
+        .unwrap()
+        .insert(room.room_id().to_owned(), aliases.clone());
+    Ok(aliases)
+}
+
+/// Save the aliases of a room
+async fn save(room: &Room, aliases: BTreeMap<String, String>) -> Result<(), String> {
+    let stored = RoomAliases { aliases };
+    account_data::set(room, ACCOUNT_DATA_TYPE, &stored).await?;
+    ROOM_ALIASES
+        .lock()
+        .unwrap()
+        .insert(room.room_id().to_owned(), stored.aliases);
+    Ok(())
+}
+
+/// Get the aliases that apply in a room, from the config and the room
+pub async fn load(room: &Room) -> Aliases {
+    let mut aliases: BTreeMap<String, String> = get_config()
+        .aliases
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    match room_aliases(room).await {
+        Ok(room_aliases) => aliases.extend(room_aliases),
+        Err(e) => error!("Unable to load the aliases of {}: {}", room.room_id(), e),
+    }
+    Aliases(aliases)
+}
+
+/// Check that an alias can be used
+fn validate(alias: &str, expansion: &str) -> Result<(), String> {
+    if !alias.starts_with('!') || alias.len() < 2 {
+        return Err("aliases start with !, like !sum".to_string());
+    }
+    if alias == "!chaz" {
+        return Err("!chaz can't be an alias".to_string());
+    }
+    if !expansion.starts_with("!chaz") {
+        return Err("aliases stand for a command that starts with !chaz".to_string());
+    }
+    Ok(())
+}
+
+/// List, add, or remove the aliases of the room
+///
+/// `!chaz alias [<alias> <command>|remove <alias>]`
+pub async fn alias(_: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let response = match (args.subcommand(), args.get("alias"), args.get("command")) {
+        (_, None, _) => {
+            let aliases = load(&room).await;
+            if aliases.0.is_empty() {
+                i18n::notice_text(&room, "aliases-empty", &[]).await
+            } else {
+                let aliases = aliases
+                    .0
+                    .iter()
+                    .map(|(alias, expansion)| format!("{} → {}", alias, expansion))
+                    .collect::<Vec<String>>()
+                    .join("\n");
+                i18n::notice_text(&room, "aliases", &[("aliases", &aliases)]).await
+            }
+        }
+        (Some(_), Some(alias), _) => match room_aliases(&room).await {
+            Ok(mut aliases) => {
+                if aliases.remove(alias).is_none() {
+                    i18n::notice_text(&room, "alias-unknown", &[("alias", alias)]).await
+                } else {
+                    match save(&room, aliases).await {
+                        Ok(()) => {
+                            i18n::notice_text(&room, "alias-removed", &[("alias", alias)]).await
+                        }
+                        Err(e) => i18n::error_text(&room, &e).await,
+                    }
+                }
+            }
+            Err(e) => i18n::error_text(&room, &e).await,
+        },
+        (None, Some(alias), Some(expansion)) => {
+            let expansion = expansion.to_string();
+            match validate(alias, &expansion) {
+                Ok(()) => match room_aliases(&room).await {
+                    Ok(aliases) if aliases.len() >= MAX_ALIASES && !aliases.contains_key(alias) => {
+                        let limit = MAX_ALIASES.to_string();
+                        i18n::notice_text(&room, "alias-limit", &[("limit", &limit)]).await
+                    }
+                    Ok(mut aliases) => {
+                        aliases.insert(alias.to_string(), expansion.clone());
+                        match save(&room, aliases).await {
+                            Ok(()) => {
+                                let args = [("alias", alias), ("command", expansion.as_str())];
+                                i18n::notice_text(&room, "alias-added", &args).await
+                            }
+                            Err(e) => i18n::error_text(&room, &e).await,
+                        }
+                    }
+                    Err(e) => i18n::error_text(&room, &e).await,
+                },
+                Err(e) => i18n::error_text(&room, &e).await,
+            }
+        }
+        (None, Some(_), None) => i18n::notice_text(&room, "alias-usage", &[]).await,
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
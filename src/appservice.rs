@@ -0,0 +1,717 @@
+/// Appservice mode
+///
+/// Instead of logging in as a user, chaz can run as a Matrix application service. The homeserver then pushes the
+/// room events to chaz, and chaz acts as a set of virtual users, one per persona, e.g. `@chaz_gpt4o` and
+/// `@chaz_llama`. Each persona has its own model and role, so users can invite several of them to a room and
+/// address the one they want by mentioning it. The registration file for the homeserver is written to
+/// `appservice.yaml` in the state directory.
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+use lazy_static::lazy_static;
+use matrix_sdk::ruma::{
+    events::room::message::RoomMessageEventContent, OwnedRoomId, OwnedUserId, RoomId,
+};
+use openai_api_rs::v1::chat_completion::MessageRole;
+use rand::Rng;
+use reqwest::{Method, Url};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tracing::{error, info, warn};
+
+use crate::{
+    admin,
+    audit::Requester,
+    backends::{BackendManager, ChatContext, Message},
+    check_limits, context, get_config, get_response_deadline, http, i18n, is_admin, is_allowed,
+    metrics, moderation, permissions, record_tokens,
+    role::get_role,
+    shutdown, DEFAULT_CONFIG,
+};
+
+/// Largest transaction accepted from the homeserver
+const MAX_REQUEST_SIZE: usize = 10 * 1024 * 1024;
+
+/// Number of messages read from the room history for the context
+const HISTORY_LIMIT: u32 = 50;
+
+/// Number of transaction IDs remembered, the homeserver only resends the recent ones
+const TRANSACTION_HISTORY: usize = 1000;
+
+/// Configuration for the appservice mode
+#[derive(Debug, Deserialize, Clone)]
+pub struct AppserviceConfig {
+    /// Port the homeserver sends the events to
+    pub port: u16,
+    /// Address to listen on, defaults to 127.0.0.1
+    ///
+    /// The transactions are plain HTTP, so only listen on other addresses behind a TLS proxy.
+    pub bind: Option<String>,
+    /// URL the homeserver reaches chaz at, defaults to http://localhost:<port>
+    pub url: Option<String>,
+    /// Token chaz uses to talk to the homeserver
+    pub as_token: String,
+    /// Token the homeserver uses to talk to chaz
+    pub hs_token: String,
+    /// Localpart of the appservice's own user, defaults to chaz
+    pub sender_localpart: Option<String>,
+    /// The virtual users, each with its own model and role
+    pub personas: Vec<Persona>,
+}
+
+/// A virtual user of the appservice
+#[derive(Debug, Deserialize, Clone)]
+pub struct Persona {
+    /// Localpart of the user, e.g. chaz_gpt4o
+    pub localpart: String,
+    pub display_name: Option<String>,
+    /// Model used by the persona, defaults to the default model of the backends
+    pub model: Option<String>,
+    /// Role used by the persona, defaults to `role`
+    pub role: Option<String>,
+}
+
+lazy_static! {
+    /// Recent transactions already handled, the homeserver resends them if the response is lost
+    static ref TRANSACTIONS: Mutex<(HashSet<String>, VecDeque<String>)> = Mutex::new(Default::default());
+
+    /// Rooms each persona has joined, by localpart
+    static ref MEMBERSHIPS: Mutex<HashMap<String, HashSet<String>>> = Mutex::new(HashMap::new());
+}
+
+/// The Client-Server API of the homeserver, used as the appservice
+#[derive(Clone)]
+struct Homeserver {
+    url: String,
+    as_token: String,
+    http: reqwest::Client,
+}
+
+impl Homeserver {
+    /// Send a request, optionally as one of the virtual users
+    async fn request(
+        &self,
+        method: Method,
+        path: &[&str],
+        user_id: Option<&str>,
+        query: &[(&str, &str)],
+        body: Option<Value>,
+    ) -> anyhow::Result<Value> {
+        let mut url = Url::parse(&self.url)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("invalid homeserver URL {}", self.url))?
+            .pop_if_empty()
+            .extend(["_matrix", "client", "v3"])
+            .extend(path);
+        {
+            let mut pairs = url.query_pairs_mut();
+            if let Some(user_id) = user_id {
+                pairs.append_pair("user_id", user_id);
+            }
+            for (key, value) in query {
+                pairs.append_pair(key, value);
+            }
+        }
+        let mut request = self.http.request(method, url).bearer_auth(&self.as_token);
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let body: Value = response.json().await.unwrap_or_default();
+        if !status.is_success() {
+            return Err(anyhow!(
+                "{}: {}",
+                status,
+                body["error"].as_str().unwrap_or_default()
+            ));
+        }
+        Ok(body)
+    }
+
+    /// Register a virtual user, it's fine if it already exists
+    async fn register(&self, localpart: &str) -> anyhow::Result<()> {
+        let body = json!({ "type": "m.login.application_service", "username": localpart });
+        match self
+            .request(Method::POST, &["register"], None, &[], Some(body))
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) if e.to_string().starts_with("400") => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Everything the request handlers need
+#[derive(Clone)]
+struct Appservice {
+    config: AppserviceConfig,
+    homeserver: Homeserver,
+    server_name: String,
+}
+
+impl Appservice {
+    /// Full user ID of a persona
+    fn user_id(&self, persona: &Persona) -> String {
+        format!("@{}:{}", persona.localpart, self.server_name)
+    }
+
+    /// Find the persona with this user ID
+    fn persona(&self, user_id: &str) -> Option<&Persona> {
+        self.config
+            .personas
+            .iter()
+            .find(|persona| self.user_id(persona) == user_id)
+    }
+
+    /// Full user ID of the appservice's own user
+    fn sender_user_id(&self) -> String {
+        format!("@{}:{}", sender_localpart(&self.config), self.server_name)
+    }
+
+    /// Check if a user belongs to the appservice, so the personas don't answer each other on their own
+    fn is_own_user(&self, user_id: &str) -> bool {
+        self.persona(user_id).is_some() || user_id == self.sender_user_id()
+    }
+
+    /// Check if the access rule of the room lets a user talk to the personas
+    ///
+    /// The rule is the one set with `!chaz access`, read from the room tags of the appservice's own user, so it's
+    /// shared with chaz running as that user. Without a rule everyone on the allow_list can use them.
+    async fn room_allows(&self, persona: &Persona, room_id: &str, sender: &OwnedUserId) -> bool {
+        let own_user = self.sender_user_id();
+        let tags = match self
+            .homeserver
+            .request(
+                Method::GET,
+                &["user", &own_user, "rooms", room_id, "tags"],
+                Some(&own_user),
+                &[],
+                None,
+            )
+            .await
+        {
+            Ok(tags) => tags,
+            Err(e) => {
+                error!("Unable to read the access rule of {}: {}", room_id, e);
+                return false;
+            }
+        };
+        let prefix = format!("{}.rule=", permissions::ACCESS_NAMESPACE);
+        let Some(rule) = tags["tags"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .find_map(|(tag, _)| tag.strip_prefix(&prefix))
+        else {
+            return true;
+        };
+        if is_admin(sender) {
+            return true;
+        }
+        let user_id = self.user_id(persona);
+        let level = match self
+            .homeserver
+            .request(
+                Method::GET,
+                &["rooms", room_id, "state", "m.room.power_levels", ""],
+                Some(&user_id),
+                &[],
+                None,
+            )
+            .await
+        {
+            Ok(levels) => levels["users"][sender.as_str()]
+                .as_i64()
+                .or(levels["users_default"].as_i64())
+                .unwrap_or(0),
+            Err(e) => {
+                error!("Unable to read the power levels of {}: {}", room_id, e);
+                0
+            }
+        };
+        permissions::rule_allows(rule, sender.as_str(), level)
+    }
+}
+
+/// Localpart of the appservice's own user
+fn sender_localpart(config: &AppserviceConfig) -> String {
+    config
+        .sender_localpart
+        .clone()
+        .unwrap_or("chaz".to_string())
+}
+
+/// Write the registration file the homeserver needs to load the appservice
+fn write_registration(config: &AppserviceConfig, path: &Path) -> anyhow::Result<()> {
+    let users: Vec<String> = std::iter::once(sender_localpart(config))
+        .chain(config.personas.iter().map(|p| p.localpart.clone()))
+        .map(|localpart| regex::escape(&localpart))
+        .collect();
+    let registration = json!({
+        "id": "chaz",
+        "url": config.url.clone().unwrap_or(format!("http://localhost:{}", config.port)),
+        "as_token": config.as_token,
+        "hs_token": config.hs_token,
+        "sender_localpart": sender_localpart(config),
+        "rate_limited": false,
+        "namespaces": {
+            "users": [{ "exclusive": true, "regex": format!("@({}):.*", users.join("|")) }],
+            "aliases": [],
+            "rooms": [],
+        },
+    });
+    std::fs::create_dir_all(path.parent().unwrap_or(Path::new(".")))?;
+    std::fs::write(path, serde_yaml::to_string(&registration)?)?;
+    Ok(())
+}
+
+/// Run chaz as an appservice until it's asked to shut down
+pub async fn run(
+    config: AppserviceConfig,
+    homeserver_url: &str,
+    state_dir: &Path,
+) -> anyhow::Result<()> {
+    let registration = state_dir.join("appservice.yaml");
+    write_registration(&config, &registration)?;
+    info!(
+        "Add {} to the appservices of the homeserver",
+        registration.display()
+    );
+
+    let homeserver = Homeserver {
+        url: homeserver_url.to_string(),
+        as_token: config.as_token.clone(),
+        http: reqwest::Client::new(),
+    };
+    let whoami = homeserver
+        .request(Method::GET, &["account", "whoami"], None, &[], None)
+        .await
+        .context("the homeserver doesn't know the appservice, is the registration loaded?")?;
+    let server_name = whoami["user_id"]
+        .as_str()
+        .and_then(|user_id| user_id.split_once(':'))
+        .map(|(_, server_name)| server_name.to_string())
+        .ok_or(anyhow!("unexpected whoami response {}", whoami))?;
+    let appservice = Appservice {
+        config,
+        homeserver,
+        server_name,
+    };
+
+    for persona in &appservice.config.personas {
+        if let Err(e) = appservice.homeserver.register(&persona.localpart).await {
+            error!("Unable to register {}: {}", persona.localpart, e);
+            continue;
+        }
+        if let Some(display_name) = &persona.display_name {
+            let user_id = appservice.user_id(persona);
+            if let Err(e) = appservice
+                .homeserver
+                .request(
+                    Method::PUT,
+                    &["profile", &user_id, "displayname"],
+                    Some(&user_id),
+                    &[],
+                    Some(json!({ "displayname": display_name })),
+                )
+                .await
+            {
+                error!("Unable to set the display name of {}: {}", user_id, e);
+            }
+        }
+        // The rooms joined before a restart aren't in the transactions
+        let user_id = appservice.user_id(persona);
+        match appservice
+            .homeserver
+            .request(Method::GET, &["joined_rooms"], Some(&user_id), &[], None)
+            .await
+        {
+            Ok(joined) => {
+                let rooms = joined["joined_rooms"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter_map(Value::as_str)
+                    .map(str::to_string)
+                    .collect();
+                MEMBERSHIPS
+                    .lock()
+                    .unwrap()
+                    .insert(persona.localpart.clone(), rooms);
+            }
+            Err(e) => error!("Unable to list the rooms of {}: {}", user_id, e),
+        }
+    }
+
+    let bind = appservice
+        .config
+        .bind
+        .clone()
+        .unwrap_or("127.0.0.1".to_string());
+    let listener = TcpListener::bind((bind.as_str(), appservice.config.port)).await?;
+    info!(
+        "Running as an appservice on {}:{} with {} personas",
+        bind,
+        appservice.config.port,
+        appservice.config.personas.len()
+    );
+    let handler = {
+        let appservice = appservice.clone();
+        move |request| {
+            let appservice = appservice.clone();
+            async move {
+                let (status, body) = handle_request(request, &appservice).await;
+                http::Response::json(status, body)
+            }
+        }
+    };
+    tokio::select! {
+        _ = http::serve(listener, MAX_REQUEST_SIZE, handler) => {}
+        _ = shutdown::wait_for_signal() => {}
+    }
+    let timeout = get_config()
+        .shutdown_timeout
+        .and_then(|timeout| crate::parse_duration(&timeout))
+        .unwrap_or(Duration::from_secs(30));
+    if !shutdown::drain(timeout).await {
+        warn!(
+            "Shutting down with {} messages still being handled",
+            shutdown::in_flight()
+        );
+    }
+    Ok(())
+}
+
+/// Route a request from the homeserver, returning the status line and the JSON body
+async fn handle_request(request: http::Request, appservice: &Appservice) -> (&'static str, String) {
+    if !request.has_token(&appservice.config.hs_token) {
+        return (
+            "403 Forbidden",
+            json!({ "errcode": "M_FORBIDDEN" }).to_string(),
+        );
+    }
+    let segments = request.segments("/_matrix/app/v1");
+    match (request.method.as_str(), segments.as_slice()) {
+        ("PUT", [transactions, id]) if transactions == "transactions" => {
+            let Ok(transaction) = serde_json::from_slice::<Value>(&request.body) else {
+                return (
+                    "400 Bad Request",
+                    json!({ "errcode": "M_NOT_JSON" }).to_string(),
+                );
+            };
+            if is_new_transaction(id) {
+                for event in transaction["events"].as_array().into_iter().flatten() {
+                    let appservice = appservice.clone();
+                    let event = event.clone();
+                    tokio::spawn(async move { handle_event(&appservice, event).await });
+                }
+            }
+            ("200 OK", "{}".to_string())
+        }
+        ("GET", [users, user_id]) if users == "users" && appservice.persona(user_id).is_some() => {
+            ("200 OK", "{}".to_string())
+        }
+        _ => (
+            "404 Not Found",
+            json!({ "errcode": "M_NOT_FOUND" }).to_string(),
+        ),
+    }
+}
+
+/// Remember a transaction, returning false if it was already handled
+fn is_new_transaction(id: &str) -> bool {
+    let mut transactions = TRANSACTIONS.lock().unwrap();
+    let (seen, order) = &mut *transactions;
+    if !seen.insert(id.to_string()) {
+        return false;
+    }
+    order.push_back(id.to_string());
+    if order.len() > TRANSACTION_HISTORY {
+        if let Some(oldest) = order.pop_front() {
+            seen.remove(&oldest);
+        }
+    }
+    true
+}
+
+/// Handle an event pushed by the homeserver
+async fn handle_event(appservice: &Appservice, event: Value) {
+    let room_id = event["room_id"].as_str().unwrap_or_default();
+    let sender = event["sender"].as_str().unwrap_or_default();
+    match event["type"].as_str() {
+        Some("m.room.member") => {
+            let Some(persona) = event["state_key"]
+                .as_str()
+                .and_then(|user_id| appservice.persona(user_id))
+            else {
+                return;
+            };
+            let membership = event["content"]["membership"].as_str();
+            {
+                let mut memberships = MEMBERSHIPS.lock().unwrap();
+                let rooms = memberships.entry(persona.localpart.clone()).or_default();
+                match membership {
+                    Some("join") => {
+                        rooms.insert(room_id.to_string());
+                    }
+                    Some("leave") | Some("ban") => {
+                        rooms.remove(room_id);
+                    }
+                    _ => {}
+                }
+            }
+            if membership == Some("invite")
+                && is_allowed_sender(sender)
+                && !admin::is_blocked(sender)
+            {
+                let user_id = appservice.user_id(persona);
+                info!("{} was invited to {} by {}", user_id, room_id, sender);
+                if let Err(e) = appservice
+                    .homeserver
+                    .request(
+                        Method::POST,
+                        &["join", room_id],
+                        Some(&user_id),
+                        &[],
+                        Some(json!({})),
+                    )
+                    .await
+                {
+                    error!("{} was unable to join {}: {}", user_id, room_id, e);
+                }
+            }
+        }
+        Some("m.room.message") => {
+            let body = event["content"]["body"].as_str().unwrap_or_default();
+            if appservice.is_own_user(sender)
+                || !is_allowed_sender(sender)
+                || event["content"]["msgtype"] != "m.text"
+                || body.starts_with("!chaz")
+                || shutdown::is_draining()
+            {
+                return;
+            }
+            let Ok(sender) = OwnedUserId::try_from(sender) else {
+                return;
+            };
+            if admin::is_blocked(sender.as_str()) {
+                return;
+            }
+            let _in_flight = shutdown::track();
+            for persona in addressed_personas(appservice, room_id, &event) {
+                if !appservice.room_allows(&persona, room_id, &sender).await {
+                    return;
+                }
+                if let Err(reason) = check_limits(sender.as_str(), room_id, get_config()) {
+                    error!("User {} is rate limited: {}", sender, reason);
+                    metrics::record_rate_limited();
+                    let language = get_config().language;
+                    let content = notice(&language, "rate-limited", &[("reason", &reason)]);
+                    if let Err(e) = send(appservice, &persona, room_id, content).await {
+                        error!("Unable to send the message limit notice: {}", e);
+                    }
+                    return;
+                }
+                if let Err(e) = answer(appservice, &persona, room_id, &sender).await {
+                    error!(
+                        "{} was unable to answer in {}: {}",
+                        persona.localpart, room_id, e
+                    );
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Check the sender against the allow_list
+fn is_allowed_sender(sender: &str) -> bool {
+    matrix_sdk::ruma::UserId::parse(sender).is_ok_and(|sender| is_allowed(&sender))
+}
+
+/// The personas a message is addressed to
+///
+/// Personas answer when they're mentioned by user ID, localpart, or display name. In a room with only one of
+/// them, it answers everything.
+fn addressed_personas(appservice: &Appservice, room_id: &str, event: &Value) -> Vec<Persona> {
+    let body = event["content"]["body"]
+        .as_str()
+        .unwrap_or_default()
+        .to_lowercase();
+    let mentions: Vec<&str> = event["content"]["m.mentions"]["user_ids"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect();
+    let memberships = MEMBERSHIPS.lock().unwrap();
+    let in_room: Vec<&Persona> = appservice
+        .config
+        .personas
+        .iter()
+        .filter(|persona| {
+            memberships
+                .get(&persona.localpart)
+                .is_some_and(|rooms| rooms.contains(room_id))
+        })
+        .collect();
+    let mentioned: Vec<Persona> = in_room
+        .iter()
+        .filter(|persona| {
+            let user_id = appservice.user_id(persona);
+            mentions.contains(&user_id.as_str())
+                || body.contains(&user_id.to_lowercase())
+                || body.contains(&persona.localpart.to_lowercase())
+                || persona
+                    .display_name
+                    .as_ref()
+                    .is_some_and(|name| body.contains(&name.to_lowercase()))
+        })
+        .map(|persona| (*persona).clone())
+        .collect();
+    if mentioned.is_empty() && in_room.len() == 1 {
+        return vec![in_room[0].clone()];
+    }
+    mentioned
+}
+
+/// Answer the conversation in a room as a persona
+async fn answer(
+    appservice: &Appservice,
+    persona: &Persona,
+    room_id: &str,
+    sender: &OwnedUserId,
+) -> anyhow::Result<()> {
+    let user_id = appservice.user_id(persona);
+    let context = build_context(appservice, persona, room_id).await?;
+    let config = get_config();
+    let language = config.language.clone();
+    let backend = BackendManager::new(Vec::new(), None).with_requester(Requester {
+        room_id: OwnedRoomId::try_from(room_id).ok(),
+        user_id: Some(sender.clone()),
+    });
+    let started = Instant::now();
+    let result = backend
+        .execute_with_deadline(&context, get_response_deadline())
+        .await;
+    if let Ok(room_id) = <&RoomId>::try_from(room_id) {
+        record_tokens(room_id, sender, &backend, &context, &result, started);
+    }
+    let content = match result {
+        Ok(response) => {
+            info!("Response from {}: {}", user_id, response.replace('\n', " "));
+            match moderation::check_persona_response(room_id, &response).await {
+                Some(moderation::Action::Block) => return Ok(()),
+                Some(moderation::Action::Redact) => notice(&language, "moderated-response", &[]),
+                _ => config.format.unwrap_or_default().content(response),
+            }
+        }
+        Err(e) => {
+            error!("Error from {}: {}", user_id, e.replace('\n', " "));
+            notice(&language, "error", &[("message", &e.replace('\n', " "))])
+        }
+    };
+    send(appservice, persona, room_id, content).await
+}
+
+/// Send a message to a room as a persona
+async fn send(
+    appservice: &Appservice,
+    persona: &Persona,
+    room_id: &str,
+    content: RoomMessageEventContent,
+) -> anyhow::Result<()> {
+    let transaction = format!("chaz{}", rand::thread_rng().gen::<u64>());
+    appservice
+        .homeserver
+        .request(
+            Method::PUT,
+            &["rooms", room_id, "send", "m.room.message", &transaction],
+            Some(&appservice.user_id(persona)),
+            &[],
+            Some(serde_json::to_value(content)?),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Build a notice in the default language, starting with "!chaz" so it's left out of the context
+fn notice(language: &Option<String>, key: &str, args: &[(&str, &str)]) -> RoomMessageEventContent {
+    RoomMessageEventContent::notice_plain(format!(
+        "!chaz {}",
+        i18n::translate(language.as_deref(), key, args)
+    ))
+}
+
+/// Build the context for a persona from the recent messages in the room
+///
+/// Messages from the persona are its own, the rest of the room, including the other personas, are the users.
+async fn build_context(
+    appservice: &Appservice,
+    persona: &Persona,
+    room_id: &str,
+) -> anyhow::Result<ChatContext> {
+    let config = get_config();
+    let user_id = appservice.user_id(persona);
+    let limit = HISTORY_LIMIT.to_string();
+    let history = appservice
+        .homeserver
+        .request(
+            Method::GET,
+            &["rooms", room_id, "messages"],
+            Some(&user_id),
+            &[("dir", "b"), ("limit", &limit)],
+            None,
+        )
+        .await?;
+    let multi_user_context = config.multi_user_context.unwrap_or(false);
+    let mut messages = Vec::new();
+    for event in history["chunk"].as_array().into_iter().flatten() {
+        let body = event["content"]["body"].as_str().unwrap_or_default();
+        if event["type"] != "m.room.message" || body.is_empty() {
+            continue;
+        }
+        // The clear command still starts a new conversation
+        if body.starts_with("!chaz clear") {
+            break;
+        }
+        if body.starts_with("!chaz") {
+            continue;
+        }
+        let sender = event["sender"].as_str().unwrap_or_default();
+        let message = if sender == user_id {
+            Message::new(MessageRole::assistant, body)
+        } else {
+            Message::new(MessageRole::user, body)
+                .with_sender(multi_user_context.then(|| sender.to_string()))
+        };
+        messages.push(message);
+    }
+    messages.reverse();
+    let mut context = ChatContext {
+        messages,
+        model: persona.model.clone(),
+        media: Vec::new(),
+        tools: Vec::new(),
+        schema: None,
+        params: Default::default(),
+        reported_cost: Default::default(),
+        role: get_role(
+            persona.role.clone().or(config.role.clone()),
+            config.roles.clone(),
+            DEFAULT_CONFIG.roles.clone(),
+        ),
+    };
+    if let Some(limit) = config.context_token_limit {
+        context::truncate_context(&mut context, limit);
+    }
+    Ok(context)
+}
@@ -0,0 +1,116 @@
+/// Audit log
+///
+/// With `audit_log` set, every request sent to a backend is written to `audit.jsonl` in the state directory along
+/// with the response or error, when it was sent, how long it took, and the room and user it was sent for. The file
+/// is rotated by size like the log file. With `redact` set, the content of the messages and responses is replaced
+/// with its length, so the log shows who used which model when without keeping the conversations.
+use std::{
+    path::Path,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use matrix_sdk::ruma::{OwnedRoomId, OwnedUserId};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::error;
+
+use crate::{
+    logging::{LogFile, LogFileConfig},
+    util::format_utc,
+    ChatContext,
+};
+
+/// Configuration for the audit log
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct AuditConfig {
+    /// Leave the content of the messages and responses out of the log
+    pub redact: Option<bool>,
+    /// Size and number of the rotated files
+    #[serde(flatten)]
+    pub rotation: LogFileConfig,
+}
+
+struct AuditLog {
+    file: LogFile,
+    redact: bool,
+}
+
+lazy_static! {
+    static ref AUDIT_LOG: Mutex<Option<AuditLog>> = Mutex::new(None);
+}
+
+/// Start writing the audit log to `audit.jsonl` in the state directory
+pub fn init(state_dir: &Path, config: &AuditConfig) {
+    let path = state_dir.join("audit.jsonl");
+    match LogFile::open(path.clone(), &config.rotation) {
+        Ok(file) => {
+            *AUDIT_LOG.lock().unwrap() = Some(AuditLog {
+                file,
+                redact: config.redact.unwrap_or(false),
+            })
+        }
+        Err(e) => error!("Unable to open the audit log {}: {}", path.display(), e),
+    }
+}
+
+/// Who a request was sent for
+#[derive(Clone, Default)]
+pub struct Requester {
+    pub room_id: Option<OwnedRoomId>,
+    pub user_id: Option<OwnedUserId>,
+}
+
+/// Write a backend request and its result to the audit log, if it's enabled
+pub fn record(
+    requester: &Requester,
+    backend: &str,
+    context: &ChatContext,
+    elapsed: Duration,
+    result: &Result<String, String>,
+) {
+    let mut audit_log = AUDIT_LOG.lock().unwrap();
+    let Some(audit_log) = audit_log.as_mut() else {
+        return;
+    };
+    let redact = audit_log.redact;
+    let content = |text: &str| -> Value {
+        if redact {
+            format!("[redacted {} characters]", text.chars().count()).into()
+        } else {
+            text.into()
+        }
+    };
+    let sent = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .saturating_sub(elapsed);
+    let messages: Vec<Value> = context
+        .messages
+        .iter()
+        .map(|message| {
+            json!({
+                "role": format!("{:?}", message.role),
+                "sender": message.sender,
+                "content": content(&message.content),
+            })
+        })
+        .collect();
+    let mut line = json!({
+        "timestamp": format_utc(sent.as_secs()),
+        "duration_ms": elapsed.as_millis() as u64,
+        "room_id": requester.room_id,
+        "user_id": requester.user_id,
+        "backend": backend,
+        "model": context.model,
+        "system": context.role.as_ref().map(|role| content(&role.get_prompt())),
+        "messages": messages,
+        "media": context.media.len(),
+    });
+    match result {
+        Ok(response) => line["response"] = content(response),
+        Err(e) => line["error"] = e.clone().into(),
+    }
+    audit_log.file.write_line(&line.to_string());
+}
@@ -0,0 +1,137 @@
+/// Logging in without a password
+///
+/// Headjack only logs in with a password, so for SSO and access tokens chaz logs in itself and writes the session
+/// file in the same format. Headjack then restores it like any other session.
+use std::{
+    io::{self, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, Context};
+use matrix_sdk::Client;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use serde::Deserialize;
+use tracing::info;
+
+use crate::session;
+
+/// Where the homeserver sends the browser after an SSO login
+///
+/// Nothing is listening there, the login token is copied out of the address bar.
+const SSO_REDIRECT_URL: &str = "http://localhost/";
+
+/// Response of the whoami endpoint
+#[derive(Deserialize)]
+struct WhoAmI {
+    user_id: String,
+    device_id: Option<String>,
+}
+
+/// Random alphanumeric string
+fn random_string(length: usize) -> String {
+    thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+/// Log in with SSO or an access token if there's no session yet
+///
+/// Does nothing if a session exists or neither is configured, leaving the password login to headjack.
+pub async fn login(
+    state_dir: &Path,
+    homeserver_url: &str,
+    sso: bool,
+    access_token: Option<&str>,
+) -> anyhow::Result<()> {
+    let session_file = state_dir.join("session");
+    if session_file.exists() || (!sso && access_token.is_none()) {
+        return Ok(());
+    }
+    std::fs::create_dir_all(state_dir)?;
+    // Same layout as headjack, the store in a random subfolder encrypted with a random passphrase
+    let db_path = state_dir.join(random_string(7));
+    let passphrase = random_string(32);
+    let user_session = match access_token {
+        Some(access_token) => token_session(homeserver_url, access_token).await?,
+        None => sso_session(homeserver_url, &db_path, &passphrase).await?,
+    };
+    let session = serde_json::json!({
+        "client_session": {
+            "homeserver": homeserver_url,
+            "db_path": db_path,
+            "passphrase": passphrase,
+        },
+        "user_session": user_session,
+    });
+    session::write(&session_file, &serde_json::to_string(&session)?)?;
+    info!("Session persisted in {}", session_file.display());
+    Ok(())
+}
+
+/// Build the user session for an existing access token
+///
+/// The token should belong to a device used only by chaz, since its encryption keys start out empty.
+async fn token_session(
+    homeserver_url: &str,
+    access_token: &str,
+) -> anyhow::Result<serde_json::Value> {
+    let whoami: WhoAmI = reqwest::Client::new()
+        .get(format!(
+            "{}/_matrix/client/v3/account/whoami",
+            homeserver_url.trim_end_matches('/')
+        ))
+        .bearer_auth(access_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let device_id = whoami
+        .device_id
+        .ok_or(anyhow!("the access token isn't tied to a device"))?;
+    info!("Logged in as {} with an access token", whoami.user_id);
+    Ok(serde_json::json!({
+        "user_id": whoami.user_id,
+        "device_id": device_id,
+        "access_token": access_token,
+    }))
+}
+
+/// Log in with SSO, asking for the login token on the command line
+async fn sso_session(
+    homeserver_url: &str,
+    db_path: &Path,
+    passphrase: &str,
+) -> anyhow::Result<serde_json::Value> {
+    let client = Client::builder()
+        .homeserver_url(homeserver_url)
+        .sqlite_store(db_path, Some(passphrase))
+        .build()
+        .await?;
+    let matrix_auth = client.matrix_auth();
+    let url = matrix_auth
+        .get_sso_login_url(SSO_REDIRECT_URL, None)
+        .await?;
+    println!("Open this URL to log in:\n\n{}\n", url);
+    println!("After logging in, the browser is sent to a page on localhost that won't load.");
+    print!("Paste its address, or the loginToken from it: ");
+    io::stdout().flush()?;
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    let input = input.trim();
+    let token = input.split_once("loginToken=").map_or(input, |(_, token)| {
+        token.split('&').next().unwrap_or_default()
+    });
+    matrix_auth
+        .login_token(token)
+        .initial_device_display_name("chaz")
+        .await
+        .context("SSO login failed")?;
+    let user_session = matrix_auth
+        .session()
+        .ok_or(anyhow!("no session after logging in"))?;
+    info!("Logged in as {} with SSO", user_session.meta.user_id);
+    Ok(serde_json::to_value(user_session)?)
+}
@@ -1,31 +1,171 @@
-use matrix_sdk::media::MediaFileHandle;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use matrix_sdk::{media::MediaFileHandle, ruma::OwnedEventId};
 use openai_api_rs::v1::chat_completion::MessageRole;
+use serde::Deserialize;
+use tracing::error;
 
 use crate::{
     aichat::AiChat,
+    audit::{self, Requester},
+    metrics,
+    ollama::Ollama,
     openai::OpenAI,
+    openrouter::OpenRouter,
     role::{prepend_role, RoleDetails},
+    tools::ToolName,
     Backend, BackendType,
 };
 
-/// Manage all the backends for chaz.
-///
-/// This module is responsible for handling dispatch, validation, and general management for all the different backends
+// Manage all the backends for chaz.
+//
+// This module is responsible for handling dispatch, validation, and general management for all the different backends
+
+pub use crate::{capabilities::Capabilities, cost::Pricing};
 
-pub trait LLMBackend {
-    fn list_models(&self) -> Vec<String>;
-    fn default_model(&self) -> Option<String>;
+/// A client for an LLM API, implemented by each type of backend
+///
+/// Implement it to add a backend to an embedded chaz with [`crate::ChazBotBuilder::register_backend`].
+#[async_trait]
+pub trait LLMBackend: Send + Sync {
+    async fn list_models(&self) -> Vec<String>;
+    async fn default_model(&self) -> Option<String>;
     async fn execute(&self, context: &ChatContext) -> Result<String, String>;
+
+    /// Describe the request `execute` sends for the context, for `!chaz debug`
+    async fn describe_request(&self, context: &ChatContext) -> Result<serde_json::Value, String>;
+
+    /// Ask the backend what the model supports
+    ///
+    /// Backends that can't tell leave the capabilities unknown.
+    async fn probe_capabilities(&self, _model: Option<&str>) -> Capabilities {
+        Capabilities::default()
+    }
+
+    /// Prices of a model published by the backend, from what it has already fetched
+    ///
+    /// Backends without a price list leave it to the config.
+    fn pricing(&self, _model: Option<&str>) -> Option<Pricing> {
+        None
+    }
+
+    /// Execute the request, appending the response to `output` as it is generated.
+    ///
+    /// Backends that can't stream only write to `output` once the response is complete.
+    async fn execute_streaming(
+        &self,
+        context: &ChatContext,
+        output: &Mutex<String>,
+    ) -> Result<String, String> {
+        let response = self.execute(context).await?;
+        output.lock().unwrap().push_str(&response);
+        Ok(response)
+    }
+}
+
+/// Appended to responses that were cut off by the response deadline
+pub(crate) const TRUNCATION_MARKER: &str = "(truncated — say !chaz continue)";
+
+impl BackendType {
+    /// Create the client for a backend of this type
+    ///
+    /// This is where each type of backend is registered, a new backend only needs its implementation of
+    /// LLMBackend and an entry here. Fails if the options of the backend are invalid.
+    fn build(&self, backend: &Backend) -> Result<Box<dyn LLMBackend>, String> {
+        Ok(match self {
+            BackendType::AIChat => Box::new(AiChat::new(backend)),
+            BackendType::OpenAICompatible => Box::new(OpenAI::new(backend)?),
+            BackendType::Ollama => Box::new(Ollama::new(backend)?),
+            BackendType::OpenRouter => Box::new(OpenRouter::new(backend)?),
+        })
+    }
+}
+
+/// A backend from the config, with its client
+struct LoadedBackend {
+    config: Backend,
+    client: Box<dyn LLMBackend>,
+}
+
+impl LoadedBackend {
+    fn new(config: Backend) -> Result<Arc<Self>, String> {
+        let client = config
+            .backend_type
+            .build(&config)
+            .map_err(|e| format!("Invalid backend {}: {}", config.get_name(), e))?;
+        Ok(Arc::new(LoadedBackend { client, config }))
+    }
+
+    /// Build a backend that isn't from the config file, logging it if it's invalid
+    fn new_or_log(config: Backend) -> Option<Arc<Self>> {
+        Self::new(config).map_err(|e| error!("{}", e)).ok()
+    }
+}
+
+lazy_static! {
+    /// The backends from the config, built when it's loaded so the requests share their clients and caches
+    static ref CONFIGURED: Mutex<Vec<Arc<LoadedBackend>>> = Mutex::new(Vec::new());
+
+    /// Backends added by the project embedding chaz, used after the ones from the config
+    static ref REGISTERED: Mutex<Vec<Arc<LoadedBackend>>> = Mutex::new(Vec::new());
+
+    /// The backend used when none are configured, for backwards compat
+    static ref FALLBACK: Arc<LoadedBackend> = {
+        let config = Backend::new(BackendType::AIChat);
+        Arc::new(LoadedBackend { client: Box::new(AiChat::new(&config)), config })
+    };
 }
 
+/// Build the backends from the config, for the requests from now on
+///
+/// If any of them is invalid, the backends in use are kept and the error is returned.
+pub(crate) fn configure(backends: &Option<Vec<Backend>>) -> Result<(), String> {
+    *CONFIGURED.lock().unwrap() = backends
+        .iter()
+        .flatten()
+        .cloned()
+        .map(LoadedBackend::new)
+        .collect::<Result<_, _>>()?;
+    Ok(())
+}
+
+/// Add a backend with its own client, selected with the name like the ones from the config
+pub(crate) fn register(name: &str, client: Box<dyn LLMBackend>) {
+    // The type only names backends without a name
+    let mut config = Backend::new(BackendType::OpenAICompatible);
+    config.name = Some(name.to_string());
+    REGISTERED
+        .lock()
+        .unwrap()
+        .push(Arc::new(LoadedBackend { config, client }));
+}
+
+/// The backends from the config, and the dispatch of requests to them
 pub struct BackendManager {
-    backends: Vec<Backend>,
+    backends: Vec<Arc<LoadedBackend>>,
+    /// Index of the backend used when the model doesn't name one
+    default: usize,
+    /// Who the requests are sent for, for the audit log
+    requester: Requester,
 }
 
 /// A generic Message
 pub struct Message {
     pub role: MessageRole,
     pub content: String,
+    /// Set if this message is a placeholder for media attached to the ChatContext
+    ///
+    /// Backends that receive the media directly can skip these messages.
+    pub attached_media: bool,
+    /// Display name of the sender, used to tell participants apart in multi-user rooms
+    pub sender: Option<String>,
+    /// ID of the event the message came from, if it's from the room
+    pub event_id: Option<OwnedEventId>,
 }
 
 impl std::fmt::Display for Message {
@@ -36,7 +176,11 @@ impl std::fmt::Display for Message {
             MessageRole::system => "SYSTEM",
             _ => "UNKNOWN",
         };
-        write!(f, "{}: {}", role, self.content)
+        if let Some(sender) = &self.sender {
+            write!(f, "{} ({}): {}", role, sender, self.content)
+        } else {
+            write!(f, "{}: {}", role, self.content)
+        }
     }
 }
 
@@ -46,6 +190,37 @@ impl Message {
         Message {
             role,
             content: content.into(),
+            attached_media: false,
+            sender: None,
+            event_id: None,
+        }
+    }
+
+    /// Create a placeholder message for media that is attached to the ChatContext
+    pub fn attached_media<S: Into<String>>(role: MessageRole, content: S) -> Message {
+        Message {
+            role,
+            content: content.into(),
+            attached_media: true,
+            sender: None,
+            event_id: None,
+        }
+    }
+
+    /// Attribute the message to a sender
+    pub fn with_sender(mut self, sender: Option<String>) -> Message {
+        self.sender = sender;
+        self
+    }
+
+    /// Get the content with the sender's name prefixed, if there is one
+    ///
+    /// Used for backends that have no other way to attribute messages.
+    pub fn attributed_content(&self) -> String {
+        if let Some(sender) = &self.sender {
+            format!("{}: {}", sender, self.content)
+        } else {
+            self.content.clone()
         }
     }
 }
@@ -59,14 +234,78 @@ pub struct ChatContext {
     pub model: Option<String>,
     pub media: Vec<MediaFileHandle>,
     pub role: Option<RoleDetails>,
+    /// Tools offered to backends that support function calling
+    pub tools: Vec<ToolName>,
+    /// JSON schema the response has to match, sent to the backends that support structured output
+    pub schema: Option<serde_json::Value>,
+    /// Generation parameters set for the room, preferred over the ones set for the model
+    pub params: GenerationParams,
+    /// Cost of the requests for this context as reported by the backend, preferred over the estimate
+    ///
+    /// It's taken when the tokens are recorded, so each request is only counted once.
+    pub reported_cost: Mutex<Option<f64>>,
+}
+
+/// Parameters controlling the generation
+///
+/// Unset parameters are left to the backend's defaults.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct GenerationParams {
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<i64>,
+    pub frequency_penalty: Option<f64>,
+    pub stop: Option<Vec<String>>,
+}
+
+impl GenerationParams {
+    /// Names of the parameters, as used in the config and `!chaz set`
+    pub const NAMES: &'static [&'static str] = &[
+        "temperature",
+        "top_p",
+        "max_tokens",
+        "frequency_penalty",
+        "stop",
+    ];
+
+    /// Fill in the parameters this one leaves unset
+    pub fn or(self, fallback: GenerationParams) -> GenerationParams {
+        GenerationParams {
+            temperature: self.temperature.or(fallback.temperature),
+            top_p: self.top_p.or(fallback.top_p),
+            max_tokens: self.max_tokens.or(fallback.max_tokens),
+            frequency_penalty: self.frequency_penalty.or(fallback.frequency_penalty),
+            stop: self.stop.or(fallback.stop),
+        }
+    }
+
+    /// Set a parameter from its text value, e.g. from `!chaz set temperature 0.2`
+    ///
+    /// Stop sequences are separated by commas.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("invalid value for {}: {}", name, value);
+        match name {
+            "temperature" => self.temperature = Some(value.parse().map_err(|_| invalid())?),
+            "top_p" => self.top_p = Some(value.parse().map_err(|_| invalid())?),
+            "max_tokens" => self.max_tokens = Some(value.parse().map_err(|_| invalid())?),
+            "frequency_penalty" => {
+                self.frequency_penalty = Some(value.parse().map_err(|_| invalid())?)
+            }
+            "stop" => self.stop = Some(value.split(',').map(str::to_string).collect()),
+            _ => return Err(format!("unknown parameter {}", name)),
+        }
+        Ok(())
+    }
 }
 
 impl ChatContext {
     /// Convert messages into a single string.
-    pub fn string_prompt(&self) -> String {
+    ///
+    /// Placeholders for attached media are skipped, the media is expected to be sent alongside the prompt.
+    pub(crate) fn string_prompt(&self) -> String {
         // TODO: consider making this markdown
         let mut prompt = String::new();
-        for message in self.messages.iter() {
+        for message in self.messages.iter().filter(|m| !m.attached_media) {
             prompt.push_str(&format!("{}\n", message))
         }
         // Indicate that the assistant needs to speak next
@@ -75,7 +314,7 @@ impl ChatContext {
     }
 
     /// Convert messages into a single string with the role prepended
-    pub fn string_prompt_with_role(&self) -> String {
+    pub(crate) fn string_prompt_with_role(&self) -> String {
         let prompt = self.string_prompt();
         if let Some(role) = &self.role {
             prepend_role(prompt, role)
@@ -86,76 +325,84 @@ impl ChatContext {
 }
 
 impl BackendManager {
-    /// Create a new backend manager
+    /// Create a backend manager with the given backends, followed by the configured and registered ones
     ///
-    /// If no backends are provided, it will default to an AIChat backend for backwards compat.
-    pub fn new(backends: &Option<Vec<Backend>>) -> Self {
+    /// The backend of the user, from `!chaz login`, comes last and is used for the models that don't name another
+    /// backend. Only the given backends are built, the others were built once when the config was loaded, and the
+    /// invalid ones are logged and left out. If there
+    /// are no backends at all, it will default to an AIChat backend for backwards compat.
+    pub(crate) fn new(backends: Vec<Backend>, user_backend: Option<Backend>) -> Self {
+        let mut backends: Vec<Arc<LoadedBackend>> = backends
+            .into_iter()
+            .filter_map(LoadedBackend::new_or_log)
+            .collect();
+        backends.extend(CONFIGURED.lock().unwrap().iter().cloned());
+        backends.extend(REGISTERED.lock().unwrap().iter().cloned());
+        let default = match user_backend.and_then(LoadedBackend::new_or_log) {
+            Some(user_backend) => {
+                backends.push(user_backend);
+                backends.len() - 1
+            }
+            None => 0,
+        };
+        if backends.is_empty() {
+            backends.push(FALLBACK.clone());
+        }
         Self {
-            backends: backends
-                .as_ref()
-                .map_or_else(|| vec![Backend::new(BackendType::AIChat)], |v| v.clone()),
+            backends,
+            default,
+            requester: Requester::default(),
         }
     }
 
+    /// Set who the requests are sent for, for the audit log
+    pub(crate) fn with_requester(mut self, requester: Requester) -> Self {
+        self.requester = requester;
+        self
+    }
+
     /// Lists all known backends
     pub fn list_known_backends(&self) -> Vec<String> {
-        self.backends.iter().map(|b| b.get_name().clone()).collect()
+        self.backends.iter().map(|b| b.config.get_name()).collect()
+    }
+
+    /// Name a model of a backend the way users select it
+    ///
+    /// With more than 1 backend, the name of the backend is prepended, e.g. openai:gpt-4o.
+    fn qualify(&self, backend: &LoadedBackend, model: String) -> String {
+        if self.backends.len() == 1 {
+            model
+        } else {
+            format!("{}:{}", backend.config.get_name(), model)
+        }
     }
 
     /// Lists all known models
     ///
     /// Models may be valid even if they aren't listed
-    pub fn list_known_models(&self) -> Vec<String> {
+    pub async fn list_known_models(&self) -> Vec<String> {
         // TODO: Cache/memoize this
-        if self.backends.len() == 1 {
-            // Don't prepend the names if there is only 1 backend
-            let backend = &self.backends[0];
-            match backend.backend_type {
-                BackendType::AIChat => AiChat::new(backend).list_models(),
-                BackendType::OpenAICompatible => OpenAI::new(backend).list_models(),
-            }
-        } else {
-            let mut models = Vec::new();
-            for backend in &self.backends {
-                match backend.backend_type {
-                    BackendType::AIChat => {
-                        let mut backend_models = AiChat::new(backend).list_models();
-                        backend_models = backend_models
-                            .into_iter()
-                            .map(|model| {
-                                format!("{}:{}", backend.name.as_deref().unwrap_or("aichat"), model)
-                            })
-                            .collect();
-                        models.append(&mut backend_models);
-                    }
-                    BackendType::OpenAICompatible => {
-                        let mut backend_models = OpenAI::new(backend).list_models();
-                        backend_models = backend_models
-                            .into_iter()
-                            .map(|model| {
-                                format!("{}:{}", backend.name.as_deref().unwrap_or("openai"), model)
-                            })
-                            .collect();
-                        models.append(&mut backend_models);
-                    }
-                }
+        let mut models = Vec::new();
+        for backend in &self.backends {
+            for model in backend.client.list_models().await {
+                models.push(self.qualify(backend, model));
             }
-            models
         }
+        models
     }
 
     /// Returns true if the model is known
     ///
     /// This doesn't mean the model is invalid, just that there is no information on the model locally.
-    pub fn is_known_model(&self, model: &str) -> bool {
-        self.list_known_models().contains(&model.to_string())
+    pub async fn is_known_model(&self, model: &str) -> bool {
+        self.list_known_models().await.contains(&model.to_string())
     }
 
     /// Validate that the model name is valid
     ///
     /// Models can have invalid names, they must be prefixed by the name of the backend if more than 1 backend exists.
-    pub fn validate_model(&self, model: &str) -> Result<(), String> {
-        if self.is_known_model(model) {
+    pub async fn validate_model(&self, model: &str) -> Result<(), String> {
+        if self.is_known_model(model).await {
             Ok(())
         } else {
             // Might still be ok, let's validate the name
@@ -165,7 +412,10 @@ impl BackendManager {
             } else {
                 // The name must be prefixed by the backend name
                 for backend in &self.backends {
-                    if model.starts_with(&format!("{}:", backend.name.as_deref().unwrap_or(""))) {
+                    if model.starts_with(&format!(
+                        "{}:",
+                        backend.config.name.as_deref().unwrap_or("")
+                    )) {
                         return Ok(());
                     }
                 }
@@ -175,61 +425,138 @@ impl BackendManager {
     }
 
     /// Get the default model
-    pub fn default_model(&self) -> Option<String> {
-        if self.backends.is_empty() {
-            None
-        } else {
-            let backend = &self.backends[0];
-            if self.backends.len() == 1 {
-                match backend.backend_type {
-                    BackendType::AIChat => AiChat::new(backend).default_model(),
-                    BackendType::OpenAICompatible => OpenAI::new(backend).default_model(),
-                }
-            } else {
-                match backend.backend_type {
-                    BackendType::AIChat => AiChat::new(backend).default_model().map(|s| {
-                        format!(
-                            "{}:{}",
-                            backend.name.clone().unwrap_or("aichat".to_string()),
-                            s
-                        )
-                    }),
-                    BackendType::OpenAICompatible => {
-                        OpenAI::new(backend).default_model().map(|s| {
-                            format!(
-                                "{}:{}",
-                                backend.name.clone().unwrap_or("openai".to_string()),
-                                s
-                            )
-                        })
-                    }
-                }
+    pub async fn default_model(&self) -> Option<String> {
+        let backend = self.backends.get(self.default)?;
+        let model = backend.client.default_model().await?;
+        Some(self.qualify(backend, model))
+    }
+
+    /// Describe the request sent to the backend for the ChatContext, as pretty JSON
+    pub(crate) async fn describe_request(&self, context: &ChatContext) -> Result<String, String> {
+        let backend = self.select_backend(context.model.as_deref())?;
+        let request = backend.client.describe_request(context).await?;
+        serde_json::to_string_pretty(&request).map_err(|e| e.to_string())
+    }
+
+    /// Get the name of the backend that will handle the ChatContext
+    pub(crate) fn backend_name(&self, context: &ChatContext) -> Option<String> {
+        self.select_backend(context.model.as_deref())
+            .ok()
+            .map(|b| b.config.get_name())
+    }
+
+    /// Get the prices of the model that will handle the ChatContext
+    pub(crate) fn pricing(&self, context: &ChatContext) -> Option<Pricing> {
+        self.model_pricing(context.model.as_deref())
+    }
+
+    /// Get the prices of a model, set in the config or published by the backend
+    ///
+    /// Without a model, it's the first model listed for the backend.
+    pub(crate) fn model_pricing(&self, model: Option<&str>) -> Option<Pricing> {
+        let backend = self.select_backend(model).ok()?;
+        if let Some(config) = backend.config.model_config(model) {
+            if config.input_cost.is_some() || config.output_cost.is_some() {
+                return Some(Pricing {
+                    input: config.input_cost.unwrap_or_default(),
+                    output: config.output_cost.unwrap_or_default(),
+                });
             }
         }
+        backend.client.pricing(model)
     }
 
-    /// Execute the ChatContext
+    /// Get what a model supports, or the default model without one
     ///
-    /// If no model is provided in the ChatContext, it will hand it off to the default model.
-    pub async fn execute(&self, context: &ChatContext) -> Result<String, String> {
+    /// The capabilities set for the model in the config win over the ones set for the backend, and the backend is
+    /// only asked for the ones left unknown.
+    pub(crate) async fn capabilities(&self, model: Option<&str>) -> Capabilities {
+        let Ok(backend) = self.select_backend(model) else {
+            return Capabilities::default();
+        };
+        let declared = backend
+            .config
+            .model_config(model)
+            .and_then(|model| model.capabilities.clone())
+            .unwrap_or_default()
+            .or(backend.config.capabilities.clone().unwrap_or_default());
+        if declared.is_complete() {
+            return declared;
+        }
+        declared.or(backend.client.probe_capabilities(model).await)
+    }
+
+    /// Pick the backend to use based on the model name given in the ChatContext
+    fn select_backend(&self, model: Option<&str>) -> Result<&LoadedBackend, String> {
         if self.backends.is_empty() {
             return Err("No backends configured".to_string());
         }
-
-        // Pick the backend to use based on the model name given in the ChatContext
-        let backend = if let Some(model) = &context.model {
+        Ok(if let Some(model) = model {
             self.backends
                 .iter()
                 .find(|backend| {
-                    backend.name.as_deref() == Some(model.split(":").next().unwrap_or(""))
+                    backend.config.name.as_deref() == Some(model.split(":").next().unwrap_or(""))
                 })
-                .unwrap_or(&self.backends[0])
+                .unwrap_or(&self.backends[self.default])
         } else {
-            &self.backends[0]
+            &self.backends[self.default]
+        })
+    }
+
+    /// Execute the ChatContext
+    ///
+    /// If no model is provided in the ChatContext, it will hand it off to the default model.
+    pub async fn execute(&self, context: &ChatContext) -> Result<String, String> {
+        let backend = self.select_backend(context.model.as_deref())?;
+        let start = Instant::now();
+        let result = backend.client.execute(context).await;
+        self.record_request(&backend.config, context, start, &result);
+        result
+    }
+
+    /// Execute the ChatContext with a soft deadline
+    ///
+    /// If the deadline passes, whatever has been generated so far is returned with the TRUNCATION_MARKER appended.
+    pub(crate) async fn execute_with_deadline(
+        &self,
+        context: &ChatContext,
+        deadline: Option<Duration>,
+    ) -> Result<String, String> {
+        let Some(deadline) = deadline else {
+            return self.execute(context).await;
         };
-        match backend.backend_type {
-            BackendType::AIChat => AiChat::new(backend).execute(context).await,
-            BackendType::OpenAICompatible => OpenAI::new(backend).execute(context).await,
-        }
+        let backend = self.select_backend(context.model.as_deref())?;
+        let output = Mutex::new(String::new());
+        let request = backend.client.execute_streaming(context, &output);
+        let start = Instant::now();
+        let result = match tokio::time::timeout(deadline, request).await {
+            Ok(result) => result,
+            Err(_) => {
+                let partial = output.lock().unwrap().clone();
+                Ok(format!("{}\n\n{}", partial.trim_end(), TRUNCATION_MARKER)
+                    .trim_start()
+                    .to_string())
+            }
+        };
+        self.record_request(&backend.config, context, start, &result);
+        result
+    }
+
+    /// Add a backend request to the metrics and the audit log
+    fn record_request(
+        &self,
+        backend: &Backend,
+        context: &ChatContext,
+        start: Instant,
+        result: &Result<String, String>,
+    ) {
+        let name = backend.get_name();
+        metrics::record_request(
+            &name,
+            context.model.as_deref().unwrap_or("default"),
+            start.elapsed(),
+            result.is_err(),
+        );
+        audit::record(&self.requester, &name, context, start.elapsed(), result);
     }
 }
@@ -0,0 +1,118 @@
+/// Model capabilities
+///
+/// What a model can do: see images, call tools, follow a system prompt, and how large a context it takes. They're
+/// declared for each model or backend in the config, and otherwise probed from backends that report them, like
+/// Ollama. Unknown capabilities are assumed to be supported, except for tools, which servers without them reject.
+/// Before a request, the context is adapted to the model, so it isn't rejected or silently ignored: images are left
+/// out with a warning, tools are dropped, the system prompt is sent as a user message, and the oldest messages are
+/// dropped to fit.
+use std::{collections::HashSet, sync::Mutex};
+
+use lazy_static::lazy_static;
+use matrix_sdk::{ruma::OwnedRoomId, Room};
+use openai_api_rs::v1::chat_completion::MessageRole;
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::{
+    backends::{BackendManager, Message},
+    context, i18n, ChatContext,
+};
+
+/// What a model supports, unset capabilities are unknown
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Capabilities {
+    /// The model can see images
+    pub supports_vision: Option<bool>,
+    /// The model can call tools
+    pub supports_tools: Option<bool>,
+    /// The model follows a system prompt
+    pub supports_system: Option<bool>,
+    /// Most tokens the model takes in a request, including the response
+    pub max_context: Option<usize>,
+}
+
+impl Capabilities {
+    /// Fill in the capabilities this one leaves unset
+    pub fn or(self, fallback: Capabilities) -> Capabilities {
+        Capabilities {
+            supports_vision: self.supports_vision.or(fallback.supports_vision),
+            supports_tools: self.supports_tools.or(fallback.supports_tools),
+            supports_system: self.supports_system.or(fallback.supports_system),
+            max_context: self.max_context.or(fallback.max_context),
+        }
+    }
+
+    /// Check if every capability is known, so there's nothing to probe
+    pub fn is_complete(&self) -> bool {
+        self.supports_vision.is_some()
+            && self.supports_tools.is_some()
+            && self.supports_system.is_some()
+            && self.max_context.is_some()
+    }
+
+    /// Check if the model can answer a context with media, or tools
+    pub fn can_answer(&self, media: bool, tools: bool) -> bool {
+        (!media || self.supports_vision != Some(false))
+            && (!tools || self.supports_tools != Some(false))
+    }
+}
+
+lazy_static! {
+    /// The rooms and models that were warned about images, so the warning is only sent once
+    static ref WARNED: Mutex<HashSet<(OwnedRoomId, String)>> = Mutex::new(HashSet::new());
+}
+
+/// Adapt the context to what the model supports
+pub async fn apply(room: &Room, backend: &BackendManager, context: &mut ChatContext) {
+    let capabilities = backend.capabilities(context.model.as_deref()).await;
+    let model = context.model.clone().unwrap_or("default".to_string());
+    if capabilities.supports_vision == Some(false) && !context.media.is_empty() {
+        let count = context.media.len();
+        context.media.clear();
+        // Send the placeholders as text, so the model still knows the files exist
+        for message in context.messages.iter_mut() {
+            message.attached_media = false;
+        }
+        info!("Left {} files out of the context for {}", count, model);
+        let first_warning = WARNED
+            .lock()
+            .unwrap()
+            .insert((room.room_id().to_owned(), model.clone()));
+        if first_warning {
+            let warning = i18n::notice(room, "images-unsupported", &[("model", &model)]).await;
+            if let Err(e) = room.send(warning).await {
+                error!("Unable to warn about images: {}", e);
+            }
+        }
+    }
+    // Tools are only sent to models known to support them
+    if capabilities.supports_tools != Some(true) {
+        context.tools.clear();
+    }
+    if capabilities.supports_system == Some(false) {
+        if let Some(role) = context.role.take() {
+            context
+                .messages
+                .insert(0, Message::new(MessageRole::user, role.get_prompt()));
+        }
+        for message in context.messages.iter_mut() {
+            if message.role == MessageRole::system {
+                message.role = MessageRole::user;
+            }
+        }
+    }
+    if let Some(max_context) = capabilities.max_context {
+        // Leave room for the response
+        let response_tokens = context.params.max_tokens.unwrap_or(0).max(0) as usize;
+        let dropped =
+            context::truncate_context(context, max_context.saturating_sub(response_tokens));
+        if !dropped.is_empty() {
+            info!(
+                "Dropped {} messages to fit the context of {}",
+                dropped.len(),
+                model
+            );
+        }
+    }
+}
@@ -0,0 +1,112 @@
+/// Checkpoints
+///
+/// `!chaz checkpoint save <name>` marks the current point in the conversation, and `!chaz checkpoint restore
+/// <name>` rewinds the context to it. The messages sent between the two are left out of the context from then on,
+/// so the conversation can branch off in a different direction while the original thread stays in the room. The
+/// checkpoints are the IDs of the last message at that point, kept in the room tags.
+use headjack::Tags;
+use matrix_sdk::{
+    ruma::{events::room::message::RoomMessageEventContent, OwnedEventId, OwnedUserId},
+    Room,
+};
+
+use crate::{commands::Args, error::ChazError, history, i18n};
+
+/// Tag namespace for the checkpoints
+const NAMESPACE: &str = "is.chaz.checkpoints";
+
+/// The command that rewinds the context, found in the history when building it
+const RESTORE_COMMAND: &str = "!chaz checkpoint restore";
+
+/// Get the message a checkpoint points to
+pub async fn get(room: &Room, name: &str) -> Option<OwnedEventId> {
+    Tags::new(room, NAMESPACE)
+        .await
+        .get_value(name)
+        .and_then(|id| OwnedEventId::try_from(id).ok())
+}
+
+/// Get the name of the checkpoint a message restores, if it's a restore command
+pub fn restored(body: &str) -> Option<&str> {
+    body.strip_prefix(RESTORE_COMMAND)?
+        .split_whitespace()
+        .next()
+}
+
+/// Check that a name can be stored as a tag
+fn is_valid_name(name: &str) -> bool {
+    name.chars()
+        .all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Get the ID of the most recent message in the room, ignoring edits
+async fn latest_message(room: &Room) -> Option<OwnedEventId> {
+    let mut history = history::walk(room);
+    while let Some(batch) = history.next().await {
+        for event in batch {
+            if event.get_field::<String>("type").ok().flatten().as_deref() != Some("m.room.message")
+            {
+                continue;
+            }
+            let content = event
+                .get_field::<serde_json::Value>("content")
+                .ok()
+                .flatten()
+                .unwrap_or_default();
+            if content["m.relates_to"]["rel_type"] == "m.replace" {
+                continue;
+            }
+            if let Some(event_id) = event.get_field::<OwnedEventId>("event_id").ok().flatten() {
+                return Some(event_id);
+            }
+        }
+    }
+    None
+}
+
+/// Save, restore, delete, or list the checkpoints, `!chaz checkpoint [save|restore|delete <name>]`
+pub async fn checkpoint(_: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let mut tags = Tags::new(&room, NAMESPACE).await;
+    let name = &args["name"];
+    let response = match args.subcommand() {
+        None => {
+            let names: Vec<&str> = tags
+                .tags()
+                .iter()
+                .filter_map(|tag| tag.split('=').next())
+                .collect();
+            if names.is_empty() {
+                i18n::notice_text(&room, "checkpoints-empty", &[]).await
+            } else {
+                let names = names.join("\n");
+                i18n::notice_text(&room, "checkpoints", &[("checkpoints", &names)]).await
+            }
+        }
+        Some("save") if !is_valid_name(name) => {
+            i18n::notice_text(&room, "checkpoint-name", &[]).await
+        }
+        Some("save") => match latest_message(&room).await {
+            Some(event_id) => {
+                tags.replace_kv(name, event_id.as_str());
+                tags.sync().await;
+                i18n::notice_text(&room, "checkpoint-saved", &[("name", name)]).await
+            }
+            None => i18n::notice_text(&room, "checkpoint-nothing", &[]).await,
+        },
+        Some("restore") => match tags.get_value(name) {
+            Some(_) => i18n::notice_text(&room, "checkpoint-restored", &[("name", name)]).await,
+            None => i18n::notice_text(&room, "checkpoint-unknown", &[("name", name)]).await,
+        },
+        _ => match tags.get_value(name) {
+            Some(_) => {
+                tags.remove_kv(name);
+                tags.sync().await;
+                i18n::notice_text(&room, "checkpoint-deleted", &[("name", name)]).await
+            }
+            None => i18n::notice_text(&room, "checkpoint-unknown", &[("name", name)]).await,
+        },
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,79 @@
+/// Splitting long responses
+///
+/// Homeservers reject events over 64KB, so responses longer than `max_message_length` are posted as several
+/// messages. They're split between paragraphs where possible, and every message but the last ends with a
+/// "(continued)" marker. A code block that's split is closed and opened again, so each part renders on its own.
+use crate::get_config;
+
+/// Appended to every part of a split response but the last
+pub const CONTINUED_MARKER: &str = "(continued)";
+
+/// Length of a message in bytes when `max_message_length` isn't set
+///
+/// Markdown is sent as both the text and the HTML, so this leaves room for both in the 64KB limit.
+const DEFAULT_MAX_LENGTH: usize = 24000;
+
+/// Shortest length a message can be set to, so the split always makes progress
+const MIN_LENGTH: usize = 500;
+
+/// Room kept in each part for the marker and closing a code block
+const RESERVED: usize = CONTINUED_MARKER.len() + 8;
+
+/// Maximum length of a message in bytes
+pub fn max_length() -> usize {
+    get_config()
+        .max_message_length
+        .unwrap_or(DEFAULT_MAX_LENGTH)
+        .max(MIN_LENGTH)
+}
+
+/// Split a response into messages no longer than `max_length` bytes
+pub fn split(text: &str, max_length: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut remaining = text.to_string();
+    while remaining.len() > max_length {
+        let cut = split_point(&remaining, max_length.saturating_sub(RESERVED));
+        let mut part = remaining[..cut].trim_end().to_string();
+        let mut rest = remaining[cut..].trim_start_matches('\n').to_string();
+        if let Some(fence) = open_fence(&part) {
+            part.push_str("\n```");
+            rest = format!("{}\n{}", fence, rest);
+        }
+        part.push_str("\n\n");
+        part.push_str(CONTINUED_MARKER);
+        parts.push(part);
+        remaining = rest;
+    }
+    parts.push(remaining);
+    parts
+}
+
+/// Find where to split the text, preferring paragraphs, then lines, then words
+fn split_point(text: &str, budget: usize) -> usize {
+    let mut limit = budget.min(text.len());
+    while !text.is_char_boundary(limit) {
+        limit -= 1;
+    }
+    let window = &text[..limit];
+    for separator in ["\n\n", "\n", " "] {
+        // Splitting too early makes for a lot of short messages
+        if let Some(index) = window.rfind(separator).filter(|index| *index > limit / 2) {
+            return index;
+        }
+    }
+    limit
+}
+
+/// Get the line opening the code block that's still open at the end of the text
+fn open_fence(text: &str) -> Option<String> {
+    let mut fence = None;
+    for line in text.lines() {
+        if line.trim_start().starts_with("```") {
+            fence = match fence {
+                Some(_) => None,
+                None => Some(line.trim().to_string()),
+            };
+        }
+    }
+    fence
+}
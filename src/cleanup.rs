@@ -0,0 +1,147 @@
+/// Leaving rooms
+///
+/// `!chaz leave` lets a room admin send chaz away, and with `cleanup` set, a background task leaves the rooms where
+/// chaz has been alone or that have been idle for too long. Leaving forgets the room and deletes what chaz kept
+/// about it: the cached history, the queued requests, and the scheduled jobs.
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use matrix_sdk::{
+    room::MessagesOptions,
+    ruma::{MilliSecondsSinceUnixEpoch, OwnedRoomId, OwnedUserId, UInt},
+    Client, Room,
+};
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::{
+    commands::Args, concurrency, error::ChazError, get_config, history, i18n, notifications,
+    parse_duration, permissions, schedule,
+};
+
+/// How often the rooms are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Configuration for leaving unused rooms
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct CleanupConfig {
+    /// Leave rooms where chaz has been the only member for this long, e.g. "1d"
+    pub alone_after: Option<String>,
+    /// Leave rooms without any messages for this long, e.g. "90d"
+    pub idle_after: Option<String>,
+}
+
+lazy_static! {
+    /// When chaz was first seen alone in each room
+    static ref ALONE_SINCE: Mutex<HashMap<OwnedRoomId, Instant>> = Mutex::new(HashMap::new());
+}
+
+/// Leave and forget a room, deleting everything kept about it
+pub async fn leave(room: &Room) -> Result<(), String> {
+    let room_id = room.room_id().to_owned();
+    room.leave().await.map_err(|e| e.to_string())?;
+    // Forgetting only works once the room is known to be left
+    if let Some(left) = room.client().get_room(&room_id) {
+        if let Err(e) = left.forget().await {
+            error!("Unable to forget room {}: {}", room_id, e);
+        }
+    }
+    history::invalidate(&room_id);
+    concurrency::forget_room(&room_id);
+    schedule::forget_room(&room_id);
+    ALONE_SINCE.lock().unwrap().remove(&room_id);
+    info!("Left room {}", room_id);
+    Ok(())
+}
+
+/// Leave the room, `!chaz leave`
+pub async fn leave_room(sender: OwnedUserId, _: Args, room: Room) -> Result<(), ChazError> {
+    if !permissions::is_room_admin(&room, &sender).await {
+        room.send(i18n::notice(&room, "leave-forbidden", &[]).await)
+            .await?;
+        return Ok(());
+    }
+    room.send(i18n::notice(&room, "goodbye", &[]).await).await?;
+    if let Err(e) = leave(&room).await {
+        let error = e.to_string();
+        room.send(i18n::notice(&room, "leave-failed", &[("error", &error)]).await)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Leave the unused rooms in the background, if `cleanup` is configured
+pub fn start(client: Client) {
+    if get_config().cleanup.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+            // The config can be reloaded in the meantime
+            let Some(config) = get_config().cleanup else {
+                continue;
+            };
+            let alone_after = config.alone_after.as_deref().and_then(parse_duration);
+            let idle_after = config.idle_after.as_deref().and_then(parse_duration);
+            let admin_room = get_config().admin_room;
+            for room in client.joined_rooms() {
+                // The admin room is needed to manage the bot
+                if admin_room.as_deref() == Some(room.room_id().as_str()) {
+                    continue;
+                }
+                let reason = match (alone_after, idle_after) {
+                    (Some(alone_after), _) if alone_for(&room) >= Some(alone_after) => {
+                        "chaz was the only member"
+                    }
+                    (_, Some(idle_after)) if idle_for(&room).await >= Some(idle_after) => {
+                        "it was idle"
+                    }
+                    _ => continue,
+                };
+                match leave(&room).await {
+                    Ok(()) => {
+                        notifications::notify(
+                            &client,
+                            &format!("Left {}, {}", room.room_id(), reason),
+                        )
+                        .await
+                    }
+                    Err(e) => error!("Unable to leave room {}: {}", room.room_id(), e),
+                }
+            }
+        }
+    });
+}
+
+/// How long chaz has been the only member of the room, as far as this process has seen
+fn alone_for(room: &Room) -> Option<Duration> {
+    let mut alone_since = ALONE_SINCE.lock().unwrap();
+    if room.joined_members_count() > 1 {
+        alone_since.remove(room.room_id());
+        return None;
+    }
+    let since = alone_since
+        .entry(room.room_id().to_owned())
+        .or_insert_with(Instant::now);
+    Some(since.elapsed())
+}
+
+/// How long ago the last event in the room was sent
+async fn idle_for(room: &Room) -> Option<Duration> {
+    let mut options = MessagesOptions::backward();
+    options.limit = UInt::from(1u32);
+    let batch = room.messages(options).await.ok()?;
+    let timestamp = batch
+        .chunk
+        .first()?
+        .event
+        .get_field::<MilliSecondsSinceUnixEpoch>("origin_server_ts")
+        .ok()??;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+    Some(now.saturating_sub(Duration::from_millis(u64::from(timestamp.0))))
+}
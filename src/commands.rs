@@ -0,0 +1,281 @@
+/// Command arguments
+///
+/// Commands declare the arguments they take with a `Signature`, which checks the message before the command runs
+/// and generates the usage shown in `!chaz help`. Arguments are separated by whitespace, and quotes group words
+/// into one argument, e.g. `!chaz backend "my backend" https://example.com/v1 key`. Commands like
+/// `!chaz memories keep 1 2` declare their subcommands, each with its own arguments.
+/// Misspelled command names get a suggestion of the command that was probably meant.
+use std::collections::HashMap;
+
+/// How an argument is matched
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Required,
+    Optional,
+    /// The rest of the message
+    Rest,
+    /// The rest of the message, if there is any
+    OptionalRest,
+}
+
+#[derive(Clone)]
+struct Param {
+    name: &'static str,
+    kind: Kind,
+}
+
+/// The arguments a command takes
+#[derive(Clone, Default)]
+pub struct Signature {
+    params: Vec<Param>,
+    /// Subcommands with their own arguments, matched by the first word before the params
+    subcommands: Vec<(&'static str, Signature)>,
+    /// Usage shown instead of the generated one
+    usage: Option<String>,
+}
+
+impl Signature {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an argument that has to be given
+    pub fn required(mut self, name: &'static str) -> Self {
+        self.params.push(Param {
+            name,
+            kind: Kind::Required,
+        });
+        self
+    }
+
+    /// Add an argument that can be left out, after the required ones
+    pub fn optional(mut self, name: &'static str) -> Self {
+        self.params.push(Param {
+            name,
+            kind: Kind::Optional,
+        });
+        self
+    }
+
+    /// Take the rest of the message as the last argument, keeping its formatting
+    pub fn rest(mut self, name: &'static str) -> Self {
+        self.params.push(Param {
+            name,
+            kind: Kind::Rest,
+        });
+        self
+    }
+
+    /// Take the rest of the message as the last argument if there is any, keeping its formatting
+    pub fn optional_rest(mut self, name: &'static str) -> Self {
+        self.params.push(Param {
+            name,
+            kind: Kind::OptionalRest,
+        });
+        self
+    }
+
+    /// Add a subcommand, used when the first argument is its name
+    pub fn subcommand(mut self, name: &'static str, signature: Signature) -> Self {
+        self.subcommands.push((name, signature));
+        self
+    }
+
+    /// Show a different usage than the generated one
+    pub fn with_usage(mut self, usage: &str) -> Self {
+        self.usage = Some(usage.to_string());
+        self
+    }
+
+    /// The usage of the command, e.g. `<name> <api_base> <api_key> [<name>]` or `[list|remove <file>]`
+    pub fn usage(&self) -> String {
+        if let Some(usage) = &self.usage {
+            return usage.clone();
+        }
+        let params = self
+            .params
+            .iter()
+            .map(|param| match param.kind {
+                Kind::Optional | Kind::OptionalRest => format!("[<{}>]", param.name),
+                _ => format!("<{}>", param.name),
+            })
+            .collect::<Vec<String>>()
+            .join(" ");
+        if self.subcommands.is_empty() {
+            return params;
+        }
+        // Without a subcommand the params are used, list them as one of the choices
+        let takes_nothing = self
+            .params
+            .iter()
+            .all(|param| matches!(param.kind, Kind::Optional | Kind::OptionalRest));
+        let params = params.replace("[<", "<").replace(">]", ">");
+        let choices: Vec<String> = (!params.is_empty())
+            .then_some(params)
+            .into_iter()
+            .chain(self.subcommands.iter().map(|(name, signature)| {
+                format!("{} {}", name, signature.usage())
+                    .trim_end()
+                    .to_string()
+            }))
+            .collect();
+        if takes_nothing {
+            format!("[{}]", choices.join("|"))
+        } else {
+            choices.join("|")
+        }
+    }
+
+    /// Parse the arguments of a message, which starts with `!chaz <command>`
+    pub fn parse(&self, text: &str) -> Result<Args, String> {
+        let (_, rest) = split_words(text, 2);
+        self.parse_args(rest)
+    }
+
+    /// Parse the arguments after the command or subcommand
+    fn parse_args(&self, rest: &str) -> Result<Args, String> {
+        let (words, after) = split_words(rest, 1);
+        if let Some((name, signature)) = words.first().and_then(|word| {
+            self.subcommands
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(word))
+        }) {
+            let mut args = signature.parse_args(after)?;
+            args.subcommand = Some(name);
+            return Ok(args);
+        }
+        let mut rest = rest;
+        let mut values = HashMap::new();
+        for param in &self.params {
+            if matches!(param.kind, Kind::Rest | Kind::OptionalRest) {
+                if rest.is_empty() {
+                    if param.kind == Kind::Rest {
+                        return Err(format!("missing <{}>", param.name));
+                    }
+                    continue;
+                }
+                values.insert(param.name, rest.to_string());
+                rest = "";
+                continue;
+            }
+            match next_token(rest)? {
+                Some((token, remaining)) => {
+                    values.insert(param.name, token);
+                    rest = remaining;
+                }
+                None if param.kind == Kind::Required => {
+                    return Err(format!("missing <{}>", param.name));
+                }
+                None => {}
+            }
+        }
+        if !rest.is_empty() {
+            return Err(format!("unexpected \"{}\"", rest));
+        }
+        Ok(Args {
+            subcommand: None,
+            values,
+        })
+    }
+}
+
+/// The parsed arguments of a command, by name
+#[derive(Default)]
+pub struct Args {
+    subcommand: Option<&'static str>,
+    values: HashMap<&'static str, String>,
+}
+
+impl Args {
+    /// Get the subcommand that was used, if any
+    pub fn subcommand(&self) -> Option<&'static str> {
+        self.subcommand
+    }
+
+    /// Get an argument, None if an optional one was left out
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+}
+
+impl std::ops::Index<&str> for Args {
+    type Output = str;
+
+    /// Get a required argument
+    fn index(&self, name: &str) -> &str {
+        self.get(name).unwrap_or_default()
+    }
+}
+
+/// Split off the first `count` words of the text, returning them and the rest with its formatting intact
+pub fn split_words(text: &str, count: usize) -> (Vec<&str>, &str) {
+    let mut words = Vec::new();
+    let mut rest = text.trim_start();
+    while words.len() < count && !rest.is_empty() {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        words.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    (words, rest.trim_end())
+}
+
+/// Get the next argument of the text and the rest after it
+///
+/// A quoted argument can contain whitespace, and `\"` inside it is a literal quote.
+fn next_token(text: &str) -> Result<Option<(String, &str)>, String> {
+    let text = text.trim_start();
+    let Some(first) = text.chars().next() else {
+        return Ok(None);
+    };
+    if first != '"' && first != '\'' {
+        let end = text.find(char::is_whitespace).unwrap_or(text.len());
+        return Ok(Some((text[..end].to_string(), text[end..].trim_start())));
+    }
+    let mut token = String::new();
+    let mut chars = text.char_indices().skip(1);
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some((_, escaped)) = chars.next() {
+                    token.push(escaped);
+                }
+            }
+            c if c == first => {
+                return Ok(Some((token, text[index + 1..].trim_start())));
+            }
+            c => token.push(c),
+        }
+    }
+    Err(format!("missing the closing {}", first))
+}
+
+/// Find the command closest to a misspelled name, if one is close enough to be what was meant
+///
+/// Short words are left alone, so a request like `!chaz is this right?` isn't mistaken for a typo.
+pub(crate) fn suggest<'a>(name: &str, commands: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let length = name.chars().count();
+    if length < 4 {
+        return None;
+    }
+    let allowed = if length < 6 { 1 } else { 2 };
+    commands
+        .map(|command| (edit_distance(name, command), command))
+        .filter(|(distance, _)| *distance <= allowed)
+        .min()
+        .map(|(_, command)| command)
+}
+
+/// Number of single character insertions, deletions, and substitutions to turn one word into another
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
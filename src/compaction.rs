@@ -0,0 +1,132 @@
+/// Context compaction
+///
+/// With `compaction` set, once the context of a room grows past the threshold, the older messages are summarized
+/// with the chat_summary_model in the background. The summary is stored in the room account data along with the ID
+/// of the newest message it covers, and from then on the context is the summary plus the messages after it. The
+/// next compaction summarizes the previous summary with the messages since, so long running rooms stay usable
+/// indefinitely. Each workspace has its own summary.
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use lazy_static::lazy_static;
+use matrix_sdk::{
+    ruma::{OwnedEventId, OwnedRoomId},
+    Room,
+};
+use openai_api_rs::v1::chat_completion::MessageRole;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+use crate::{account_data, backends::Message, context, get_backend, get_chat_summary_model};
+
+/// Type of the room account data the summaries are stored in
+const EVENT_TYPE: &str = "is.chaz.compaction";
+
+/// Messages kept after the summary by default
+const DEFAULT_KEEP_MESSAGES: usize = 10;
+
+/// Start of the message the summary is added to the context as
+const SUMMARY_PREFIX: &str = "Summary of the earlier conversation:";
+
+/// Configuration for the context compaction
+#[derive(Debug, Deserialize, Clone)]
+pub struct CompactionConfig {
+    /// Estimated tokens in the context that start a compaction
+    pub threshold: usize,
+    /// Most recent messages that are kept as they are, defaults to 10
+    pub keep_messages: Option<usize>,
+}
+
+/// The summary of the start of a conversation
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Summary {
+    pub summary: String,
+    /// The newest message the summary covers
+    pub until: OwnedEventId,
+}
+
+lazy_static! {
+    /// The rooms being compacted, so only one compaction runs at a time in each
+    static ref COMPACTING: Mutex<HashSet<OwnedRoomId>> = Mutex::new(HashSet::new());
+}
+
+/// Load the summaries of the room, by workspace
+async fn load(room: &Room) -> HashMap<String, Summary> {
+    match account_data::get(room, EVENT_TYPE).await {
+        Ok(summaries) => summaries.unwrap_or_default(),
+        Err(e) => {
+            error!("Unable to load the summary of {}: {}", room.room_id(), e);
+            HashMap::new()
+        }
+    }
+}
+
+/// Get the summary of the conversation in the workspace
+pub async fn get(room: &Room, workspace: &str) -> Option<Summary> {
+    load(room).await.remove(workspace)
+}
+
+/// Make the message the summary is added to the context as
+pub fn summary_message(summary: &Summary) -> Message {
+    Message::new(
+        MessageRole::system,
+        format!("{} {}", SUMMARY_PREFIX, summary.summary),
+    )
+}
+
+/// Compact the context in the background if it's grown past the threshold
+///
+/// The messages are the full context of the workspace, oldest first, starting with the current summary if there
+/// is one.
+pub fn compact_if_needed(
+    room: &Room,
+    workspace: &str,
+    messages: &[Message],
+    config: &CompactionConfig,
+) {
+    let tokens: usize = messages.iter().map(context::estimate_message_tokens).sum();
+    if tokens <= config.threshold {
+        return;
+    }
+    let keep = config.keep_messages.unwrap_or(DEFAULT_KEEP_MESSAGES);
+    let older = &messages[..messages.len().saturating_sub(keep)];
+    // The summary has to end at a message in the room, so it can be found in the history again
+    let Some(end) = older.iter().rposition(|m| m.event_id.is_some()) else {
+        return;
+    };
+    let Some(until) = older[end].event_id.clone() else {
+        return;
+    };
+    if !COMPACTING.lock().unwrap().insert(room.room_id().to_owned()) {
+        return;
+    }
+    let older: Vec<Message> = older[..=end]
+        .iter()
+        .map(|m| Message::new(m.role.clone(), m.content.clone()).with_sender(m.sender.clone()))
+        .collect();
+    let room = room.clone();
+    let workspace = workspace.to_string();
+    tokio::spawn(async move {
+        let count = older.len();
+        let backend = get_backend(&room, None).await;
+        match context::summarize_messages(&backend, get_chat_summary_model(), older).await {
+            Some(summary) => {
+                let summary = summary
+                    .trim()
+                    .trim_start_matches(SUMMARY_PREFIX)
+                    .trim()
+                    .to_string();
+                let mut summaries = load(&room).await;
+                summaries.insert(workspace, Summary { summary, until });
+                match account_data::set(&room, EVENT_TYPE, &summaries).await {
+                    Ok(()) => info!("Compacted {} messages in {}", count, room.room_id()),
+                    Err(e) => error!("Unable to save the summary of {}: {}", room.room_id(), e),
+                }
+            }
+            None => error!("Unable to summarize the context of {}", room.room_id()),
+        }
+        COMPACTING.lock().unwrap().remove(room.room_id());
+    });
+}
@@ -0,0 +1,64 @@
+/// Request scheduling
+///
+/// Messages and commands are handled outside of the sync, so a slow backend doesn't hold up the other rooms. Each
+/// room has its own queue so its messages are still answered in the order they were sent, and at most
+/// `max_concurrent_requests` are handled at once across all the rooms.
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Mutex};
+
+use lazy_static::lazy_static;
+use matrix_sdk::ruma::{OwnedRoomId, RoomId};
+use tokio::sync::{mpsc, Semaphore};
+
+use crate::get_config;
+
+/// Messages and commands handled at once when `max_concurrent_requests` isn't set
+const DEFAULT_LIMIT: usize = 4;
+
+type Task = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+lazy_static! {
+    /// The queue of each room, worked through by a task of its own
+    static ref QUEUES: Mutex<HashMap<OwnedRoomId, mpsc::UnboundedSender<Task>>> =
+        Mutex::new(HashMap::new());
+
+    /// Limits the messages and commands handled at once
+    static ref LIMIT: Semaphore = Semaphore::new(
+        get_config()
+            .max_concurrent_requests
+            .unwrap_or(DEFAULT_LIMIT)
+            .max(1)
+    );
+}
+
+/// Handle a message or command once the ones before it in the room are done
+pub fn spawn<F>(room_id: &RoomId, task: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let mut queues = QUEUES.lock().unwrap();
+    let mut task: Task = Box::pin(task);
+    if let Some(queue) = queues.get(room_id) {
+        match queue.send(task) {
+            Ok(()) => return,
+            // The worker is gone after a panic, so start a new one
+            Err(mpsc::error::SendError(unsent)) => task = unsent,
+        }
+    }
+    let (queue, tasks) = mpsc::unbounded_channel();
+    let _ = queue.send(task);
+    queues.insert(room_id.to_owned(), queue);
+    tokio::spawn(work(tasks));
+}
+
+/// Drop the queue of a room chaz left, the worker stops once it's done with the tasks already queued
+pub fn forget_room(room_id: &RoomId) {
+    QUEUES.lock().unwrap().remove(room_id);
+}
+
+/// Work through the queue of a room, one task at a time
+async fn work(mut tasks: mpsc::UnboundedReceiver<Task>) {
+    while let Some(task) = tasks.recv().await {
+        let _permit = LIMIT.acquire().await;
+        task.await;
+    }
+}
@@ -0,0 +1,170 @@
+/// Context window management
+///
+/// Keeps the context sent to the backends within a token budget.
+use openai_api_rs::v1::chat_completion::MessageRole;
+
+use crate::backends::{BackendManager, ChatContext, Message};
+
+/// Fixed overhead per message for the role and formatting
+pub const TOKENS_PER_MESSAGE: usize = 4;
+
+/// Estimate the number of tokens in a string.
+///
+/// BPE tokenizers average about 4 characters per token for English text, and never merge across whitespace,
+/// so each word is counted separately.
+pub fn estimate_tokens(text: &str) -> usize {
+    estimate_tokens_with(text, &GENERIC)
+}
+
+/// The encoding of models that aren't recognized
+const GENERIC: Encoding = Encoding {
+    name: "estimated at 4 characters per token",
+    chars_per_token: 4,
+    count: None,
+};
+
+/// How a family of models splits text into tokens
+pub struct Encoding {
+    /// How the tokens are counted, shown with the counts
+    pub name: &'static str,
+    /// Average characters in a token of English text, for the estimates
+    chars_per_token: usize,
+    /// Counts the tokens with tiktoken
+    count: Option<fn(&str) -> usize>,
+}
+
+/// Get the encoding used by a model, guessed from its name
+///
+/// Newer OpenAI models use o200k_base, older ones cl100k_base, and those are counted exactly with tiktoken.
+/// Llama 3 extends cl100k_base, so that's close. Models with smaller vocabularies, like Llama 2 and Mistral, split
+/// text into more tokens, and are only estimated.
+pub fn encoding_for(model: &str) -> Encoding {
+    // Ignore the backend name, e.g. "openai:gpt-4o", but keep the tag in "ollama:llama3:8b"
+    let model = model
+        .split_once(':')
+        .map_or(model, |(_, model)| model)
+        .to_lowercase();
+    if ["gpt-4o", "gpt-4.1", "o1", "o3", "o4"]
+        .iter()
+        .any(|prefix| model.starts_with(prefix))
+    {
+        Encoding {
+            name: "counted with o200k_base",
+            chars_per_token: 5,
+            count: Some(count_o200k),
+        }
+    } else if model.starts_with("gpt-") {
+        Encoding {
+            name: "counted with cl100k_base",
+            chars_per_token: 4,
+            count: Some(count_cl100k),
+        }
+    } else if model.contains("llama3") || model.contains("llama-3") {
+        Encoding {
+            name: "approximated with cl100k_base",
+            chars_per_token: 4,
+            count: Some(count_cl100k),
+        }
+    } else if ["llama", "mistral", "mixtral", "gemma", "phi"]
+        .iter()
+        .any(|family| model.contains(family))
+    {
+        Encoding {
+            name: "estimated at 3 characters per token",
+            chars_per_token: 3,
+            count: None,
+        }
+    } else {
+        GENERIC
+    }
+}
+
+fn count_o200k(text: &str) -> usize {
+    tiktoken_rs::o200k_base_singleton()
+        .lock()
+        .encode_ordinary(text)
+        .len()
+}
+
+fn count_cl100k(text: &str) -> usize {
+    tiktoken_rs::cl100k_base_singleton()
+        .lock()
+        .encode_ordinary(text)
+        .len()
+}
+
+/// Count the tokens in a string for an encoding, or estimate them if tiktoken doesn't have it
+pub fn estimate_tokens_with(text: &str, encoding: &Encoding) -> usize {
+    if let Some(count) = encoding.count {
+        return count(text);
+    }
+    text.split_whitespace()
+        .map(|word| word.chars().count().div_ceil(encoding.chars_per_token))
+        .sum()
+}
+
+/// Estimate the number of tokens used by a single message
+pub fn estimate_message_tokens(message: &Message) -> usize {
+    estimate_tokens(&message.content) + TOKENS_PER_MESSAGE
+}
+
+/// Estimate the number of tokens used by the full context, including the role
+pub fn estimate_context_tokens(context: &ChatContext) -> usize {
+    let role_tokens = context.role.as_ref().map_or(0, |role| {
+        estimate_tokens(&role.get_prompt()) + TOKENS_PER_MESSAGE
+    });
+    role_tokens
+        + context
+            .messages
+            .iter()
+            .map(estimate_message_tokens)
+            .sum::<usize>()
+}
+
+/// Drop the oldest messages until the context fits in the token limit.
+///
+/// The most recent message is always kept. Returns the messages that were dropped, oldest first.
+pub fn truncate_context(context: &mut ChatContext, token_limit: usize) -> Vec<Message> {
+    let mut dropped = Vec::new();
+    let mut tokens = estimate_context_tokens(context);
+    while tokens > token_limit && context.messages.len() > 1 {
+        let message = context.messages.remove(0);
+        tokens -= estimate_message_tokens(&message);
+        if message.attached_media && !context.media.is_empty() {
+            // The media is in the same order as the placeholders, so this is the matching file
+            context.media.remove(0);
+        }
+        dropped.push(message);
+    }
+    dropped
+}
+
+/// Summarize messages that were dropped from the context
+///
+/// Returns None if the backend fails to produce a summary.
+pub async fn summarize_messages(
+    backend: &BackendManager,
+    model: Option<String>,
+    messages: Vec<Message>,
+) -> Option<String> {
+    let mut context = ChatContext {
+        messages,
+        model,
+        media: Vec::new(),
+        tools: Vec::new(),
+        schema: None,
+        params: Default::default(),
+        reported_cost: Default::default(),
+        role: None,
+    };
+    context.messages.push(Message::new(
+        MessageRole::user,
+        [
+            "Summarize the conversation so far in a few sentences.",
+            "Include any facts, decisions, or open questions that are needed to continue the conversation.",
+            "Do not output anything except for the summary text.",
+        ]
+        .join(" "),
+    ));
+    backend.execute(&context).await.ok()
+}
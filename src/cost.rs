@@ -0,0 +1,216 @@
+/// Cost tracking
+///
+/// With prices set on the models of the backends, the estimated cost of every request is added up for the user
+/// that sent it and the room it was sent in, and shown with `!chaz cost`. A budget per user stops chaz from
+/// answering them once it's spent. The totals are saved in the state directory.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{
+    session,
+    util::{format_utc, read_state},
+};
+
+/// Configuration for the cost tracking
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct CostConfig {
+    /// Symbol shown with the amounts, defaults to $
+    pub currency: Option<String>,
+    /// Maximum spend per user, in total
+    pub budget: Option<f64>,
+    /// Maximum spend per user each month
+    pub monthly_budget: Option<f64>,
+}
+
+impl CostConfig {
+    /// Format an amount with the currency
+    fn format(&self, amount: f64) -> String {
+        format!("{}{:.4}", self.currency.as_deref().unwrap_or("$"), amount)
+    }
+}
+
+/// Prices of a model, per million tokens
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Pricing {
+    pub input: f64,
+    pub output: f64,
+}
+
+impl Pricing {
+    /// Estimated cost of a request
+    pub fn cost(&self, input_tokens: usize, output_tokens: usize) -> f64 {
+        (input_tokens as f64 * self.input + output_tokens as f64 * self.output) / 1_000_000.0
+    }
+
+    /// Describe the prices, e.g. "$3 in, $15 out per million tokens"
+    pub fn describe(&self, config: &CostConfig) -> String {
+        let currency = config.currency.as_deref().unwrap_or("$");
+        format!(
+            "{}{} in, {}{} out per million tokens",
+            currency, self.input, currency, self.output
+        )
+    }
+}
+
+/// Spend of a user or room
+#[derive(Serialize, Deserialize, Default)]
+struct Spend {
+    total: f64,
+    /// The month of the monthly spend, e.g. "2024-05"
+    month: String,
+    monthly: f64,
+}
+
+impl Spend {
+    /// Reset the monthly spend if the month is over
+    fn roll_over(&mut self, month: &str) {
+        if self.month != month {
+            self.month = month.to_string();
+            self.monthly = 0.0;
+        }
+    }
+
+    fn add(&mut self, amount: f64) {
+        self.roll_over(&this_month());
+        self.total += amount;
+        self.monthly += amount;
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Totals {
+    users: HashMap<String, Spend>,
+    rooms: HashMap<String, Spend>,
+}
+
+struct Costs {
+    path: PathBuf,
+    totals: Totals,
+}
+
+lazy_static! {
+    static ref COSTS: Mutex<Option<Costs>> = Mutex::new(None);
+}
+
+/// The current month in UTC, e.g. "2024-05"
+fn this_month() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    format_utc(seconds)[..7].to_string()
+}
+
+/// Load the saved totals from the state directory
+pub fn init(state_dir: &Path) -> anyhow::Result<()> {
+    let path = state_dir.join("cost.json");
+    let totals = read_state(&path)?;
+    *COSTS.lock().unwrap() = Some(Costs { path, totals });
+    Ok(())
+}
+
+/// Add the cost of a request to the user and the room
+pub fn record(user: &str, room: &str, amount: f64) {
+    if amount <= 0.0 {
+        return;
+    }
+    let mut costs = COSTS.lock().unwrap();
+    let Some(costs) = costs.as_mut() else {
+        return;
+    };
+    costs
+        .totals
+        .users
+        .entry(user.to_string())
+        .or_default()
+        .add(amount);
+    costs
+        .totals
+        .rooms
+        .entry(room.to_string())
+        .or_default()
+        .add(amount);
+    save(costs);
+}
+
+/// Check the user's spend against the budgets, returning the reason if one is used up
+pub fn check(user: &str, config: &CostConfig) -> Result<(), String> {
+    let mut costs = COSTS.lock().unwrap();
+    let Some(costs) = costs.as_mut() else {
+        return Ok(());
+    };
+    let Some(spend) = costs.totals.users.get_mut(user) else {
+        return Ok(());
+    };
+    spend.roll_over(&this_month());
+    let budgets = [
+        (spend.total, config.budget, "budget"),
+        (spend.monthly, config.monthly_budget, "monthly budget"),
+    ];
+    for (spent, budget, name) in budgets {
+        if let Some(budget) = budget {
+            if spent >= budget {
+                return Err(format!(
+                    "you have used up your {} of {}",
+                    name,
+                    config.format(budget)
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Describe the spend of a user and a room
+pub fn report(user: &str, room: &str, config: &CostConfig) -> String {
+    let mut costs = COSTS.lock().unwrap();
+    let Some(costs) = costs.as_mut() else {
+        return "Costs aren't being tracked".to_string();
+    };
+    let month = this_month();
+    let describe = |spends: &mut HashMap<String, Spend>, key: &str| {
+        let spend = spends.entry(key.to_string()).or_default();
+        spend.roll_over(&month);
+        (spend.monthly, spend.total)
+    };
+    let (user_monthly, user_total) = describe(&mut costs.totals.users, user);
+    let (room_monthly, room_total) = describe(&mut costs.totals.rooms, room);
+    let budget = |budget: Option<f64>| match budget {
+        Some(budget) => format!(" of {}", config.format(budget)),
+        None => String::new(),
+    };
+    [
+        format!(
+            "{}: {}{} this month, {}{} in total",
+            user,
+            config.format(user_monthly),
+            budget(config.monthly_budget),
+            config.format(user_total),
+            budget(config.budget)
+        ),
+        format!(
+            "This room: {} this month, {} in total",
+            config.format(room_monthly),
+            config.format(room_total)
+        ),
+    ]
+    .join("\n")
+}
+
+/// Write the totals to disk
+fn save(costs: &Costs) {
+    let result = serde_json::to_string(&costs.totals)
+        .map_err(anyhow::Error::from)
+        .and_then(|contents| Ok(session::write(&costs.path, &contents)?));
+    if let Err(e) = result {
+        error!("Unable to save costs: {}", e);
+    }
+}
@@ -0,0 +1,103 @@
+/// Debug mode
+///
+/// `!chaz debug on` makes chaz post the request it sends to the backend before each response in the room: the
+/// system prompt, the messages, the model, and the parameters, as the backend receives them. The payload is in a
+/// collapsed block, so it doesn't take over the room. Only room admins and bot admins can turn it on, since the
+/// payload shows the role and everything added to the context.
+use headjack::Tags;
+use matrix_sdk::{
+    ruma::{events::room::message::RoomMessageEventContent, OwnedUserId},
+    Room,
+};
+use tracing::error;
+
+use crate::{
+    backends::BackendManager, chunking, commands::Args, error::ChazError, i18n, is_admin,
+    permissions, ChatContext,
+};
+
+/// Tag namespace for the setting
+const NAMESPACE: &str = "is.chaz.debug";
+
+/// Check if debug mode is on in the room
+pub async fn is_enabled(room: &Room) -> bool {
+    Tags::new(room, NAMESPACE)
+        .await
+        .get_value("enabled")
+        .is_some_and(|value| value == "on")
+}
+
+/// Show or toggle debug mode, `!chaz debug [on|off]`
+pub async fn debug(sender: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let response = match args.subcommand() {
+        None => {
+            let state = if is_enabled(&room).await { "on" } else { "off" };
+            i18n::notice_text(&room, "debug", &[("state", state)]).await
+        }
+        Some(_) if !is_admin(&sender) && !permissions::is_room_admin(&room, &sender).await => {
+            i18n::notice_text(&room, "debug-forbidden", &[]).await
+        }
+        Some(setting) => {
+            let mut tags = Tags::new(&room, NAMESPACE).await;
+            if setting == "on" {
+                tags.replace_kv("enabled", "on");
+            } else {
+                tags.remove_kv("enabled");
+            }
+            tags.sync().await;
+            i18n::notice_text(&room, "debug", &[("state", setting)]).await
+        }
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// Post the request about to be sent to the backend, if debug mode is on in the room
+pub async fn post_request(room: &Room, backend: &BackendManager, context: &ChatContext) {
+    if !is_enabled(room).await {
+        return;
+    }
+    let backend_name = backend
+        .backend_name(context)
+        .unwrap_or("no backend".to_string());
+    let mut payload = match backend.describe_request(context).await {
+        Ok(payload) => payload,
+        Err(e) => format!("Unable to describe the request: {}", e),
+    };
+    // Leave room for the markup around it in the message
+    let max_length = chunking::max_length() / 2;
+    if payload.len() > max_length {
+        let mut cut = max_length;
+        while !payload.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        payload.truncate(cut);
+        payload.push_str("\n… (truncated)");
+    }
+    let (messages, media) = (
+        context.messages.len().to_string(),
+        context.media.len().to_string(),
+    );
+    let args = [
+        ("backend", backend_name.as_str()),
+        ("messages", messages.as_str()),
+        ("media", media.as_str()),
+    ];
+    let summary = i18n::tr(room, "debug-request", &args).await;
+    let escaped = payload
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    // The notice starts with "!chaz" so it's left out of the context
+    let content = RoomMessageEventContent::notice_html(
+        format!("!chaz {}\n{}", summary, payload),
+        format!(
+            "<details><summary>!chaz {}</summary><pre><code class=\"language-json\">{}</code></pre></details>",
+            summary, escaped
+        ),
+    );
+    if let Err(e) = room.send(content).await {
+        error!("Unable to post the debug request: {}", e);
+    }
+}
@@ -11,12 +11,65 @@ lazy_static! {
 homeserver_url: ""
 username: ""
 
+# Optional. Additional endpoints for the homeserver, used in order when syncing repeatedly fails
+#homeserver_urls: []
+#sync_failure_limit: 3
+
 # Optional, if not given it will be asked for on first run
 #password: ""
 
+# Optional. Log in with SSO on first run, prints a URL to open and asks for the login token
+#sso: false
+
+# Optional. Log in with an access token instead, it should be for a new device
+#access_token: ""
+
+# Optional. More accounts to run in the same process, each with its own default role and model
+#accounts:
+#  - name: coder
+#    username: ""
+#    password: ""
+#    role: ""
+#    model: ""
+
+# Optional. Run as an appservice instead, with a virtual user for each persona
+# The registration for the homeserver is written to appservice.yaml in the state directory
+#appservice:
+#  port: 9000
+#  bind: "127.0.0.1" # The events are plain HTTP, keep it local or behind a TLS proxy
+#  as_token: ""
+#  hs_token: ""
+#  personas:
+#    - localpart: chaz_gpt4o
+#      model: ""
+#      role: ""
+
 # Technically optional, but the bot won't respond without it
 #allow_list: ""
 
+# Optional, regex for accounts that are ignored even if they match the allow_list
+#deny_list: ""
+
+# Optional, regex for accounts allowed to run admin commands
+#admin_list: ""
+
+# Optional, room ID where chaz posts notifications and errors for the admins
+# Startup, errors, rate limits, invites, and moderation flags are reported there
+#admin_room: ""
+
+# Optional, check GitHub for a newer release on startup and post it to the admin room
+#update_check: false
+
+# Optional, where the store passphrase and ${secret:<name>} values are kept: env, file, or keyring
+# With `env` they're read from CHAZ_SECRET_<NAME>, with `file` from the files in `path`
+#secrets:
+#  provider: file
+#  path: ""
+
+# Optional, secret storage recovery key
+# Restores the cross-signing identity and room key backup on startup
+#recovery_key: ""
+
 # Optional. Not setting it here because reading it from an XDG library is safer.
 #state_dir: "$XDG_STATE_HOME/username"
 
@@ -30,14 +83,301 @@ username: ""
 # Optional. Set a role, A.K.A. system prompt, to use by default
 #role: ""
 
-# Optional. Set a per-account message limit.
-#message_limit: 0
+# Optional. Default room language, picks the translation of the role if it has one, and of the notices
+#language: ""
+
+# Optional. Mention the user a response is for in rooms with more than one user, so they get notified
+#mention_sender: true
+
+# Optional. Let the model answer an ambiguous request with a poll of what was meant, and answer once the user votes
+#disambiguation_polls: false
+
+# Optional. Schemas `!chaz json <schema> <prompt>` can answer with, each with a name, a description, and the schema
+#json_schemas:
+#  - name: todo
+#    description: Action items from the conversation
+#    schema:
+#      type: array
+#      items:
+#        type: object
+#        properties:
+#          task: { type: string }
+#          owner: { type: string }
+#        required: [task]
+
+# Optional. Let `!chaz model router` pick a model for each request
+# The first route whose rules all match is used, then the classifier is asked if there's a prompt
+#router:
+#  default: ""
+#  classifier_model: "" # Defaults to chat_summary_model
+#  prompt: "Pick the cheapest model that can answer the request well."
+#  routes:
+#    - model: ""
+#      code: true
+#      min_tokens: 50000
+#      keywords: []
+#      description: ""
+
+# Optional. Send responses as replies to the message that prompted them: always, groups, or never
+# groups replies everywhere but in direct messages
+#rich_replies: groups
+
+# Optional. Send responses as markdown, plain text, or notices, can be set per room with `!chaz format`
+#format: markdown
+
+# Optional. Shortcuts for commands in every room, e.g. "!sum": "!chaz summarize"
+#aliases:
+#  "!c": "!chaz"
+
+# Optional. Directory of Fluent files translating the notices, named after the language, e.g. de.ftl
+#locales_dir: ""
+
+# Optional. Directory of role files, each a YAML role like in `roles` or a Markdown prompt with an optional YAML
+# front matter, named after the file. Reloaded when they change, the roles in this config win over the files
+#roles_dir: ""
+
+# Optional. Limit the messages per hour of each user and each room, allowing a burst of them in a row
+# The limits are saved in the state directory, so they hold across restarts
+#rate_limit:
+#  messages_per_hour: 30
+#  burst: 5
+#  room_messages_per_hour: 120
+#  room_burst: 10
+
+# Optional. Per-account daily and monthly quotas, tokens are estimated for the context and the response
+#quotas:
+#  daily_messages: 100
+#  monthly_messages: 1000
+#  daily_tokens: 100000
+#  monthly_tokens: 1000000
+
+# Optional. Budgets per user for the costs estimated from the input_cost and output_cost of the models
+# The prices are per million tokens, set on the models of the backends. See `!chaz cost`
+# OpenRouter backends (`type: openrouter`) use the prices from its catalogue, and the cost it reports for each request
+#cost:
+#  currency: "$"
+#  budget: 10
+#  monthly_budget: 2
+
+# Optional. Check the responses, and with check_input the messages, before they're posted or answered
+# Flagged text is blocked, redacted to a notice, or flagged to the admin_room and let through
+#moderation:
+#  keywords: []
+#  patterns: []
+#  api_base: https://api.openai.com/v1
+#  api_key: ""
+#  model: omni-moderation-latest
+#  action: redact
+#  check_input: false
+
+# Optional. Queue questions while the backend is down, and answer them once it recovers
+# Only connection errors, timeouts, and 5xx responses are queued, other errors are posted right away
+#offline_queue:
+#  size: 20
+#  retry_interval: 1m
+#  max_wait: 1h
 
 # Optional. Set a room size limit to respond in.
 #room_size_limit: 0
 
-# Optional. Set to true to disable sending media context to aichat
+# Optional. Leave rooms that aren't used anymore, checked every hour
+#cleanup:
+#  alone_after: 1d # Leave rooms where chaz has been the only member for this long
+#  idle_after: 90d # Leave rooms without any messages for this long
+
+# Optional. Posted when joining a room, replacing the default that explains how to talk to chaz, the model, and
+# what is sent to the backend. {model}, {room}, and {inviter} are filled in, set to "" to post nothing
+#welcome_message: "Hi {inviter}, mention me or start a message with !chaz. I'm using {model}."
+
+# Optional. Set to true to disable sending images and the content of attached files to the backends
 #disable_media_context: false
+# Models that can't see images, call tools, or follow a system prompt can say so with `capabilities` on the model
+# or the backend, e.g. `capabilities: { supports_vision: false, max_context: 8192 }`, and Chaz adapts the requests
+# OpenAI compatible backends can list the models they serve with `query_models: true`, refreshed every
+# `models_cache_ttl` (default 1h), so `!chaz list` matches what a proxy like LiteLLM or vLLM actually has
+# Backends can send extra `headers` with every request, e.g. `headers: { X-Gateway-Key: "${secret:gateway_key}" }`,
+# and go through a `proxy`, e.g. `proxy: http://proxy.corp.example:3128`, alongside the `ca_bundle` for a private CA
+
+# Optional. Limits for the text and PDF files inlined into the context
+# PDFs are converted with pdftotext from poppler, which has to be installed
+#attachments:
+#  text_limit: 20000 # characters
+#  pdf_limit: 20000 # characters
+#  max_file_size: 5 # MB
+
+# Optional. Soft deadline in seconds, after which the partial response is posted
+#response_deadline: 60
+
+# Optional. Maximum tokens in a response, for the models that don't set their own max_tokens
+# aichat backends ignore it, their limit comes from the aichat config
+#max_response_tokens: 2000
+
+# Optional. Maximum length of a message in bytes, longer responses are split into several messages
+# Homeservers reject events over 64KB, and Markdown is sent as both text and HTML
+#max_message_length: 24000
+
+# Optional. Limit the estimated tokens sent as context, dropping the oldest messages
+#context_token_limit: 8000
+
+# Optional. Messages starting with this prefix are answered without the room history, like `!chaz send`
+#no_context_prefix: "!!"
+
+# Optional. Start a new conversation after this long without messages, e.g. "12h" or "7d"
+#context_ttl: "7d"
+
+# Optional. The room history is cached in memory, set to true to save it in the state directory too
+# Encrypted rooms are never saved, so their messages aren't written to disk decrypted
+#persist_history: false
+
+# Optional. Summarize the messages dropped by the token limit using the chat_summary_model
+#summarize_truncated_context: false
+
+# Optional. Once the context grows past the threshold, summarize the older messages with the chat_summary_model
+# in the background, and use the summary plus the recent messages as the context from then on
+#compaction:
+#  threshold: 6000
+#  keep_messages: 10
+
+# Optional. Set to true to include the sender's name with each message
+#multi_user_context: false
+
+# Optional. Set to true to include stickers and the reactions to messages in the context
+#social_context: false
+
+# Optional. Model used to decide whether to chime in when listening in a room, defaults to chat_summary_model
+# Only messages that name chaz, ask a question, or mention one of the topics are checked
+#interjection_model: ""
+
+# Optional. Topics chaz will chime in on when listening in a room
+#interjection_topics: []
+
+# Optional. Show a banner in the topic of DM rooms while a backend keeps failing
+#status_banner: false
+#degraded_threshold: 3
+
+# Optional. Filter the events received from the homeserver
+# Presence, read receipts, and typing notifications are left out by default
+#sync_filter:
+#  presence: false
+#  ephemeral: false
+#  exclude_types: ["m.reaction"]
+
+# Optional. Image generation backend for `!chaz imagine`
+# Use `openai` for the OpenAI images API, or `stablediffusion` for the AUTOMATIC1111 web UI API
+#image_generation:
+#  type: openai
+#  api_base: https://api.openai.com/v1
+#  api_key: ""
+#  model: dall-e-3
+#  size: 1024x1024
+
+# Optional. Transcribe voice messages and include them in the context
+# Use `openai` for a Whisper compatible API, or `whispercpp` for a local whisper.cpp binary
+#transcription:
+#  type: openai
+#  api_base: https://api.openai.com/v1
+#  api_key: ""
+#  model: whisper-1
+
+# Optional. Index a directory of markdown/text files and search it for every room
+# Embeddings are created with an OpenAI compatible backend, and the directory is re-indexed when files change
+#knowledge:
+#  directory: /path/to/notes
+#  embedding_backend: openai
+#  embedding_model: text-embedding-3-small
+#  top_k: 3
+#  chunk_size: 1500
+#  reindex_interval: 300
+
+# Optional. Let users index the files uploaded to a room with `!chaz index`, and search them in that room
+# PDFs are converted with pdftotext from poppler, which has to be installed
+#documents:
+#  embedding_backend: openai
+#  embedding_model: text-embedding-3-small
+#  top_k: 3
+#  chunk_size: 1500
+#  max_file_size: 10 # MB
+
+# Optional. Suites of prompts with expected answers, run by admins with `!chaz eval <suite>`
+#eval_suites:
+#  - name: basics
+#    models: [openai:gpt-4o-mini] # Defaults to the model of the room
+#    cases:
+#      - prompt: "What is 2 + 2? Answer with just the number."
+#        equals: "4"
+#      - prompt: "What is the capital of France?"
+#        contains: "Paris"
+
+# Optional. Tools offered to the models that support function calling, the OpenAI API, Ollama and OpenRouter
+# models, or any with `capabilities: { supports_tools: true }`
+# web_fetch lets the model fetch any URL on the public internet, private and local addresses are refused
+#tools: [time, calculator, web_fetch]
+
+# Optional. Record 👍 and 👎 reactions to responses in feedback.jsonl in the state directory
+#feedback_log: false
+
+# Optional. Show chaz as typing while it waits for the backend, and mark the messages it handles as read
+#typing_notifications: true
+#read_receipts: true
+
+# Optional. How many messages and commands are handled at once across all rooms
+# Each room is answered one message at a time, in order
+#max_concurrent_requests: 4
+
+# Optional. On SIGTERM or SIGINT, how long to wait for the messages being answered before exiting
+#shutdown_timeout: 30s
+
+# Optional. Serve Prometheus metrics at /metrics on this port
+# Includes messages handled, backend requests, errors, and latencies by backend and model, rate limit rejections, and rooms joined
+#metrics_port: 9090
+
+# Optional. Serve health checks at /healthz, for the sync loop, and /readyz, which also checks the backends
+#health_port: 8080
+
+# Optional. Follow things in the home over MQTT, and let the model read and control them in these rooms
+#mqtt:
+#  broker: mqtt://localhost:1883 # Or mqtts:// for TLS
+#  ca_bundle: /etc/ssl/mqtt-ca.pem # Optional, a PEM CA bundle to trust for mqtts://
+#  username: chaz
+#  password: ${secret:mqtt_password}
+#  rooms: ["!home:example.com"]
+#  entities:
+#    - name: porch light
+#      state_topic: home/porch_light/state
+#      command_topic: home/porch_light/set
+#      description: send ON or OFF
+#      payloads: ["ON", "OFF"] # Optional, the only commands that can be sent
+
+# Optional. Let other services post messages and prompts to the rooms with POST /webhook
+#webhooks:
+#  port: 8090
+#  bind: "127.0.0.1" # The requests are plain HTTP, keep it local or behind a TLS proxy
+#  token: ${secret:webhook_token}
+#  rooms: ["!alerts:example.com"] # Optional, defaults to all the rooms chaz is in
+
+# Optional. Send a JSON event to these URLs for every request to a backend
+# With the room, sender, backend, model, latency, token counts, and cost
+#event_webhooks:
+#  - url: https://analytics.example.com/chaz
+#    token: ${secret:analytics_token} # Optional, sent as a bearer token
+#    include_text: false # Optional, also send the prompt and the response
+
+# Optional. Minimum power level in the room needed to use each command
+# Commands that aren't listed can be used by everyone on the allow_list
+#permissions:
+#  model: 50
+#  backend: 50
+
+# Optional. Also write the logs as JSON lines to chaz.log in the state directory, rotated by size
+#log_file:
+#  max_size: 10 # MB
+#  max_files: 5
+
+# Optional. Write every backend request and response, with the room and user, to audit.jsonl in the state directory
+#audit_log:
+#  redact: false # Replace the content of the messages and responses with their length
+#  max_size: 10 # MB
+#  max_files: 5
 
 # Predefined roles here to use above
 # These roles are builtin and can be set by any user
@@ -83,6 +423,7 @@ roles:
       The output should be a valid Bash command that directly aligns with the user's intent, ready for execution in a command-line environment.
       Do not output anything except for the command.
       No code block, no English explanation, no newlines, and no start/end tags.
+    postprocess: [extract_code, trim]
   - name: fish
     description: Get a fish shell command
     prompt: >
@@ -92,6 +433,7 @@ roles:
       The output should be a valid Fish command that directly aligns with the user's intent, ready for execution in a command-line environment.
       Do not output anything except for the command.
       No code block, no English explanation, no newlines, and no start/end tags.
+    postprocess: [extract_code, trim]
   - name: zsh
     description: Get a zsh shell command
     prompt: >
@@ -101,6 +443,7 @@ roles:
       The output should be a valid Zsh command that directly aligns with the user's intent, ready for execution in a command-line environment.
       Do not output anything except for the command.
       No code block, no English explanation, no newlines, and no start/end tags.
+    postprocess: [extract_code, trim]
   - name: nu
     description: Get a nushell command
     prompt: >
@@ -110,5 +453,6 @@ roles:
       The output should be a valid Nushell command that directly aligns with the user's intent, ready for execution in a command-line environment.
       Do not output anything except for the command.
       No code block, no English explanation, no newlines, and no start/end tags.
+    postprocess: [extract_code, trim]
 "#).unwrap();
 }
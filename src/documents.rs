@@ -0,0 +1,280 @@
+/// Room documents
+///
+/// Files uploaded to a room are indexed with `!chaz index`, and the parts relevant to each question are added to
+/// the context in that room. Each room has its own vector store in the `documents` directory of the state
+/// directory.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use lazy_static::lazy_static;
+use matrix_sdk::{
+    room::MessagesOptions,
+    ruma::{
+        events::room::message::{FileMessageEventContent, MessageType, RoomMessageEventContent},
+        OwnedEventId, OwnedRoomId, OwnedUserId, RoomId,
+    },
+    Room,
+};
+use openai_api_rs::v1::chat_completion::MessageRole;
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::{
+    backends::ChatContext,
+    commands::Args,
+    error::ChazError,
+    get_config, i18n,
+    knowledge::{excerpts_message, Embedder, VectorStore},
+    media,
+};
+
+/// Number of recent events searched for files to index
+const SEARCH_LIMIT: u32 = 50;
+
+/// Configuration for indexing the files uploaded to rooms
+#[derive(Debug, Deserialize, Clone)]
+pub struct DocumentsConfig {
+    /// Name of the OpenAI compatible backend used to create embeddings
+    ///
+    /// Defaults to the first OpenAI compatible backend
+    pub embedding_backend: Option<String>,
+    /// Model used to create embeddings, e.g. "text-embedding-3-small"
+    pub embedding_model: String,
+    /// Number of excerpts to add to the context
+    pub top_k: Option<usize>,
+    /// Maximum size of each excerpt, in characters
+    pub chunk_size: Option<usize>,
+    /// Largest file that will be indexed, in MB
+    pub max_file_size: Option<u64>,
+}
+
+lazy_static! {
+    /// Directory holding the stores
+    static ref DOCUMENTS_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+    /// Stores that have been loaded, by room
+    static ref STORES: Mutex<HashMap<OwnedRoomId, VectorStore>> = Mutex::new(HashMap::new());
+}
+
+/// Set the directory the stores are kept in
+pub fn init(state_dir: &Path) {
+    *DOCUMENTS_DIR.lock().unwrap() = Some(state_dir.join("documents"));
+}
+
+/// Path of the store of a room
+fn store_path(room: &RoomId) -> Option<PathBuf> {
+    // Room IDs contain characters that aren't safe in all filesystems
+    let name: String = room
+        .as_str()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    Some(
+        DOCUMENTS_DIR
+            .lock()
+            .unwrap()
+            .as_ref()?
+            .join(format!("{}.json", name)),
+    )
+}
+
+/// Get the store of a room, loading it if needed
+fn load_store(room: &RoomId) -> VectorStore {
+    let mut stores = STORES.lock().unwrap();
+    if let Some(store) = stores.get(room) {
+        return store.clone();
+    }
+    let store = store_path(room)
+        .map(|path| VectorStore::load(&path))
+        .unwrap_or_default();
+    stores.insert(room.to_owned(), store.clone());
+    store
+}
+
+/// Save the store of a room
+fn save_store(room: &RoomId, store: VectorStore) {
+    if let Some(path) = store_path(room) {
+        if let Err(e) = store.save(&path) {
+            error!("Unable to save the documents of {}: {}", room, e);
+        }
+    }
+    STORES.lock().unwrap().insert(room.to_owned(), store);
+}
+
+/// Create the embedder from the current config
+fn embedder(config: &DocumentsConfig) -> Result<Embedder, String> {
+    Embedder::new(
+        &get_config().backends.unwrap_or_default(),
+        config.embedding_backend.as_deref(),
+        &config.embedding_model,
+    )
+}
+
+/// Find the files recently uploaded to the room
+async fn recent_files(room: &Room) -> Result<Vec<(OwnedEventId, FileMessageEventContent)>, String> {
+    let mut options = MessagesOptions::backward();
+    options.limit = SEARCH_LIMIT.into();
+    let batch = room.messages(options).await.map_err(|e| e.to_string())?;
+    Ok(batch
+        .chunk
+        .iter()
+        .filter_map(|event| {
+            let event_id = event.event.get_field::<OwnedEventId>("event_id").ok()??;
+            let content = event
+                .event
+                .get_field::<RoomMessageEventContent>("content")
+                .ok()??;
+            match content.msgtype {
+                MessageType::File(file) => Some((event_id, file)),
+                _ => None,
+            }
+        })
+        .collect())
+}
+
+/// Index the files recently uploaded to the room, or list and remove the indexed files
+///
+/// `!chaz index [list|remove <file>|clear]`
+pub async fn index(_: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let Some(config) = get_config().documents else {
+        room.send(i18n::notice(&room, "documents-not-configured", &[]).await)
+            .await?;
+        return Ok(());
+    };
+    let response = match args.subcommand() {
+        None => index_recent(&room, &config).await,
+        Some("list") => {
+            let store = load_store(room.room_id());
+            if store.sources.is_empty() {
+                i18n::notice_text(&room, "documents-empty", &[]).await
+            } else {
+                let mut sources: Vec<&String> = store.sources.keys().collect();
+                sources.sort();
+                let files = sources
+                    .iter()
+                    .map(|source| format!("- {}", display_name(source)))
+                    .collect::<Vec<String>>()
+                    .join("\n");
+                i18n::notice_text(&room, "documents", &[("files", &files)]).await
+            }
+        }
+        Some("remove") => {
+            let name = &args["file"];
+            let mut store = load_store(room.room_id());
+            let sources: Vec<String> = store
+                .sources
+                .keys()
+                .filter(|source| display_name(source) == name)
+                .cloned()
+                .collect();
+            if sources.is_empty() {
+                i18n::notice_text(&room, "document-unknown", &[("file", name)]).await
+            } else {
+                for source in &sources {
+                    store.remove_source(source);
+                }
+                save_store(room.room_id(), store);
+                i18n::notice_text(&room, "document-removed", &[("file", name)]).await
+            }
+        }
+        _ => {
+            save_store(room.room_id(), VectorStore::default());
+            i18n::notice_text(&room, "documents-cleared", &[]).await
+        }
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// Sources are named "<event ID> <file name>", so that files with the same name are kept apart
+fn display_name(source: &str) -> &str {
+    source.split_once(' ').map_or(source, |(_, name)| name)
+}
+
+/// Index the recent files that aren't indexed yet
+async fn index_recent(room: &Room, config: &DocumentsConfig) -> String {
+    let embedder = match embedder(config) {
+        Ok(embedder) => embedder,
+        Err(e) => return i18n::error_text(room, &e).await,
+    };
+    let files = match recent_files(room).await {
+        Ok(files) => files,
+        Err(e) => return i18n::error_text(room, &e).await,
+    };
+    let mut store = load_store(room.room_id());
+    let max_size = config.max_file_size.unwrap_or(10) * 1024 * 1024;
+    let chunk_size = config.chunk_size.unwrap_or(1500);
+    let mut results = Vec::new();
+    for (event_id, file) in files {
+        let source = format!("{} {}", event_id, file.body);
+        if store.sources.contains_key(&source) {
+            continue;
+        }
+        let result = match media::extract_text(room, &file, max_size).await {
+            Ok(text) => store
+                .add_source(&embedder, &source, 0, &text, chunk_size)
+                .await
+                .map(|chunks| format!("- {}: {} excerpts", file.body, chunks)),
+            Err(e) => Err(e),
+        };
+        results.push(result.unwrap_or_else(|e| format!("- {}: {}", file.body, e)));
+    }
+    if results.is_empty() {
+        return i18n::notice_text(room, "documents-nothing-new", &[]).await;
+    }
+    info!("Indexed {} files in {}", results.len(), room.room_id());
+    save_store(room.room_id(), store);
+    let files = results.join("\n");
+    i18n::notice_text(room, "documents-indexed", &[("files", &files)]).await
+}
+
+/// Add the parts of the room's documents relevant to the latest user message to the context
+pub async fn augment_context(room: &Room, context: &mut ChatContext) {
+    let Some(config) = get_config().documents else {
+        return;
+    };
+    let store = load_store(room.room_id());
+    if store.chunks.is_empty() {
+        return;
+    }
+    let Some(query) = context
+        .messages
+        .iter()
+        .rev()
+        .find(|m| m.role == MessageRole::user)
+        .map(|m| m.content.clone())
+    else {
+        return;
+    };
+    let embedding = match embedder(&config) {
+        Ok(embedder) => match embedder.embed(&[query]).await {
+            Ok(mut embeddings) if !embeddings.is_empty() => embeddings.remove(0),
+            Ok(_) => return,
+            Err(e) => {
+                error!("Unable to embed query for the room documents: {}", e);
+                return;
+            }
+        },
+        Err(e) => {
+            error!("Unable to search the room documents: {}", e);
+            return;
+        }
+    };
+    let mut chunks = store.search(&embedding, config.top_k.unwrap_or(3));
+    for chunk in chunks.iter_mut() {
+        chunk.source = display_name(&chunk.source).to_string();
+    }
+    if !chunks.is_empty() {
+        context.messages.insert(
+            0,
+            excerpts_message(
+                "Use these excerpts from the files uploaded to this room to answer if they are relevant:",
+                &chunks,
+            ),
+        );
+    }
+}
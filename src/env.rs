@@ -0,0 +1,120 @@
+/// Environment variables in the config
+///
+/// Any value in the config can read an environment variable with `${VAR}`, and the login settings and API keys can
+/// be set entirely with `CHAZ_*` variables. Deployments can then keep their secrets out of the config file.
+/// `${secret:<name>}` reads a secret from the provider in `secrets` instead.
+use anyhow::{anyhow, Context};
+use serde_yaml::{Mapping, Value};
+
+use crate::secrets::SecretsConfig;
+
+/// Top level settings that can be set with `CHAZ_<SETTING>`, e.g. `CHAZ_PASSWORD`
+const SETTINGS: &[&str] = &[
+    "homeserver_url",
+    "username",
+    "password",
+    "access_token",
+    "allow_list",
+    "deny_list",
+    "admin_list",
+    "admin_room",
+    "state_dir",
+    "recovery_key",
+];
+
+/// Fill in the environment variables in the parsed config
+pub fn apply(config: &mut Value) -> anyhow::Result<()> {
+    let secrets: SecretsConfig = match config.get("secrets") {
+        Some(secrets) => serde_yaml::from_value(secrets.clone())?,
+        None => SecretsConfig::default(),
+    };
+    substitute(config, &secrets)?;
+    let Some(config) = config.as_mapping_mut() else {
+        return Ok(());
+    };
+    for setting in SETTINGS {
+        if let Ok(value) = std::env::var(format!("CHAZ_{}", setting.to_uppercase())) {
+            config.insert(Value::from(*setting), Value::from(value));
+        }
+    }
+    // API keys are set per backend with `CHAZ_<NAME>_API_KEY`, using the name chaz shows for the backend
+    if let Some(backends) = config.get_mut("backends").and_then(Value::as_sequence_mut) {
+        for backend in backends.iter_mut().filter_map(Value::as_mapping_mut) {
+            let Some(name) = backend_name(backend) else {
+                continue;
+            };
+            if let Ok(api_key) = std::env::var(format!("CHAZ_{}_API_KEY", name)) {
+                backend.insert(Value::from("api_key"), Value::from(api_key));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Name of a backend as used in its environment variable, e.g. "openai" becomes "OPENAI"
+fn backend_name(backend: &Mapping) -> Option<String> {
+    let name = backend.get("name").or(backend.get("type"))?.as_str()?;
+    Some(
+        name.chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Replace `${VAR}` in every string with the value of the variable
+///
+/// `$${` is left in as a literal `${`.
+fn substitute(value: &mut Value, secrets: &SecretsConfig) -> anyhow::Result<()> {
+    match value {
+        Value::String(text) => *text = interpolate(text, secrets)?,
+        Value::Sequence(sequence) => {
+            for value in sequence {
+                substitute(value, secrets)?;
+            }
+        }
+        Value::Mapping(mapping) => {
+            for (_, value) in mapping.iter_mut() {
+                substitute(value, secrets)?;
+            }
+        }
+        Value::Tagged(tagged) => substitute(&mut tagged.value, secrets)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Interpolate the environment variables and secrets in a single string
+fn interpolate(text: &str, secrets: &SecretsConfig) -> anyhow::Result<String> {
+    let mut output = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            output.push_str(&rest[..start - 1]);
+            output.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        output.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("missing '}}' in \"{}\"", text))?;
+        let name = &rest[start + 2..start + end];
+        let value = match name.strip_prefix("secret:") {
+            Some(secret) => secrets
+                .get(secret)
+                .with_context(|| format!("unable to read secret {}", secret))?,
+            None => std::env::var(name)
+                .with_context(|| format!("environment variable {} is not set", name))?,
+        };
+        output.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
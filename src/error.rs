@@ -0,0 +1,45 @@
+/// Errors from handling events
+///
+/// Handlers return these instead of panicking, so an edge case in one event only fails that event.
+/// Failures are logged and posted to the admin room by `report`.
+use std::fmt;
+
+use matrix_sdk::Client;
+use tracing::error;
+
+use crate::{metrics, notifications};
+
+/// An error while handling an event
+#[derive(Debug)]
+pub enum ChazError {
+    /// A request to the homeserver failed
+    Matrix(Box<matrix_sdk::Error>),
+    /// The event is missing something chaz needs
+    InvalidEvent(String),
+}
+
+impl fmt::Display for ChazError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChazError::Matrix(e) => write!(f, "Matrix error: {}", e),
+            ChazError::InvalidEvent(e) => write!(f, "Invalid event: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ChazError {}
+
+impl From<matrix_sdk::Error> for ChazError {
+    fn from(e: matrix_sdk::Error) -> Self {
+        ChazError::Matrix(Box::new(e))
+    }
+}
+
+/// Log an error from a handler, and let the admins know about it
+///
+/// `task` describes what was being handled, e.g. the command name.
+pub async fn report(client: &Client, task: &str, e: &ChazError) {
+    error!("Error handling {}: {}", task, e);
+    metrics::record_error(task);
+    notifications::notify(client, &format!("Error handling {}: {}", task, e)).await;
+}
@@ -0,0 +1,124 @@
+/// Evaluation suites
+///
+/// Runs a fixed set of prompts with expected answers against one or more models, so a new backend or model can
+/// be checked before switching rooms over to it.
+use openai_api_rs::v1::chat_completion::MessageRole;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::backends::{BackendManager, ChatContext, Message};
+
+/// A named set of test cases, defined in the config
+#[derive(Debug, Deserialize, Clone)]
+pub struct EvalSuite {
+    /// Name of the suite, used to run it
+    pub name: String,
+    /// Models to evaluate, defaults to the model of the room
+    pub models: Option<Vec<String>>,
+    /// The test cases
+    pub cases: Vec<EvalCase>,
+}
+
+/// A single prompt and the checks its answer must pass
+///
+/// All the checks that are set must pass.
+#[derive(Debug, Deserialize, Clone)]
+pub struct EvalCase {
+    /// Prompt sent to the model, without any context
+    pub prompt: String,
+    /// The answer must contain this text, ignoring case
+    pub contains: Option<String>,
+    /// The answer must match this regular expression
+    pub regex: Option<String>,
+    /// The answer must be exactly this text, ignoring case and surrounding whitespace
+    pub equals: Option<String>,
+}
+
+impl EvalCase {
+    /// Check an answer, returning the reason it failed
+    fn check(&self, answer: &str) -> Result<(), String> {
+        if let Some(contains) = &self.contains {
+            if !answer.to_lowercase().contains(&contains.to_lowercase()) {
+                return Err(format!("expected it to contain \"{}\"", contains));
+            }
+        }
+        if let Some(regex) = &self.regex {
+            let re = Regex::new(regex).map_err(|e| format!("invalid regex: {}", e))?;
+            if !re.is_match(answer) {
+                return Err(format!("expected it to match `{}`", regex));
+            }
+        }
+        if let Some(equals) = &self.equals {
+            if !answer.trim().eq_ignore_ascii_case(equals.trim()) {
+                return Err(format!("expected \"{}\"", equals));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run a suite against each of the models and format a scorecard
+pub async fn run_suite(backend: &BackendManager, suite: &EvalSuite, models: &[String]) -> String {
+    let mut scores = Vec::new();
+    let mut failures = Vec::new();
+    for model in models {
+        let mut passed = 0;
+        for (index, case) in suite.cases.iter().enumerate() {
+            let context = ChatContext {
+                messages: vec![Message::new(MessageRole::user, case.prompt.clone())],
+                model: Some(model.clone()),
+                media: Vec::new(),
+                tools: Vec::new(),
+                schema: None,
+                params: Default::default(),
+                reported_cost: Default::default(),
+                role: None,
+            };
+            let result = match backend.execute(&context).await {
+                Ok(answer) => case.check(&answer).map_err(|reason| {
+                    format!("{}, got \"{}\"", reason, shorten(answer.trim(), 80))
+                }),
+                Err(e) => Err(format!("error: {}", shorten(&e, 80))),
+            };
+            match result {
+                Ok(()) => passed += 1,
+                Err(reason) => failures.push(format!(
+                    "- `{}` case {} ({}): {}",
+                    model,
+                    index + 1,
+                    shorten(&case.prompt, 40),
+                    reason
+                )),
+            }
+        }
+        let mark = if passed == suite.cases.len() {
+            "✅"
+        } else {
+            "❌"
+        };
+        scores.push(format!(
+            "- {} `{}`: {}/{} passed",
+            mark,
+            model,
+            passed,
+            suite.cases.len()
+        ));
+    }
+
+    let mut scorecard = scores.join("\n");
+    if !failures.is_empty() {
+        scorecard.push_str("\n\nFailures:\n\n");
+        scorecard.push_str(&failures.join("\n"));
+    }
+    scorecard
+}
+
+/// Shorten text to a maximum number of characters for display
+fn shorten(text: &str, max: usize) -> String {
+    let text = text.replace('\n', " ");
+    if text.chars().count() > max {
+        format!("{}…", text.chars().take(max).collect::<String>())
+    } else {
+        text
+    }
+}
@@ -0,0 +1,125 @@
+/// Homeserver failover
+///
+/// Retries the sync with a backoff when it fails, and rotates between the configured homeserver endpoints when
+/// it keeps failing.
+///
+/// The Matrix client can't change its homeserver once built, so switching endpoints rewrites the homeserver in the
+/// saved session and restarts chaz in place. The session, and with it the encryption keys, is kept.
+use std::{
+    path::Path,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use matrix_sdk::{ruma::api::client::filter::FilterDefinition, Client};
+use tracing::{error, warn};
+
+use crate::sync;
+
+/// Longest wait between sync attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// A sync that ran this long was working, so the failures before it don't count anymore
+const HEALTHY_RUN: Duration = Duration::from_secs(300);
+
+/// Sync until the client stops, retrying in place when the sync fails
+///
+/// Once `failure_limit` consecutive failures are reached with more than one endpoint, the session is pointed at
+/// the next endpoint and the error is returned, so chaz can `restart` on it.
+pub async fn sync(
+    client: &Client,
+    session_file: &Path,
+    filter: FilterDefinition,
+    endpoints: &[String],
+    failure_limit: u32,
+) -> anyhow::Result<()> {
+    let mut failures = 0;
+    loop {
+        let started = Instant::now();
+        let Err(e) = sync::run(client, session_file, filter.clone()).await else {
+            return Ok(());
+        };
+        if started.elapsed() > HEALTHY_RUN {
+            failures = 0;
+        }
+        failures += 1;
+        error!("Sync failed {failures} times in a row: {e}");
+        if let Err(e) = sync::flush(client).await {
+            error!("Unable to save the sync token: {e}");
+        }
+        if failures >= failure_limit && endpoints.len() > 1 {
+            match rotate_homeserver(session_file, endpoints) {
+                Ok(endpoint) => {
+                    warn!("Sync failed {failures} times, switching homeserver to {endpoint}");
+                    return Err(e);
+                }
+                Err(e) => error!("Unable to switch homeserver: {e}"),
+            }
+        }
+        // Back off before trying again, doubling from 10s up to 5 minutes
+        let delay = Duration::from_secs(5 * 2u64.pow(failures.min(6))).min(MAX_BACKOFF);
+        warn!("Retrying the sync in {}s", delay.as_secs());
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Point the session at the next endpoint and restart on it
+///
+/// Only returns if the restart fails.
+pub fn switch_endpoint(session_file: &Path, endpoints: &[String]) -> anyhow::Error {
+    match rotate_homeserver(session_file, endpoints) {
+        Ok(endpoint) => warn!("Switching homeserver to {endpoint}"),
+        Err(e) => error!("Unable to switch homeserver: {e}"),
+    }
+    restart()
+}
+
+/// Restart chaz with the same arguments, on the endpoint now in the session
+///
+/// Only returns if the restart fails.
+pub fn restart() -> anyhow::Error {
+    let mut args = std::env::args();
+    let program = args.next().unwrap_or("chaz".to_string());
+    let mut command = Command::new(program);
+    command.args(args);
+    replace_process(command)
+}
+
+/// Replace this process with the command
+#[cfg(unix)]
+fn replace_process(mut command: Command) -> anyhow::Error {
+    use std::os::unix::process::CommandExt;
+    command.exec().into()
+}
+
+/// Run the command in place of this process, exiting with its status
+///
+/// Other platforms can't replace the process, so it's a child until it exits.
+#[cfg(not(unix))]
+fn replace_process(mut command: Command) -> anyhow::Error {
+    match command.status() {
+        Ok(status) => std::process::exit(status.code().unwrap_or(1)),
+        Err(e) => e.into(),
+    }
+}
+
+/// Point the saved session at the endpoint after the one it currently uses
+///
+/// Returns the new endpoint.
+fn rotate_homeserver(session_file: &Path, endpoints: &[String]) -> anyhow::Result<String> {
+    let mut session: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(session_file)?)?;
+    let current = session["client_session"]["homeserver"]
+        .as_str()
+        .unwrap_or_default()
+        .trim_end_matches('/')
+        .to_string();
+    let next = endpoints
+        .iter()
+        .position(|endpoint| endpoint.trim_end_matches('/') == current)
+        .map_or(0, |index| (index + 1) % endpoints.len());
+    let endpoint = endpoints[next].clone();
+    session["client_session"]["homeserver"] = serde_json::Value::String(endpoint.clone());
+    crate::session::write(session_file, &serde_json::to_string(&session)?)?;
+    Ok(endpoint)
+}
@@ -0,0 +1,81 @@
+/// Response formatting
+///
+/// Responses are sent as Markdown by default, which some clients render badly. `!chaz format` switches a room to
+/// plain text or to notices, and is stored in the room tags under `is.chaz.format`. It falls back to `format` in
+/// the config, and a user's own `format` preference takes precedence over both.
+use headjack::Tags;
+use matrix_sdk::{ruma::events::room::message::RoomMessageEventContent, Room};
+use serde::{Deserialize, Serialize};
+
+use crate::get_config;
+
+/// Tag namespace for the setting
+const NAMESPACE: &str = "is.chaz.format";
+
+/// How the responses are sent
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Rendered as HTML, most LLMs like responding with Markdown
+    #[default]
+    Markdown,
+    /// Sent as the raw text
+    Plain,
+    /// Sent as plain text notices, which bots and bridges ignore
+    Notice,
+}
+
+impl Format {
+    /// Parse the name of a format
+    pub fn parse(name: &str) -> Option<Format> {
+        match name {
+            "markdown" => Some(Format::Markdown),
+            "plain" => Some(Format::Plain),
+            "notice" => Some(Format::Notice),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Markdown => "markdown",
+            Format::Plain => "plain",
+            Format::Notice => "notice",
+        }
+    }
+
+    /// Build the content of a message in this format
+    pub fn content(&self, text: String) -> RoomMessageEventContent {
+        match self {
+            Format::Markdown => RoomMessageEventContent::text_markdown(text),
+            Format::Plain => RoomMessageEventContent::text_plain(text),
+            Format::Notice => RoomMessageEventContent::notice_plain(text),
+        }
+    }
+}
+
+/// Get the format set for the room, if there is one
+async fn get_room(room: &Room) -> Option<Format> {
+    Tags::new(room, NAMESPACE)
+        .await
+        .get_value("format")
+        .and_then(|name| Format::parse(&name))
+}
+
+/// Get the format of the room, falling back to the config
+pub async fn get(room: &Room) -> Format {
+    get_room(room)
+        .await
+        .or(get_config().format)
+        .unwrap_or_default()
+}
+
+/// Set the format of the room, or go back to the config with None
+pub async fn set(room: &Room, format: Option<Format>) {
+    let mut tags = Tags::new(room, NAMESPACE).await;
+    match format {
+        Some(format) => tags.replace_kv("format", format.name()),
+        None => tags.remove_kv("format"),
+    }
+    tags.sync().await;
+}
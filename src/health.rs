@@ -0,0 +1,137 @@
+/// Health checks
+///
+/// When `health_port` is set, `/healthz` reports whether the sync loop is alive and `/readyz` whether chaz can
+/// answer messages: it has synced recently and its backends are reachable. Docker and Kubernetes can then restart
+/// chaz when the sync loop wedges.
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+
+use crate::{get_config, http, BackendType};
+
+/// The sync loop is considered wedged after this long without a successful sync
+///
+/// Each sync waits at most 30 seconds for new events, so this allows for a few failed attempts.
+const SYNC_STALE_AFTER: Duration = Duration::from_secs(300);
+
+/// How long the initial sync can take before chaz is considered wedged
+const STARTUP_GRACE: Duration = Duration::from_secs(900);
+
+/// How long the result of a backend check is reused
+const BACKEND_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long a backend has to respond to the check
+const BACKEND_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest request accepted, the checks are plain GETs
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+lazy_static! {
+    /// When chaz started
+    static ref STARTED: Instant = Instant::now();
+
+    /// When the last sync succeeded
+    static ref LAST_SYNC: Mutex<Option<Instant>> = Mutex::new(None);
+
+    /// The last backend check, with the backends that couldn't be reached
+    static ref BACKEND_CHECK: Mutex<Option<(Instant, Vec<String>)>> = Mutex::new(None);
+}
+
+/// Note that a sync succeeded
+pub fn record_sync() {
+    *LAST_SYNC.lock().unwrap() = Some(Instant::now());
+}
+
+/// Check that the sync loop is alive, returning the problem if it isn't
+fn liveness() -> Result<String, String> {
+    match *LAST_SYNC.lock().unwrap() {
+        Some(last_sync) if last_sync.elapsed() > SYNC_STALE_AFTER => Err(format!(
+            "no successful sync for {} seconds",
+            last_sync.elapsed().as_secs()
+        )),
+        Some(last_sync) => Ok(format!(
+            "last sync {} seconds ago",
+            last_sync.elapsed().as_secs()
+        )),
+        None if STARTED.elapsed() > STARTUP_GRACE => Err(format!(
+            "the initial sync hasn't finished after {} seconds",
+            STARTED.elapsed().as_secs()
+        )),
+        None => Ok("starting".to_string()),
+    }
+}
+
+/// Check that chaz can answer messages, returning the problem if it can't
+async fn readiness() -> Result<String, String> {
+    if LAST_SYNC.lock().unwrap().is_none() {
+        return Err("the initial sync hasn't finished".to_string());
+    }
+    let status = liveness()?;
+    let unreachable = unreachable_backends().await;
+    if !unreachable.is_empty() {
+        return Err(format!("unable to reach {}", unreachable.join(", ")));
+    }
+    Ok(status)
+}
+
+/// Get the backends that can't be reached, checking them at most once a minute
+///
+/// Any HTTP response counts, the check is only for the network. The aichat backend runs locally and is skipped.
+async fn unreachable_backends() -> Vec<String> {
+    if let Some((checked, unreachable)) = BACKEND_CHECK.lock().unwrap().as_ref() {
+        if checked.elapsed() < BACKEND_CHECK_INTERVAL {
+            return unreachable.clone();
+        }
+    }
+    let mut unreachable = Vec::new();
+    for backend in get_config().backends.unwrap_or_default() {
+        let Some(api_base) = &backend.api_base else {
+            continue;
+        };
+        if backend.backend_type == BackendType::AIChat {
+            continue;
+        }
+        let reachable = match backend.http_client() {
+            Ok(client) => client
+                .get(api_base)
+                .timeout(BACKEND_CHECK_TIMEOUT)
+                .send()
+                .await
+                .is_ok(),
+            Err(_) => false,
+        };
+        if !reachable {
+            unreachable.push(backend.get_name());
+        }
+    }
+    *BACKEND_CHECK.lock().unwrap() = Some((Instant::now(), unreachable.clone()));
+    unreachable
+}
+
+/// Serve the health checks on the port in the background
+pub fn serve(port: u16) {
+    // Start counting the startup grace from now
+    lazy_static::initialize(&STARTED);
+    tokio::spawn(async move {
+        let Some(listener) = http::listen("health checks", "0.0.0.0", port).await else {
+            return;
+        };
+        http::serve(listener, MAX_REQUEST_SIZE, |request| async move {
+            let result = match request.path.as_str() {
+                "/healthz" => liveness(),
+                "/readyz" => readiness().await,
+                _ => return http::Response::text("404 Not Found", String::new()),
+            };
+            match result {
+                Ok(body) => http::Response::text("200 OK", format!("ok: {}\n", body)),
+                Err(body) => {
+                    http::Response::text("503 Service Unavailable", format!("error: {}\n", body))
+                }
+            }
+        })
+        .await;
+    });
+}
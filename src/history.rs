@@ -0,0 +1,454 @@
+/// Cached room history
+///
+/// Building the context walks back through the room history, which used to be paged in from the homeserver for
+/// every message. The events of each room are cached instead, newest first, and kept up to date from the sync.
+/// Once a cache has caught up with its room, building the context doesn't fetch anything, and the older events
+/// are only fetched if the walk goes past the cache. Redactions, clear commands, and gaps in the sync drop the
+/// cache of the room, so the next walk starts over.
+///
+/// With `persist_history`, the caches are saved in `history` in the state directory so they survive restarts.
+/// Encrypted rooms are only ever cached in memory, so their messages aren't written to disk decrypted.
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use lazy_static::lazy_static;
+use matrix_sdk::{
+    room::MessagesOptions,
+    ruma::{
+        events::{AnySyncTimelineEvent, AnyTimelineEvent},
+        serde::Raw,
+        OwnedEventId, OwnedRoomId, RoomId, UInt,
+    },
+    sync::SyncResponse,
+    Client, Room,
+};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{get_config, session};
+
+/// Number of events fetched at a time while walking the history
+const PAGE_SIZE: u32 = 100;
+
+/// Number of the newest events fetched at a time to catch up with the room
+const TOP_UP_SIZE: u32 = 20;
+
+/// Pages fetched to catch up before the cache is given up on
+const MAX_TOP_UP_PAGES: usize = 3;
+
+/// Number of the newest cached events checked for duplicates, new events can only repeat those
+const DEDUP_WINDOW: usize = 100;
+
+/// Maximum number of events cached per room
+const MAX_EVENTS: usize = 2000;
+
+/// The cached history of a room
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct RoomHistory {
+    /// Events, newest first
+    events: Vec<Raw<AnyTimelineEvent>>,
+    /// Token to fetch the events older than the cache, None once the start of the room is cached
+    end: Option<String>,
+    /// Set when the oldest events were dropped to keep the cache small, so `end` no longer follows them
+    truncated: bool,
+}
+
+impl RoomHistory {
+    /// Add newer events, skipping the ones already cached
+    fn prepend(&mut self, events: Vec<Raw<AnyTimelineEvent>>) {
+        let cached: HashSet<OwnedEventId> = self
+            .events
+            .iter()
+            .take(DEDUP_WINDOW)
+            .filter_map(event_id)
+            .collect();
+        let mut events: Vec<_> = events
+            .into_iter()
+            .filter(|event| event_id(event).is_none_or(|id| !cached.contains(&id)))
+            .collect();
+        events.append(&mut self.events);
+        self.events = events;
+        if self.events.len() > MAX_EVENTS {
+            self.events.truncate(MAX_EVENTS);
+            self.truncated = true;
+        }
+    }
+}
+
+lazy_static! {
+    /// Where the caches are saved, None if they're only kept in memory
+    static ref HISTORY_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+    /// The cached history of the rooms
+    static ref HISTORY: Mutex<HashMap<OwnedRoomId, RoomHistory>> = Mutex::new(HashMap::new());
+
+    /// Number of times the cache of each room was dropped, so a walk that started before doesn't save it again
+    static ref GENERATIONS: Mutex<HashMap<OwnedRoomId, u64>> = Mutex::new(HashMap::new());
+
+    /// The rooms whose cache caught up with the room and has followed the sync since, so it isn't missing anything
+    static ref LIVE: Mutex<HashSet<OwnedRoomId>> = Mutex::new(HashSet::new());
+}
+
+/// Set the directory the caches are saved in
+pub fn init(state_dir: &Path) {
+    if get_config().persist_history.unwrap_or(false) {
+        *HISTORY_DIR.lock().unwrap() = Some(state_dir.join("history"));
+    }
+}
+
+/// Keep the caches up to date with the timeline events from the sync
+///
+/// Registered before the initial sync, so the redactions sent while chaz was offline still drop the caches.
+pub fn register_handler(client: &Client) {
+    client.add_event_handler(|event: Raw<AnySyncTimelineEvent>, room: Room| async move {
+        let kind = event.get_field::<String>("type").ok().flatten();
+        let body = event
+            .get_field::<serde_json::Value>("content")
+            .ok()
+            .flatten()
+            .and_then(|content| content["body"].as_str().map(str::to_string));
+        if kind.as_deref() == Some("m.room.redaction")
+            || body.is_some_and(|body| body.starts_with("!chaz clear"))
+        {
+            invalidate(room.room_id());
+            return;
+        }
+        if let Some(history) = HISTORY.lock().unwrap().get_mut(room.room_id()) {
+            history.prepend(vec![event.cast()]);
+        }
+    });
+}
+
+/// Drop the caches of the rooms with a gap in the sync, the events in between never reached the handler
+pub fn check_gaps(response: &SyncResponse) {
+    for (room_id, room) in &response.rooms.join {
+        if room.timeline.limited {
+            invalidate(room_id);
+        }
+    }
+}
+
+/// Drop the cache of a room
+pub fn invalidate(room_id: &RoomId) {
+    *GENERATIONS
+        .lock()
+        .unwrap()
+        .entry(room_id.to_owned())
+        .or_default() += 1;
+    HISTORY.lock().unwrap().remove(room_id);
+    LIVE.lock().unwrap().remove(room_id);
+    if let Some(path) = file(room_id) {
+        if path.exists() {
+            if let Err(e) = std::fs::remove_file(&path) {
+                error!("Unable to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Path of the saved cache of a room
+fn file(room_id: &RoomId) -> Option<PathBuf> {
+    Some(
+        HISTORY_DIR
+            .lock()
+            .unwrap()
+            .as_ref()?
+            .join(format!("{}.json", room_id)),
+    )
+}
+
+/// Get the cache of a room, loading it from the state directory the first time if it's saved there
+fn load(room_id: &RoomId, persist: bool) -> Option<RoomHistory> {
+    if let Some(history) = HISTORY.lock().unwrap().get(room_id) {
+        return Some(history.clone());
+    }
+    if !persist {
+        return None;
+    }
+    let history: RoomHistory =
+        serde_json::from_str(&std::fs::read_to_string(file(room_id)?).ok()?).ok()?;
+    HISTORY
+        .lock()
+        .unwrap()
+        .insert(room_id.to_owned(), history.clone());
+    Some(history)
+}
+
+/// Save the cache of a room, unless it was dropped since `generation`
+///
+/// `start` is the newest event of the cache when the walk started, and it's only written to the state directory
+/// with `persist`.
+fn store(
+    room_id: &RoomId,
+    mut history: RoomHistory,
+    start: Option<OwnedEventId>,
+    generation: u64,
+    persist: bool,
+) {
+    if generation != current_generation(room_id) {
+        return;
+    }
+    // Keep the events the sync added while the walk was running
+    if let Some(current) = HISTORY.lock().unwrap().get(room_id) {
+        if let Some(index) = current
+            .events
+            .iter()
+            .position(|event| start.is_some() && event_id(event) == start)
+        {
+            history.prepend(current.events[..index].to_vec());
+        }
+    }
+    if let Some(path) = file(room_id).filter(|_| persist) {
+        let result = serde_json::to_string(&history)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| {
+                std::fs::create_dir_all(path.parent().unwrap_or(Path::new(".")))?;
+                Ok(session::write(&path, &contents)?)
+            });
+        if let Err(e) = result {
+            error!("Unable to save the history of {}: {}", room_id, e);
+        }
+    }
+    HISTORY.lock().unwrap().insert(room_id.to_owned(), history);
+}
+
+/// Number of times the cache of a room was dropped
+fn current_generation(room_id: &RoomId) -> u64 {
+    GENERATIONS
+        .lock()
+        .unwrap()
+        .get(room_id)
+        .copied()
+        .unwrap_or_default()
+}
+
+/// ID of an event
+fn event_id(event: &Raw<AnyTimelineEvent>) -> Option<OwnedEventId> {
+    event.get_field("event_id").ok().flatten()
+}
+
+/// Where the walk continues from once the batch it has is used up
+enum Next {
+    /// Catch up with the room, then go through the cache
+    Start,
+    /// Fetch the events from the token, None for the newest ones
+    Fetch(Option<String>),
+    /// The cache doesn't reach back far enough, so start over from the newest events and skip to the end of the
+    /// cache
+    Rewalk(Option<OwnedEventId>),
+    Done,
+}
+
+/// A walk back through the history of a room, newest events first
+pub struct Walk<'a> {
+    room: &'a Room,
+    next: Next,
+    /// The cache as it's being extended by the walk
+    history: RoomHistory,
+    /// Older events are still being added to the cache
+    caching: bool,
+    /// Events that the walk skips until it reaches this one, while starting over
+    skip_until: Option<OwnedEventId>,
+    /// The cache has to be saved
+    changed: bool,
+    /// Newest event of the cache when the walk started, the sync adds the ones after it
+    start: Option<OwnedEventId>,
+    /// The cache caught up with the room, so it can be used without fetching the newest events next time
+    ///
+    /// Only a cache that was in memory when the walk started, the sync events in the meantime aren't kept otherwise.
+    caught_up: bool,
+    /// The cache can be saved in the state directory, false for encrypted rooms
+    persist: bool,
+    generation: u64,
+}
+
+/// Start walking back through the history of a room
+pub fn walk(room: &Room) -> Walk<'_> {
+    Walk {
+        room,
+        next: Next::Start,
+        history: RoomHistory::default(),
+        caching: true,
+        skip_until: None,
+        changed: false,
+        start: None,
+        caught_up: false,
+        persist: false,
+        generation: current_generation(room.room_id()),
+    }
+}
+
+impl Walk<'_> {
+    /// Get the next batch of events, older than the ones before
+    pub async fn next(&mut self) -> Option<Vec<Raw<AnyTimelineEvent>>> {
+        loop {
+            match std::mem::replace(&mut self.next, Next::Done) {
+                Next::Start => {
+                    // Unknown rooms are treated as encrypted
+                    self.persist = !self.room.is_encrypted().await.unwrap_or(true);
+                    let Some(history) = load(self.room.room_id(), self.persist) else {
+                        self.next = Next::Fetch(None);
+                        continue;
+                    };
+                    self.start = history.events.first().and_then(event_id);
+                    if LIVE.lock().unwrap().contains(self.room.room_id()) {
+                        return Some(self.use_cache(history));
+                    }
+                    return self.top_up(history).await;
+                }
+                Next::Fetch(from) => {
+                    let (mut events, end) = self.fetch(from, PAGE_SIZE).await?;
+                    self.extend(&events, end.clone());
+                    if end.is_some() {
+                        self.next = Next::Fetch(end);
+                    }
+                    self.skip_cached(&mut events);
+                    return Some(events);
+                }
+                Next::Rewalk(oldest) => {
+                    // The cache is rebuilt from the new walk
+                    self.history = RoomHistory::default();
+                    self.caching = true;
+                    self.skip_until = oldest;
+                    self.next = Next::Fetch(None);
+                }
+                Next::Done => return None,
+            }
+        }
+    }
+
+    /// Drop the events that came from the cache earlier in the walk, while starting over
+    fn skip_cached(&mut self, events: &mut Vec<Raw<AnyTimelineEvent>>) {
+        let Some(oldest) = &self.skip_until else {
+            return;
+        };
+        match events
+            .iter()
+            .position(|event| event_id(event).as_ref() == Some(oldest))
+        {
+            Some(index) => {
+                events.drain(..=index);
+                self.skip_until = None;
+            }
+            None => events.clear(),
+        }
+    }
+
+    /// Catch up with the events the cache is missing, returning the whole cache as the first batch
+    ///
+    /// If the cache is too far behind, the events fetched to catch up start a new one.
+    async fn top_up(&mut self, mut history: RoomHistory) -> Option<Vec<Raw<AnyTimelineEvent>>> {
+        let cached: HashSet<OwnedEventId> = history.events.iter().filter_map(event_id).collect();
+        let mut newer = Vec::new();
+        let mut from = None;
+        let mut reached_start = false;
+        for _ in 0..MAX_TOP_UP_PAGES {
+            let (events, end) = self.fetch(from.clone(), TOP_UP_SIZE).await?;
+            let overlap = events
+                .iter()
+                .position(|event| event_id(event).is_some_and(|id| cached.contains(&id)));
+            match overlap {
+                Some(index) => {
+                    newer.extend(events.into_iter().take(index));
+                    self.changed = !newer.is_empty();
+                    history.prepend(newer);
+                    self.caught_up = true;
+                    return Some(self.use_cache(history));
+                }
+                None => {
+                    newer.extend(events);
+                    match end {
+                        Some(end) => from = Some(end),
+                        None => {
+                            reached_start = true;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        // Too far behind, the events fetched so far start a new cache
+        let end = if reached_start { None } else { from };
+        self.history = RoomHistory::default();
+        self.extend(&newer, end.clone());
+        self.next = match end {
+            Some(end) => Next::Fetch(Some(end)),
+            None => Next::Done,
+        };
+        Some(newer)
+    }
+
+    /// Go through an up to date cache, returning it as the first batch
+    fn use_cache(&mut self, history: RoomHistory) -> Vec<Raw<AnyTimelineEvent>> {
+        self.next = match (&history.end, history.truncated) {
+            (_, true) => Next::Rewalk(history.events.last().and_then(event_id)),
+            (Some(end), false) => Next::Fetch(Some(end.clone())),
+            (None, false) => Next::Done,
+        };
+        let events = history.events.clone();
+        self.history = history;
+        events
+    }
+
+    /// Fetch a page of events, older than the token
+    async fn fetch(
+        &self,
+        from: Option<String>,
+        limit: u32,
+    ) -> Option<(Vec<Raw<AnyTimelineEvent>>, Option<String>)> {
+        let mut options = MessagesOptions::backward().from(from.as_deref());
+        options.limit = UInt::from(limit);
+        match self.room.messages(options).await {
+            Ok(batch) => Some((
+                batch.chunk.into_iter().map(|event| event.event).collect(),
+                batch.end,
+            )),
+            Err(e) => {
+                error!(
+                    "Unable to read the history of {}: {}",
+                    self.room.room_id(),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Add older events to the cache, along with the token to continue from
+    fn extend(&mut self, events: &[Raw<AnyTimelineEvent>], end: Option<String>) {
+        if !self.caching {
+            return;
+        }
+        if self.history.events.len() + events.len() > MAX_EVENTS {
+            self.caching = false;
+            return;
+        }
+        self.history.events.extend_from_slice(events);
+        self.history.end = end;
+        self.changed = true;
+    }
+
+    /// Save the cache extended by the walk
+    pub fn finish(self) {
+        let room_id = self.room.room_id();
+        if self.changed {
+            store(
+                room_id,
+                self.history,
+                self.start,
+                self.generation,
+                self.persist,
+            );
+        }
+        // From now on the sync keeps the cache up to date
+        if self.caught_up
+            && self.generation == current_generation(room_id)
+            && HISTORY.lock().unwrap().contains_key(room_id)
+        {
+            LIVE.lock().unwrap().insert(room_id.to_owned());
+        }
+    }
+}
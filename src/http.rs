@@ -0,0 +1,215 @@
+/// HTTP requests
+///
+/// The appservice, the webhooks, the health checks, and the metrics take requests from other services over HTTP.
+/// They only need to read a request and send back a response, so this is a small HTTP/1.1 server instead of a
+/// framework.
+use std::{collections::HashMap, future::Future, time::Duration};
+
+use reqwest::Url;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{error, info};
+
+/// How long a client has to send its whole request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A request read from a connection
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// The headers, with lowercase names
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Get the bearer token from the Authorization header
+    pub fn token(&self) -> Option<&str> {
+        self.headers
+            .get("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+    }
+
+    /// Check the bearer token, comparing in constant time so the token can't be guessed from the timing
+    pub fn has_token(&self, expected: &str) -> bool {
+        let Some(token) = self.token() else {
+            return false;
+        };
+        token.len() == expected.len()
+            && token
+                .bytes()
+                .zip(expected.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    /// Split the path into its decoded segments, after the prefix
+    pub fn segments(&self, prefix: &str) -> Vec<String> {
+        self.path
+            .strip_prefix(prefix)
+            .unwrap_or(&self.path)
+            .trim_start_matches('/')
+            .split('/')
+            .map(percent_decode)
+            .collect()
+    }
+}
+
+/// A response to send back
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json(status: &'static str, body: String) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    pub fn text(status: &'static str, body: String) -> Self {
+        Response {
+            status,
+            content_type: "text/plain",
+            body,
+        }
+    }
+}
+
+/// Listen on the address, logging the error if it's unavailable
+pub async fn listen(what: &str, bind: &str, port: u16) -> Option<TcpListener> {
+    match TcpListener::bind((bind, port)).await {
+        Ok(listener) => {
+            info!("Serving {} on {}:{}", what, bind, port);
+            Some(listener)
+        }
+        Err(e) => {
+            error!("Unable to serve {} on {}:{}: {}", what, bind, port, e);
+            None
+        }
+    }
+}
+
+/// Answer every request on the listener with the handler, one task per connection
+pub async fn serve<F, R>(listener: TcpListener, max_size: usize, handler: F)
+where
+    F: Fn(Request) -> R + Clone + Send + 'static,
+    R: Future<Output = Response> + Send,
+{
+    loop {
+        let Ok((mut stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let Some(request) = read_request(&mut stream, max_size).await else {
+                return;
+            };
+            let response = handler(request).await;
+            write_response(&mut stream, &response).await;
+        });
+    }
+}
+
+/// Read a full HTTP request from the connection, if it's no larger than `max_size`
+///
+/// Gives up if the client takes longer than `READ_TIMEOUT` to send it.
+pub async fn read_request(stream: &mut TcpStream, max_size: usize) -> Option<Request> {
+    tokio::time::timeout(READ_TIMEOUT, read(stream, max_size))
+        .await
+        .ok()
+        .flatten()
+}
+
+async fn read(stream: &mut TcpStream, max_size: usize) -> Option<Request> {
+    let mut data = Vec::new();
+    let mut buffer = [0; 8192];
+    let header_end = loop {
+        let read = stream.read(&mut buffer).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        data.extend_from_slice(&buffer[..read]);
+        if let Some(end) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if data.len() > max_size {
+            return None;
+        }
+    };
+    let head = String::from_utf8_lossy(&data[..header_end]).to_string();
+    let mut lines = head.lines();
+    let mut request_line = lines.next()?.split_whitespace();
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?;
+    let headers: HashMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+    let length: usize = headers
+        .get("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    if length > max_size {
+        return None;
+    }
+    let mut body = data[header_end + 4..].to_vec();
+    while body.len() < length {
+        let read = stream.read(&mut buffer).await.ok()?;
+        if read == 0 {
+            return None;
+        }
+        body.extend_from_slice(&buffer[..read]);
+    }
+    let url = Url::parse(&format!("http://localhost{}", target)).ok()?;
+    Some(Request {
+        method,
+        path: url.path().to_string(),
+        headers,
+        body,
+    })
+}
+
+/// Send a response and close the connection
+pub async fn write_response(stream: &mut TcpStream, response: &Response) {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.content_type,
+        response.body.len(),
+        response.body
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+/// Decode the percent escapes in a path segment
+///
+/// A `%` that isn't followed by two hex digits is kept as is.
+pub fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(byte) = bytes
+                .get(i + 1..i + 3)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
@@ -0,0 +1,156 @@
+/// Localization
+///
+/// The notices chaz posts are looked up by key in the language of the room, falling back to English.
+/// Translations are [Fluent](https://projectfluent.org/) files named after the language, e.g. `de.ftl`, in the
+/// `locales_dir` from the config. English is built in, see `locales/en.ftl` for the keys.
+///
+/// The files are read by a small parser for the subset of Fluent the notices need: messages, multiline values,
+/// comments, and `{ $variable }` placeables. Selectors and plurals, terms, attributes, functions, and escapes
+/// aren't supported, terms and attributes are skipped and the rest is kept as literal text.
+use std::{collections::HashMap, path::Path, sync::Mutex};
+
+use lazy_static::lazy_static;
+use matrix_sdk::{ruma::events::room::message::RoomMessageEventContent, Room};
+use tracing::{error, info};
+
+use crate::language;
+
+/// The built-in English messages
+const ENGLISH: &str = include_str!("../locales/en.ftl");
+
+/// Language used when a message isn't translated
+const FALLBACK: &str = "en";
+
+lazy_static! {
+    /// Messages by language, then by key
+    static ref LOCALES: Mutex<HashMap<String, HashMap<String, String>>> =
+        Mutex::new(HashMap::from([(FALLBACK.to_string(), parse(ENGLISH))]));
+}
+
+/// Load the translations in a directory
+///
+/// A translation of English replaces the built-in messages it defines.
+pub fn init(locales_dir: &Path) {
+    let entries = match std::fs::read_dir(locales_dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!(
+                "Unable to read the locales in {}: {}",
+                locales_dir.display(),
+                e
+            );
+            return;
+        }
+    };
+    let mut locales = LOCALES.lock().unwrap();
+    for path in entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+    {
+        if path.extension().and_then(|extension| extension.to_str()) != Some("ftl") {
+            continue;
+        }
+        let Some(language) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                let messages = parse(&contents);
+                info!("Loaded {} messages for {}", messages.len(), language);
+                locales
+                    .entry(language.to_string())
+                    .or_default()
+                    .extend(messages);
+            }
+            Err(e) => error!("Unable to read {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Parse the messages of a Fluent file
+///
+/// Multiline values are indented on the lines after the key, and can have blank lines between them. Comments,
+/// terms, and attributes are skipped.
+fn parse(contents: &str) -> HashMap<String, String> {
+    let mut messages = HashMap::new();
+    let mut current: Option<(String, Vec<String>)> = None;
+    for line in contents.lines() {
+        let is_continuation = (line.starts_with([' ', '\t'])
+            && !line.trim_start().starts_with('.'))
+            || (line.trim().is_empty() && current.is_some());
+        if is_continuation {
+            if let Some((_, value)) = current.as_mut() {
+                value.push(line.trim().to_string());
+            }
+            continue;
+        }
+        if let Some((key, value)) = current.take() {
+            messages.insert(key, value.join("\n").trim().to_string());
+        }
+        if line.starts_with('#') || line.starts_with('-') || line.trim().is_empty() {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            let key = key.trim();
+            if !key.is_empty()
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                current = Some((key.to_string(), vec![value.trim().to_string()]));
+            }
+        }
+    }
+    if let Some((key, value)) = current {
+        messages.insert(key, value.join("\n").trim().to_string());
+    }
+    messages
+}
+
+/// Look up a message in a language, without falling back to English
+///
+/// A regional language like `pt-BR` falls back to `pt`.
+pub fn lookup(language: &str, key: &str) -> Option<String> {
+    let locales = LOCALES.lock().unwrap();
+    let base = language.split(['-', '_']).next().unwrap_or(language);
+    [language, base]
+        .iter()
+        .find_map(|language| locales.get(*language)?.get(key).cloned())
+}
+
+/// Get a message in a language, with its placeables filled in from the arguments
+///
+/// Falls back to English, and then to the key itself.
+pub fn translate(language: Option<&str>, key: &str, args: &[(&str, &str)]) -> String {
+    let message = language
+        .and_then(|language| lookup(language, key))
+        .or_else(|| lookup(FALLBACK, key))
+        .unwrap_or_else(|| key.to_string());
+    args.iter().fold(message, |message, (name, value)| {
+        message
+            .replace(&format!("{{ ${} }}", name), value)
+            .replace(&format!("{{${}}}", name), value)
+    })
+}
+
+/// Get a message in the language of the room
+pub async fn tr(room: &Room, key: &str, args: &[(&str, &str)]) -> String {
+    translate(language::get(room).await.as_deref(), key, args)
+}
+
+/// Get the text of a notice in the language of the room
+///
+/// Notices start with "!chaz" so they're left out of the context, whatever the translation says.
+pub async fn notice_text(room: &Room, key: &str, args: &[(&str, &str)]) -> String {
+    format!("!chaz {}", tr(room, key, args).await)
+}
+
+/// Get the text of an error notice in the language of the room
+pub async fn error_text(room: &Room, message: &str) -> String {
+    notice_text(room, "error", &[("message", message)]).await
+}
+
+/// Build a notice in the language of the room
+pub async fn notice(room: &Room, key: &str, args: &[(&str, &str)]) -> RoomMessageEventContent {
+    RoomMessageEventContent::notice_plain(notice_text(room, key, args).await)
+}
@@ -0,0 +1,167 @@
+/// Image generation
+///
+/// Backends that turn a prompt into an image, used by `!chaz imagine`.
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+
+/// The types of image generation backends
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageBackendType {
+    /// The OpenAI images API, e.g. DALL-E
+    OpenAI,
+    /// The AUTOMATIC1111 Stable Diffusion web UI API
+    StableDiffusion,
+}
+
+/// Configuration for image generation
+#[derive(Debug, Deserialize, Clone)]
+pub struct ImageConfig {
+    #[serde(rename = "type")]
+    pub backend_type: ImageBackendType,
+    /// Base URL of the API, e.g. https://api.openai.com/v1 or http://localhost:7860
+    pub api_base: String,
+    pub api_key: Option<String>,
+    /// Model to request, only used by the OpenAI API
+    pub model: Option<String>,
+    /// Size of the image, in the form "1024x1024"
+    pub size: Option<String>,
+}
+
+impl ImageConfig {
+    /// Generate an image with the configured backend, returning the PNG data
+    pub async fn generate(&self, prompt: &str) -> Result<Vec<u8>, String> {
+        match self.backend_type {
+            ImageBackendType::OpenAI => {
+                OpenAIImages {
+                    config: self.clone(),
+                }
+                .generate(prompt)
+                .await
+            }
+            ImageBackendType::StableDiffusion => {
+                StableDiffusion {
+                    config: self.clone(),
+                }
+                .generate(prompt)
+                .await
+            }
+        }
+    }
+
+    /// The base URL without a trailing slash
+    fn api_base(&self) -> &str {
+        self.api_base.trim_end_matches('/')
+    }
+
+    /// The size of the image, defaulting to 1024x1024
+    fn size(&self) -> String {
+        self.size.clone().unwrap_or("1024x1024".to_string())
+    }
+}
+
+/// A backend that generates images
+pub trait ImageBackend {
+    /// Generate an image from the prompt, returning the PNG data
+    async fn generate(&self, prompt: &str) -> Result<Vec<u8>, String>;
+}
+
+/// Generate images with the OpenAI images API
+struct OpenAIImages {
+    config: ImageConfig,
+}
+
+#[derive(Deserialize)]
+struct OpenAIImagesResponse {
+    data: Vec<OpenAIImage>,
+}
+
+#[derive(Deserialize)]
+struct OpenAIImage {
+    b64_json: String,
+}
+
+impl ImageBackend for OpenAIImages {
+    async fn generate(&self, prompt: &str) -> Result<Vec<u8>, String> {
+        let mut request = serde_json::json!({
+            "prompt": prompt,
+            "n": 1,
+            "size": self.config.size(),
+            "response_format": "b64_json",
+        });
+        if let Some(model) = &self.config.model {
+            request["model"] = serde_json::Value::String(model.clone());
+        }
+        let response = reqwest::Client::new()
+            .post(format!("{}/images/generations", self.config.api_base()))
+            .bearer_auth(self.config.api_key.clone().unwrap_or_default())
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let response = check_status(response).await?;
+        let response = response
+            .json::<OpenAIImagesResponse>()
+            .await
+            .map_err(|e| e.to_string())?;
+        let image = response
+            .data
+            .into_iter()
+            .next()
+            .ok_or("No image returned".to_string())?;
+        STANDARD.decode(image.b64_json).map_err(|e| e.to_string())
+    }
+}
+
+/// Generate images with the Stable Diffusion web UI API
+struct StableDiffusion {
+    config: ImageConfig,
+}
+
+#[derive(Deserialize)]
+struct StableDiffusionResponse {
+    images: Vec<String>,
+}
+
+impl ImageBackend for StableDiffusion {
+    async fn generate(&self, prompt: &str) -> Result<Vec<u8>, String> {
+        let size = self.config.size();
+        let (width, height) = size
+            .split_once('x')
+            .and_then(|(w, h)| Some((w.parse::<u32>().ok()?, h.parse::<u32>().ok()?)))
+            .ok_or(format!("Invalid image size: {}", size))?;
+        let mut request = reqwest::Client::new()
+            .post(format!("{}/sdapi/v1/txt2img", self.config.api_base()))
+            .json(&serde_json::json!({
+                "prompt": prompt,
+                "width": width,
+                "height": height,
+            }));
+        if let Some(api_key) = &self.config.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        let response = check_status(response).await?;
+        let response = response
+            .json::<StableDiffusionResponse>()
+            .await
+            .map_err(|e| e.to_string())?;
+        let image = response
+            .images
+            .into_iter()
+            .next()
+            .ok_or("No image returned".to_string())?;
+        STANDARD.decode(image).map_err(|e| e.to_string())
+    }
+}
+
+/// Turn an error status into an error with the response body
+async fn check_status(response: reqwest::Response) -> Result<reqwest::Response, String> {
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        Err(format!("{}: {}", status, body))
+    }
+}
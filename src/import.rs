@@ -0,0 +1,207 @@
+/// Conversation import
+///
+/// `!chaz import` reads a transcript attached to the room and uses it as the start of the conversation, so
+/// conversations can be continued from ChatGPT or another room. The transcript is stored in the room account data
+/// under `is.chaz.import`, and a notice marks where it was imported in the history.
+use matrix_sdk::{
+    deserialized_responses::TimelineEvent,
+    media::{MediaFormat, MediaRequest},
+    room::MessagesOptions,
+    ruma::{
+        events::room::message::{MessageType, RoomMessageEventContent},
+        EventId, OwnedUserId,
+    },
+    Room,
+};
+use openai_api_rs::v1::chat_completion::MessageRole;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{account_data, backends::Message, commands::Args, error::ChazError, i18n};
+
+/// Start of the notice posted after an import
+pub const IMPORT_NOTICE: &str = "!chaz import: ";
+
+/// Account data type the transcript is stored in
+const ACCOUNT_DATA_TYPE: &str = "is.chaz.import";
+
+/// Number of recent events searched for the transcript
+const SEARCH_LIMIT: u32 = 50;
+
+/// A message of an imported transcript
+#[derive(Serialize, Deserialize)]
+struct ImportedMessage {
+    role: String,
+    content: String,
+}
+
+/// The stored transcript
+#[derive(Serialize, Deserialize)]
+struct Import {
+    /// The notice posted for the import, older notices don't use this transcript
+    notice: String,
+    messages: Vec<ImportedMessage>,
+}
+
+/// The JSON formats that can be imported
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonTranscript {
+    Messages(Vec<ImportedMessage>),
+    Object { messages: Vec<ImportedMessage> },
+}
+
+/// Parse a transcript, either JSON or the text format of `!chaz print`
+///
+/// JSON is a list of messages with a `role` and `content`, as used by the OpenAI API, or an object with them in
+/// `messages`. In the text format each message starts with `USER:`, `ASSISTANT:`, or `SYSTEM:`, optionally
+/// as a markdown heading or in bold.
+fn parse(transcript: &str) -> Result<Vec<ImportedMessage>, String> {
+    let messages = if transcript.trim_start().starts_with(['[', '{']) {
+        match serde_json::from_str(transcript).map_err(|e| format!("invalid JSON: {}", e))? {
+            JsonTranscript::Messages(messages) | JsonTranscript::Object { messages } => messages,
+        }
+    } else {
+        parse_text(transcript)
+    };
+    let mut imported = Vec::new();
+    for mut message in messages {
+        message.role = message.role.to_lowercase();
+        if !matches!(message.role.as_str(), "user" | "assistant" | "system") {
+            return Err(format!("unknown role \"{}\"", message.role));
+        }
+        if !message.content.trim().is_empty() {
+            imported.push(message);
+        }
+    }
+    if imported.is_empty() {
+        return Err("no messages found in the transcript".to_string());
+    }
+    Ok(imported)
+}
+
+/// Parse the text format of a transcript
+fn parse_text(transcript: &str) -> Vec<ImportedMessage> {
+    let start = Regex::new(
+        r"(?i)^(?:#+\s*)?\**(user|assistant|system)(?: \([^)]*\))?(?::\**|\**:|\**$)\s?(.*)$",
+    )
+    .unwrap();
+    let mut messages: Vec<ImportedMessage> = Vec::new();
+    for line in transcript.lines() {
+        if let Some(captures) = start.captures(line) {
+            messages.push(ImportedMessage {
+                role: captures[1].to_string(),
+                content: captures[2].to_string(),
+            });
+        } else if let Some(message) = messages.last_mut() {
+            message.content.push('\n');
+            message.content.push_str(line);
+        }
+    }
+    for message in messages.iter_mut() {
+        message.content = message.content.trim().to_string();
+    }
+    messages
+}
+
+/// Find the most recent file sent by the user and download it
+async fn find_transcript(room: &Room, sender: &OwnedUserId) -> Result<(String, Vec<u8>), String> {
+    let mut options = MessagesOptions::backward();
+    options.limit = SEARCH_LIMIT.into();
+    let batch = room.messages(options).await.map_err(|e| e.to_string())?;
+    let file = batch.chunk.iter().find_map(|event: &TimelineEvent| {
+        let event_sender = event.event.get_field::<String>("sender").ok()??;
+        let content = event
+            .event
+            .get_field::<RoomMessageEventContent>("content")
+            .ok()??;
+        match content.msgtype {
+            MessageType::File(file) if event_sender == sender.as_str() => Some(file),
+            _ => None,
+        }
+    });
+    let Some(file) = file else {
+        return Err("attach the transcript as a file before running `!chaz import`".to_string());
+    };
+    let request = MediaRequest {
+        source: file.source.clone(),
+        format: MediaFormat::File,
+    };
+    let contents = room
+        .client()
+        .media()
+        .get_media_content(&request, true)
+        .await
+        .map_err(|e| format!("unable to download the transcript: {}", e))?;
+    Ok((file.body, contents))
+}
+
+/// Import the most recent transcript attached by the user
+pub async fn import(sender: OwnedUserId, _: Args, room: Room) -> Result<(), ChazError> {
+    let result = async {
+        let (name, contents) = find_transcript(&room, &sender).await?;
+        let transcript = String::from_utf8(contents)
+            .map_err(|_| "the transcript has to be a text or JSON file".to_string())?;
+        let messages = parse(&transcript)?;
+        let count = messages.len();
+        let mut import = Import {
+            notice: String::new(),
+            messages,
+        };
+        if serde_json::to_string(&import).map_or(0, |json| json.len()) > account_data::MAX_SIZE {
+            return Err("the transcript is too large to import".to_string());
+        }
+        let notice = room
+            .send(RoomMessageEventContent::notice_plain(format!(
+                "{}imported {} messages from {}",
+                IMPORT_NOTICE, count, name
+            )))
+            .await
+            .map_err(|e| e.to_string())?;
+        import.notice = notice.event_id.to_string();
+        save(&room, &import).await
+    }
+    .await;
+    if let Err(e) = result {
+        room.send(i18n::notice(&room, "error", &[("message", &e)]).await)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Store the transcript in the room account data
+async fn save(room: &Room, import: &Import) -> Result<(), String> {
+    account_data::set(room, ACCOUNT_DATA_TYPE, import).await
+}
+
+/// Get the transcript imported with a notice
+///
+/// Returns nothing if a newer import replaced it.
+pub async fn load(room: &Room, notice: &EventId) -> Vec<Message> {
+    let import = match account_data::get::<Import>(room, ACCOUNT_DATA_TYPE).await {
+        Ok(import) => import,
+        Err(e) => {
+            error!("Unable to load the imported transcript: {}", e);
+            return Vec::new();
+        }
+    };
+    let Some(import) = import else {
+        return Vec::new();
+    };
+    if import.notice != notice.as_str() {
+        return Vec::new();
+    }
+    import
+        .messages
+        .into_iter()
+        .map(|message| {
+            let role = match message.role.as_str() {
+                "assistant" => MessageRole::assistant,
+                "system" => MessageRole::system,
+                _ => MessageRole::user,
+            };
+            Message::new(role, message.content)
+        })
+        .collect()
+}
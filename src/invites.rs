@@ -0,0 +1,121 @@
+/// Joining rooms on invite
+///
+/// Replaces the handler in headjack so that invites are checked against the current `allow_list` and
+/// `room_size_limit`, which can change when the config is reloaded. Once joined, chaz posts a welcome message
+/// explaining how to talk to it and what is sent to the backend.
+use std::time::Duration;
+
+use matrix_sdk::{
+    ruma::{
+        events::room::{member::StrippedRoomMemberEvent, message::RoomMessageEventContent},
+        UserId,
+    },
+    Client, Room, RoomMemberships,
+};
+use tracing::{error, info, warn};
+
+use crate::{accounts, get_backend, get_config, i18n, is_allowed, notifications, space};
+
+/// Join the rooms chaz is invited to by allowed users
+pub fn join_rooms(client: &Client) {
+    client.add_event_handler(
+        |room_member: StrippedRoomMemberEvent, client: Client, room: Room| async move {
+            if Some(room_member.state_key.as_ref()) != client.user_id() {
+                // The invite is for someone else
+                return;
+            }
+            if !is_allowed(&room_member.sender) {
+                return;
+            }
+            info!("Received an invite from {}", room_member.sender);
+            notifications::notify_later(
+                &client,
+                format!("Invited to {} by {}", room.room_id(), room_member.sender),
+            );
+
+            // Joining waits for the sync to return the new room state, and the sync waits for the event
+            // handlers, so join in a separate task
+            tokio::spawn(async move {
+                info!("Autojoining room {}", room.room_id());
+                let mut delay = 2;
+                // Synapse can send the invite before the user can join, so retry
+                // See https://github.com/matrix-org/synapse/issues/4345
+                while let Err(e) = room.join().await {
+                    warn!(
+                        "Failed to join room {} ({e:?}), retrying in {delay}s",
+                        room.room_id()
+                    );
+                    tokio::time::sleep(Duration::from_secs(delay)).await;
+                    delay *= 2;
+                    if delay > 3600 {
+                        error!("Can't join room {} ({e:?})", room.room_id());
+                        return;
+                    }
+                }
+                // Leave right away if the room is too large
+                let room_size = room
+                    .members(RoomMemberships::ACTIVE)
+                    .await
+                    .unwrap_or_default()
+                    .len();
+                if room_size > get_config().room_size_limit.unwrap_or(usize::MAX) {
+                    warn!(
+                        "Room {} has too many members, refusing to join",
+                        room.room_id()
+                    );
+                    notifications::notify(
+                        &client,
+                        &format!(
+                            "Left {}, it has {} members which is over the room_size_limit",
+                            room.room_id(),
+                            room_size
+                        ),
+                    )
+                    .await;
+                    if let Err(e) = room.leave().await {
+                        error!("Error leaving room: {:?}", e);
+                    }
+                    return;
+                }
+                info!("Successfully joined room {}", room.room_id());
+                welcome(&room, &room_member.sender).await;
+            });
+        },
+    );
+}
+
+/// Post the welcome message in a room chaz just joined
+///
+/// `welcome_message` in the config replaces the default, with `{model}`, `{room}`, and `{inviter}` filled in.
+/// Set it to an empty string to not post anything.
+async fn welcome(room: &Room, inviter: &UserId) {
+    let model = match space::settings(room)
+        .await
+        .model
+        .or_else(|| accounts::settings(room).and_then(|account| account.model))
+    {
+        Some(model) => Some(model),
+        None => get_backend(room, None).await.default_model().await,
+    };
+    let model = model.unwrap_or("the default model".to_string());
+    let room_name = room.name().unwrap_or(room.room_id().to_string());
+    let args = [
+        ("model", model.as_str()),
+        ("room", room_name.as_str()),
+        ("inviter", inviter.as_str()),
+    ];
+    let content = match get_config().welcome_message {
+        Some(message) if message.trim().is_empty() => return,
+        Some(message) => {
+            let message = args.iter().fold(message, |message, (name, value)| {
+                message.replace(&format!("{{{}}}", name), value)
+            });
+            // Notices starting with "!chaz" are left out of the context
+            RoomMessageEventContent::notice_plain(format!("!chaz {}", message.trim()))
+        }
+        None => i18n::notice(room, "welcome", &args).await,
+    };
+    if let Err(e) = room.send(content).await {
+        error!("Unable to post the welcome message: {:?}", e);
+    }
+}
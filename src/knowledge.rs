@@ -0,0 +1,378 @@
+/// Knowledge base
+///
+/// Indexes a local directory of markdown and text files with embeddings, and retrieves the relevant parts of it
+/// into the context for every room.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use openai_api_rs::v1::chat_completion::MessageRole;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::{
+    backends::{ChatContext, Message},
+    openai::OpenAI,
+    session, Backend,
+};
+
+/// File extensions that are indexed
+const EXTENSIONS: [&str; 4] = ["md", "markdown", "txt", "rst"];
+
+/// Most chunks embedded in one request, so large files stay within the request limits of the backend
+const EMBED_BATCH_SIZE: usize = 64;
+
+/// Configuration for the knowledge base
+#[derive(Debug, Deserialize, Clone)]
+pub struct KnowledgeConfig {
+    /// Directory containing the markdown/text files
+    pub directory: String,
+    /// Name of the OpenAI compatible backend used to create embeddings
+    ///
+    /// Defaults to the first OpenAI compatible backend
+    pub embedding_backend: Option<String>,
+    /// Model used to create embeddings, e.g. "text-embedding-3-small"
+    pub embedding_model: String,
+    /// Number of excerpts to add to the context
+    pub top_k: Option<usize>,
+    /// Maximum size of each excerpt, in characters
+    pub chunk_size: Option<usize>,
+    /// How often to check the directory for changes, in seconds
+    pub reindex_interval: Option<u64>,
+}
+
+/// Creates embeddings using an OpenAI compatible backend
+#[derive(Clone)]
+pub struct Embedder {
+    openai: Arc<OpenAI>,
+    model: String,
+}
+
+impl Embedder {
+    /// Find the backend to use for embeddings from the configured backends
+    pub fn new(backends: &[Backend], name: Option<&str>, model: &str) -> Result<Self, String> {
+        let backend = backends
+            .iter()
+            .find(|b| match name {
+                Some(name) => b.get_name() == name,
+                None => matches!(b.backend_type, crate::BackendType::OpenAICompatible),
+            })
+            .ok_or("no OpenAI compatible backend is available for embeddings")?;
+        Ok(Embedder {
+            openai: Arc::new(OpenAI::new(backend)?),
+            model: model.to_string(),
+        })
+    }
+
+    /// Embed a batch of texts
+    pub async fn embed(&self, inputs: &[String]) -> Result<Vec<Vec<f32>>, String> {
+        self.openai.embed(&self.model, inputs).await
+    }
+}
+
+/// A piece of a document along with its embedding
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Chunk {
+    /// Where the text came from, e.g. the file path
+    pub source: String,
+    pub text: String,
+    pub embedding: Vec<f32>,
+}
+
+/// A simple vector store, searched by brute force
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct VectorStore {
+    pub chunks: Vec<Chunk>,
+    /// Version of each indexed source, used to detect changes
+    pub sources: HashMap<String, u64>,
+}
+
+impl VectorStore {
+    /// Load the store from disk, or create an empty one
+    pub fn load(path: &Path) -> Self {
+        std::fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    /// Save the store to disk
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        session::write(path, &serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Remove all the chunks from a source
+    pub fn remove_source(&mut self, source: &str) {
+        self.chunks.retain(|chunk| chunk.source != source);
+        self.sources.remove(source);
+    }
+
+    /// Chunk, embed, and add a source to the store, replacing any previous version
+    pub async fn add_source(
+        &mut self,
+        embedder: &Embedder,
+        source: &str,
+        version: u64,
+        text: &str,
+        chunk_size: usize,
+    ) -> Result<usize, String> {
+        let texts = chunk_text(text, chunk_size);
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(EMBED_BATCH_SIZE) {
+            let batch_embeddings = embedder.embed(batch).await?;
+            if batch_embeddings.len() != batch.len() {
+                return Err(format!(
+                    "expected {} embeddings, got {}",
+                    batch.len(),
+                    batch_embeddings.len()
+                ));
+            }
+            embeddings.extend(batch_embeddings);
+        }
+        self.remove_source(source);
+        let count = texts.len();
+        for (text, embedding) in texts.into_iter().zip(embeddings) {
+            self.chunks.push(Chunk {
+                source: source.to_string(),
+                text,
+                embedding,
+            });
+        }
+        self.sources.insert(source.to_string(), version);
+        Ok(count)
+    }
+
+    /// Find the chunks most similar to the query embedding
+    pub fn search(&self, query: &[f32], top_k: usize) -> Vec<Chunk> {
+        let mut scored: Vec<(f32, &Chunk)> = self
+            .chunks
+            .iter()
+            .map(|chunk| (cosine_similarity(query, &chunk.embedding), chunk))
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored
+            .into_iter()
+            .take(top_k)
+            .map(|(_, chunk)| chunk.clone())
+            .collect()
+    }
+}
+
+/// Split text into chunks of at most `chunk_size` characters, preferring paragraph boundaries
+pub fn chunk_text(text: &str, chunk_size: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    for paragraph in text.split("\n\n").map(str::trim).filter(|p| !p.is_empty()) {
+        if !current.is_empty() && current.len() + paragraph.len() + 2 > chunk_size {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if paragraph.len() > chunk_size {
+            // Paragraphs that are too long on their own are split on character boundaries
+            let chars: Vec<char> = paragraph.chars().collect();
+            for piece in chars.chunks(chunk_size) {
+                chunks.push(piece.iter().collect());
+            }
+            continue;
+        }
+        if !current.is_empty() {
+            current.push_str("\n\n");
+        }
+        current.push_str(paragraph);
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    chunks
+}
+
+/// Cosine similarity between two vectors
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+/// The loaded knowledge base
+struct Knowledge {
+    store: VectorStore,
+    embedder: Embedder,
+    top_k: usize,
+}
+
+lazy_static! {
+    /// Holds the knowledge base, if one is configured
+    static ref KNOWLEDGE: Mutex<Option<Knowledge>> = Mutex::new(None);
+}
+
+/// Start indexing the knowledge directory in the background
+///
+/// The index is persisted to `index_file` and the directory is re-checked for changes periodically.
+pub fn start_indexer(config: KnowledgeConfig, embedder: Embedder, index_file: PathBuf) {
+    let top_k = config.top_k.unwrap_or(3);
+    let mut store = VectorStore::load(&index_file);
+    *KNOWLEDGE.lock().unwrap() = Some(Knowledge {
+        store: store.clone(),
+        embedder: embedder.clone(),
+        top_k,
+    });
+    tokio::spawn(async move {
+        let interval = Duration::from_secs(config.reindex_interval.unwrap_or(300));
+        let chunk_size = config.chunk_size.unwrap_or(1500);
+        loop {
+            match reindex(
+                &mut store,
+                &embedder,
+                Path::new(&config.directory),
+                chunk_size,
+            )
+            .await
+            {
+                Ok(true) => {
+                    info!(
+                        "Knowledge base indexed: {} files, {} chunks",
+                        store.sources.len(),
+                        store.chunks.len()
+                    );
+                    if let Err(e) = store.save(&index_file) {
+                        error!("Unable to save knowledge index: {}", e);
+                    }
+                    if let Some(knowledge) = KNOWLEDGE.lock().unwrap().as_mut() {
+                        knowledge.store = store.clone();
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => error!("Unable to index knowledge base: {}", e),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+/// Bring the store up to date with the directory
+///
+/// Returns true if anything changed.
+async fn reindex(
+    store: &mut VectorStore,
+    embedder: &Embedder,
+    directory: &Path,
+    chunk_size: usize,
+) -> Result<bool, String> {
+    let mut files = Vec::new();
+    collect_files(directory, &mut files).map_err(|e| e.to_string())?;
+    let mut changed = false;
+
+    // Remove files that no longer exist
+    let current: Vec<String> = files.iter().map(|(path, _)| path.clone()).collect();
+    let removed: Vec<String> = store
+        .sources
+        .keys()
+        .filter(|source| !current.contains(source))
+        .cloned()
+        .collect();
+    for source in removed {
+        store.remove_source(&source);
+        changed = true;
+    }
+
+    // Embed any new or modified files, skipping the ones that can't be read as text
+    for (path, modified) in files {
+        if store.sources.get(&path) == Some(&modified) {
+            continue;
+        }
+        let text = match tokio::fs::read_to_string(&path).await {
+            Ok(text) => text,
+            Err(e) => {
+                warn!("Skipping {} in the knowledge base: {}", path, e);
+                continue;
+            }
+        };
+        store
+            .add_source(embedder, &path, modified, &text, chunk_size)
+            .await?;
+        changed = true;
+    }
+    Ok(changed)
+}
+
+/// Recursively find the indexable files in a directory, along with their modification time
+fn collect_files(directory: &Path, files: &mut Vec<(String, u64)>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path
+            .extension()
+            .is_some_and(|ext| EXTENSIONS.contains(&ext.to_string_lossy().as_ref()))
+        {
+            let modified = path
+                .metadata()?
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            files.push((path.to_string_lossy().to_string(), modified));
+        }
+    }
+    Ok(())
+}
+
+/// Format excerpts into a system message for the context
+pub fn excerpts_message(intro: &str, chunks: &[Chunk]) -> Message {
+    let mut content = intro.to_string();
+    for chunk in chunks {
+        content.push_str(&format!("\n\n[{}]\n{}", chunk.source, chunk.text));
+    }
+    Message::new(MessageRole::system, content)
+}
+
+/// Add the parts of the knowledge base relevant to the latest user message to the context
+pub async fn augment_context(context: &mut ChatContext) {
+    let Some(query) = context
+        .messages
+        .iter()
+        .rev()
+        .find(|m| m.role == MessageRole::user)
+        .map(|m| m.content.clone())
+    else {
+        return;
+    };
+    let (embedder, top_k) = match KNOWLEDGE.lock().unwrap().as_ref() {
+        Some(knowledge) if !knowledge.store.chunks.is_empty() => {
+            (knowledge.embedder.clone(), knowledge.top_k)
+        }
+        _ => return,
+    };
+    let embedding = match embedder.embed(&[query]).await {
+        Ok(mut embeddings) if !embeddings.is_empty() => embeddings.remove(0),
+        Ok(_) => return,
+        Err(e) => {
+            error!("Unable to embed query for the knowledge base: {}", e);
+            return;
+        }
+    };
+    let chunks = match KNOWLEDGE.lock().unwrap().as_ref() {
+        Some(knowledge) => knowledge.store.search(&embedding, top_k),
+        None => return,
+    };
+    if !chunks.is_empty() {
+        context.messages.insert(
+            0,
+            excerpts_message(
+                "Use these excerpts from the knowledge base to answer if they are relevant:",
+                &chunks,
+            ),
+        );
+    }
+}
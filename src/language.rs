@@ -0,0 +1,29 @@
+/// Room language
+///
+/// The language of a room picks the translation of the role, for roles that have one, and of the notices.
+/// It's stored in the room tags under `is.chaz.language`, and falls back to `language` in the config.
+use headjack::Tags;
+use matrix_sdk::Room;
+
+use crate::get_config;
+
+/// Tag namespace for the setting
+const NAMESPACE: &str = "is.chaz.language";
+
+/// Get the language of the room
+pub async fn get(room: &Room) -> Option<String> {
+    Tags::new(room, NAMESPACE)
+        .await
+        .get_value("language")
+        .or(get_config().language)
+}
+
+/// Set the language of the room, or go back to the config with None
+pub async fn set(room: &Room, language: Option<&str>) {
+    let mut tags = Tags::new(room, NAMESPACE).await;
+    match language {
+        Some(language) => tags.replace_kv("language", language),
+        None => tags.remove_kv("language"),
+    }
+    tags.sync().await;
+}
@@ -0,0 +1,4425 @@
+//! Chaz is an AI chatbot for Matrix.
+//!
+//! The bot can be embedded in other projects with [`ChazBot::builder`], which also takes extra commands.
+//! The pieces the commands build on are public too: [`backends`] sends the requests to the models,
+//! [`get_context`] builds the conversation of a room that [`context`] fits in the token budget, [`role`] has the
+//! system prompts, and [`commands`] parses the arguments.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! chaz::ChazBot::builder()
+//!     .config("config.yaml")
+//!     .command("ping", chaz::Signature::new(), "Check that chaz is alive", |_, _, room| async move {
+//!         room.send(chaz::RoomMessageEventContent::notice_plain("!chaz pong"))
+//!             .await?;
+//!         Ok(())
+//!     })
+//!     .build()?
+//!     .run()
+//!     .await
+//! # }
+//! ```
+
+mod accessibility;
+mod account_data;
+mod accounts;
+mod activity;
+mod admin;
+mod aichat;
+mod aliases;
+mod appservice;
+mod audit;
+mod auth;
+pub mod backends;
+mod capabilities;
+mod checkpoints;
+mod chunking;
+mod cleanup;
+pub mod commands;
+mod compaction;
+mod concurrency;
+pub mod context;
+mod cost;
+mod debug;
+mod defaults;
+mod documents;
+mod env;
+mod error;
+mod eval;
+mod failover;
+mod format;
+mod health;
+mod history;
+mod http;
+mod i18n;
+mod images;
+mod import;
+mod invites;
+mod knowledge;
+mod language;
+mod logging;
+mod media;
+mod memory;
+mod metrics;
+mod migrate;
+mod moderation;
+mod mqtt;
+mod names;
+mod notifications;
+mod ollama;
+mod openai;
+mod openrouter;
+mod permissions;
+mod polls;
+mod preferences;
+mod prompt;
+mod queue;
+mod ratelimit;
+mod reactions;
+mod reload;
+mod responses;
+pub mod role;
+mod router;
+mod schedule;
+mod secrets;
+mod session;
+mod shutdown;
+mod snippets;
+mod space;
+mod status;
+mod structured;
+mod sync;
+mod templates;
+mod tools;
+mod transcription;
+mod update;
+mod usage;
+mod util;
+mod verification;
+mod webhooks;
+mod workspace;
+
+pub use backends::{ChatContext, Message};
+pub use commands::{Args, Signature};
+pub use error::ChazError;
+pub use logging::LogFileLayer;
+pub use matrix_sdk::ruma::events::room::message::RoomMessageEventContent;
+
+use headjack::Tags;
+use headjack::*;
+use lazy_static::lazy_static;
+use matrix_sdk::{
+    attachment::AttachmentConfig,
+    media::{MediaFileHandle, MediaFormat, MediaRequest},
+    ruma::{
+        events::relation::InReplyTo,
+        events::room::{
+            message::OriginalSyncRoomMessageEvent,
+            message::{
+                AudioMessageEventContent, ImageMessageEventContent, MessageType, Relation,
+                ReplacementMetadata,
+            },
+            redaction::OriginalSyncRoomRedactionEvent,
+        },
+        events::{AnyTimelineEvent, Mentions},
+        serde::Raw,
+        EventId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedUserId, RoomId, UInt, UserId,
+    },
+    Client, Room, RoomMemberships, RoomState,
+};
+use openai_api_rs::v1::chat_completion::MessageRole;
+use regex::Regex;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+use std::format;
+use std::{
+    borrow::Cow, collections::HashMap, fs::File, future::Future, io::Read, path::Path,
+    path::PathBuf, pin::Pin, sync::Arc, sync::Mutex, sync::PoisonError, time::Duration,
+    time::Instant,
+};
+use tracing::{error, info, warn, Instrument};
+
+use accounts::AccountConfig;
+use appservice::AppserviceConfig;
+use audit::{AuditConfig, Requester};
+use backends::{BackendManager, GenerationParams, LLMBackend, TRUNCATION_MARKER};
+use capabilities::Capabilities;
+use cleanup::CleanupConfig;
+use compaction::CompactionConfig;
+use cost::CostConfig;
+use defaults::DEFAULT_CONFIG;
+use documents::DocumentsConfig;
+use eval::EvalSuite;
+use images::ImageConfig;
+use knowledge::{Embedder, KnowledgeConfig};
+use logging::LogFileConfig;
+use media::{describe_media, AttachmentLimits};
+use moderation::ModerationConfig;
+use mqtt::MqttConfig;
+use queue::QueueConfig;
+use ratelimit::RateLimitConfig;
+use role::{get_role, RoleDetails};
+use router::RouterConfig;
+use secrets::SecretsConfig;
+use space::SpaceConfig;
+use structured::JsonSchema;
+use sync::SyncFilterConfig;
+use tools::ToolName;
+use transcription::TranscriptionConfig;
+use usage::QuotaConfig;
+use webhooks::{EventWebhook, WebhookConfig};
+
+/// Future returned by the handler of an extra command
+pub type CommandFuture = Pin<Box<dyn Future<Output = Result<(), ChazError>> + Send>>;
+
+/// Handler of a command, called with the sender, the message, and the room
+type CommandHandler = Arc<dyn Fn(OwnedUserId, String, Room) -> CommandFuture + Send + Sync>;
+
+/// Handler of an extra command, called with the sender, the parsed arguments, and the room
+type ExtraCommandHandler = Arc<dyn Fn(OwnedUserId, Args, Room) -> CommandFuture + Send + Sync>;
+
+/// A command registered by the project embedding chaz
+struct ExtraCommand {
+    name: String,
+    signature: Signature,
+    help: String,
+    handler: ExtraCommandHandler,
+}
+
+/// The chaz bot, built with [`ChazBot::builder`]
+pub struct ChazBot {
+    config_path: PathBuf,
+    commands: Vec<ExtraCommand>,
+    backends: Vec<(String, Box<dyn LLMBackend>)>,
+}
+
+impl ChazBot {
+    /// Start building the bot
+    pub fn builder() -> ChazBotBuilder {
+        ChazBotBuilder::default()
+    }
+
+    /// Log in and run the bot, only returns on error
+    pub async fn run(self) -> anyhow::Result<()> {
+        for (name, client) in self.backends {
+            backends::register(&name, client);
+        }
+        run(self.config_path, self.commands).await
+    }
+}
+
+/// Builder for [`ChazBot`]
+#[derive(Default)]
+pub struct ChazBotBuilder {
+    config_path: Option<PathBuf>,
+    commands: Vec<ExtraCommand>,
+    backends: Vec<(String, Box<dyn LLMBackend>)>,
+}
+
+impl ChazBotBuilder {
+    /// Path of the config file, required
+    pub fn config(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_path = Some(path.into());
+        self
+    }
+
+    /// Add a command, run with `!chaz <name>` and the arguments of the signature
+    ///
+    /// It's subject to the allow_list like the builtin commands, and errors are reported to the admin room.
+    pub fn command<F, Fut>(
+        mut self,
+        name: &str,
+        signature: Signature,
+        help: &str,
+        handler: F,
+    ) -> Self
+    where
+        F: Fn(OwnedUserId, Args, Room) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), ChazError>> + Send + 'static,
+    {
+        self.commands.push(ExtraCommand {
+            name: name.to_string(),
+            signature,
+            help: help.to_string(),
+            handler: Arc::new(move |sender, text, room| Box::pin(handler(sender, text, room))),
+        });
+        self
+    }
+
+    /// Add a backend of your own, after the ones in the config
+    ///
+    /// Its models are selected like those of the configured backends, e.g. `!chaz model <name>:<model>`.
+    pub fn register_backend(mut self, name: &str, backend: impl LLMBackend + 'static) -> Self {
+        self.backends.push((name.to_string(), Box::new(backend)));
+        self
+    }
+
+    /// Build the bot
+    pub fn build(self) -> anyhow::Result<ChazBot> {
+        Ok(ChazBot {
+            config_path: self
+                .config_path
+                .ok_or(anyhow::anyhow!("the config path is required"))?,
+            commands: self.commands,
+            backends: self.backends,
+        })
+    }
+}
+
+/// Configuration info for a backend
+///
+/// Holds the config info for an OpenAPI compatible backend
+#[derive(Debug, Deserialize, Clone)]
+struct Backend {
+    /// The type of backend
+    ///
+    /// Currently supports AIChat, OpenAICompatible, Ollama, or OpenRouter
+    #[serde(rename = "type")]
+    backend_type: BackendType,
+    /// The base URL for the API
+    api_base: Option<String>,
+    /// The API key to use for the API
+    api_key: Option<String>,
+    /// Available models for this backend
+    models: Option<Vec<Model>>,
+    /// Name of this backend
+    ///
+    /// Will be used by Chaz to name the model as "name:model_name"
+    /// Will default to the backend_type, "aichat", "openai", "ollama", or "openrouter"
+    name: Option<String>,
+    /// Set the config directory
+    /// Used by the aichat backend
+    #[allow(dead_code)]
+    config_dir: Option<String>,
+    /// Path to a PEM encoded CA bundle to trust for this backend
+    ///
+    /// Useful for self-hosted endpoints using a private PKI
+    ca_bundle: Option<String>,
+    /// Disable TLS certificate verification for this backend
+    ///
+    /// Only use this for endpoints you trust on a network you trust
+    insecure_skip_verify: Option<bool>,
+    /// Extra HTTP headers sent with every request to this backend, e.g. for a gateway with its own auth
+    headers: Option<HashMap<String, String>>,
+    /// URL of the proxy to send the requests to this backend through, e.g. "http://proxy.corp:3128"
+    ///
+    /// Without one, the HTTP_PROXY and HTTPS_PROXY environment variables are used
+    proxy: Option<String>,
+    /// How long to wait for aichat before killing it, e.g. "2m", defaults to 5 minutes
+    ///
+    /// Used by the aichat backend
+    timeout: Option<String>,
+    /// What the models of this backend support, unless set for the model
+    capabilities: Option<Capabilities>,
+    /// Ask the backend which models it serves, with GET /models
+    ///
+    /// Used by the OpenAI compatible backend, the models are listed after the ones in the config
+    query_models: Option<bool>,
+    /// How long to reuse the queried models, e.g. "10m", defaults to 1 hour
+    models_cache_ttl: Option<String>,
+}
+
+impl Backend {
+    pub fn new(backend_type: BackendType) -> Self {
+        Backend {
+            backend_type,
+            api_base: None,
+            api_key: None,
+            models: None,
+            name: None,
+            config_dir: None,
+            ca_bundle: None,
+            insecure_skip_verify: None,
+            headers: None,
+            proxy: None,
+            timeout: None,
+            capabilities: None,
+            query_models: None,
+            models_cache_ttl: None,
+        }
+    }
+
+    /// Build an HTTP client with the TLS, proxy, and header options for this backend
+    pub fn http_client(&self) -> Result<reqwest::Client, String> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(ca_bundle) = &self.ca_bundle {
+            let pem = std::fs::read(ca_bundle)
+                .map_err(|e| format!("Unable to read CA bundle {}: {}", ca_bundle, e))?;
+            for cert in reqwest::Certificate::from_pem_bundle(&pem).map_err(|e| e.to_string())? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if self.insecure_skip_verify.unwrap_or(false) {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        if let Some(headers) = &self.headers {
+            let mut map = HeaderMap::new();
+            for (name, value) in headers {
+                let name = HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|e| format!("Invalid header name {}: {}", name, e))?;
+                let value = HeaderValue::from_str(value)
+                    .map_err(|e| format!("Invalid value for header {}: {}", name, e))?;
+                map.insert(name, value);
+            }
+            builder = builder.default_headers(map);
+        }
+        if let Some(proxy) = &self.proxy {
+            let proxy = reqwest::Proxy::all(proxy)
+                .map_err(|e| format!("Invalid proxy {}: {}", proxy, e))?;
+            builder = builder.proxy(proxy);
+        }
+        builder.build().map_err(|e| e.to_string())
+    }
+
+    /// Get the generation parameters set in the config for a model of this backend
+    pub fn model_params(&self, model: &str) -> GenerationParams {
+        let mut params: GenerationParams = self
+            .models
+            .iter()
+            .flatten()
+            .find(|m| m.name == model)
+            .map(|m| m.params.clone())
+            .unwrap_or_default();
+        params.max_tokens = params.max_tokens.or(get_config().max_response_tokens);
+        params
+    }
+
+    /// Get the config of a model of this backend, with or without the backend name
+    ///
+    /// Without a model, it's the first model listed.
+    pub fn model_config(&self, model: Option<&str>) -> Option<&Model> {
+        let models = self.models.as_ref()?;
+        match model {
+            Some(model) => models
+                .iter()
+                .find(|m| m.name == model || format!("{}:{}", self.get_name(), m.name) == model),
+            None => models.first(),
+        }
+    }
+
+    /// Get the name for this backend
+    pub fn get_name(&self) -> String {
+        if let Some(name) = &self.name {
+            name.clone()
+        } else {
+            match self.backend_type {
+                BackendType::AIChat => "aichat".to_string(),
+                BackendType::OpenAICompatible => "openai".to_string(),
+                BackendType::Ollama => "ollama".to_string(),
+                BackendType::OpenRouter => "openrouter".to_string(),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct Model {
+    /// The name of the model
+    ///
+    /// This is passed to the backend to select the model, e.g. "gpt-3.5-turbo"
+    name: String,
+    /// Price of a million input tokens, for `!chaz cost`
+    input_cost: Option<f64>,
+    /// Price of a million output tokens
+    output_cost: Option<f64>,
+    /// Generation parameters for the model
+    #[serde(flatten)]
+    params: GenerationParams,
+    /// What the model supports
+    capabilities: Option<Capabilities>,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum BackendType {
+    AIChat,
+    OpenAICompatible,
+    Ollama,
+    OpenRouter,
+}
+
+/// When responses are sent as replies to the message that prompted them
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+enum RichReplies {
+    Always,
+    /// Everywhere but direct messages
+    #[default]
+    Groups,
+    Never,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Config {
+    homeserver_url: String,
+    /// Additional endpoints for the same homeserver, e.g. a failover proxy
+    ///
+    /// On repeated sync failures chaz switches to the next endpoint, keeping the same session.
+    homeserver_urls: Option<Vec<String>>,
+    /// Number of consecutive sync failures before switching endpoints
+    sync_failure_limit: Option<u32>,
+    username: String,
+    /// Optionally specify the password, if not set it will be asked for on cmd line
+    password: Option<String>,
+    /// Log in with SSO, printing the URL to open and asking for the login token
+    sso: Option<bool>,
+    /// Log in with an existing access token instead of a password
+    access_token: Option<String>,
+    /// Allow list of which accounts we will respond to
+    allow_list: Option<String>,
+    /// Regex for accounts chaz ignores, even if they're on the allow_list
+    deny_list: Option<String>,
+    /// Regex for the accounts allowed to run admin commands
+    admin_list: Option<String>,
+    /// Room ID of the room where chaz posts notifications and errors for the admins
+    admin_room: Option<String>,
+    /// Check GitHub for a newer release on startup, and post it to the admin room
+    update_check: Option<bool>,
+    /// More accounts to run in the same process, each with its own persona
+    accounts: Option<Vec<AccountConfig>>,
+    /// Run as an appservice with a virtual user for each persona, instead of logging in as `username`
+    appservice: Option<AppserviceConfig>,
+    /// Where the store passphrase and the `${secret:<name>}` values in the config are kept
+    secrets: Option<SecretsConfig>,
+    /// Recovery key for secret storage
+    ///
+    /// Restores the cross-signing identity and room key backup on startup, e.g. after the state is lost
+    recovery_key: Option<String>,
+    /// Replaced by `rate_limit`, only read to warn that it's ignored
+    message_limit: Option<u64>,
+    /// Messages per hour for each user and room
+    rate_limit: Option<RateLimitConfig>,
+    /// Per-account daily and monthly quotas
+    quotas: Option<QuotaConfig>,
+    /// Currency and per-user budgets for the costs estimated from the model prices
+    cost: Option<CostConfig>,
+    /// Check the responses, and optionally the messages, against keywords, regexes, or a moderation endpoint
+    moderation: Option<ModerationConfig>,
+    /// Room size limit to respond to
+    room_size_limit: Option<usize>,
+    /// Set the state directory for chaz
+    /// Defaults to $XDG_STATE_HOME/chaz
+    state_dir: Option<String>,
+    /// Model to use for summarizing chats
+    /// Used for setting the room name/topic
+    chat_summary_model: Option<String>,
+    /// Default role
+    role: Option<String>,
+    /// Definitions of roles
+    roles: Option<Vec<RoleDetails>>,
+    /// Directory of role files, YAML or Markdown, added to the roles and reloaded when they change
+    roles_dir: Option<String>,
+    /// Queue questions while the backend is down, and answer them once it recovers
+    offline_queue: Option<QueueConfig>,
+    /// Default language of the rooms, selects the translation of the role and of the notices
+    language: Option<String>,
+    /// Leave rooms that are empty or idle
+    cleanup: Option<CleanupConfig>,
+    /// Posted when joining a room, with `{model}`, `{room}`, and `{inviter}` filled in. Empty to post nothing
+    welcome_message: Option<String>,
+    /// Mention the user a response is for in rooms with more than one user, defaults to true
+    mention_sender: Option<bool>,
+    /// When responses are sent as replies to the message that prompted them
+    rich_replies: Option<RichReplies>,
+    /// Default format of the responses, markdown, plain, or notice. Can be set per room with `!chaz format`
+    format: Option<format::Format>,
+    /// Shortcuts for commands in every room, e.g. "!sum": "!chaz summarize"
+    aliases: Option<HashMap<String, String>>,
+    /// Directory of Fluent files translating the notices, named after the language, e.g. de.ftl
+    locales_dir: Option<String>,
+    /// Disable sending media context to aichat
+    disable_media_context: Option<bool>,
+    /// Limits for the text and PDF files inlined into the context
+    attachments: Option<AttachmentLimits>,
+    /// Backend configuration
+    ///
+    /// If set, this will be used instead of AiChat
+    backends: Option<Vec<Backend>>,
+    /// Maximum tokens in a response, for the models that don't set their own `max_tokens`
+    max_response_tokens: Option<i64>,
+    /// Maximum length of a message in bytes, longer responses are split into several messages
+    ///
+    /// Defaults to 24000, which keeps the events under the 64KB limit of the homeservers.
+    max_message_length: Option<usize>,
+    /// Soft deadline for a response, in seconds
+    ///
+    /// After this, whatever has been generated so far is posted and marked as truncated
+    response_deadline: Option<u64>,
+    /// Maximum estimated tokens to include in the context
+    ///
+    /// The oldest messages are dropped to fit. Can be overridden per room.
+    context_token_limit: Option<usize>,
+    /// Start a new conversation after this long without messages, e.g. "12h" or "7d"
+    ///
+    /// Can be overridden per room.
+    context_ttl: Option<String>,
+    /// Prefix for messages that are answered on their own without the room history, e.g. "!!"
+    ///
+    /// A shorthand for `!chaz send`.
+    no_context_prefix: Option<String>,
+    /// Summarize messages dropped by the context_token_limit with the chat_summary_model
+    summarize_truncated_context: Option<bool>,
+    /// Summarize the older messages in the background once the context grows past a threshold
+    compaction: Option<CompactionConfig>,
+    /// Include the sender's display name with each message
+    ///
+    /// Lets the model tell participants apart in multi-user rooms
+    multi_user_context: Option<bool>,
+    /// Include stickers and the reactions to messages in the context, defaults to false
+    social_context: Option<bool>,
+    /// Model used to decide whether to chime in on rooms with listening enabled
+    ///
+    /// Defaults to the chat_summary_model
+    interjection_model: Option<String>,
+    /// Topics that chaz will chime in on in rooms with listening enabled
+    interjection_topics: Option<Vec<String>>,
+    /// Show a banner in the topic of DM rooms while a backend is failing
+    status_banner: Option<bool>,
+    /// Number of consecutive failures before a backend is considered degraded
+    degraded_threshold: Option<u32>,
+    /// A directory of markdown/text files that is indexed and searched for every room
+    knowledge: Option<KnowledgeConfig>,
+    /// Index the files uploaded to a room with `!chaz index`, and search them for that room
+    documents: Option<DocumentsConfig>,
+    /// Transcribe voice messages so they are included in the context
+    transcription: Option<TranscriptionConfig>,
+    /// Image generation backend used by `!chaz imagine`
+    image_generation: Option<ImageConfig>,
+    /// Save the cached room history in the state directory so it survives restarts, defaults to false
+    ///
+    /// Encrypted rooms are only cached in memory.
+    persist_history: Option<bool>,
+    /// Filter for the events received on each sync
+    ///
+    /// By default presence, receipts, and typing notifications are left out
+    sync_filter: Option<SyncFilterConfig>,
+    /// Suites of prompts with expected answers, run with `!chaz eval`
+    eval_suites: Option<Vec<EvalSuite>>,
+    /// Tools offered to backends that support function calling
+    tools: Option<Vec<ToolName>>,
+    /// Log 👍 and 👎 reactions to responses in the state directory
+    feedback_log: Option<bool>,
+    /// Show chaz as typing while it waits for the backend, defaults to true
+    typing_notifications: Option<bool>,
+    /// Maximum number of messages and commands handled at once, across all rooms, defaults to 4
+    ///
+    /// Messages in the same room are always handled one at a time, in order.
+    max_concurrent_requests: Option<usize>,
+    /// How long to wait for the messages being handled when shutting down, e.g. "30s", the default
+    shutdown_timeout: Option<String>,
+    /// Send read receipts for the messages chaz handles, defaults to true
+    read_receipts: Option<bool>,
+    /// Serve Prometheus metrics at `/metrics` on this port
+    metrics_port: Option<u16>,
+    /// Port to serve the health checks on, at /healthz and /readyz
+    health_port: Option<u16>,
+    /// Let other services post messages and prompts to the rooms
+    webhooks: Option<WebhookConfig>,
+    /// Webhooks sent an event for every request to a backend
+    event_webhooks: Option<Vec<EventWebhook>>,
+    /// Follow the state of things in the home over MQTT, and let the model read and control them
+    mqtt: Option<MqttConfig>,
+    /// Let the model answer ambiguous requests with a poll of what was meant, defaults to false
+    disambiguation_polls: Option<bool>,
+    /// Schemas `!chaz json` can answer with
+    json_schemas: Option<Vec<JsonSchema>>,
+    /// Rules and a classifier prompt for the `router` model, which picks a model for each request
+    router: Option<RouterConfig>,
+    /// Write JSON logs to a rotating file in the state directory
+    log_file: Option<LogFileConfig>,
+    /// Write every backend request and response to audit.jsonl in the state_dir
+    audit_log: Option<AuditConfig>,
+    /// Minimum power level in the room needed to use each command, e.g. `model: 50`
+    ///
+    /// Commands that aren't listed can be used by everyone on the allow_list
+    permissions: Option<HashMap<String, i64>>,
+}
+
+lazy_static! {
+    /// Holds the config for the bot
+    static ref GLOBAL_CONFIG: Mutex<Option<Config>> = Mutex::new(None);
+
+    /// Path of the config file, to reload it from
+    static ref CONFIG_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+    /// Handlers of the registered commands, by name
+    static ref COMMAND_HANDLERS: Mutex<HashMap<String, CommandHandler>> = Mutex::new(HashMap::new());
+
+    /// Help text for each command, in the order they were registered, as (command, usage, description)
+    static ref GLOBAL_HELP: Mutex<Vec<(String, String, Option<String>)>> = Mutex::new(Vec::new());
+}
+
+/// Run the bot
+async fn run(config_path: PathBuf, commands: Vec<ExtraCommand>) -> anyhow::Result<()> {
+    // Read in the config file
+    let config = read_config(&config_path)?;
+    backends::configure(&config.backends).map_err(anyhow::Error::msg)?;
+    *GLOBAL_CONFIG.lock().unwrap() = Some(config.clone());
+    *CONFIG_PATH.lock().unwrap() = Some(config_path.clone());
+    reload::watch(config_path);
+
+    let mut endpoints = vec![config.homeserver_url.clone()];
+    endpoints.extend(config.homeserver_urls.clone().unwrap_or_default());
+    let sync_failure_limit = config.sync_failure_limit.unwrap_or(3);
+
+    // The config file is read, now we can start the bot
+    let mut bot = Bot::new(BotConfig {
+        command_prefix: None,
+        room_size_limit: config.room_size_limit,
+        login: Login {
+            homeserver_url: config.homeserver_url,
+            username: config.username.clone(),
+            password: config.password,
+        },
+        name: Some("chaz".to_string()),
+        // The allow_list is checked by chaz so that it can be reloaded, headjack only filters out our own messages
+        allow_list: Some(".*".to_string()),
+        state_dir: config.state_dir,
+    })
+    .await;
+
+    if let Some(log_file) = &config.log_file {
+        logging::init(&bot.state_dir(), log_file);
+    }
+    if let Some(audit_log) = &config.audit_log {
+        audit::init(&bot.state_dir(), audit_log);
+    }
+
+    // The appservice personas share the blocks, quotas, and limits with the bot
+    admin::init(&bot.state_dir())?;
+    usage::init(&bot.state_dir())?;
+    cost::init(&bot.state_dir())?;
+    ratelimit::init(&bot.state_dir())?;
+
+    if let Some(appservice) = config.appservice.clone() {
+        return appservice::run(appservice, &endpoints[0], &bot.state_dir()).await;
+    }
+
+    session::check(&bot.state_dir().join("session"));
+    if let Err(e) = auth::login(
+        &bot.state_dir(),
+        &endpoints[0],
+        config.sso.unwrap_or(false),
+        config.access_token.as_deref(),
+    )
+    .await
+    {
+        error!("Error logging in: {e}");
+    }
+    let session_file = bot.state_dir().join("session");
+    let passphrase = config
+        .secrets
+        .as_ref()
+        .map(|secrets| secrets::restore_passphrase(secrets, &session_file));
+    if let Err(e) = bot.login().await {
+        error!("Error logging in: {e}");
+    }
+    drop(passphrase);
+    accounts::add(None, bot.client().clone());
+
+    // Let admins verify this device
+    verification::register_handlers(bot.client());
+
+    // React to invites.
+    // We set this up before the initial sync so that we join rooms
+    // even if they were invited before the bot was started.
+    invites::join_rooms(bot.client());
+    history::register_handler(bot.client());
+
+    responses::init(&bot.state_dir())?;
+    history::init(&bot.state_dir());
+    reactions::init(&bot.state_dir());
+    if config.message_limit.is_some() {
+        warn!("message_limit was replaced by rate_limit and is ignored");
+    }
+    schedule::init(&bot.state_dir())?;
+    documents::init(&bot.state_dir());
+    if let Some(locales_dir) = &config.locales_dir {
+        i18n::init(Path::new(locales_dir));
+    }
+
+    // Index the knowledge directory in the background
+    if let Some(knowledge) = config.knowledge.clone() {
+        match Embedder::new(
+            &config.backends.clone().unwrap_or_default(),
+            knowledge.embedding_backend.as_deref(),
+            &knowledge.embedding_model,
+        ) {
+            Ok(embedder) => knowledge::start_indexer(
+                knowledge,
+                embedder,
+                bot.state_dir().join("knowledge.json"),
+            ),
+            Err(e) => error!("Unable to create the knowledge embeddings: {}", e),
+        }
+    }
+
+    // Served before the initial sync, which can take a while
+    if let Some(port) = config.health_port {
+        health::serve(port);
+    }
+
+    // Syncs to the current state
+    // The initial sync retries forever, so give up on the endpoint if it takes too long
+    let sync_filter = sync::build_filter(&config.sync_filter.clone().unwrap_or_default());
+    if endpoints.len() > 1 {
+        match tokio::time::timeout(
+            Duration::from_secs(600),
+            sync::initial_sync(bot.client(), &session_file, sync_filter.clone()),
+        )
+        .await
+        {
+            Ok(Err(e)) => info!("Error syncing: {e}"),
+            Ok(Ok(())) => {}
+            Err(_) => {
+                error!("Initial sync timed out");
+                return Err(failover::switch_endpoint(&session_file, &endpoints));
+            }
+        }
+    } else if let Err(e) =
+        sync::initial_sync(bot.client(), &session_file, sync_filter.clone()).await
+    {
+        info!("Error syncing: {e}");
+    }
+
+    info!("The client is ready! Listening to new messages…");
+    notifications::notify_later(
+        bot.client(),
+        format!(
+            "chaz {} started as {}, in {} rooms",
+            env!("CARGO_PKG_VERSION"),
+            bot.full_name(),
+            bot.client().joined_rooms().len()
+        ),
+    );
+
+    if let Some(recovery_key) = &config.recovery_key {
+        verification::recover(bot.client(), recovery_key).await;
+    }
+
+    // Answer the questions queued while the backend was down
+    queue::start();
+
+    // Post reminders and run scheduled prompts
+    schedule::start(bot.client().clone());
+
+    // Leave the rooms that aren't used anymore
+    cleanup::start(bot.client().clone());
+
+    // Follow the home states for the MQTT tools
+    mqtt::start();
+
+    if let Some(port) = config.metrics_port {
+        metrics::serve(port, bot.client().clone());
+    }
+
+    if let Some(webhooks) = config.webhooks.clone() {
+        webhooks::serve(webhooks);
+    }
+
+    // Move models set by `!chaz model` messages into the tags, this only runs once
+    let client = bot.client().clone();
+    let state_dir = bot.state_dir();
+    tokio::spawn(async move { migrate::migrate_once(&client, &state_dir).await });
+
+    if config.update_check.unwrap_or(false) {
+        let client = bot.client().clone();
+        tokio::spawn(async move { update::check_for_update(&client).await });
+    }
+
+    // The party command is from the matrix-rust-sdk examples
+    // Keeping it as an easter egg
+    // TODO: Remove `party` from the help text
+    register_parsed_command(
+        "party",
+        Signature::new(),
+        "Party!",
+        |_, _, room| async move {
+            send_out_of_context(&room, ".🎉🎊🥳 let's PARTY!! 🥳🎊🎉").await?;
+            Ok(())
+        },
+    );
+
+    register_parsed_command(
+        "index",
+        Signature::new()
+            .subcommand("list", Signature::new())
+            .subcommand("remove", Signature::new().rest("file"))
+            .subcommand("clear", Signature::new()),
+        "Index the files recently uploaded to this room, so they're used to answer questions",
+        documents::index,
+    );
+
+    register_parsed_command(
+        "summarize",
+        Signature::new()
+            .optional("n")
+            .optional("messages")
+            .subcommand("since", Signature::new().required("duration"))
+            .with_usage("[since <duration>|<n> messages]"),
+        "Post a digest of the conversation, or of the recent messages",
+        summarize,
+    );
+
+    register_parsed_command(
+        "remind",
+        Signature::new().required("time").rest("text"),
+        "Post a reminder after a delay like 30m, or at a time of day in UTC like 14:30",
+        schedule::remind,
+    );
+
+    register_parsed_command(
+        "schedule",
+        Signature::new()
+            .optional("minute")
+            .optional("hour")
+            .optional("day")
+            .optional("month")
+            .optional("weekday")
+            .optional_rest("prompt")
+            .subcommand("list", Signature::new())
+            .subcommand("cancel", Signature::new().required("id"))
+            .with_usage(schedule::SCHEDULE_USAGE),
+        "Run a prompt on a cron schedule in UTC, or list what's scheduled in this room",
+        schedule::schedule,
+    );
+
+    register_parsed_command(
+        "poll",
+        Signature::new()
+            .rest("poll")
+            .with_usage("<question> | <option> | <option>..."),
+        "Post a poll to the room",
+        polls::poll,
+    );
+
+    register_parsed_command(
+        "template",
+        Signature::new()
+            .subcommand("save", Signature::new().required("name").rest("prompt"))
+            .subcommand("run", Signature::new().required("name").optional_rest("args"))
+            .subcommand("delete", Signature::new().required("name")),
+        "Save prompts with {1}, {2}... placeholders and run them with the arguments, or list your templates",
+        templates::template,
+    );
+
+    register_parsed_command(
+        "json",
+        Signature::new()
+            .optional("schema")
+            .optional_rest("prompt")
+            .with_usage("[<schema> <prompt>]"),
+        "Answer with JSON matching a configured schema, or list the schemas",
+        structured::json,
+    );
+
+    register_parsed_command(
+        "remember",
+        Signature::new().rest("fact"),
+        "Remember a fact in every conversation in this room",
+        memory::remember,
+    );
+
+    register_parsed_command(
+        "memories",
+        Signature::new()
+            .subcommand("suggest", Signature::new())
+            .subcommand("keep", Signature::new().rest("n"))
+            .subcommand("clear", Signature::new())
+            .with_usage("[suggest|keep <n>...|keep all|clear]"),
+        "List what's remembered in this room, or have the model suggest memories from the conversation",
+        memory::memories,
+    );
+
+    register_parsed_command(
+        "forget",
+        Signature::new().rest("n").with_usage("<n>..."),
+        "Forget memories by their number in the list",
+        memory::forget,
+    );
+
+    register_parsed_command(
+        "import",
+        Signature::new(),
+        "Continue the conversation from the transcript you last attached to the room",
+        import::import,
+    );
+
+    register_parsed_command(
+        "print",
+        Signature::new(),
+        "Print the conversation",
+        |_, _, room| async move {
+            let context = get_context(&room).await?;
+            send_out_of_context(&room, &context.string_prompt()).await?;
+            Ok(())
+        },
+    );
+
+    register_parsed_command(
+        "send",
+        Signature::new().rest("message"),
+        "Send a message without context",
+        |sender, args, room| async move { send_standalone(&room, &sender, &args["message"]).await },
+    );
+
+    register_parsed_command(
+        "model",
+        Signature::new()
+            .optional("model")
+            .optional("locked model")
+            .with_usage("[<model>|lock <model>|unlock]"),
+        "Select the model to use, room admins can lock it",
+        model,
+    );
+
+    register_parsed_command(
+        "backend",
+        Signature::new()
+            .required("name")
+            .required("api_base")
+            .required("api_key"),
+        "Manually enter an OpenAI Compatible Backend",
+        set_backend,
+    );
+
+    register_parsed_command(
+        "login",
+        Signature::new()
+            .required("api_base")
+            .required("api_key")
+            .optional("name"),
+        "Use your own OpenAI Compatible Backend for your messages in this room",
+        login,
+    );
+
+    register_parsed_command(
+        "logout",
+        Signature::new(),
+        "Remove your own backend from this room",
+        logout,
+    );
+
+    register_parsed_command(
+        "role",
+        Signature::new()
+            .optional("role")
+            .optional_rest("prompt")
+            .subcommand("list", Signature::new())
+            .with_usage("[list|<role>] [<prompt>]"),
+        "Get the role info, list the roles, set the role, or define a new role",
+        set_role,
+    );
+
+    register_parsed_command(
+        "prompt",
+        Signature::new()
+            .subcommand("set", Signature::new().rest("text"))
+            .subcommand("clear", Signature::new()),
+        "Show the full system prompt, or replace the role with a prompt for this room",
+        prompt::prompt,
+    );
+
+    register_parsed_command(
+        "leave",
+        Signature::new(),
+        "Make chaz leave this room and forget it, only room admins can",
+        cleanup::leave_room,
+    );
+
+    register_parsed_command(
+        "access",
+        Signature::new()
+            .subcommand("set", Signature::new().required("rule"))
+            .subcommand("clear", Signature::new())
+            .with_usage("[set <regex|power level>|clear]"),
+        "Show or restrict who can use chaz in this room, room admins can change it",
+        permissions::access,
+    );
+
+    register_parsed_command(
+        "list",
+        Signature::new(),
+        "List available models",
+        list_models,
+    );
+
+    register_parsed_command(
+        "clear",
+        Signature::new(),
+        "Ignore all messages before this point",
+        |_, _, room| async move {
+            room.send(i18n::notice(&room, "context-cleared", &[]).await)
+                .await?;
+            Ok(())
+        },
+    );
+
+    register_parsed_command(
+        "checkpoint",
+        Signature::new()
+            .subcommand("save", Signature::new().required("name"))
+            .subcommand("restore", Signature::new().required("name"))
+            .subcommand("delete", Signature::new().required("name"))
+            .with_usage("[save|restore|delete <name>]"),
+        "Save a point in the conversation to rewind the context to later, or list the checkpoints",
+        checkpoints::checkpoint,
+    );
+
+    register_parsed_command(
+        "prune",
+        Signature::new()
+            .required("limit")
+            .with_usage("<N|duration>"),
+        "Ignore all but the last N messages, or messages older than the duration",
+        prune,
+    );
+
+    register_parsed_command(
+        "continue",
+        Signature::new(),
+        "Continue a response that was truncated",
+        continue_response,
+    );
+
+    register_parsed_command(
+        "context",
+        Signature::new()
+            .optional("tokens")
+            .subcommand("ttl", Signature::new().required("duration"))
+            .with_usage("[<tokens>|none|ttl <duration|none>]"),
+        "Show the context size, or set the token limit or expiry for this room",
+        set_context_limit,
+    );
+
+    register_parsed_command(
+        "tokens",
+        Signature::new(),
+        "Estimate the tokens the context of this room uses with the current model",
+        count_tokens,
+    );
+
+    register_parsed_command(
+        "listen",
+        Signature::new()
+            .subcommand("on", Signature::new())
+            .subcommand("off", Signature::new())
+            .subcommand("topics", Signature::new().optional_rest("topics"))
+            .with_usage("[on|off|topics <topic>, ...]"),
+        "Chime in on conversations without being addressed",
+        listen,
+    );
+
+    register_admin_command(
+        "eval",
+        Signature::new().optional("suite"),
+        "run an evaluation suite against its models",
+        run_eval,
+    );
+
+    register_parsed_command(
+        "name",
+        Signature::new()
+            .optional_rest("name")
+            .with_usage("[<name>|none]"),
+        "Set what chaz calls you in this room, or show the known names",
+        set_name,
+    );
+
+    register_admin_command(
+        "admin",
+        Signature::new()
+            .subcommand("leave", Signature::new().required("room"))
+            .subcommand("say", Signature::new().required("room").rest("text"))
+            .subcommand("block", Signature::new().required("user"))
+            .subcommand("unblock", Signature::new().required("user"))
+            .subcommand("quota", Signature::new().required("user").required("quota"))
+            .subcommand("queue", Signature::new())
+            .subcommand("reload", Signature::new())
+            .subcommand("migrate-tags", Signature::new())
+            .with_usage(ADMIN_USAGE),
+        "manage the rooms, users, and config of the bot",
+        admin,
+    );
+
+    register_parsed_command(
+        "imagine",
+        Signature::new().rest("prompt"),
+        "Generate an image from the prompt",
+        imagine,
+    );
+
+    register_admin_command(
+        "verify",
+        Signature::new()
+            .subcommand("confirm", Signature::new())
+            .subcommand("cancel", Signature::new()),
+        "show the encryption status or answer a device verification",
+        verify,
+    );
+
+    register_parsed_command(
+        "set",
+        Signature::new()
+            .optional("parameter")
+            .optional("value")
+            .with_usage("[<parameter> <value|none>]"),
+        "Show or set the generation parameters for this room, e.g. temperature",
+        set_param,
+    );
+
+    register_parsed_command(
+        "language",
+        Signature::new()
+            .optional("code")
+            .with_usage("[<code>|none]"),
+        "Show or set the language of this room, used to pick translated roles and notices",
+        set_language,
+    );
+
+    register_parsed_command(
+        "format",
+        Signature::new()
+            .optional("format")
+            .with_usage("[markdown|plain|notice|none]"),
+        "Show or set how responses are sent in this room, for clients that render markdown badly",
+        set_format,
+    );
+
+    register_parsed_command(
+        "accessible",
+        Signature::new()
+            .subcommand("on", Signature::new().optional("scope"))
+            .subcommand("off", Signature::new().optional("scope"))
+            .with_usage("[on|off] [room]"),
+        "Format responses for screen readers, for you or the whole room",
+        accessible,
+    );
+
+    register_parsed_command(
+        "alias",
+        Signature::new()
+            .optional("alias")
+            .optional_rest("command")
+            .subcommand("remove", Signature::new().required("alias")),
+        "List the aliases in this room, or add a shortcut for a command, e.g. !chaz alias !sum !chaz summarize",
+        aliases::alias,
+    );
+
+    register_parsed_command(
+        "prefs",
+        Signature::new()
+            .subcommand("set", Signature::new().required("key").rest("value"))
+            .subcommand("unset", Signature::new().required("key")),
+        "Set your defaults in every room: model, language, length (short|medium|long), or format (markdown|plain)",
+        preferences::prefs,
+    );
+
+    register_parsed_command(
+        "workspace",
+        Signature::new().optional("name"),
+        "Switch to a separate conversation in this room, or list the workspaces",
+        switch_workspace,
+    );
+
+    register_parsed_command(
+        "debug",
+        Signature::new()
+            .subcommand("on", Signature::new())
+            .subcommand("off", Signature::new()),
+        "Post the request sent to the backend before each response, room admins can turn it on",
+        debug::debug,
+    );
+
+    register_parsed_command(
+        "status",
+        Signature::new(),
+        "Show the backend, model, role, context size, and quotas used for you in this room",
+        show_status,
+    );
+
+    register_parsed_command(
+        "usage",
+        Signature::new().optional("user"),
+        "Show your usage and quotas, admins can see other users",
+        show_usage,
+    );
+
+    register_parsed_command(
+        "cost",
+        Signature::new().optional("user"),
+        "Show what you and this room have spent, admins can see other users",
+        show_cost,
+    );
+
+    register_parsed_command(
+        "rename",
+        Signature::new(),
+        "Rename the room and set the topic based on the chat content",
+        rename,
+    );
+
+    register_parsed_command(
+        "snippet",
+        Signature::new()
+            .subcommand("save", Signature::new().required("name").optional_rest("text"))
+            .subcommand("use", Signature::new().required("name").optional_rest("text"))
+            .subcommand("delete", Signature::new().required("name"))
+            .with_usage("[save|use|delete <name>]"),
+        "Save the message you reply to as a snippet for the room, use a snippet as a prompt, or list them",
+        snippets::snippet,
+    );
+
+    register_handlers(&bot);
+
+    // Commands added by the project embedding chaz
+    for command in commands {
+        let handler = command.handler;
+        register_parsed_command(
+            &command.name,
+            command.signature,
+            &command.help,
+            move |sender, args, room| handler(sender, args, room),
+        );
+    }
+
+    // The other accounts share the commands, so start them once those are registered
+    // Headjack's login can't be sent between threads, so they run alongside the main sync instead of in tasks
+    let state_dir = bot.state_dir();
+    let other_accounts = futures_util::future::join_all(
+        config
+            .accounts
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|account| accounts::run(account, &state_dir, sync_filter.clone())),
+    );
+
+    // Run the bot, this only returns on error or when shutting down
+    let sync = async {
+        let (result, _) = tokio::join!(
+            failover::sync(
+                bot.client(),
+                &session_file,
+                sync_filter,
+                &endpoints,
+                sync_failure_limit
+            ),
+            other_accounts
+        );
+        result
+    };
+    tokio::pin!(sync);
+    let result = tokio::select! {
+        result = &mut sync => result,
+        () = shutdown::wait_for_signal() => {
+            let timeout = get_config()
+                .shutdown_timeout
+                .and_then(|timeout| parse_duration(&timeout))
+                .unwrap_or(Duration::from_secs(30));
+            info!(
+                "Shutting down, waiting up to {}s for {} messages",
+                timeout.as_secs(),
+                shutdown::in_flight()
+            );
+            notifications::notify(bot.client(), "chaz is going offline").await;
+            // The handlers run inside the sync, so keep it going until they're done
+            tokio::select! {
+                result = &mut sync => result,
+                finished = shutdown::drain(timeout) => {
+                    if !finished {
+                        warn!("Shutting down with {} messages unanswered", shutdown::in_flight());
+                    }
+                    Ok(())
+                }
+            }
+        }
+    };
+    if let Err(e) = sync::flush(bot.client()).await {
+        error!("Unable to save the sync token: {e}");
+    }
+    // The sync only stops with an error once it's switched to the next endpoint
+    if let Err(e) = result {
+        error!("Error running bot: {e}");
+        return Err(failover::restart());
+    }
+
+    Ok(())
+}
+
+/// Respond to a message that isn't a command
+async fn handle_message(
+    sender: OwnedUserId,
+    body: String,
+    room: Room,
+    event: OriginalSyncRoomMessageEvent,
+) -> Result<(), ChazError> {
+    if !is_allowed(&sender)
+        || admin::is_blocked(sender.as_str())
+        || !permissions::room_allows(&room, &sender).await
+    {
+        return Ok(());
+    }
+    // Aliases stand for commands, which the dispatcher doesn't recognize in their short form
+    if let Cow::Owned(expanded) = aliases::load(&room).await.expand(&body) {
+        // Errors are reported by the command itself
+        let _ = dispatch_command(sender, expanded, room).await;
+        return Ok(());
+    }
+    // The no-context prefix is a shorthand for `!chaz send`, so it works in any room
+    if let Some(input) = strip_no_context_prefix(&body) {
+        activity::mark_read(&room, &event.event_id).await;
+        metrics::record_message();
+        return send_standalone(&room, &sender, input).await;
+    }
+
+    // If this room is not marked as a direct message, ignore messages
+    let is_direct = is_direct(&room).await;
+
+    // If the message is not a command, check if it mentions the bot
+    let mentions_bot = event
+        .content
+        .mentions
+        .as_ref()
+        .map(|mentions| {
+            mentions
+                .user_ids
+                .iter()
+                .any(|mention| room.client().user_id() == Some(mention.as_ref()))
+        })
+        .unwrap_or(false);
+
+    // An edited prompt regenerates the response to it
+    if let Some(Relation::Replacement(replacement)) = &event.content.relates_to {
+        return edit_response(&room, &sender, &replacement.event_id).await;
+    }
+
+    // In listening rooms, messages that pass a cheap filter go to the classifier to decide whether to chime in
+    let topics = if is_direct || body.starts_with("!chaz") || mentions_bot {
+        None
+    } else {
+        match listening_topics(&room).await {
+            Some(topics) if might_interject(&room, &body, &topics) => Some(topics),
+            _ => return Ok(()),
+        }
+    };
+    // If it's not a command, we should send the full context without commands to the server
+    let (context, expired) = build_context(&room, None, None, true).await?;
+    if let Some(topics) = topics {
+        if !should_interject(&room, &context, &topics).await {
+            return Ok(());
+        }
+    }
+
+    activity::mark_read(&room, &event.event_id).await;
+    metrics::record_message();
+    if rate_limit(&room, &sender).await || moderate_message(&room, &sender, &body).await? {
+        return Ok(());
+    }
+    if expired {
+        // The start of the notice marks where the context was cut off, so only the rest is translated
+        room.send(RoomMessageEventContent::notice_plain(format!(
+            "{} {}",
+            CONTEXT_EXPIRED_NOTICE,
+            i18n::tr(&room, "context-expired", &[]).await
+        )))
+        .await?;
+    }
+    respond(&room, &sender, context, Some(&event.event_id)).await
+}
+
+/// Answer a message on its own, ignoring the room history
+async fn send_standalone(room: &Room, sender: &OwnedUserId, input: &str) -> Result<(), ChazError> {
+    if rate_limit(room, sender).await || moderate_message(room, sender, input).await? {
+        return Ok(());
+    }
+    // But we do need to read the context to figure out the model to use
+    let context = get_context(room).await?;
+    let mut no_context = ChatContext {
+        messages: vec![Message::new(MessageRole::user, input.to_string())],
+        model: context.model,
+        role: context.role,
+        media: Vec::new(),
+        tools: Vec::new(),
+        schema: None,
+        params: GenerationParams::default(),
+        reported_cost: Default::default(),
+    };
+    router::route(room, &mut no_context).await;
+    logging::record_model(no_context.model.as_deref());
+    knowledge::augment_context(&mut no_context).await;
+
+    info!(
+        "Request: {} - {}",
+        sender.as_str(),
+        input.replace('\n', " ")
+    );
+    let backend = get_backend(room, Some(sender)).await;
+    capabilities::apply(room, &backend, &mut no_context).await;
+    debug::post_request(room, &backend, &no_context).await;
+    let started = Instant::now();
+    let result = activity::while_typing(
+        room,
+        backend.execute_with_deadline(&no_context, get_response_deadline()),
+    )
+    .await;
+    record_tokens(
+        room.room_id(),
+        sender,
+        &backend,
+        &no_context,
+        &result,
+        started,
+    );
+    if let Ok(result) = result {
+        info!(
+            "Response: {} - {}",
+            sender.as_str(),
+            result.replace('\n', " ")
+        );
+        match moderation::check_response(room, &result).await {
+            Some(moderation::Action::Block) => return Ok(()),
+            Some(moderation::Action::Redact) => {
+                room.send(i18n::notice(room, "moderated-response", &[]).await)
+                    .await?;
+            }
+            _ => {
+                for part in chunking::split(&result, chunking::max_length()) {
+                    send_out_of_context(room, &part).await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Send a notice that isn't part of the conversation
+///
+/// Notices from chaz are added to the context as responses, so these are marked to be left out.
+async fn send_out_of_context(room: &Room, text: &str) -> Result<(), ChazError> {
+    room.send_raw(
+        "m.room.message",
+        serde_json::json!({
+            "msgtype": "m.notice",
+            "body": text,
+            OUT_OF_CONTEXT_FIELD: true,
+        }),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Check a message with the moderation rules, returning true if it shouldn't be answered
+async fn moderate_message(room: &Room, sender: &UserId, body: &str) -> Result<bool, ChazError> {
+    match moderation::check_message(room, sender, body).await {
+        Some(moderation::Action::Block) => Ok(true),
+        Some(moderation::Action::Redact) => {
+            room.send(i18n::notice(room, "moderated-message", &[]).await)
+                .await?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Get the message without the no-context prefix, if it has one
+fn strip_no_context_prefix(body: &str) -> Option<&str> {
+    let prefix = get_config().no_context_prefix?;
+    if prefix.is_empty() {
+        return None;
+    }
+    body.strip_prefix(prefix.as_str()).map(str::trim)
+}
+
+/// Regenerate the response to a prompt that was edited
+///
+/// The previous response is edited in place. Prompts that chaz never responded to are ignored.
+async fn edit_response(
+    room: &Room,
+    sender: &OwnedUserId,
+    prompt: &EventId,
+) -> Result<(), ChazError> {
+    if responses::get(prompt).is_none() || rate_limit(room, sender).await {
+        return Ok(());
+    }
+    let context = get_context_at(room, Some(prompt)).await?;
+    respond(room, sender, context, Some(prompt)).await
+}
+
+/// Register a command, and add it to the help
+///
+/// Commands are run by the one event handler installed with `register_dispatcher`.
+fn register_command<F, Fut, OptString>(
+    command: &str,
+    args: OptString,
+    short_help: OptString,
+    callback: F,
+) where
+    F: FnOnce(OwnedUserId, String, Room) -> Fut + Send + 'static + Clone + Sync,
+    Fut: std::future::Future<Output = Result<(), ChazError>> + Send + 'static,
+    OptString: Into<Option<String>>,
+{
+    let args = args.into();
+    let short_help = short_help.into();
+    let mut usage = format!("`!chaz {}", command);
+    if let Some(args) = &args {
+        usage.push_str(&format!(" {}", args));
+    }
+    usage.push('`');
+    GLOBAL_HELP
+        .lock()
+        .unwrap()
+        .push((command.to_string(), usage, short_help));
+    let name = command.to_string();
+    let handler: CommandHandler = Arc::new(move |sender, text, room| {
+        let callback = callback.clone();
+        let name = name.clone();
+        Box::pin(async move {
+            if let Some(level) = permissions::missing_level(&room, &sender, &name).await {
+                room.send(
+                    i18n::notice(
+                        &room,
+                        "permission-level",
+                        &[("command", &name), ("level", &level.to_string())],
+                    )
+                    .await,
+                )
+                .await?;
+                return Ok(());
+            }
+            callback(sender, text, room).await
+        })
+    });
+    COMMAND_HANDLERS
+        .lock()
+        .unwrap()
+        .insert(command.to_string(), handler);
+}
+
+/// Run the handler of a command, if the sender is allowed to
+///
+/// Every command goes through here, so this is where the checks, logging, and metrics common to all of them go.
+async fn run_command(
+    name: &str,
+    handler: &CommandHandler,
+    sender: OwnedUserId,
+    text: String,
+    room: Room,
+) -> Result<(), ()> {
+    if !is_allowed(&sender)
+        || admin::is_blocked(sender.as_str())
+        || !permissions::room_allows(&room, &sender).await
+    {
+        return Ok(());
+    }
+    let _in_flight = shutdown::track();
+    metrics::record_command(name);
+    let client = room.client();
+    let span = logging::request_span(&room, &sender);
+    if let Err(e) = handler(sender, text, room).instrument(span).await {
+        error::report(&client, &format!("!chaz {}", name), &e).await;
+        return Err(());
+    }
+    Ok(())
+}
+
+/// Install the event handlers of an account
+fn register_handlers(bot: &Bot) {
+    // The text handler is called for every non-command message
+    // It is also called if _only_ `!chaz` is sent. That sounds like a feature to me.
+    bot.register_text_handler(on_message);
+
+    // Keep track of the display names of the members
+    bot.client().add_event_handler(names::on_member_event);
+
+    // When a prompt is redacted, redact the response to it as well
+    bot.client().add_event_handler(
+        |event: OriginalSyncRoomRedactionEvent, room: Room| async move {
+            let Some(redacts) = event.redacts.or(event.content.redacts) else {
+                return;
+            };
+            if let Some(response) = responses::remove(&redacts) {
+                if let Err(e) = room
+                    .redact(&response, Some("The prompt was redacted"), None)
+                    .await
+                {
+                    error!("Unable to redact response {}: {}", response, e);
+                }
+            }
+        },
+    );
+
+    // Regenerate responses and record feedback with reactions
+    bot.client().add_event_handler(reactions::on_reaction);
+
+    // Answer disambiguated requests once the poll is voted on
+    bot.client().add_event_handler(polls::on_response);
+
+    register_dispatcher(bot.client());
+}
+
+/// Queue a message that isn't a command, handling it in a request span and reporting any error
+async fn on_message(
+    sender: OwnedUserId,
+    body: String,
+    room: Room,
+    event: OriginalSyncRoomMessageEvent,
+) -> Result<(), ()> {
+    if shutdown::is_draining() || accounts::is_own_user(&sender) {
+        return Ok(());
+    }
+    // Queued messages are waited for on shutdown too
+    let in_flight = shutdown::track();
+    let room_id = room.room_id().to_owned();
+    concurrency::spawn(&room_id, async move {
+        let _in_flight = in_flight;
+        let client = room.client();
+        let span = logging::request_span(&room, &sender);
+        if let Err(e) = handle_message(sender, body, room, event)
+            .instrument(span)
+            .await
+        {
+            error::report(&client, "a message", &e).await;
+        }
+    });
+    Ok(())
+}
+
+/// Install the one event handler that runs all the commands
+///
+/// A message that starts with `!chaz ` runs the command it names. If it doesn't name one, a misspelled command
+/// gets a suggestion and anything else is a request, like `!chaz explain that to me`.
+fn register_dispatcher(client: &Client) {
+    client.add_event_handler(
+        |event: OriginalSyncRoomMessageEvent, room: Room| async move {
+            if room.state() != RoomState::Joined || accounts::is_own_user(&event.sender) {
+                return;
+            }
+            let MessageType::Text(text) = &event.content.msgtype else {
+                return;
+            };
+            let body = text.body.trim_start().to_string();
+            // Only `!chaz` on its own is left to the text handler
+            let Some(name) = command_name(&body) else {
+                return;
+            };
+            let sender = event.sender.clone();
+            if is_chaz_command(&name) {
+                if shutdown::is_draining() {
+                    return;
+                }
+                // Commands are queued with the messages, so they apply in the order they were sent
+                let in_flight = shutdown::track();
+                let room_id = room.room_id().to_owned();
+                concurrency::spawn(&room_id, async move {
+                    let _in_flight = in_flight;
+                    let _ = dispatch_command(sender, body, room).await;
+                });
+            } else if let Some(suggestion) = suggest_command(&name) {
+                if !is_allowed(&sender)
+                    || admin::is_blocked(sender.as_str())
+                    || !permissions::room_allows(&room, &sender).await
+                {
+                    return;
+                }
+                let notice = i18n::notice(
+                    &room,
+                    "unknown-command",
+                    &[
+                        ("command", &name),
+                        ("suggestion", &format!("!chaz {}", suggestion)),
+                    ],
+                )
+                .await;
+                if let Err(e) = room.send(notice).await {
+                    error!("Unable to suggest a command: {}", e);
+                }
+            } else {
+                let _ = on_message(sender, body, room, event).await;
+            }
+        },
+    );
+}
+
+/// Get the lowercase name of the command in a message, if it starts with `!chaz `
+fn command_name(body: &str) -> Option<String> {
+    body.strip_prefix("!chaz ")?
+        .split_whitespace()
+        .next()
+        .map(|name| name.to_lowercase())
+}
+
+/// Suggest the command a misspelled name was probably meant to be
+fn suggest_command(name: &str) -> Option<String> {
+    let handlers = COMMAND_HANDLERS.lock().unwrap();
+    let names = handlers
+        .keys()
+        .map(String::as_str)
+        .chain(std::iter::once("help"));
+    commands::suggest(name, names).map(str::to_string)
+}
+
+/// Run the command named in a message
+async fn dispatch_command(sender: OwnedUserId, text: String, room: Room) -> Result<(), ()> {
+    let Some(name) = text
+        .strip_prefix("!chaz")
+        .and_then(|command| command.split_whitespace().next())
+        .map(|name| name.to_lowercase())
+    else {
+        return Ok(());
+    };
+    if name == "help" {
+        return help(sender, text, room).await;
+    }
+    let handler = COMMAND_HANDLERS.lock().unwrap().get(&name).cloned();
+    match handler {
+        Some(handler) => run_command(&name, &handler, sender, text, room).await,
+        None => Ok(()),
+    }
+}
+
+/// Register a command that declares its arguments
+///
+/// The message is parsed before the command runs, and the usage is posted if the arguments don't match.
+fn register_parsed_command<F, Fut>(
+    command: &str,
+    signature: Signature,
+    short_help: &str,
+    callback: F,
+) where
+    F: FnOnce(OwnedUserId, Args, Room) -> Fut + Send + 'static + Clone + Sync,
+    Fut: std::future::Future<Output = Result<(), ChazError>> + Send + 'static,
+{
+    let name = command.to_string();
+    let usage = signature.usage();
+    register_command(
+        command,
+        usage.clone(),
+        short_help.to_string(),
+        |sender, text, room| async move {
+            match signature.parse(&text) {
+                Ok(args) => callback(sender, args, room).await,
+                Err(e) => {
+                    let args = [("error", e.as_str()), ("command", &name), ("usage", &usage)];
+                    room.send(i18n::notice(&room, "invalid-arguments", &args).await)
+                        .await?;
+                    Ok(())
+                }
+            }
+        },
+    );
+}
+
+/// Register a command that only admins can run
+fn register_admin_command<F, Fut>(
+    command: &str,
+    signature: Signature,
+    short_help: &str,
+    callback: F,
+) where
+    F: FnOnce(OwnedUserId, Args, Room) -> Fut + Send + 'static + Clone + Sync,
+    Fut: std::future::Future<Output = Result<(), ChazError>> + Send + 'static,
+{
+    let name = command.to_string();
+    register_parsed_command(
+        command,
+        signature,
+        &format!("Admin only, {}", short_help),
+        |sender, args, room| async move {
+            if !is_admin(&sender) {
+                room.send(i18n::notice(&room, "admin-only", &[("command", &name)]).await)
+                    .await?;
+                return Ok(());
+            }
+            callback(sender, args, room).await
+        },
+    );
+}
+
+/// Print the help for all the registered commands
+async fn help(sender: OwnedUserId, _: String, room: Room) -> Result<(), ()> {
+    if !is_allowed(&sender) {
+        return Ok(());
+    }
+    let language = language::get(&room).await;
+    let mut response = format!(
+        "`!chaz help`\n\n{}",
+        i18n::translate(language.as_deref(), "help-header", &[])
+    );
+    for (command, usage, description) in GLOBAL_HELP.lock().unwrap().iter() {
+        // Translations of the command descriptions are optional, the registered one is English
+        let description = language
+            .as_deref()
+            .and_then(|language| i18n::lookup(language, &format!("help-{}", command)))
+            .or(description.clone());
+        match description {
+            Some(description) => response.push_str(&format!("\n{} - {}", usage, description)),
+            None => response.push_str(&format!("\n{}", usage)),
+        }
+    }
+    response.push_str(&format!(
+        "\n`!chaz help` - {}",
+        i18n::translate(language.as_deref(), "help-help", &[])
+    ));
+    room.send(RoomMessageEventContent::text_markdown(response))
+        .await
+        .map_err(|_| ())?;
+    Ok(())
+}
+
+/// Send the context to the backend and post the response to the room
+///
+/// If chaz already responded to the prompt, that response is edited instead.
+pub async fn respond(
+    room: &Room,
+    sender: &OwnedUserId,
+    context: ChatContext,
+    prompt: Option<&EventId>,
+) -> Result<(), ChazError> {
+    let (result, style) = generate(room, sender, context).await;
+    // Queue questions while the backend is down, instead of posting the error
+    if let (Err(e), Some(prompt)) = (&result, prompt) {
+        if let Some(waiting) = queue::is_unavailable(e)
+            .then(|| queue::push(room.room_id().to_owned(), sender.clone(), prompt.to_owned()))
+            .flatten()
+        {
+            error!("Queueing a question after a backend error: {}", e);
+            room.send(i18n::notice(room, "queued", &[("waiting", &waiting.to_string())]).await)
+                .await?;
+            return Ok(());
+        }
+    }
+    post_response(room, sender, result, style, prompt).await
+}
+
+/// How a response is formatted for the user that prompted it
+#[derive(Clone, Copy)]
+struct ResponseStyle {
+    /// Accessibility mode is on
+    accessible: bool,
+    /// How the response is sent, from the user's preferences or the room
+    format: format::Format,
+}
+
+/// Run the context through the backend
+///
+/// Also returns the style the response has to be formatted in.
+async fn generate(
+    room: &Room,
+    sender: &OwnedUserId,
+    mut context: ChatContext,
+) -> (Result<String, String>, ResponseStyle) {
+    let preferences = preferences::get(&room.client(), sender).await;
+    preferences::apply(room, &preferences, &mut context).await;
+    let format = match preferences.format {
+        Some(format) => format,
+        None => format::get(room).await,
+    };
+    if format != format::Format::Markdown {
+        let instructions = "Respond in plain text, without markdown.";
+        match context.role.as_mut() {
+            Some(role) => role.append_prompt(instructions),
+            None => {
+                context.role = Some(RoleDetails::new(
+                    "format",
+                    None,
+                    Some(instructions.to_string()),
+                    None,
+                ))
+            }
+        }
+    }
+    // Polls are answered by the user, so chaz can't ask itself
+    if get_config().disambiguation_polls.unwrap_or(false) && room.client().user_id() != Some(sender)
+    {
+        match context.role.as_mut() {
+            Some(role) => role.append_prompt(polls::INSTRUCTIONS),
+            None => {
+                context.role = Some(RoleDetails::new(
+                    "polls",
+                    None,
+                    Some(polls::INSTRUCTIONS.to_string()),
+                    None,
+                ))
+            }
+        }
+    }
+    router::route(room, &mut context).await;
+    logging::record_model(context.model.as_deref());
+    knowledge::augment_context(&mut context).await;
+    documents::augment_context(room, &mut context).await;
+    context.tools = get_config().tools.unwrap_or_default();
+    if mqtt::is_enabled(room) {
+        context
+            .tools
+            .extend([ToolName::HomeState, ToolName::HomeControl]);
+    }
+    let accessible = accessibility::is_enabled(room, sender).await;
+    if accessible {
+        match context.role.as_mut() {
+            Some(role) => role.append_prompt(accessibility::PROMPT),
+            None => {
+                context.role = Some(RoleDetails::new(
+                    "accessibility",
+                    None,
+                    Some(accessibility::PROMPT.to_string()),
+                    None,
+                ))
+            }
+        }
+    }
+    let backend = get_backend(room, Some(sender)).await;
+    capabilities::apply(room, &backend, &mut context).await;
+    debug::post_request(room, &backend, &context).await;
+    let started = Instant::now();
+    let result = activity::while_typing(
+        room,
+        backend.execute_with_deadline(&context, get_response_deadline()),
+    )
+    .await;
+    record_tokens(room.room_id(), sender, &backend, &context, &result, started);
+    let result = match &context.role {
+        Some(role) => result.map(|response| role.postprocess(response)),
+        None => result,
+    };
+    let config = get_config();
+    if config.status_banner.unwrap_or(false) {
+        if let Some(name) = backend.backend_name(&context) {
+            status::record_result(
+                &room.client(),
+                &name,
+                &result,
+                config.degraded_threshold.unwrap_or(3),
+            )
+            .await;
+        }
+    }
+    let style = ResponseStyle { accessible, format };
+    (result, style)
+}
+
+/// Post the response from the backend, or the error
+///
+/// If the response answers a prompt, any previous response to it is edited instead.
+async fn post_response(
+    room: &Room,
+    sender: &UserId,
+    result: Result<String, String>,
+    style: ResponseStyle,
+    prompt: Option<&EventId>,
+) -> Result<(), ChazError> {
+    let stdout = match result {
+        Ok(stdout) => stdout,
+        Err(stderr) => {
+            error!("Command failed: {}", stderr.replace('\n', " "));
+            let content =
+                i18n::notice(room, "error", &[("message", &stderr.replace('\n', " "))]).await;
+            return send_response(room, content, prompt).await;
+        }
+    };
+    info!("Response: {}", stdout.replace('\n', " "));
+    match moderation::check_response(room, &stdout).await {
+        Some(moderation::Action::Block) => return Ok(()),
+        Some(moderation::Action::Redact) => {
+            let content = i18n::notice(room, "moderated-response", &[]).await;
+            return send_response(room, content, prompt).await;
+        }
+        _ => {}
+    }
+    if get_config().disambiguation_polls.unwrap_or(false) {
+        if let Some((question, options)) = polls::requested(&stdout) {
+            return polls::ask(room, &sender.to_owned(), question, options).await;
+        }
+    }
+    let stdout = if style.accessible {
+        accessibility::format_response(&stdout)
+    } else {
+        stdout
+    };
+    // Long responses are posted in parts, only the first one replaces a previous response
+    let mut parts = chunking::split(&stdout, chunking::max_length()).into_iter();
+    if let Some(first) = parts.next() {
+        let mut content = style.format.content(first);
+        // Webhook prompts are answered as chaz itself
+        if get_config().mention_sender.unwrap_or(true)
+            && !is_direct(room).await
+            && room.client().user_id() != Some(sender)
+        {
+            mention(room, sender, &mut content).await;
+        }
+        send_response(room, content, prompt).await?;
+    }
+    for part in parts {
+        room.send(style.format.content(part)).await?;
+    }
+    Ok(())
+}
+
+/// Post a response, replacing the previous response to the prompt if there is one
+async fn send_response(
+    room: &Room,
+    mut content: RoomMessageEventContent,
+    prompt: Option<&EventId>,
+) -> Result<(), ChazError> {
+    match prompt {
+        Some(prompt) => match responses::get(prompt) {
+            Some(previous) => {
+                room.send(content.make_replacement(ReplacementMetadata::new(previous, None), None))
+                    .await?;
+            }
+            None => {
+                if replies_to_prompt(room).await {
+                    content.relates_to = Some(Relation::Reply {
+                        in_reply_to: InReplyTo::new(prompt.to_owned()),
+                    });
+                }
+                let response = room.send(content).await?;
+                responses::record(prompt, &response.event_id);
+            }
+        },
+        None => {
+            room.send(content).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Address a response to the user it's for, so they're notified when it arrives
+///
+/// The display name is added to the text for clients that don't support `m.mentions`.
+async fn mention(room: &Room, user: &UserId, content: &mut RoomMessageEventContent) {
+    let name = get_display_name(room, user.as_str(), &mut HashMap::new()).await;
+    let (body, formatted) = match &mut content.msgtype {
+        MessageType::Text(text) => (&mut text.body, &mut text.formatted),
+        MessageType::Notice(notice) => (&mut notice.body, &mut notice.formatted),
+        _ => return,
+    };
+    *body = format!("{}: {}", name, body);
+    if let Some(formatted) = formatted {
+        let name = name
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;");
+        formatted.body = format!(
+            "<a href=\"{}\">{}</a>: {}",
+            user.matrix_to_uri(),
+            name,
+            formatted.body
+        );
+    }
+    content.mentions = Some(Mentions::with_user_ids([user.to_owned()]));
+}
+
+/// Remove the name a response was addressed to, so it isn't part of the context
+async fn strip_mention<'a>(
+    room: &Room,
+    content: &RoomMessageEventContent,
+    body: &'a str,
+    display_names: &mut HashMap<String, String>,
+) -> &'a str {
+    let Some(user) = content
+        .mentions
+        .as_ref()
+        .and_then(|mentions| mentions.user_ids.first())
+    else {
+        return body;
+    };
+    let name = get_display_name(room, user.as_str(), display_names).await;
+    body.strip_prefix(&format!("{}: ", name)).unwrap_or(body)
+}
+
+/// Check if the room is a direct message
+///
+/// Direct message detection/conversion may be buggy? Recognize a direct message by either the room setting _or_ number
+/// of members
+async fn is_direct(room: &Room) -> bool {
+    room.is_direct().await.unwrap_or(false) || room.joined_members_count() < 3
+}
+
+/// Check if responses in the room are sent as replies to the message that prompted them
+async fn replies_to_prompt(room: &Room) -> bool {
+    match get_config().rich_replies.unwrap_or_default() {
+        RichReplies::Always => true,
+        RichReplies::Groups => !is_direct(room).await,
+        RichReplies::Never => false,
+    }
+}
+
+/// Continue a response that was cut off by the response deadline
+async fn continue_response(sender: OwnedUserId, _: Args, room: Room) -> Result<(), ChazError> {
+    if rate_limit(&room, &sender).await {
+        return Ok(());
+    }
+    let mut context = get_context(&room).await?;
+    context.messages.push(Message::new(
+        MessageRole::user,
+        "Continue your previous response from exactly where it was cut off.",
+    ));
+    respond(&room, &sender, context, None).await
+}
+
+/// Number of recent messages shown to the interjection classifier
+const INTERJECTION_WINDOW: usize = 10;
+
+/// Get the topics chaz listens for in the room, or None if listening isn't enabled
+async fn listening_topics(room: &Room) -> Option<Vec<String>> {
+    let tags = Tags::new(room, "is.chaz.listen").await;
+    if tags.get_value("enabled").as_deref() != Some("true") {
+        return None;
+    }
+    let mut topics = get_config().interjection_topics.unwrap_or_default();
+    if let Some(room_topics) = tags.get_value("topics") {
+        topics.extend(room_topics.split(',').map(|t| t.trim().to_string()));
+    }
+    Some(topics)
+}
+
+/// Check if a message could be worth chiming in on, before asking the classifier
+///
+/// Only messages that name chaz, ask a question, or mention one of the topics pass, so most of the conversation
+/// never costs a classifier call.
+fn might_interject(room: &Room, body: &str, topics: &[String]) -> bool {
+    let body = body.to_lowercase();
+    let mut names = vec!["chaz".to_string()];
+    if let Some(user_id) = room.client().user_id() {
+        names.push(user_id.localpart().to_lowercase());
+    }
+    body.contains('?')
+        || names.iter().any(|name| body.contains(name.as_str()))
+        || topics.iter().any(|topic| {
+            let topic = topic.to_lowercase();
+            // A topic can be a phrase, so any of its longer words counts too
+            body.contains(&topic)
+                || topic
+                    .split_whitespace()
+                    .any(|word| word.len() > 3 && body.contains(word))
+        })
+}
+
+/// Decide whether chaz should respond to a message it wasn't addressed in
+///
+/// A cheap classifier pass over the recent messages of the context decides whether chaz was addressed indirectly
+/// or one of the topics came up.
+async fn should_interject(room: &Room, context: &ChatContext, topics: &[String]) -> bool {
+    let config = get_config();
+    // Only the recent conversation matters, and the classifier shouldn't see media or the persona
+    let skip = context.messages.len().saturating_sub(INTERJECTION_WINDOW);
+    let mut context = ChatContext {
+        messages: context.messages[skip..]
+            .iter()
+            .filter(|m| !m.attached_media)
+            .map(|m| Message::new(m.role.clone(), m.content.clone()).with_sender(m.sender.clone()))
+            .collect(),
+        model: config
+            .interjection_model
+            .clone()
+            .or(config.chat_summary_model.clone()),
+        media: Vec::new(),
+        tools: Vec::new(),
+        schema: None,
+        params: GenerationParams::default(),
+        reported_cost: Default::default(),
+        role: None,
+    };
+
+    let mut instructions = vec![
+        "You are deciding whether an AI assistant named chaz should join this group conversation.".to_string(),
+        "Answer YES if the last message is addressed to chaz or asks something an assistant should answer.".to_string(),
+    ];
+    if !topics.is_empty() {
+        instructions.push(format!(
+            "Also answer YES if the last message is about any of these topics: {}.",
+            topics.join(", ")
+        ));
+    }
+    instructions.push("Otherwise answer NO. Do not output anything except YES or NO.".to_string());
+    context
+        .messages
+        .push(Message::new(MessageRole::user, instructions.join(" ")));
+
+    match get_backend(room, None).await.execute(&context).await {
+        Ok(decision) => {
+            info!("Interjection decision: {}", decision.trim());
+            decision.trim().to_uppercase().starts_with("YES")
+        }
+        Err(e) => {
+            error!("Interjection classifier failed: {}", e);
+            false
+        }
+    }
+}
+
+/// Control passive listening in this room
+///
+/// While listening, chaz responds to messages it wasn't addressed in when the classifier decides it should.
+async fn listen(_: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let mut tags = Tags::new(&room, "is.chaz.listen").await;
+    let response = match args.subcommand() {
+        Some("on") => {
+            tags.replace_kv("enabled", "true");
+            tags.sync().await;
+            i18n::notice_text(&room, "listen-enabled", &[]).await
+        }
+        Some("off") => {
+            tags.remove_kv("enabled");
+            tags.sync().await;
+            i18n::notice_text(&room, "listen-disabled", &[]).await
+        }
+        Some(_) => match args.get("topics") {
+            None => {
+                tags.remove_kv("topics");
+                tags.sync().await;
+                i18n::notice_text(&room, "listen-topics-cleared", &[]).await
+            }
+            Some(topics) => {
+                tags.replace_kv("topics", topics);
+                tags.sync().await;
+                i18n::notice_text(&room, "listen-topics", &[("topics", topics)]).await
+            }
+        },
+        None => {
+            let enabled = tags.get_value("enabled").as_deref() == Some("true");
+            let topics = tags.get_value("topics").unwrap_or("none".to_string());
+            let args = [
+                ("state", if enabled { "on" } else { "off" }),
+                ("topics", &topics),
+            ];
+            i18n::notice_text(&room, "listen-status", &args).await
+        }
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// Check the quotas, the cost budget, and the rate limit of a sender, counting the message if it's allowed
+///
+/// Returns the reason if the sender is being limited.
+fn check_limits(sender: &str, room_id: &str, config: Config) -> Result<(), String> {
+    let mut quotas = config.quotas.unwrap_or_default();
+    if let Some(daily_messages) = admin::daily_messages(sender) {
+        quotas.daily_messages = Some(daily_messages);
+    }
+    // The rate limit takes a token when the message is allowed, so it's checked last
+    usage::check(sender, &quotas)
+        .and_then(|()| cost::check(sender, &config.cost.unwrap_or_default()))
+        .and_then(|()| ratelimit::check(sender, room_id, &config.rate_limit.unwrap_or_default()))?;
+    usage::record(sender, 1, 0);
+    Ok(())
+}
+
+/// Rate limit the user to a set number of messages
+/// Returns true if the user is being rate limited
+async fn rate_limit(room: &Room, sender: &OwnedUserId) -> bool {
+    let room_size = room
+        .members(RoomMemberships::ACTIVE)
+        .await
+        .unwrap_or(Vec::new())
+        .len();
+    let config = get_room_config(room).await;
+    // If the room is too big we will silently ignore the message
+    // This is to prevent the bot from spamming large rooms
+    if room_size > config.room_size_limit.unwrap_or(usize::MAX) {
+        return true;
+    }
+    let Err(reason) = check_limits(sender.as_str(), room.room_id().as_str(), config) else {
+        return false;
+    };
+    error!("User {} is rate limited: {}", sender, reason);
+    metrics::record_rate_limited();
+    notifications::notify_later(
+        &room.client(),
+        format!(
+            "{} is rate limited in {}: {}",
+            sender,
+            room.room_id(),
+            reason
+        ),
+    );
+    if let Err(e) = room
+        .send(i18n::notice(room, "rate-limited", &[("reason", &reason)]).await)
+        .await
+    {
+        error!("Unable to send the message limit notice: {}", e);
+    }
+    true
+}
+
+/// Count the estimated tokens of a request and its response, and export it to the event webhooks
+fn record_tokens(
+    room_id: &RoomId,
+    sender: &OwnedUserId,
+    backend: &BackendManager,
+    context: &ChatContext,
+    result: &Result<String, String>,
+    started: Instant,
+) {
+    let input_tokens = context::estimate_context_tokens(context);
+    let output_tokens = result
+        .as_ref()
+        .map_or(0, |response| context::estimate_tokens(response));
+    usage::record(sender.as_str(), 0, (input_tokens + output_tokens) as u64);
+    let reported_cost = context.reported_cost.lock().unwrap().take();
+    let cost = reported_cost.or_else(|| {
+        backend
+            .pricing(context)
+            .map(|pricing| pricing.cost(input_tokens, output_tokens))
+    });
+    if let Some(cost) = cost {
+        cost::record(sender.as_str(), room_id.as_str(), cost);
+    }
+    webhooks::export(webhooks::Exchange {
+        room_id: room_id.to_string(),
+        sender: sender.to_string(),
+        backend: backend.backend_name(context),
+        model: context.model.clone(),
+        latency_ms: started.elapsed().as_millis() as u64,
+        input_tokens,
+        output_tokens,
+        cost,
+        error: result.as_ref().err().cloned(),
+        prompt: context.messages.last().map(|m| m.content.clone()),
+        response: result.as_ref().ok().cloned(),
+    });
+}
+
+/// Show the usage of the sender, or of another user for admins
+async fn show_usage(sender: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let quotas = get_config().quotas.unwrap_or_default();
+    let response = match args.get("user") {
+        Some(_) if !is_admin(&sender) => i18n::notice_text(&room, "usage-admin-only", &[]).await,
+        user => {
+            let user = user.unwrap_or(sender.as_str());
+            let report = usage::report(user, &quotas);
+            i18n::notice_text(&room, "usage", &[("user", user), ("report", &report)]).await
+        }
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// Show what chaz uses to answer the sender in this room, to explain its behavior
+async fn show_status(sender: OwnedUserId, _: Args, room: Room) -> Result<(), ChazError> {
+    let config = get_room_config(&room).await;
+    // The status is only read, so the context isn't truncated, summarized, or compacted
+    let mut context = get_unfitted_context(&room).await?;
+    let preferences = preferences::get(&room.client(), &sender).await;
+    preferences::apply(&room, &preferences, &mut context).await;
+    let backend = get_backend(&room, Some(&sender)).await;
+    let model = match &context.model {
+        Some(model) => Some(model.clone()),
+        None => backend.default_model().await,
+    };
+    let routed = model.as_deref() == Some(router::MODEL);
+    let mut quotas = config.quotas.unwrap_or_default();
+    if let Some(daily_messages) = admin::daily_messages(sender.as_str()) {
+        quotas.daily_messages = Some(daily_messages);
+    }
+    let language = language::get(&room).await;
+    let line = |key: &str, args: &[(&str, &str)]| i18n::translate(language.as_deref(), key, args);
+    let backend_name = backend.backend_name(&context).unwrap_or("none".to_string());
+    let role = context
+        .role
+        .as_ref()
+        .map_or("none".to_string(), |role| role.name.clone());
+    let quotas_left =
+        usage::remaining(sender.as_str(), &quotas).unwrap_or_else(|| line("status-no-quotas", &[]));
+    let media = if config.disable_media_context.unwrap_or(false) {
+        "off"
+    } else {
+        "on"
+    };
+    let mut lines = vec![
+        line("status-backend", &[("backend", &backend_name)]),
+        line(
+            "status-model",
+            &[("model", &model.unwrap_or("default".to_string()))],
+        ),
+        line("status-role", &[("role", &role)]),
+        line(
+            "status-context",
+            &[
+                ("count", &context.messages.len().to_string()),
+                (
+                    "tokens",
+                    &context::estimate_context_tokens(&context).to_string(),
+                ),
+            ],
+        ),
+        line("status-quotas", &[("quotas", &quotas_left)]),
+        line("status-media", &[("state", media)]),
+    ];
+    if routed {
+        let selection =
+            router::last_selection(&room).unwrap_or_else(|| line("status-not-routed", &[]));
+        lines.push(line("status-routed", &[("model", &selection)]));
+    }
+    if let Some(status) = status::current_status() {
+        lines.push(line("status-degraded", &[("status", &status)]));
+    }
+    room.send(i18n::notice(&room, "status", &[("status", &lines.join("\n"))]).await)
+        .await?;
+    Ok(())
+}
+
+/// Show the spend of the sender, or another user for admins, and of the room
+async fn show_cost(sender: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let config = get_config().cost.unwrap_or_default();
+    let response = match args.get("user") {
+        Some(_) if !is_admin(&sender) => i18n::notice_text(&room, "cost-admin-only", &[]).await,
+        user => {
+            let user = user.unwrap_or(sender.as_str());
+            let report = cost::report(user, room.room_id().as_str(), &config);
+            i18n::notice_text(&room, "cost", &[("report", &report)]).await
+        }
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// List the available models
+async fn list_models(sender: OwnedUserId, _: Args, room: Room) -> Result<(), ChazError> {
+    let context = get_context(&room).await?;
+    let backends = get_backend(&room, Some(&sender)).await;
+    let locked = Tags::new(&room, "is.chaz.model")
+        .await
+        .get_value("locked")
+        .is_some();
+    let cost_config = get_config().cost.unwrap_or_default();
+    let mut models: Vec<String> = backends
+        .list_known_models()
+        .await
+        .into_iter()
+        .map(|model| match backends.model_pricing(Some(&model)) {
+            Some(pricing) => format!("{} ({})", model, pricing.describe(&cost_config)),
+            None => model,
+        })
+        .collect();
+    if get_config().router.is_some() {
+        models.push(router::MODEL.to_string());
+    }
+    let model = match context.model {
+        Some(model) => model,
+        None => backends
+            .default_model()
+            .await
+            .unwrap_or("unknown".to_string()),
+    };
+    let key = if locked { "models-locked" } else { "models" };
+    let args = [
+        ("model", model.as_str()),
+        ("backends", &backends.list_known_backends().join("\n")),
+        ("models", &models.join("\n")),
+    ];
+    room.send(i18n::notice(&room, key, &args).await).await?;
+    Ok(())
+}
+
+/// Control the roles
+///
+/// With no args, we print the info.
+/// With one arg, we print that and set it as the default role
+/// With more than 1, the first is the name of the role, and the rest is the prompt
+async fn set_role(_: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let mut tags = Tags::new(&room, "is.chaz.role").await;
+    let config = get_room_config(&room).await;
+    let language = language::get(&room).await;
+    let roles = list_roles(&tags, &config, language.as_deref());
+    let response = match (args.subcommand(), args.get("role")) {
+        (Some(_), _) => i18n::notice_text(&room, "role-list", &[("roles", &roles)]).await,
+        (None, Some(name)) => {
+            // If more arguments exist, that's the prompt
+            if let Some(prompt) = args.get("prompt") {
+                // Set the role
+                tags.replace_kv(name, prompt);
+            }
+            if tags.get_value(name).is_some()
+                || get_role(
+                    Some(name.to_string()),
+                    config.roles.clone(),
+                    DEFAULT_CONFIG.roles.clone(),
+                )
+                .is_some()
+            {
+                // This name is now the default role
+                tags.replace_kv("chazdefault", name);
+                tags.sync().await;
+                i18n::notice_text(&room, "role-set", &[("role", name)]).await
+            } else {
+                i18n::notice_text(&room, "role-unknown", &[("role", name)]).await
+            }
+        }
+        (None, None) => {
+            // 0 args, print the current role and the list
+            let current_role = tags
+                .get_value("chazdefault")
+                .or(config.role.clone())
+                .unwrap_or("none".to_string());
+            let args = [("role", current_role.as_str()), ("roles", &roles)];
+            i18n::notice_text(&room, "role-current", &args).await
+        }
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// List the roles available in a room, with their descriptions
+fn list_roles(tags: &Tags, config: &Config, language: Option<&str>) -> String {
+    let mut room_roles = Vec::new();
+    for tag in tags.tags() {
+        let role = tag.split('=').next().unwrap();
+        if role != "chazdefault" {
+            room_roles.push(role.to_string());
+        }
+    }
+    let describe = |roles: Option<Vec<RoleDetails>>| -> Vec<String> {
+        roles
+            .unwrap_or_default()
+            .iter()
+            .map(|role| match role.get_description() {
+                Some(description) => format!("{} - {}", role.name, description),
+                None => role.name.clone(),
+            })
+            .collect()
+    };
+    let config_roles = describe(config.roles.clone());
+    let default_roles = describe(DEFAULT_CONFIG.roles.clone());
+
+    [
+        ("roles-room", room_roles),
+        ("roles-configured", config_roles),
+        ("roles-builtin", default_roles),
+    ]
+    .into_iter()
+    .filter(|(_, roles)| !roles.is_empty())
+    .map(|(key, roles)| {
+        format!(
+            "{}\n{}",
+            i18n::translate(language, key, &[]),
+            roles.join("\n")
+        )
+    })
+    .collect::<Vec<String>>()
+    .join("\n\n")
+}
+
+/// Add a backend provider into the room tags
+async fn set_backend(_: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let (name, url, token) = (&args["name"], &args["api_base"], &args["api_key"]);
+    let mut tags = Tags::new(&room, "is.chaz.backend").await;
+    // The Scheme is like so:
+    // chazdefault=<name>
+    // <name>.url=<url>
+    // <name>.token=<token>
+    // <other name>.url=<url>
+    // <other name>.token=<token>
+    //
+    // TODO: Support "is.chaz.backend.<name>.model.<known models>"
+    // That will make it so that Chaz can validate and list those models
+    tags.replace_kv("chazdefault", name);
+    tags.replace_kv(&format!("{}.url", name), url);
+    tags.replace_kv(&format!("{}.token", name), token);
+    tags.sync().await;
+    room.send(i18n::notice(&room, "backend-added", &[("name", name)]).await)
+        .await?;
+    Ok(())
+}
+
+/// Register the sender's own OpenAI compatible backend for use in this room
+///
+/// Only allowed in direct messages, since the key is visible in the room history.
+async fn login(sender: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let is_direct = is_direct(&room).await;
+    if !is_direct {
+        room.send(i18n::notice(&room, "login-not-direct", &[]).await)
+            .await?;
+        return Ok(());
+    }
+    let mut tags = Tags::new(&room, "is.chaz.login").await;
+    // The Scheme is like so:
+    // <user id>.url=<url>
+    // <user id>.token=<token>
+    // <user id>.name=<name>
+    tags.replace_kv(&format!("{}.url", sender), &args["api_base"]);
+    tags.replace_kv(&format!("{}.token", sender), &args["api_key"]);
+    if let Some(name) = args.get("name") {
+        tags.replace_kv(&format!("{}.name", sender), name);
+    } else {
+        tags.remove_kv(&format!("{}.name", sender));
+    }
+    tags.sync().await;
+    room.send(i18n::notice(&room, "logged-in", &[]).await)
+        .await?;
+    Ok(())
+}
+
+/// Remove the sender's own backend from this room
+async fn logout(sender: OwnedUserId, _: Args, room: Room) -> Result<(), ChazError> {
+    let mut tags = Tags::new(&room, "is.chaz.login").await;
+    tags.remove_kv(&format!("{}.url", sender));
+    tags.remove_kv(&format!("{}.token", sender));
+    tags.remove_kv(&format!("{}.name", sender));
+    tags.sync().await;
+    room.send(i18n::notice(&room, "logged-out", &[]).await)
+        .await?;
+    Ok(())
+}
+
+/// Set the model to use for this chat
+async fn model(sender: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let model = args.get("model");
+    if matches!(model, Some("lock" | "unlock")) {
+        return lock_model(
+            &sender,
+            model == Some("lock"),
+            args.get("locked model"),
+            &room,
+        )
+        .await;
+    }
+    if let Some(model) = model {
+        if let Some(locked) = Tags::new(&room, "is.chaz.model").await.get_value("locked") {
+            room.send(i18n::notice(&room, "model-locked", &[("model", &locked)]).await)
+                .await?;
+            return Ok(());
+        }
+        if !space::settings(&room).await.allows_model(model) {
+            room.send(i18n::notice(&room, "model-not-allowed", &[("model", model)]).await)
+                .await?;
+            return Ok(());
+        }
+        let backend = get_backend(&room, Some(&sender)).await;
+        if model == router::MODEL {
+            if get_config().router.is_none() {
+                room.send(i18n::notice(&room, "router-not-configured", &[]).await)
+                    .await?;
+                return Ok(());
+            }
+            room.send(i18n::notice(&room, "model-router", &[]).await)
+                .await?;
+        } else if backend.is_known_model(model).await {
+            room.send(i18n::notice(&room, "model-set", &[("model", model)]).await)
+                .await?;
+        } else if let Err(e) = backend.validate_model(model).await {
+            room.send(i18n::notice(&room, "error", &[("message", &e.to_string())]).await)
+                .await?;
+        } else {
+            room.send(i18n::notice(&room, "model-unknown", &[("model", model)]).await)
+                .await?;
+        }
+        // Each workspace keeps its own model
+        let current = workspace::current(&room).await;
+        match workspace::settings(&room, &current).await {
+            Some(mut settings) => {
+                settings.replace_kv("model", model);
+                settings.sync().await;
+            }
+            None => {
+                let mut tags = Tags::new(&room, "is.chaz.model").await;
+                tags.replace_kv("default", model);
+                tags.sync().await;
+            }
+        }
+    } else {
+        list_models(sender, Args::default(), room).await?;
+    }
+    Ok(())
+}
+
+/// Lock the model of the room, so that only room admins can change it
+///
+/// The lock is kept separately from the model set with `!chaz model`, which applies again once it's unlocked.
+async fn lock_model(
+    sender: &OwnedUserId,
+    lock: bool,
+    model: Option<&str>,
+    room: &Room,
+) -> Result<(), ChazError> {
+    let response = if !permissions::is_room_admin(room, sender).await {
+        i18n::notice_text(room, "model-lock-admin-only", &[]).await
+    } else if !lock {
+        let mut tags = Tags::new(room, "is.chaz.model").await;
+        tags.remove_kv("locked");
+        tags.sync().await;
+        i18n::notice_text(room, "model-unlocked", &[]).await
+    } else {
+        match model {
+            None => i18n::notice_text(room, "model-lock-usage", &[]).await,
+            Some(model) if !space::settings(room).await.allows_model(model) => {
+                i18n::notice_text(room, "model-not-allowed", &[("model", model)]).await
+            }
+            Some(model) => match get_backend(room, Some(sender))
+                .await
+                .validate_model(model)
+                .await
+            {
+                Err(e) => i18n::error_text(room, &e.to_string()).await,
+                Ok(()) => {
+                    let mut tags = Tags::new(room, "is.chaz.model").await;
+                    tags.replace_kv("locked", model);
+                    tags.sync().await;
+                    i18n::notice_text(room, "model-lock-set", &[("model", model)]).await
+                }
+            },
+        }
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// Generate an image and post it to the room
+async fn imagine(sender: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let prompt = args["prompt"].to_string();
+    let config = get_config();
+    let error = match config.image_generation {
+        None => i18n::notice_text(&room, "image-not-configured", &[]).await,
+        Some(image_config) => {
+            if rate_limit(&room, &sender).await {
+                return Ok(());
+            }
+            info!("Image request: {} - {}", sender.as_str(), prompt);
+            match image_config.generate(&prompt).await {
+                Ok(image) => {
+                    // The body is the alt text of the image
+                    let body = if accessibility::is_enabled(&room, &sender).await {
+                        accessibility::alt_text(&room, &prompt).await
+                    } else {
+                        prompt
+                    };
+                    // Uploads the image to the media repo and posts it
+                    match room
+                        .send_attachment(
+                            &body,
+                            &"image/png".parse().unwrap(),
+                            image,
+                            AttachmentConfig::new(),
+                        )
+                        .await
+                    {
+                        Ok(_) => return Ok(()),
+                        Err(e) => {
+                            let error = e.to_string();
+                            i18n::notice_text(&room, "image-upload-failed", &[("error", &error)])
+                                .await
+                        }
+                    }
+                }
+                Err(e) => i18n::error_text(&room, &e.replace('\n', " ")).await,
+            }
+        }
+    };
+    error!(error);
+    room.send(RoomMessageEventContent::notice_plain(error))
+        .await?;
+    Ok(())
+}
+
+async fn rename(sender: OwnedUserId, _: Args, room: Room) -> Result<(), ChazError> {
+    if rate_limit(&room, &sender).await {
+        return Ok(());
+    }
+    if let Ok(context) = get_context(&room).await {
+        let mut context = context;
+        context.model = get_chat_summary_model();
+        context.messages.push(Message::new(
+            MessageRole::user,
+            [
+                "Summarize this conversation in less than 20 characters to use as the title of this conversation.",
+                "The output should be a single line of text describing the conversation.",
+                "Do not output anything except for the summary text.",
+                "Only the first 20 characters will be used.",
+                ].join(" ")));
+
+        let response = get_backend(&room, Some(&sender))
+            .await
+            .execute(&context)
+            .await;
+        if let Ok(result) = response {
+            info!(
+                "Response: {} - {}",
+                sender.as_str(),
+                result.replace('\n', " ")
+            );
+            let result = clean_summary_response(&result, None);
+            if moderation::blocks_output(&room, &result).await {
+                return Ok(());
+            }
+            if room.set_name(result).await.is_err() {
+                room.send(i18n::notice(&room, "rename-forbidden", &[]).await)
+                    .await?;
+
+                // If we can't set the name, we can't set the topic either
+                return Ok(());
+            }
+        }
+        // Remove the title summary request
+        context.messages.pop();
+
+        context.model = get_chat_summary_model();
+        context.messages.push(Message::new(
+            MessageRole::user,
+            [
+                "Summarize this conversation in less than 50 characters.",
+                "Do not output anything except for the summary text.",
+                "Do not include any commentary or context, only the summary.",
+            ]
+            .join(" "),
+        ));
+
+        let response = get_backend(&room, Some(&sender))
+            .await
+            .execute(&context)
+            .await;
+        if let Ok(result) = response {
+            info!(
+                "Response: {} - {}",
+                sender.as_str(),
+                result.replace('\n', " ")
+            );
+            let result = clean_summary_response(&result, None);
+            if moderation::blocks_output(&room, &result).await {
+                return Ok(());
+            }
+            if room.set_room_topic(&result).await.is_err() {
+                room.send(i18n::notice(&room, "topic-forbidden", &[]).await)
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Get the backend defined in the room tags.
+async fn get_tag_backend(room: &Room) -> Option<Vec<Backend>> {
+    let mut backends = Vec::new();
+    let tags = Tags::new(room, "is.chaz.backend").await;
+    let default_backend = tags.get_value("chazdefault");
+    for tag in tags.tags() {
+        if tag.split('.').nth(1).is_some_and(|x| x.starts_with("url")) {
+            let name = tag.split('.').next().unwrap_or_default();
+            let mut backend = Backend::new(BackendType::OpenAICompatible);
+            backend.name = Some(name.to_string());
+            backend.api_base = tags.get_value(&format!("{}.url", name));
+            backend.api_key = tags.get_value(&format!("{}.token", name));
+            if backend.api_base.is_some() && backend.api_key.is_some() {
+                backends.push(backend);
+            }
+        }
+    }
+    if let Some(default_backend) = default_backend {
+        // Reorder so that the default backend is first
+        if let Some(index) = backends
+            .iter()
+            .position(|x| x.name == Some(default_backend.to_string()))
+        {
+            backends.swap(0, index);
+        }
+    }
+    Some(backends)
+}
+
+/// Get the backend a user registered for themselves with `!chaz login`
+///
+/// Credentials are namespaced by the user ID, so each user only ever uses their own.
+async fn get_login_backend(room: &Room, sender: &OwnedUserId) -> Option<Backend> {
+    let tags = Tags::new(room, "is.chaz.login").await;
+    let mut backend = Backend::new(BackendType::OpenAICompatible);
+    backend.name = Some(
+        tags.get_value(&format!("{}.name", sender))
+            .unwrap_or("mine".to_string()),
+    );
+    backend.api_base = Some(tags.get_value(&format!("{}.url", sender))?);
+    backend.api_key = Some(tags.get_value(&format!("{}.token", sender))?);
+    Some(backend)
+}
+
+/// Returns the backend based on the global config
+///
+/// If a sender is given, the backend they registered with `!chaz login` is added after the others, and is used
+/// unless the model names another backend.
+async fn get_backend(room: &Room, sender: Option<&OwnedUserId>) -> BackendManager {
+    let login_backend = match sender {
+        Some(sender) => get_login_backend(room, sender).await,
+        None => None,
+    };
+    // Pull the tags in the current room, and add that backend
+    let backends = get_tag_backend(room).await.unwrap_or_default();
+    BackendManager::new(backends, login_backend).with_requester(Requester {
+        room_id: Some(room.room_id().to_owned()),
+        user_id: sender.cloned(),
+    })
+}
+
+/// Try to clean up the response from the model containing a summary
+/// Sometimes the models will return extra info, so we want to clean it if possible
+fn clean_summary_response(response: &str, max_length: Option<usize>) -> String {
+    let response = {
+        // Try to clean the response
+        // Should look for the first quoted string
+        let re = Regex::new(r#""([^"]*)""#).unwrap();
+        // If there are any matches, return the first one
+        if let Some(caps) = re.captures(response) {
+            caps.get(1).map_or("", |m| m.as_str())
+        } else {
+            response
+        }
+    };
+    if let Some(max_length) = max_length {
+        return response.chars().take(max_length).collect::<String>();
+    }
+    response.to_string()
+}
+
+/// Parse a duration like "30s", "15m", "2h", "1d", or "1w"
+fn parse_duration(input: &str) -> Option<Duration> {
+    let input = input.trim();
+    let split = input.find(|c: char| !c.is_ascii_digit())?;
+    let (value, unit) = input.split_at(split);
+    let value = value.parse::<u64>().ok()?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        "w" => 60 * 60 * 24 * 7,
+        _ => return None,
+    };
+    Some(Duration::from_secs(value * seconds))
+}
+
+/// Check if a word is the name of a command, these are left out of the context
+fn is_chaz_command(name: &str) -> bool {
+    name == "help" || COMMAND_HANDLERS.lock().unwrap().contains_key(name)
+}
+
+/// Field of the notices from chaz that aren't part of the conversation
+const OUT_OF_CONTEXT_FIELD: &str = "is.chaz.out_of_context";
+
+/// Start of the notice posted when a conversation expires
+const CONTEXT_EXPIRED_NOTICE: &str = "!chaz context expired";
+
+/// Check if a notice from chaz was marked as not part of the conversation
+fn is_out_of_context(message: &Raw<AnyTimelineEvent>) -> bool {
+    message
+        .get_field::<serde_json::Value>("content")
+        .ok()
+        .flatten()
+        .is_some_and(|content| content[OUT_OF_CONTEXT_FIELD] == true)
+}
+
+/// Get the context TTL, preferring the room setting over the config
+fn get_context_ttl(tags: &Tags, config: &Config) -> Option<Duration> {
+    tags.get_value("ttl")
+        .or(config.context_ttl.clone())
+        .and_then(|ttl| parse_duration(&ttl))
+}
+
+/// Where the context is cut off by a prune command
+#[derive(Clone, Copy)]
+enum PruneBoundary {
+    /// Only this many messages are kept
+    Messages(usize),
+    /// Messages older than this are dropped
+    Before(MilliSecondsSinceUnixEpoch),
+}
+
+/// Parse a `!chaz prune <N|duration>` command sent at the given time
+fn parse_prune(body: &str, sent: Option<MilliSecondsSinceUnixEpoch>) -> Option<PruneBoundary> {
+    let mut words = body.split_whitespace();
+    if words.nth(1) != Some("prune") {
+        return None;
+    }
+    let arg = words.next()?;
+    if let Ok(count) = arg.parse::<usize>() {
+        return Some(PruneBoundary::Messages(count));
+    }
+    let duration = parse_duration(arg)?;
+    let cutoff = u64::from(sent?.0).saturating_sub(duration.as_millis() as u64);
+    Some(PruneBoundary::Before(MilliSecondsSinceUnixEpoch(
+        UInt::new_saturating(cutoff),
+    )))
+}
+
+/// Post a bulleted digest of the conversation, or of a recent window of it
+///
+/// `!chaz summarize [since <duration>|<n> messages]`
+async fn summarize(sender: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let duration = &args["duration"];
+    let window = (args.subcommand(), args.get("n"), args.get("messages"));
+    let (window, description) = match window {
+        (None, None, _) => (None, i18n::tr(&room, "summary-conversation", &[]).await),
+        (Some(_), _, _) if parse_duration(duration).is_some() => {
+            let cutoff = u64::from(MilliSecondsSinceUnixEpoch::now().0)
+                .saturating_sub(parse_duration(duration).unwrap_or_default().as_millis() as u64);
+            (
+                Some(PruneBoundary::Before(MilliSecondsSinceUnixEpoch(
+                    UInt::new_saturating(cutoff),
+                ))),
+                i18n::tr(&room, "summary-since", &[("duration", duration)]).await,
+            )
+        }
+        (None, Some(count), None | Some("messages")) if count.parse::<usize>().is_ok() => (
+            count.parse().ok().map(PruneBoundary::Messages),
+            i18n::tr(&room, "summary-messages", &[("count", count)]).await,
+        ),
+        _ => {
+            room.send(i18n::notice(&room, "summarize-usage", &[]).await)
+                .await?;
+            return Ok(());
+        }
+    };
+    if rate_limit(&room, &sender).await {
+        return Ok(());
+    }
+    let mut context = match window {
+        Some(window) => get_context_window(&room, window).await?,
+        None => get_context(&room).await?,
+    };
+    if context.messages.is_empty() {
+        room.send(i18n::notice(&room, "summarize-empty", &[("window", &description)]).await)
+            .await?;
+        return Ok(());
+    }
+    context.model = get_chat_summary_model().or(context.model);
+    context.messages.push(Message::new(
+        MessageRole::user,
+        [
+            "Summarize the conversation above as a digest for someone who missed it.",
+            "Write a markdown bulleted list of the main topics, decisions, and open questions.",
+            "Do not output anything except for the list.",
+        ]
+        .join(" "),
+    ));
+    router::route(&room, &mut context).await;
+    let backend = get_backend(&room, Some(&sender)).await;
+    let started = Instant::now();
+    let result = activity::while_typing(&room, backend.execute(&context)).await;
+    record_tokens(
+        room.room_id(),
+        &sender,
+        &backend,
+        &context,
+        &result,
+        started,
+    );
+    let content = match result {
+        Ok(digest) => {
+            match moderation::check_response(&room, &digest).await {
+                Some(moderation::Action::Block) => return Ok(()),
+                Some(moderation::Action::Redact) => {
+                    room.send(i18n::notice(&room, "moderated-response", &[]).await)
+                        .await?;
+                    return Ok(());
+                }
+                _ => {}
+            }
+            let args = [("window", description.as_str()), ("summary", digest.trim())];
+            format::get(&room)
+                .await
+                .content(i18n::tr(&room, "summary", &args).await)
+        }
+        Err(e) => i18n::notice(&room, "error", &[("message", &e.replace('\n', " "))]).await,
+    };
+    room.send(content).await?;
+    Ok(())
+}
+
+/// Prune the context, dropping old messages without a full clear
+async fn prune(_: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let arg = &args["limit"];
+    let key = if arg.parse::<usize>().is_ok() {
+        "pruned-messages"
+    } else if parse_duration(arg).is_some() {
+        "pruned-duration"
+    } else {
+        "prune-usage"
+    };
+    room.send(i18n::notice(&room, key, &[("limit", arg)]).await)
+        .await?;
+    Ok(())
+}
+
+/// Read the config file, filling in the environment variables
+///
+/// The file can be left out if the required settings are in the environment.
+fn read_config(path: &Path) -> anyhow::Result<Config> {
+    let mut contents = String::new();
+    match File::open(path) {
+        Ok(mut file) => {
+            file.read_to_string(&mut contents)?;
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!(
+                "No config file at {}, using the environment",
+                path.display()
+            );
+        }
+        Err(e) => return Err(e.into()),
+    }
+    let mut config: serde_yaml::Value = serde_yaml::from_str(&contents)?;
+    if config.is_null() {
+        config = serde_yaml::Value::Mapping(Default::default());
+    }
+    env::apply(&mut config)?;
+    let mut config: Config = serde_yaml::from_value(config)?;
+    // The roles defined in the config win over the files with the same name
+    if let Some(roles_dir) = &config.roles_dir {
+        let mut roles = config.roles.take().unwrap_or_default();
+        for role in role::load_dir(Path::new(roles_dir)) {
+            if !roles.iter().any(|r| r.name == role.name) {
+                roles.push(role);
+            }
+        }
+        config.roles = Some(roles);
+    }
+    Ok(config)
+}
+
+/// Read the config file again, and use it for everything from now on
+///
+/// Settings used only on startup, like the login and the homeserver, need a restart to change.
+fn reload_config() -> Result<(), String> {
+    let path = CONFIG_PATH
+        .lock()
+        .unwrap()
+        .clone()
+        .ok_or("The config path is unknown".to_string())?;
+    let config = read_config(&path).map_err(|e| e.to_string())?;
+    for regex in [&config.allow_list, &config.deny_list, &config.admin_list]
+        .into_iter()
+        .flatten()
+    {
+        Regex::new(regex).map_err(|e| e.to_string())?;
+    }
+    backends::configure(&config.backends)?;
+    *GLOBAL_CONFIG.lock().unwrap_or_else(PoisonError::into_inner) = Some(config);
+    info!("Reloaded the config from {}", path.display());
+    Ok(())
+}
+
+/// Get the global config
+///
+/// A handler that panicked while holding the lock doesn't take the config down with it.
+fn get_config() -> Config {
+    GLOBAL_CONFIG
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+        .expect("config is set on startup")
+}
+
+/// Get the config for a room, with the settings of its spaces applied
+async fn get_room_config(room: &Room) -> Config {
+    with_space_settings(
+        with_account_settings(get_config(), room),
+        &space::settings(room).await,
+    )
+}
+
+/// Apply the persona of the account a room belongs to over the config
+fn with_account_settings(mut config: Config, room: &Room) -> Config {
+    if let Some(account) = accounts::settings(room) {
+        config.role = account.role.or(config.role);
+    }
+    config
+}
+
+/// Apply the settings of a room's spaces over the config
+fn with_space_settings(mut config: Config, space: &SpaceConfig) -> Config {
+    config.role = space.role.clone().or(config.role);
+    config.context_token_limit = space.context_token_limit.or(config.context_token_limit);
+    config.context_ttl = space.context_ttl.clone().or(config.context_ttl);
+    config.quotas = space.quotas.clone().or(config.quotas);
+    config
+}
+
+/// Get the admin room, if one is configured and chaz has joined it
+fn get_admin_room(client: &Client) -> Option<Room> {
+    let config = get_config();
+    let room_id = RoomId::parse(config.admin_room?).ok()?;
+    // The admin room may only have been joined by one of the accounts
+    client
+        .get_room(&room_id)
+        .or_else(|| accounts::get_room(&room_id))
+}
+
+/// Get the chat summary model from the global config
+fn get_chat_summary_model() -> Option<String> {
+    let config = get_config();
+    config.chat_summary_model
+}
+
+/// Get the display name for a user in the room, falling back to their user ID
+///
+/// Names are cached in `display_names` to avoid repeated lookups.
+async fn get_display_name(
+    room: &Room,
+    sender: &str,
+    display_names: &mut HashMap<String, String>,
+) -> String {
+    if let Some(name) = display_names.get(sender) {
+        return name.clone();
+    }
+    let name = match OwnedUserId::try_from(sender) {
+        Ok(user_id) => room
+            .get_member(&user_id)
+            .await
+            .ok()
+            .flatten()
+            .and_then(|member| member.display_name().map(String::from))
+            .unwrap_or(sender.to_string()),
+        Err(_) => sender.to_string(),
+    };
+    display_names.insert(sender.to_string(), name.clone());
+    name
+}
+
+/// Note the reactions to a message after it, if the message was added to the context at `index`
+///
+/// The context is built newest first, so the note goes before the message until it's reversed.
+async fn annotate_reactions(
+    room: &Room,
+    context: &mut ChatContext,
+    index: usize,
+    reactions: Option<Vec<(String, String)>>,
+    display_names: &mut HashMap<String, String>,
+) {
+    let Some(reactions) = reactions else {
+        return;
+    };
+    if context.messages.len() <= index {
+        return;
+    }
+    let mut described = Vec::new();
+    // The reactions were collected newest first
+    for (sender, key) in reactions.iter().rev() {
+        let name = get_display_name(room, sender, display_names).await;
+        described.push(format!("{} reacted {}", name, key));
+    }
+    context.messages.insert(
+        index,
+        Message::new(
+            MessageRole::user,
+            format!("({} to the previous message)", described.join(", ")),
+        ),
+    );
+}
+
+/// Get the response deadline from the global config
+fn get_response_deadline() -> Option<Duration> {
+    let config = get_config();
+    config.response_deadline.map(Duration::from_secs)
+}
+
+/// Transcribe an audio message
+///
+/// Returns None if transcription isn't configured or fails.
+async fn transcribe_audio(
+    room: &Room,
+    transcription: Option<&TranscriptionConfig>,
+    event_id: Option<&EventId>,
+    audio_content: &AudioMessageEventContent,
+) -> Option<String> {
+    let transcription = transcription?;
+    let request = MediaRequest {
+        source: audio_content.source.clone(),
+        format: MediaFormat::File,
+    };
+    let audio = match room
+        .client()
+        .media()
+        .get_media_content(&request, true)
+        .await
+    {
+        Ok(audio) => audio,
+        Err(e) => {
+            error!("Unable to download audio: {}", e);
+            return None;
+        }
+    };
+    match transcription
+        .transcribe(event_id.map(EventId::as_str), audio, &audio_content.body)
+        .await
+    {
+        Ok(transcript) => Some(transcript),
+        Err(e) => {
+            error!("Unable to transcribe audio: {}", e);
+            None
+        }
+    }
+}
+
+/// Download an image message into a temporary file
+async fn download_image(
+    room: &Room,
+    image_content: &ImageMessageEventContent,
+) -> Result<MediaFileHandle, ChazError> {
+    let mime = image_content
+        .info
+        .as_ref()
+        .and_then(|info| info.mimetype.as_ref())
+        .ok_or(ChazError::InvalidEvent("image has no mimetype".to_string()))?
+        .parse()
+        .map_err(|e| ChazError::InvalidEvent(format!("invalid image mimetype: {}", e)))?;
+    let request = MediaRequest {
+        source: image_content.source.clone(),
+        format: MediaFormat::File,
+    };
+    Ok(room
+        .client()
+        .media()
+        .get_media_file(&request, None, &mime, true, None)
+        .await?)
+}
+
+/// Gets the context of the current conversation
+///
+/// The token_limit is the maximum number of tokens to add into the context.
+/// If no token_limit is given, the context will include the full room
+pub async fn get_context(room: &Room) -> Result<ChatContext, ChazError> {
+    get_context_at(room, None).await
+}
+
+/// Get the context as it was at a given message, with edits applied
+///
+/// Everything sent after the message is ignored.
+async fn get_context_at(room: &Room, at: Option<&EventId>) -> Result<ChatContext, ChazError> {
+    Ok(build_context(room, at, None, true).await?.0)
+}
+
+/// Get only the most recent part of the context
+async fn get_context_window(room: &Room, window: PruneBoundary) -> Result<ChatContext, ChazError> {
+    Ok(build_context(room, None, Some(window), true).await?.0)
+}
+
+/// Get the full context, without compacting it or fitting it into the token budget
+///
+/// Nothing is summarized, so it's cheap enough to measure the context with.
+async fn get_unfitted_context(room: &Room) -> Result<ChatContext, ChazError> {
+    Ok(build_context(room, None, None, false).await?.0)
+}
+
+/// Build the context as it was at a given message
+///
+/// The window limits the context like a prune command. Without `fit`, the context isn't compacted or fit into the
+/// token budget. Also returns whether older messages were left out because the conversation expired.
+async fn build_context(
+    room: &Room,
+    at: Option<&EventId>,
+    window: Option<PruneBoundary>,
+    fit: bool,
+) -> Result<(ChatContext, bool), ChazError> {
+    let mut context = ChatContext {
+        messages: Vec::new(),
+        model: None,
+        media: Vec::new(),
+        tools: Vec::new(),
+        schema: None,
+        params: GenerationParams::default(),
+        reported_cost: Default::default(),
+        role: None,
+    };
+    let space = space::settings(room).await;
+    let config = with_space_settings(with_account_settings(get_config(), room), &space);
+    context.role = get_role(
+        config.role.clone(),
+        config.roles.clone(),
+        DEFAULT_CONFIG.roles.clone(),
+    );
+
+    let mut history = history::walk(room);
+
+    let enable_media_context = !config.disable_media_context.unwrap_or(false);
+    let multi_user_context = config.multi_user_context.unwrap_or(false);
+    let social_context = config.social_context.unwrap_or(false);
+    // The reactions to each message, found before the message itself because we're going backwards
+    let mut reactions: HashMap<OwnedEventId, Vec<(String, String)>> = HashMap::new();
+    let mut display_names = HashMap::new();
+    // Set by the window or the most recent prune command, everything past it is ignored
+    let mut prune_boundary: Option<PruneBoundary> = window;
+    // The latest edit of each message, found before the message itself because we're going backwards
+    let mut edits: HashMap<OwnedEventId, MessageType> = HashMap::new();
+    let mut at = at.map(ToOwned::to_owned);
+    // Room settings for the context, preferred over the config
+    let context_tags = Tags::new(room, "is.chaz.context").await;
+    let context_ttl = get_context_ttl(&context_tags, &config);
+    // Time of the newer message, to find gaps in the conversation longer than the TTL
+    let mut newer_timestamp: Option<MilliSecondsSinceUnixEpoch> = None;
+    let mut expired = false;
+    // Only the messages sent in the current workspace are included
+    let current_workspace = workspace::current(room).await;
+    let mut workspace_history = workspace::history(room, &current_workspace).await;
+    let aliases = aliases::load(room).await;
+    // The messages up to the summary are replaced by it
+    let summary = match &config.compaction {
+        Some(_) => compaction::get(room, &current_workspace).await,
+        None => None,
+    };
+    // Only the latest full context is compacted
+    let is_latest = fit && at.is_none() && window.is_none();
+
+    'outer: while let Some(batch) = history.next().await {
+        // This assumes that the messages are in reverse order, which they should be
+        for message in batch {
+            let timestamp = message
+                .get_field::<MilliSecondsSinceUnixEpoch>("origin_server_ts")
+                .unwrap_or(None);
+            match prune_boundary {
+                Some(PruneBoundary::Messages(count)) if context.messages.len() >= count => {
+                    break 'outer;
+                }
+                Some(PruneBoundary::Before(cutoff)) if timestamp.is_some_and(|ts| ts < cutoff) => {
+                    break 'outer;
+                }
+                _ => {}
+            }
+            let event_id = message
+                .get_field::<OwnedEventId>("event_id")
+                .unwrap_or(None);
+            let event_type = message.get_field::<String>("type").unwrap_or(None);
+            // Only the messages sent in the current workspace are included, the switches themselves never are
+            let in_workspace = workspace_history.contains(event_id.as_ref());
+            if social_context && event_type.as_deref() == Some("m.reaction") {
+                let sender = message.get_field::<String>("sender").unwrap_or(None);
+                let content = message
+                    .get_field::<serde_json::Value>("content")
+                    .unwrap_or(None)
+                    .unwrap_or_default();
+                let target = content["m.relates_to"]["event_id"]
+                    .as_str()
+                    .and_then(|id| OwnedEventId::try_from(id).ok());
+                let key = content["m.relates_to"]["key"].as_str();
+                if let (Some(sender), Some(target), Some(key)) = (sender, target, key) {
+                    let is_bot = room
+                        .client()
+                        .user_id()
+                        .is_some_and(|uid| sender == uid.as_str());
+                    if !is_bot {
+                        reactions
+                            .entry(target)
+                            .or_default()
+                            .push((sender, key.to_string()));
+                    }
+                }
+                continue;
+            }
+            if social_context && event_type.as_deref() == Some("m.sticker") && at.is_none() {
+                let sender = message
+                    .get_field::<String>("sender")
+                    .unwrap_or(None)
+                    .unwrap_or_default();
+                let content = message
+                    .get_field::<serde_json::Value>("content")
+                    .unwrap_or(None)
+                    .unwrap_or_default();
+                if in_workspace {
+                    let name = get_display_name(room, &sender, &mut display_names).await;
+                    let pushed = context.messages.len();
+                    context.messages.push(Message::new(
+                        MessageRole::user,
+                        format!(
+                            "{} sent a sticker: {}",
+                            name,
+                            content["body"].as_str().unwrap_or_default()
+                        ),
+                    ));
+                    annotate_reactions(
+                        room,
+                        &mut context,
+                        pushed,
+                        event_id.as_ref().and_then(|id| reactions.remove(id)),
+                        &mut display_names,
+                    )
+                    .await;
+                }
+                continue;
+            }
+            if let Some((sender, mut content)) =
+                message.get_field::<String>("sender").unwrap_or(None).zip(
+                    message
+                        .get_field::<RoomMessageEventContent>("content")
+                        .unwrap_or(None),
+                )
+            {
+                // Edits only replace the content of the original message
+                if let Some(Relation::Replacement(replacement)) = &content.relates_to {
+                    edits
+                        .entry(replacement.event_id.clone())
+                        .or_insert(replacement.new_content.msgtype.clone());
+                    continue;
+                }
+                // Skip ahead to the requested message
+                if at.is_some() {
+                    if event_id != at {
+                        continue;
+                    }
+                    at = None;
+                }
+                if let Some(msgtype) = event_id.as_ref().and_then(|id| edits.remove(id)) {
+                    content.msgtype = msgtype;
+                }
+                let is_bot = room
+                    .client()
+                    .user_id()
+                    .is_some_and(|uid| sender == uid.as_str());
+                if !in_workspace {
+                    continue;
+                }
+                if let Some(summary) = summary
+                    .as_ref()
+                    .filter(|s| event_id.as_ref() == Some(&s.until))
+                {
+                    // The context is reversed, so this ends up first
+                    context.messages.push(compaction::summary_message(summary));
+                    break 'outer;
+                }
+                // A previous expiry already started a new conversation
+                if let MessageType::Notice(notice) = &content.msgtype {
+                    if is_bot && notice.body.starts_with(CONTEXT_EXPIRED_NOTICE) {
+                        break 'outer;
+                    }
+                    // An imported transcript is the start of the conversation
+                    if is_bot && notice.body.starts_with(import::IMPORT_NOTICE) {
+                        if let Some(event_id) = &event_id {
+                            // The context is reversed into the right order at the end
+                            let imported = import::load(room, event_id).await;
+                            context.messages.extend(imported.into_iter().rev());
+                        }
+                        break 'outer;
+                    }
+                }
+                // A long enough gap in the conversation starts a new one
+                if let (Some(ttl), Some(newer), Some(timestamp)) =
+                    (context_ttl, newer_timestamp, timestamp)
+                {
+                    if u64::from(newer.0).saturating_sub(u64::from(timestamp.0))
+                        > ttl.as_millis() as u64
+                    {
+                        expired = true;
+                        break 'outer;
+                    }
+                }
+                newer_timestamp = timestamp.or(newer_timestamp);
+                // Attribute user messages to their sender so the model can tell participants apart
+                let sender_name = if multi_user_context
+                    && room
+                        .client()
+                        .user_id()
+                        .is_none_or(|uid| sender != uid.as_str())
+                {
+                    Some(get_display_name(room, &sender, &mut display_names).await)
+                } else {
+                    None
+                };
+                let pushed = context.messages.len();
+                match &content.msgtype {
+                    MessageType::Image(image_content) => {
+                        let role = if room
+                            .client()
+                            .user_id()
+                            .is_some_and(|uid| sender == uid.as_str())
+                        {
+                            MessageRole::assistant
+                        } else {
+                            MessageRole::user
+                        };
+                        let placeholder = describe_media(&content.msgtype).unwrap_or_default();
+                        // An image that can't be downloaded is still mentioned, so the model knows it exists
+                        let image = if enable_media_context {
+                            match download_image(room, image_content).await {
+                                Ok(image) => Some(image),
+                                Err(e) => {
+                                    error!("Unable to add image to the context: {}", e);
+                                    None
+                                }
+                            }
+                        } else {
+                            None
+                        };
+                        if let Some(image) = image {
+                            context.media.push(image);
+                            context.messages.push(
+                                Message::attached_media(role, placeholder).with_sender(sender_name),
+                            );
+                        } else {
+                            context
+                                .messages
+                                .push(Message::new(role, placeholder).with_sender(sender_name));
+                        }
+                    }
+                    MessageType::Audio(audio_content) => {
+                        // Use the transcript if possible, otherwise just let the model know it exists
+                        let text = match transcribe_audio(
+                            room,
+                            config.transcription.as_ref(),
+                            event_id.as_deref(),
+                            audio_content,
+                        )
+                        .await
+                        {
+                            Some(transcript) => transcript,
+                            None => describe_media(&content.msgtype).unwrap_or_default(),
+                        };
+                        if room
+                            .client()
+                            .user_id()
+                            .is_some_and(|uid| sender == uid.as_str())
+                        {
+                            context
+                                .messages
+                                .push(Message::new(MessageRole::assistant, text));
+                        } else {
+                            context.messages.push(
+                                Message::new(MessageRole::user, text).with_sender(sender_name),
+                            );
+                        }
+                    }
+                    MessageType::File(_) | MessageType::Video(_) => {
+                        // Text and PDF files are inlined, anything else can't be passed to any backend,
+                        // so let the model know it exists
+                        let inlined = match &content.msgtype {
+                            MessageType::File(file) if enable_media_context => {
+                                media::inline_file(
+                                    room,
+                                    file,
+                                    &config.attachments.clone().unwrap_or_default(),
+                                )
+                                .await
+                            }
+                            _ => None,
+                        };
+                        if let Some(placeholder) =
+                            inlined.or_else(|| describe_media(&content.msgtype))
+                        {
+                            if room
+                                .client()
+                                .user_id()
+                                .is_some_and(|uid| sender == uid.as_str())
+                            {
+                                context
+                                    .messages
+                                    .push(Message::new(MessageRole::assistant, placeholder));
+                            } else {
+                                context.messages.push(
+                                    Message::new(MessageRole::user, placeholder)
+                                        .with_sender(sender_name),
+                                );
+                            }
+                        }
+                    }
+                    // Responses sent as notices, whatever the current format is. The notices of the commands
+                    // start with "!chaz"
+                    MessageType::Notice(notice)
+                        if is_bot
+                            && !notice.body.starts_with("!chaz")
+                            && !is_out_of_context(&message) =>
+                    {
+                        let body =
+                            strip_mention(room, &content, &notice.body, &mut display_names).await;
+                        context.messages.push(Message::new(
+                            MessageRole::assistant,
+                            body.trim_end_matches(TRUNCATION_MARKER)
+                                .trim_end_matches(chunking::CONTINUED_MARKER)
+                                .trim_end(),
+                        ));
+                    }
+                    // Standalone messages aren't part of the conversation
+                    MessageType::Text(text_content)
+                        if strip_no_context_prefix(&text_content.body).is_some() => {}
+                    MessageType::Text(text_content) => {
+                        let body = aliases.expand(&text_content.body);
+                        // Commands are always prefixed with a !, regardless of the name
+                        if is_command("!", &body) {
+                            // if the message was a clear command, we are finished
+                            if body.starts_with("!chaz clear") {
+                                break 'outer;
+                            }
+                            // if it restored a checkpoint, skip back to the message it points to
+                            if let Some(name) = checkpoints::restored(&body) {
+                                if let Some(checkpoint) = checkpoints::get(room, name).await {
+                                    at = Some(checkpoint);
+                                    // The time spent on the other branch isn't a gap in the conversation
+                                    newer_timestamp = None;
+                                }
+                                continue;
+                            }
+                            // if the message was a prune command, only keep what it allows
+                            if prune_boundary.is_none() {
+                                prune_boundary =
+                                    parse_prune(&body, timestamp).map(|boundary| match boundary {
+                                        PruneBoundary::Messages(count) => {
+                                            PruneBoundary::Messages(context.messages.len() + count)
+                                        }
+                                        boundary => boundary,
+                                    });
+                            }
+                            // if it's not a recognized command, remove the "!chaz" and add that to messages
+                            if body.starts_with("!chaz") {
+                                let command = body.trim_start_matches("!chaz").trim();
+                                if command.is_empty() {
+                                    continue;
+                                }
+                                if let Some(command) = command.split_whitespace().next() {
+                                    // Recognized command, so skip adding it
+                                    if is_chaz_command(&command.to_lowercase()) {
+                                        continue;
+                                    }
+                                }
+                                if room
+                                    .client()
+                                    .user_id()
+                                    .is_some_and(|uid| sender == uid.as_str())
+                                {
+                                    context.messages.push(Message::new(
+                                        MessageRole::assistant,
+                                        command.to_string(),
+                                    ));
+                                } else {
+                                    context.messages.push(
+                                        Message::new(MessageRole::user, command.to_string())
+                                            .with_sender(sender_name),
+                                    );
+                                }
+                            }
+                        } else {
+                            // Push the sender and message to the front of the string
+                            if room
+                                .client()
+                                .user_id()
+                                .is_some_and(|uid| sender == uid.as_str())
+                            {
+                                // Sender is the bot
+                                // Drop the truncation marker so the model doesn't repeat it
+                                let body = strip_mention(
+                                    room,
+                                    &content,
+                                    &text_content.body,
+                                    &mut display_names,
+                                )
+                                .await;
+                                context.messages.push(Message::new(
+                                    MessageRole::assistant,
+                                    body.trim_end_matches(TRUNCATION_MARKER)
+                                        .trim_end_matches(chunking::CONTINUED_MARKER)
+                                        .trim_end(),
+                                ));
+                            } else {
+                                context.messages.push(
+                                    Message::new(MessageRole::user, text_content.body.clone())
+                                        .with_sender(sender_name),
+                                );
+                            }
+                        }
+                    }
+                    _ => {}
+                };
+                for message in &mut context.messages[pushed..] {
+                    message.event_id = event_id.clone();
+                }
+                annotate_reactions(
+                    room,
+                    &mut context,
+                    pushed,
+                    event_id.as_ref().and_then(|id| reactions.remove(id)),
+                    &mut display_names,
+                )
+                .await;
+            }
+        }
+    }
+    history.finish();
+    // Get the model name from the tags if it exists, falling back to the space and then the account
+    // Models set in the history by older versions are migrated into the tags
+    context.model = space
+        .model
+        .clone()
+        .or_else(|| accounts::settings(room).and_then(|account| account.model));
+    let tags = Tags::new(room, "is.chaz.model").await;
+    if let Some(model) = tags.get_value("default") {
+        context.model = Some(model);
+    }
+    if let Some(model) = workspace::settings(room, &current_workspace)
+        .await
+        .and_then(|settings| settings.get_value("model"))
+    {
+        context.model = Some(model);
+    }
+    // A model locked by a room admin wins over the one set by users
+    if let Some(model) = tags.get_value("locked") {
+        context.model = Some(model);
+    }
+    if context
+        .model
+        .as_ref()
+        .is_some_and(|model| !space.allows_model(model))
+    {
+        context.model = space.model.clone();
+    }
+    context.params = get_room_params(room).await;
+    // Get the role from the tags if it exists
+    let tags = Tags::new(room, "is.chaz.role").await;
+    if let Some(role) = tags.get_value("chazdefault") {
+        if let Some(prompt) = tags.get_value(&role) {
+            context.role = Some(RoleDetails::new(&role, None, Some(prompt), None));
+        } else {
+            context.role = get_role(
+                Some(role),
+                config.roles.clone(),
+                DEFAULT_CONFIG.roles.clone(),
+            );
+        }
+    }
+    // A prompt set for the room wins over any role
+    if let Some(prompt) = prompt::get(room).await {
+        context.role = Some(RoleDetails::new(
+            prompt::ROLE_NAME,
+            None,
+            Some(prompt),
+            None,
+        ));
+    }
+
+    // Use the translation of the role for the room's language
+    if let Some(language) = language::get(room).await {
+        context.role = context.role.map(|role| role.localized(&language));
+    }
+
+    // Tell the model what the members want to be called
+    if let Some(names) = names::names_prompt(room).await {
+        match context.role.as_mut() {
+            Some(role) => role.append_prompt(&names),
+            None => context.role = Some(RoleDetails::new("names", None, Some(names), None)),
+        }
+    }
+
+    // Reverse context so that it's in the correct order
+    context.messages.reverse();
+    context.media.reverse();
+
+    if let Some(compaction) = config.compaction.as_ref().filter(|_| is_latest) {
+        compaction::compact_if_needed(room, &current_workspace, &context.messages, compaction);
+    }
+
+    // Fit the context into the token budget, preferring the room setting
+    let token_limit = context_tags
+        .get_value("token_limit")
+        .and_then(|limit| limit.parse::<usize>().ok())
+        .or(config.context_token_limit)
+        .filter(|_| fit);
+    if let Some(token_limit) = token_limit {
+        let dropped = context::truncate_context(&mut context, token_limit);
+        if !dropped.is_empty() && config.summarize_truncated_context.unwrap_or(false) {
+            let backend = get_backend(room, None).await;
+            if let Some(summary) =
+                context::summarize_messages(&backend, get_chat_summary_model(), dropped).await
+            {
+                context.messages.insert(
+                    0,
+                    Message::new(
+                        MessageRole::system,
+                        format!("Summary of the earlier conversation: {}", summary.trim()),
+                    ),
+                );
+            }
+        }
+    }
+
+    // The room's memories are kept even when the rest of the context doesn't fit
+    memory::augment_context(room, &mut context).await;
+    Ok((context, expired))
+}
+
+/// Check if chaz responds to the sender, based on the allow_list and the deny_list
+///
+/// The deny_list wins, so a few accounts can be banned from a server that's allowed as a whole.
+fn is_allowed(sender: &UserId) -> bool {
+    let config = get_config();
+    let matches = |list: Option<String>| {
+        list.is_some_and(|list| {
+            Regex::new(&list)
+                .map(|regex| regex.is_match(sender.as_str()))
+                .unwrap_or(false)
+        })
+    };
+    matches(config.allow_list) && !matches(config.deny_list)
+}
+
+/// Check if the sender is allowed to run admin commands
+fn is_admin(sender: &OwnedUserId) -> bool {
+    let config = get_config();
+    config.admin_list.is_some_and(|admin_list| {
+        Regex::new(&admin_list)
+            .map(|regex| regex.is_match(sender.as_str()))
+            .unwrap_or(false)
+    })
+}
+
+/// Usage of `!chaz admin`, which always takes a subcommand
+const ADMIN_USAGE: &str = "leave <room>|say <room> <text>|block <user>|unblock <user>|quota <user> <n|none>|queue|reload|migrate-tags";
+
+/// Run an admin command
+async fn admin(_: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let (room_id, user) = (&args["room"], &args["user"]);
+    let response = match args.subcommand() {
+        Some("leave") => {
+            match RoomId::parse(room_id)
+                .ok()
+                .and_then(|room_id| room.client().get_room(&room_id))
+            {
+                Some(target) => match cleanup::leave(&target).await {
+                    Ok(()) => i18n::notice_text(&room, "admin-left", &[("room", room_id)]).await,
+                    Err(e) => {
+                        let args = [("room", room_id), ("error", &e.to_string())];
+                        i18n::notice_text(&room, "admin-leave-failed", &args).await
+                    }
+                },
+                None => i18n::notice_text(&room, "admin-not-in-room", &[("room", room_id)]).await,
+            }
+        }
+        Some("say") => {
+            let message = &args["text"];
+            match RoomId::parse(room_id)
+                .ok()
+                .and_then(|room_id| room.client().get_room(&room_id))
+            {
+                Some(target) => match target
+                    .send(RoomMessageEventContent::text_markdown(message))
+                    .await
+                {
+                    Ok(_) => i18n::notice_text(&room, "admin-posted", &[("room", room_id)]).await,
+                    Err(e) => {
+                        let args = [("room", room_id), ("error", &e.to_string())];
+                        i18n::notice_text(&room, "admin-post-failed", &args).await
+                    }
+                },
+                None => i18n::notice_text(&room, "admin-not-in-room", &[("room", room_id)]).await,
+            }
+        }
+        Some("block") => {
+            admin::set_blocked(user, true);
+            i18n::notice_text(&room, "admin-blocked", &[("user", user)]).await
+        }
+        Some("unblock") => {
+            admin::set_blocked(user, false);
+            i18n::notice_text(&room, "admin-unblocked", &[("user", user)]).await
+        }
+        Some("quota") if &args["quota"] == "none" => {
+            admin::set_daily_messages(user, None);
+            i18n::notice_text(&room, "admin-quota-cleared", &[("user", user)]).await
+        }
+        Some("quota") => match args["quota"].parse::<u64>() {
+            Ok(quota) => {
+                admin::set_daily_messages(user, Some(quota));
+                let args = [("user", user), ("quota", &quota.to_string())];
+                i18n::notice_text(&room, "admin-quota-set", &args).await
+            }
+            Err(_) => i18n::notice_text(&room, "admin-quota-invalid", &[]).await,
+        },
+        Some("queue") => queue::report(language::get(&room).await.as_deref()),
+        Some("reload") => match reload_config() {
+            Ok(()) => i18n::notice_text(&room, "admin-reloaded", &[]).await,
+            Err(e) => i18n::notice_text(&room, "admin-reload-failed", &[("error", &e)]).await,
+        },
+        Some("migrate-tags") => {
+            let count = migrate::migrate_model_tags(&room.client())
+                .await
+                .to_string();
+            i18n::notice_text(&room, "admin-migrated", &[("count", &count)]).await
+        }
+        _ => i18n::notice_text(&room, "admin-unknown", &[("usage", ADMIN_USAGE)]).await,
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// Show the encryption status, or confirm or cancel a pending verification
+async fn verify(_: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let response = match args.subcommand() {
+        Some("confirm") => match verification::confirm().await {
+            Ok(()) => i18n::notice_text(&room, "verify-confirmed", &[]).await,
+            Err(e) => i18n::error_text(&room, &e.to_string()).await,
+        },
+        Some(_) => match verification::cancel().await {
+            Ok(()) => i18n::notice_text(&room, "verify-cancelled", &[]).await,
+            Err(e) => i18n::error_text(&room, &e.to_string()).await,
+        },
+        None => {
+            let status = verification::status(&room.client()).await;
+            i18n::notice_text(&room, "verify-status", &[("status", &status)]).await
+        }
+    };
+    room.send(RoomMessageEventContent::notice_markdown(response))
+        .await?;
+    Ok(())
+}
+
+/// Run an evaluation suite and post the scorecard
+async fn run_eval(_: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let config = get_config();
+    let suites = config.eval_suites.unwrap_or_default();
+    let Some(name) = args.get("suite") else {
+        let names: Vec<&str> = suites.iter().map(|suite| suite.name.as_str()).collect();
+        let notice = if names.is_empty() {
+            i18n::notice(&room, "eval-no-suites", &[]).await
+        } else {
+            i18n::notice(&room, "eval-suites", &[("suites", &names.join(", "))]).await
+        };
+        room.send(notice).await?;
+        return Ok(());
+    };
+    let Some(suite) = suites.iter().find(|suite| suite.name == name) else {
+        room.send(i18n::notice(&room, "eval-unknown", &[("suite", name)]).await)
+            .await?;
+        return Ok(());
+    };
+
+    let backend = get_backend(&room, None).await;
+    let models = match &suite.models {
+        Some(models) => models.clone(),
+        None => {
+            let context = get_context(&room).await?;
+            match context.model {
+                Some(model) => vec![model],
+                None => backend.default_model().await.into_iter().collect(),
+            }
+        }
+    };
+    info!("Running eval suite {} on {:?}", suite.name, models);
+    let scorecard = eval::run_suite(&backend, suite, &models).await;
+    // The scorecard quotes the answers
+    if moderation::blocks_output(&room, &scorecard).await {
+        room.send(i18n::notice(&room, "moderated-response", &[]).await)
+            .await?;
+        return Ok(());
+    }
+    let args = [("suite", suite.name.as_str()), ("scorecard", &scorecard)];
+    room.send(RoomMessageEventContent::notice_markdown(
+        i18n::notice_text(&room, "eval-results", &args).await,
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Set the name the sender wants to be called in this room
+async fn set_name(sender: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let response = match args.get("name").unwrap_or_default() {
+        "" => {
+            let names = names::get_names(&room).await;
+            if names.is_empty() {
+                i18n::notice_text(&room, "names-empty", &[]).await
+            } else {
+                let language = language::get(&room).await;
+                let names = names
+                    .iter()
+                    .map(|(user, name)| {
+                        let args = [("user", user.as_str()), ("name", name.as_str())];
+                        i18n::translate(language.as_deref(), "names-entry", &args)
+                    })
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                i18n::notice_text(&room, "names", &[("names", &names)]).await
+            }
+        }
+        "none" => {
+            names::set_preferred(&room, sender.as_str(), None).await;
+            i18n::notice_text(&room, "name-cleared", &[]).await
+        }
+        name if name.contains('=') => i18n::notice_text(&room, "name-invalid", &[]).await,
+        name => {
+            names::set_preferred(&room, sender.as_str(), Some(name)).await;
+            let args = [("user", sender.as_str()), ("name", name)];
+            i18n::notice_text(&room, "name-set", &args).await
+        }
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// Switch the room to another workspace
+async fn switch_workspace(_: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let current = workspace::current(&room).await;
+    let response = match args.get("name") {
+        None => {
+            let workspaces = workspace::list(&room).await.join("\n");
+            let args = [("workspace", current.as_str()), ("workspaces", &workspaces)];
+            i18n::notice_text(&room, "workspace-current", &args).await
+        }
+        Some(name) if name == current => {
+            i18n::notice_text(&room, "workspace-already", &[("workspace", name)]).await
+        }
+        Some(name) if name.contains([',', '=']) => {
+            i18n::notice_text(&room, "workspace-invalid", &[]).await
+        }
+        Some(name) => match workspace::switch(&room, name).await {
+            // The switch posts its own notice
+            Ok(()) => return Ok(()),
+            Err(e) => i18n::error_text(&room, &e).await,
+        },
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// Turn accessibility mode on or off for the sender or the room
+async fn accessible(sender: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let for_room = args.get("scope") == Some("room");
+    let user = if for_room { None } else { Some(&sender) };
+    let response = match args.subcommand() {
+        Some(_) if !for_room && args.get("scope").is_some() => {
+            i18n::notice_text(&room, "accessible-usage", &[]).await
+        }
+        Some(setting) => {
+            let enabled = setting == "on";
+            accessibility::set_enabled(&room, user, enabled).await;
+            let key = match (enabled, for_room) {
+                (true, true) => "accessible-on-room",
+                (true, false) => "accessible-on",
+                (false, true) => "accessible-off-room",
+                (false, false) => "accessible-off",
+            };
+            i18n::notice_text(&room, key, &[]).await
+        }
+        None if accessibility::is_enabled(&room, &sender).await => {
+            i18n::notice_text(&room, "accessible-on", &[]).await
+        }
+        None => i18n::notice_text(&room, "accessible-off", &[]).await,
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// Get the generation parameters set for the room with `!chaz set`
+async fn get_room_params(room: &Room) -> GenerationParams {
+    let tags = Tags::new(room, "is.chaz.params").await;
+    let mut params = GenerationParams::default();
+    for name in GenerationParams::NAMES {
+        if let Some(value) = tags.get_value(name) {
+            if let Err(e) = params.set(name, &value) {
+                error!("Ignoring the room's {}", e);
+            }
+        }
+    }
+    params
+}
+
+/// Show or set the generation parameters of the room
+async fn set_param(_: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let mut tags = Tags::new(&room, "is.chaz.params").await;
+    let response = match (args.get("parameter"), args.get("value")) {
+        (Some(name), Some("none")) if GenerationParams::NAMES.contains(&name) => {
+            tags.remove_kv(name);
+            tags.sync().await;
+            i18n::notice_text(&room, "param-default", &[("parameter", name)]).await
+        }
+        (Some(name), Some(value)) => match GenerationParams::default().set(name, value) {
+            Ok(()) => {
+                tags.replace_kv(name, value);
+                tags.sync().await;
+                let args = [("parameter", name), ("value", value)];
+                i18n::notice_text(&room, "param-set", &args).await
+            }
+            Err(e) => i18n::error_text(&room, &e).await,
+        },
+        (None, _) => {
+            let mut response = i18n::notice_text(&room, "params", &[]).await;
+            for name in GenerationParams::NAMES {
+                let value = tags.get_value(name).unwrap_or("default".to_string());
+                response.push_str(&format!("\n{}: {}", name, value));
+            }
+            response
+        }
+        (Some(_), None) => {
+            let names = GenerationParams::NAMES.join("|");
+            i18n::notice_text(&room, "param-usage", &[("parameters", &names)]).await
+        }
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// Show or set the language of the room
+async fn set_language(_: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let response = match args.get("code") {
+        Some("none") => {
+            language::set(&room, None).await;
+            i18n::notice_text(&room, "language-default", &[]).await
+        }
+        Some(code) => {
+            language::set(&room, Some(code)).await;
+            i18n::notice_text(&room, "language-set", &[("language", code)]).await
+        }
+        None => match language::get(&room).await {
+            Some(code) => i18n::notice_text(&room, "language", &[("language", &code)]).await,
+            None => i18n::notice_text(&room, "language-not-set", &[]).await,
+        },
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// Show or set the format of the responses in this room
+async fn set_format(_: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let response = match args.get("format") {
+        Some("none") => {
+            format::set(&room, None).await;
+            let format = get_config().format.unwrap_or_default();
+            i18n::notice_text(&room, "format-default", &[("format", format.name())]).await
+        }
+        Some(name) => match format::Format::parse(name) {
+            Some(format) => {
+                format::set(&room, Some(format)).await;
+                i18n::notice_text(&room, "format-set", &[("format", format.name())]).await
+            }
+            None => i18n::notice_text(&room, "format-usage", &[]).await,
+        },
+        None => {
+            let format = format::get(&room).await;
+            i18n::notice_text(&room, "format", &[("format", format.name())]).await
+        }
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// Estimate the tokens the context uses with the model of the sender
+///
+/// The counts use the encoding of the model's family, so they're close but not exact.
+async fn count_tokens(sender: OwnedUserId, _: Args, room: Room) -> Result<(), ChazError> {
+    let config = get_room_config(&room).await;
+    // Measured before it's fit into the limit, so it can show how far over the limit the context is
+    let mut context = get_unfitted_context(&room).await?;
+    let preferences = preferences::get(&room.client(), &sender).await;
+    preferences::apply(&room, &preferences, &mut context).await;
+    let model = match &context.model {
+        Some(model) => Some(model.clone()),
+        None => {
+            get_backend(&room, Some(&sender))
+                .await
+                .default_model()
+                .await
+        }
+    }
+    .unwrap_or("default".to_string());
+    let encoding = context::encoding_for(&model);
+    let role_tokens = context.role.as_ref().map_or(0, |role| {
+        context::estimate_tokens_with(&role.get_prompt(), &encoding) + context::TOKENS_PER_MESSAGE
+    });
+    let message_tokens: usize = context
+        .messages
+        .iter()
+        .map(|message| {
+            context::estimate_tokens_with(&message.content, &encoding) + context::TOKENS_PER_MESSAGE
+        })
+        .sum();
+    let total = role_tokens + message_tokens;
+    let limit = Tags::new(&room, "is.chaz.context")
+        .await
+        .get_value("token_limit")
+        .and_then(|limit| limit.parse::<usize>().ok())
+        .or(config.context_token_limit);
+    let language = language::get(&room).await;
+    let line = |key: &str, args: &[(&str, &str)]| i18n::translate(language.as_deref(), key, args);
+    let mut lines = vec![
+        line(
+            "tokens-model",
+            &[("model", &model), ("encoding", encoding.name)],
+        ),
+        line("tokens-role", &[("tokens", &role_tokens.to_string())]),
+        line(
+            "tokens-messages",
+            &[
+                ("tokens", &message_tokens.to_string()),
+                ("count", &context.messages.len().to_string()),
+            ],
+        ),
+    ];
+    if !context.media.is_empty() {
+        lines.push(line(
+            "tokens-media",
+            &[("count", &context.media.len().to_string())],
+        ));
+    }
+    lines.push(match limit {
+        Some(limit) => line(
+            "tokens-total-limit",
+            &[
+                ("tokens", &total.to_string()),
+                ("limit", &limit.to_string()),
+            ],
+        ),
+        None => line("tokens-total", &[("tokens", &total.to_string())]),
+    });
+    // Near the limit the oldest messages are about to be dropped
+    if limit.is_some_and(|limit| total * 10 >= limit * 8) {
+        lines.push(line("tokens-near-limit", &[]));
+    }
+    room.send(i18n::notice(&room, "tokens", &[("tokens", &lines.join("\n"))]).await)
+        .await?;
+    Ok(())
+}
+
+/// Set the token limit or the TTL for the context in this room
+async fn set_context_limit(_: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let mut tags = Tags::new(&room, "is.chaz.context").await;
+    let response = match (args.subcommand(), args.get("tokens")) {
+        (Some(_), _) => match &args["duration"] {
+            "none" => {
+                // Stored rather than removed, so it also overrides the config
+                tags.replace_kv("ttl", "none");
+                tags.sync().await;
+                i18n::notice_text(&room, "context-ttl-disabled", &[]).await
+            }
+            ttl if parse_duration(ttl).is_some() => {
+                tags.replace_kv("ttl", ttl);
+                tags.sync().await;
+                i18n::notice_text(&room, "context-ttl-set", &[("ttl", ttl)]).await
+            }
+            _ => i18n::notice_text(&room, "context-ttl-usage", &[]).await,
+        },
+        (None, Some("none")) => {
+            tags.remove_kv("token_limit");
+            tags.sync().await;
+            i18n::notice_text(&room, "context-limit-removed", &[]).await
+        }
+        (None, Some(limit)) => {
+            if let Ok(limit) = limit.parse::<usize>() {
+                tags.replace_kv("token_limit", &limit.to_string());
+                tags.sync().await;
+                let limit = limit.to_string();
+                i18n::notice_text(&room, "context-limit-set", &[("limit", &limit)]).await
+            } else {
+                i18n::notice_text(&room, "context-usage", &[]).await
+            }
+        }
+        (None, None) => {
+            let context = get_unfitted_context(&room).await?;
+            let not_set = i18n::tr(&room, "not-set", &[]).await;
+            let tokens = context::estimate_context_tokens(&context).to_string();
+            let limit = tags.get_value("token_limit").unwrap_or(not_set.clone());
+            let ttl = tags
+                .get_value("ttl")
+                .or(get_room_config(&room).await.context_ttl)
+                .unwrap_or(not_set);
+            let args = [
+                ("tokens", tokens.as_str()),
+                ("limit", &limit),
+                ("ttl", &ttl),
+            ];
+            i18n::notice_text(&room, "context-size", &args).await
+        }
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,212 @@
+/// Structured logging
+///
+/// Messages and commands are handled in a `request` span with the room, sender, model, and a request ID, so the
+/// logs of requests running at the same time can be told apart. With `log_file` set, the logs are also written as
+/// JSON lines to `chaz.log` in the state directory, which is rotated by size.
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use matrix_sdk::{ruma::UserId, Room};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Span, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+use crate::util::format_utc;
+
+/// Configuration for the log file
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct LogFileConfig {
+    /// Size in MB at which the file is rotated, 10 by default
+    pub max_size: Option<u64>,
+    /// Number of rotated files to keep, 5 by default
+    pub max_files: Option<usize>,
+}
+
+/// A file of JSON lines, rotated by size
+pub struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl LogFile {
+    /// Open the file for appending, creating it if needed
+    pub fn open(path: PathBuf, config: &LogFileConfig) -> std::io::Result<LogFile> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or_default();
+        Ok(LogFile {
+            path,
+            file,
+            size,
+            max_size: config.max_size.unwrap_or(10) * 1024 * 1024,
+            max_files: config.max_files.unwrap_or(5),
+        })
+    }
+
+    pub fn write_line(&mut self, line: &str) {
+        if self.size + line.len() as u64 > self.max_size {
+            self.rotate();
+        }
+        if writeln!(self.file, "{}", line).is_ok() {
+            self.size += line.len() as u64 + 1;
+        }
+    }
+
+    /// Move e.g. `chaz.log` to `chaz.log.1`, shifting the older files up and dropping the oldest
+    fn rotate(&mut self) {
+        let rotated = |index: usize| PathBuf::from(format!("{}.{}", self.path.display(), index));
+        let _ = std::fs::remove_file(rotated(self.max_files));
+        for index in (1..self.max_files).rev() {
+            let _ = std::fs::rename(rotated(index), rotated(index + 1));
+        }
+        if self.max_files > 0 {
+            let _ = std::fs::rename(&self.path, rotated(1));
+        }
+        match File::create(&self.path) {
+            Ok(file) => {
+                self.file = file;
+                self.size = 0;
+            }
+            // Tracing can't be used from inside the layer
+            Err(e) => eprintln!("Unable to rotate the log file: {}", e),
+        }
+    }
+}
+
+lazy_static! {
+    static ref LOG_FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+}
+
+/// Start writing the logs to `chaz.log` in the state directory
+///
+/// Only has an effect if the `LogFileLayer` was added to the subscriber.
+pub fn init(state_dir: &Path, config: &LogFileConfig) {
+    let path = state_dir.join("chaz.log");
+    match LogFile::open(path.clone(), config) {
+        Ok(log_file) => *LOG_FILE.lock().unwrap() = Some(log_file),
+        Err(e) => tracing::error!("Unable to open the log file {}: {}", path.display(), e),
+    }
+}
+
+/// Create the span for handling a message or command
+pub fn request_span(room: &Room, sender: &UserId) -> Span {
+    tracing::info_span!(
+        "request",
+        request_id = %format!("{:08x}", rand::random::<u32>()),
+        room_id = %room.room_id(),
+        sender = %sender,
+        model = tracing::field::Empty,
+    )
+}
+
+/// Add the model to the current request span
+pub fn record_model(model: Option<&str>) {
+    Span::current().record("model", model.unwrap_or("default"));
+}
+
+/// Collects fields as JSON values
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value).into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+}
+
+/// The fields of a span, stored in its extensions
+struct SpanFields(Map<String, Value>);
+
+/// Layer that writes the logs as JSON lines to the log file set up by `init`
+///
+/// ```no_run
+/// use tracing_subscriber::prelude::*;
+///
+/// tracing_subscriber::registry()
+///     .with(tracing_subscriber::fmt::layer())
+///     .with(chaz::LogFileLayer)
+///     .init();
+/// ```
+pub struct LogFileLayer;
+
+impl<S> Layer<S> for LogFileLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Map::new();
+        attrs.record(&mut JsonVisitor(&mut fields));
+        span.extensions_mut().insert(SpanFields(fields));
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(fields) = extensions.get_mut::<SpanFields>() {
+            values.record(&mut JsonVisitor(&mut fields.0));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut log_file = LOG_FILE.lock().unwrap();
+        let Some(log_file) = log_file.as_mut() else {
+            return;
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut line = Map::new();
+        line.insert("timestamp".to_string(), format_utc(now.as_secs()).into());
+        line.insert(
+            "level".to_string(),
+            event.metadata().level().as_str().into(),
+        );
+        line.insert("target".to_string(), event.metadata().target().into());
+        // Span fields go first so the event's own fields win
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(fields) = span.extensions().get::<SpanFields>() {
+                    line.extend(fields.0.clone());
+                }
+            }
+        }
+        event.record(&mut JsonVisitor(&mut line));
+        log_file.write_line(&Value::Object(line).to_string());
+    }
+}
@@ -0,0 +1,262 @@
+/// Media handling
+///
+/// Helpers for turning Matrix media events into something a text-only model can understand.
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use lazy_static::lazy_static;
+use matrix_sdk::{
+    media::{MediaFormat, MediaRequest},
+    ruma::{
+        events::room::{
+            message::{FileMessageEventContent, MessageType},
+            MediaSource,
+        },
+        OwnedMxcUri, UInt,
+    },
+    Room,
+};
+use serde::Deserialize;
+
+/// File extensions that are read as text, for clients that don't set a text mimetype
+const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "md", "markdown", "rst", "csv", "tsv", "json", "yaml", "yml", "toml", "ini", "xml",
+    "html", "css", "log", "sql", "sh", "py", "rs", "js", "ts", "go", "c", "h", "cpp", "hpp",
+    "java", "kt", "rb", "php", "swift", "nix",
+];
+
+/// Number of extracted files kept in memory, so they aren't downloaded for every message
+const CACHE_SIZE: usize = 100;
+
+/// Longest `pdftotext` can run on a single file
+const PDF_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Limits for the text of attachments added to the context
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct AttachmentLimits {
+    /// Maximum characters of a text file added to the context, 20000 by default
+    pub text_limit: Option<usize>,
+    /// Maximum characters of a PDF added to the context, 20000 by default
+    pub pdf_limit: Option<usize>,
+    /// Largest file that's downloaded, in MB, 5 by default
+    pub max_file_size: Option<u64>,
+}
+
+lazy_static! {
+    /// Text extracted from files, or why it couldn't be, by their URI
+    static ref EXTRACTED: Mutex<HashMap<OwnedMxcUri, Result<String, String>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Describe a media message as a short textual placeholder.
+///
+/// Returns None if the message is not a media message.
+/// The placeholder includes whatever metadata is available, e.g. the filename, type, dimensions and size.
+pub fn describe_media(msgtype: &MessageType) -> Option<String> {
+    let (kind, body, details) = match msgtype {
+        MessageType::Image(content) => {
+            let info = content.info.as_deref();
+            (
+                "an image",
+                &content.body,
+                vec![
+                    info.and_then(|i| i.mimetype.clone()),
+                    info.and_then(|i| dimensions(i.width, i.height)),
+                    info.and_then(|i| i.size.map(human_size)),
+                ],
+            )
+        }
+        MessageType::File(content) => {
+            let info = content.info.as_deref();
+            (
+                "a file",
+                &content.body,
+                vec![
+                    info.and_then(|i| i.mimetype.clone()),
+                    info.and_then(|i| i.size.map(human_size)),
+                ],
+            )
+        }
+        MessageType::Audio(content) => {
+            let info = content.info.as_deref();
+            (
+                "an audio clip",
+                &content.body,
+                vec![
+                    info.and_then(|i| i.mimetype.clone()),
+                    info.and_then(|i| i.duration.map(|d| format!("{}s", d.as_secs()))),
+                    info.and_then(|i| i.size.map(human_size)),
+                ],
+            )
+        }
+        MessageType::Video(content) => {
+            let info = content.info.as_deref();
+            (
+                "a video",
+                &content.body,
+                vec![
+                    info.and_then(|i| i.mimetype.clone()),
+                    info.and_then(|i| dimensions(i.width, i.height)),
+                    info.and_then(|i| i.duration.map(|d| format!("{}s", d.as_secs()))),
+                    info.and_then(|i| i.size.map(human_size)),
+                ],
+            )
+        }
+        _ => return None,
+    };
+    let details: Vec<String> = details.into_iter().flatten().collect();
+    // The body is the filename or a caption, depending on the client
+    let mut placeholder = format!("[Shared {}: {}", kind, body);
+    if !details.is_empty() {
+        placeholder.push_str(&format!(" ({})", details.join(", ")));
+    }
+    placeholder.push(']');
+    Some(placeholder)
+}
+
+/// Format the dimensions of an image or video
+fn dimensions(width: Option<UInt>, height: Option<UInt>) -> Option<String> {
+    Some(format!("{}x{}", width?, height?))
+}
+
+/// Format a size in bytes to be human readable
+fn human_size(size: UInt) -> String {
+    let size = u64::from(size);
+    if size >= 1024 * 1024 {
+        format!("{:.1} MB", size as f64 / (1024.0 * 1024.0))
+    } else if size >= 1024 {
+        format!("{:.1} KB", size as f64 / 1024.0)
+    } else {
+        format!("{} B", size)
+    }
+}
+
+/// Check if a file is a PDF
+fn is_pdf(file: &FileMessageEventContent) -> bool {
+    file.body.to_lowercase().ends_with(".pdf")
+        || file.info.as_ref().and_then(|info| info.mimetype.as_deref()) == Some("application/pdf")
+}
+
+/// Check if a file can be read as text
+fn is_text(file: &FileMessageEventContent) -> bool {
+    let mimetype = file.info.as_ref().and_then(|info| info.mimetype.as_deref());
+    mimetype.is_some_and(|mimetype| {
+        mimetype.starts_with("text/")
+            || matches!(
+                mimetype,
+                "application/json" | "application/xml" | "application/x-yaml" | "application/toml"
+            )
+    }) || file
+        .body
+        .rsplit_once('.')
+        .is_some_and(|(_, extension)| TEXT_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+}
+
+/// Download a text or PDF file and extract its text
+///
+/// Files larger than `max_size` bytes aren't downloaded, or are dropped once downloaded if the sender didn't give
+/// the size.
+pub async fn extract_text(
+    room: &Room,
+    file: &FileMessageEventContent,
+    max_size: u64,
+) -> Result<String, String> {
+    let pdf = is_pdf(file);
+    if !pdf && !is_text(file) {
+        return Err("only text and PDF files can be read".to_string());
+    }
+    if file
+        .info
+        .as_ref()
+        .and_then(|info| info.size)
+        .is_some_and(|size| u64::from(size) > max_size)
+    {
+        return Err("the file is too large".to_string());
+    }
+    let request = MediaRequest {
+        source: file.source.clone(),
+        format: MediaFormat::File,
+    };
+    let contents = room
+        .client()
+        .media()
+        .get_media_content(&request, false)
+        .await
+        .map_err(|e| format!("unable to download: {}", e))?;
+    // The size in the event is set by the sender, so it can't be trusted
+    if contents.len() as u64 > max_size {
+        return Err("the file is too large".to_string());
+    }
+    if pdf {
+        return pdf_to_text(&contents).await;
+    }
+    String::from_utf8(contents).map_err(|_| "the file isn't valid UTF-8 text".to_string())
+}
+
+/// Convert a PDF to text with `pdftotext` from poppler
+async fn pdf_to_text(pdf: &[u8]) -> Result<String, String> {
+    let path = std::env::temp_dir().join(format!("chaz-{:016x}.pdf", rand::random::<u64>()));
+    tokio::fs::write(&path, pdf)
+        .await
+        .map_err(|e| e.to_string())?;
+    let output = tokio::time::timeout(
+        PDF_TIMEOUT,
+        tokio::process::Command::new("pdftotext")
+            .arg(&path)
+            .arg("-")
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await;
+    let _ = tokio::fs::remove_file(&path).await;
+    let output = output
+        .map_err(|_| "pdftotext timed out".to_string())?
+        .map_err(|e| format!("unable to run pdftotext, is poppler installed? {}", e))?;
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Get the content of an attached file for the context, truncated to the limits
+///
+/// Returns None for files that can't be read, which are described instead.
+pub async fn inline_file(
+    room: &Room,
+    file: &FileMessageEventContent,
+    config: &AttachmentLimits,
+) -> Option<String> {
+    let uri = match &file.source {
+        MediaSource::Plain(uri) => uri.clone(),
+        MediaSource::Encrypted(encrypted) => encrypted.url.clone(),
+    };
+    let cached = EXTRACTED.lock().unwrap().get(&uri).cloned();
+    let text = match cached {
+        Some(text) => text,
+        None => {
+            let max_size = config.max_file_size.unwrap_or(5) * 1024 * 1024;
+            // Failures are cached too, so an unreadable file isn't downloaded again for every message
+            let text = extract_text(room, file, max_size).await;
+            let mut extracted = EXTRACTED.lock().unwrap();
+            if extracted.len() >= CACHE_SIZE {
+                extracted.clear();
+            }
+            extracted.insert(uri, text.clone());
+            text
+        }
+    }
+    .ok()?;
+    let limit = if is_pdf(file) {
+        config.pdf_limit.unwrap_or(20000)
+    } else {
+        config.text_limit.unwrap_or(20000)
+    };
+    let mut content: String = text.chars().take(limit).collect();
+    if content.len() < text.len() {
+        content.push_str("\n[truncated]");
+    }
+    Some(format!(
+        "[Shared a file: {}]\n```\n{}\n```",
+        file.body,
+        content.trim_end()
+    ))
+}
@@ -0,0 +1,307 @@
+/// Room memories
+///
+/// Facts saved with `!chaz remember` are added to the start of every context in the room, so they're kept across
+/// clears and expired conversations. They're stored in the room account data under `is.chaz.memories`.
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+use lazy_static::lazy_static;
+use matrix_sdk::{
+    ruma::{events::room::message::RoomMessageEventContent, OwnedRoomId, OwnedUserId, RoomId},
+    Room,
+};
+use openai_api_rs::v1::chat_completion::MessageRole;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{
+    account_data, activity,
+    backends::{ChatContext, Message},
+    commands::Args,
+    error::ChazError,
+    get_backend, get_chat_summary_model, get_context, i18n, moderation, rate_limit, record_tokens,
+    router,
+};
+
+/// Account data type the memories are stored in
+const ACCOUNT_DATA_TYPE: &str = "is.chaz.memories";
+
+/// Maximum number of memories in a room
+const MAX_MEMORIES: usize = 50;
+
+/// Maximum length of a memory, in characters
+const MAX_LENGTH: usize = 500;
+
+/// The stored memories
+#[derive(Serialize, Deserialize, Default)]
+struct Memories {
+    memories: Vec<String>,
+}
+
+lazy_static! {
+    /// Memories that have been loaded, by room
+    static ref MEMORIES: Mutex<HashMap<OwnedRoomId, Vec<String>>> = Mutex::new(HashMap::new());
+
+    /// Memories suggested by the model that are waiting to be kept, by room
+    static ref SUGGESTED: Mutex<HashMap<OwnedRoomId, Vec<String>>> = Mutex::new(HashMap::new());
+}
+
+/// Get the memories of a room, loading them if needed
+async fn load(room: &Room) -> Result<Vec<String>, String> {
+    if let Some(memories) = MEMORIES.lock().unwrap().get(room.room_id()) {
+        return Ok(memories.clone());
+    }
+    let memories = account_data::get::<Memories>(room, ACCOUNT_DATA_TYPE)
+        .await?
+        .unwrap_or_default()
+        .memories;
+    cache(room.room_id(), memories.clone());
+    Ok(memories)
+}
+
+/// Save the memories of a room
+async fn save(room: &Room, memories: Vec<String>) -> Result<(), String> {
+    let memories = Memories { memories };
+    if serde_json::to_string(&memories).map_or(0, |json| json.len()) > account_data::MAX_SIZE {
+        return Err("there are too many memories to store, forget some first".to_string());
+    }
+    account_data::set(room, ACCOUNT_DATA_TYPE, &memories).await?;
+    cache(room.room_id(), memories.memories);
+    Ok(())
+}
+
+fn cache(room: &RoomId, memories: Vec<String>) {
+    MEMORIES.lock().unwrap().insert(room.to_owned(), memories);
+}
+
+/// Add memories to a room, skipping the ones it already has
+///
+/// Returns the number of memories added.
+async fn add(room: &Room, facts: Vec<String>) -> Result<usize, String> {
+    let mut memories = load(room).await?;
+    let before = memories.len();
+    for fact in facts {
+        if fact.chars().count() > MAX_LENGTH {
+            return Err(format!("memories are limited to {} characters", MAX_LENGTH));
+        }
+        if !memories.contains(&fact) {
+            memories.push(fact);
+        }
+    }
+    if memories.len() > MAX_MEMORIES {
+        return Err(format!(
+            "rooms are limited to {} memories, forget some first",
+            MAX_MEMORIES
+        ));
+    }
+    let added = memories.len() - before;
+    if added > 0 {
+        save(room, memories).await?;
+    }
+    Ok(added)
+}
+
+/// Save a fact to the room's memories
+///
+/// `!chaz remember <fact>`
+pub async fn remember(_: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let response = match add(&room, vec![args["fact"].to_string()]).await {
+        Ok(0) => i18n::notice_text(&room, "memory-known", &[]).await,
+        Ok(_) => i18n::notice_text(&room, "memory-saved", &[]).await,
+        Err(e) => i18n::error_text(&room, &e).await,
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// Remove memories by their number in `!chaz memories`
+///
+/// `!chaz forget <n>...`
+pub async fn forget(_: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let numbers: Option<Vec<usize>> = args["n"]
+        .split_whitespace()
+        .map(|n| n.parse().ok())
+        .collect();
+    let response = match numbers {
+        Some(numbers) => match load(&room).await {
+            Ok(memories) if numbers.iter().all(|n| (1..=memories.len()).contains(n)) => {
+                let remaining = memories
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(index, _)| !numbers.contains(&(index + 1)))
+                    .map(|(_, memory)| memory)
+                    .collect();
+                match save(&room, remaining).await {
+                    Ok(()) => {
+                        let count = numbers.len().to_string();
+                        i18n::notice_text(&room, "memory-forgot", &[("count", &count)]).await
+                    }
+                    Err(e) => i18n::error_text(&room, &e).await,
+                }
+            }
+            Ok(_) => i18n::notice_text(&room, "memory-missing", &[]).await,
+            Err(e) => i18n::error_text(&room, &e).await,
+        },
+        None => i18n::notice_text(&room, "memory-forget-usage", &[]).await,
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// List the room's memories, or have the model suggest some from the conversation
+///
+/// `!chaz memories [suggest|keep <n>...|keep all|clear]`
+pub async fn memories(sender: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let response = match args.subcommand() {
+        None => match load(&room).await {
+            Ok(memories) if memories.is_empty() => {
+                i18n::notice_text(&room, "memory-empty", &[]).await
+            }
+            Ok(memories) => {
+                let memories = numbered(&memories);
+                i18n::notice_text(&room, "memory-list", &[("memories", &memories)]).await
+            }
+            Err(e) => i18n::error_text(&room, &e).await,
+        },
+        Some("suggest") => match suggest(&room, &sender).await {
+            Some(response) => response,
+            // Rate limited, the user was already told
+            None => return Ok(()),
+        },
+        Some("keep") => {
+            let numbers: Vec<&str> = args["n"].split_whitespace().collect();
+            keep(&room, &numbers).await
+        }
+        _ => match save(&room, Vec::new()).await {
+            Ok(()) => i18n::notice_text(&room, "memory-cleared", &[]).await,
+            Err(e) => i18n::error_text(&room, &e).await,
+        },
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// Format memories as a numbered list
+fn numbered(memories: &[String]) -> String {
+    memories
+        .iter()
+        .enumerate()
+        .map(|(index, memory)| format!("{}. {}", index + 1, memory))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Ask the model for facts worth remembering from the conversation
+///
+/// Returns None if the sender was rate limited.
+async fn suggest(room: &Room, sender: &OwnedUserId) -> Option<String> {
+    if rate_limit(room, sender).await {
+        return None;
+    }
+    let mut context = match get_context(room).await {
+        Ok(context) => context,
+        Err(e) => return Some(i18n::error_text(room, &e.to_string()).await),
+    };
+    context.model = get_chat_summary_model().or(context.model);
+    context.messages.push(Message::new(
+        MessageRole::user,
+        [
+            "List the facts from the conversation above that are worth remembering in future conversations,",
+            "such as preferences, decisions, and details about the people and projects discussed.",
+            "Skip the facts you were already told to remember.",
+            "Write one short fact per line, with no numbering or other text.",
+            "Output NONE if there is nothing worth remembering.",
+        ]
+        .join(" "),
+    ));
+    router::route(room, &mut context).await;
+    let backend = get_backend(room, Some(sender)).await;
+    let started = Instant::now();
+    let result = activity::while_typing(room, backend.execute(&context)).await;
+    record_tokens(room.room_id(), sender, &backend, &context, &result, started);
+    let suggestions: Vec<String> = match result {
+        Ok(response) if moderation::blocks_output(room, &response).await => {
+            return Some(i18n::notice_text(room, "moderated-response", &[]).await)
+        }
+        Ok(response) => response
+            .lines()
+            .map(|line| {
+                line.trim()
+                    .trim_start_matches(['-', '*'])
+                    .trim()
+                    .to_string()
+            })
+            .filter(|line| !line.is_empty() && line != "NONE")
+            .collect(),
+        Err(e) => return Some(i18n::error_text(room, &e.replace('\n', " ")).await),
+    };
+    if suggestions.is_empty() {
+        SUGGESTED.lock().unwrap().remove(room.room_id());
+        return Some(i18n::notice_text(room, "memory-no-suggestions", &[]).await);
+    }
+    let memories = numbered(&suggestions);
+    let response = i18n::notice_text(room, "memory-suggestions", &[("memories", &memories)]).await;
+    SUGGESTED
+        .lock()
+        .unwrap()
+        .insert(room.room_id().to_owned(), suggestions);
+    Some(response)
+}
+
+/// Keep memories suggested by the model, by their number in the suggestions
+async fn keep(room: &Room, args: &[&str]) -> String {
+    let Some(suggestions) = SUGGESTED.lock().unwrap().get(room.room_id()).cloned() else {
+        return i18n::notice_text(room, "memory-keep-nothing", &[]).await;
+    };
+    let facts: Option<Vec<String>> = match args {
+        ["all"] => Some(suggestions),
+        _ => args
+            .iter()
+            .map(|n| {
+                let n = n.parse::<usize>().ok()?;
+                suggestions.get(n.checked_sub(1)?).cloned()
+            })
+            .collect(),
+    };
+    let Some(facts) = facts else {
+        return i18n::notice_text(room, "memory-keep-usage", &[]).await;
+    };
+    match add(room, facts).await {
+        Ok(added) => {
+            SUGGESTED.lock().unwrap().remove(room.room_id());
+            let added = added.to_string();
+            i18n::notice_text(room, "memory-kept", &[("count", &added)]).await
+        }
+        Err(e) => i18n::error_text(room, &e).await,
+    }
+}
+
+/// Add the room's memories to the start of the context
+pub async fn augment_context(room: &Room, context: &mut ChatContext) {
+    let memories = match load(room).await {
+        Ok(memories) => memories,
+        Err(e) => {
+            error!("Unable to load the memories of {}: {}", room.room_id(), e);
+            return;
+        }
+    };
+    if memories.is_empty() {
+        return;
+    }
+    context.messages.insert(
+        0,
+        Message::new(
+            MessageRole::system,
+            format!(
+                "Things to remember about this room:\n{}",
+                memories
+                    .iter()
+                    .map(|memory| format!("- {}", memory))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            ),
+        ),
+    );
+}
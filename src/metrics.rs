@@ -0,0 +1,213 @@
+/// Prometheus metrics
+///
+/// When `metrics_port` is set, the counters are served in the Prometheus text format at `/metrics`.
+use std::{collections::BTreeMap, fmt::Write as _, sync::Mutex, time::Duration};
+
+use lazy_static::lazy_static;
+use matrix_sdk::Client;
+
+use crate::http;
+
+/// Largest request accepted, the scrapes are plain GETs
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 9] = [0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0];
+
+/// Requests to a single backend and model
+#[derive(Default)]
+struct RequestStats {
+    requests: u64,
+    errors: u64,
+    /// Count of requests in each latency bucket, not cumulative
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    latency_sum: f64,
+}
+
+#[derive(Default)]
+struct Metrics {
+    messages: u64,
+    rate_limited: u64,
+    /// Commands that were run, by name
+    commands: BTreeMap<String, u64>,
+    /// Errors handling commands and messages, by what was being handled
+    errors: BTreeMap<String, u64>,
+    /// Backend requests by backend and model
+    requests: BTreeMap<(String, String), RequestStats>,
+}
+
+lazy_static! {
+    static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics::default());
+}
+
+/// Count a message that chaz handled
+pub fn record_message() {
+    METRICS.lock().unwrap().messages += 1;
+}
+
+/// Count a message rejected by the rate limit or quotas
+pub fn record_rate_limited() {
+    METRICS.lock().unwrap().rate_limited += 1;
+}
+
+/// Count a command that was run
+pub fn record_command(command: &str) {
+    *METRICS
+        .lock()
+        .unwrap()
+        .commands
+        .entry(command.to_string())
+        .or_default() += 1;
+}
+
+/// Count an error from a handler
+pub fn record_error(task: &str) {
+    *METRICS
+        .lock()
+        .unwrap()
+        .errors
+        .entry(task.to_string())
+        .or_default() += 1;
+}
+
+/// Count a request to a backend
+pub fn record_request(backend: &str, model: &str, latency: Duration, is_error: bool) {
+    let mut metrics = METRICS.lock().unwrap();
+    let stats = metrics
+        .requests
+        .entry((backend.to_string(), model.to_string()))
+        .or_default();
+    stats.requests += 1;
+    if is_error {
+        stats.errors += 1;
+    }
+    let seconds = latency.as_secs_f64();
+    stats.latency_sum += seconds;
+    if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+        stats.buckets[bucket] += 1;
+    }
+}
+
+/// Escape a label value for the text format
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Render the metrics in the Prometheus text format
+fn render(client: &Client) -> String {
+    let metrics = METRICS.lock().unwrap();
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "# HELP chaz_messages_total Messages handled by chaz\n# TYPE chaz_messages_total counter\nchaz_messages_total {}",
+        metrics.messages
+    );
+    let _ = writeln!(
+        out,
+        "# HELP chaz_rate_limited_total Messages rejected by the rate limit or quotas\n# TYPE chaz_rate_limited_total counter\nchaz_rate_limited_total {}",
+        metrics.rate_limited
+    );
+    let _ = writeln!(
+        out,
+        "# HELP chaz_rooms_joined Rooms chaz is a member of\n# TYPE chaz_rooms_joined gauge\nchaz_rooms_joined {}",
+        client.joined_rooms().len()
+    );
+    let _ = writeln!(
+        out,
+        "# HELP chaz_commands_total Commands run by chaz\n# TYPE chaz_commands_total counter"
+    );
+    for (command, count) in &metrics.commands {
+        let _ = writeln!(
+            out,
+            "chaz_commands_total{{command=\"{}\"}} {}",
+            label(command),
+            count
+        );
+    }
+    let _ = writeln!(
+        out,
+        "# HELP chaz_errors_total Errors handling commands and messages\n# TYPE chaz_errors_total counter"
+    );
+    for (task, count) in &metrics.errors {
+        let _ = writeln!(
+            out,
+            "chaz_errors_total{{task=\"{}\"}} {}",
+            label(task),
+            count
+        );
+    }
+    let _ = writeln!(
+        out,
+        "# HELP chaz_backend_requests_total Requests sent to the backends\n# TYPE chaz_backend_requests_total counter"
+    );
+    for ((backend, model), stats) in &metrics.requests {
+        let _ = writeln!(
+            out,
+            "chaz_backend_requests_total{{backend=\"{}\",model=\"{}\"}} {}",
+            label(backend),
+            label(model),
+            stats.requests
+        );
+    }
+    let _ = writeln!(
+        out,
+        "# HELP chaz_backend_errors_total Requests to the backends that failed\n# TYPE chaz_backend_errors_total counter"
+    );
+    for ((backend, model), stats) in &metrics.requests {
+        let _ = writeln!(
+            out,
+            "chaz_backend_errors_total{{backend=\"{}\",model=\"{}\"}} {}",
+            label(backend),
+            label(model),
+            stats.errors
+        );
+    }
+    let _ = writeln!(
+        out,
+        "# HELP chaz_backend_latency_seconds Time taken by the backend requests\n# TYPE chaz_backend_latency_seconds histogram"
+    );
+    for ((backend, model), stats) in &metrics.requests {
+        let labels = format!("backend=\"{}\",model=\"{}\"", label(backend), label(model));
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(stats.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "chaz_backend_latency_seconds_bucket{{{},le=\"{}\"}} {}",
+                labels, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "chaz_backend_latency_seconds_bucket{{{},le=\"+Inf\"}} {}\nchaz_backend_latency_seconds_sum{{{}}} {}\nchaz_backend_latency_seconds_count{{{}}} {}",
+            labels, stats.requests, labels, stats.latency_sum, labels, stats.requests
+        );
+    }
+    out
+}
+
+/// Serve the metrics on the port in the background
+pub fn serve(port: u16, client: Client) {
+    tokio::spawn(async move {
+        let Some(listener) = http::listen("metrics", "0.0.0.0", port).await else {
+            return;
+        };
+        http::serve(listener, MAX_REQUEST_SIZE, move |request| {
+            let client = client.clone();
+            async move {
+                if request.path != "/metrics" {
+                    return http::Response::text("404 Not Found", String::new());
+                }
+                http::Response {
+                    status: "200 OK",
+                    content_type: "text/plain; version=0.0.4",
+                    body: render(&client),
+                }
+            }
+        })
+        .await;
+    });
+}
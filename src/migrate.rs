@@ -0,0 +1,94 @@
+/// Migrations of room state
+///
+/// Older versions of chaz stored the model of a room only in the `!chaz model` messages in its history.
+/// This moves that into the `is.chaz.model` tags, which is what chaz reads now.
+use std::path::Path;
+
+use headjack::Tags;
+use matrix_sdk::{
+    room::MessagesOptions,
+    ruma::events::room::message::{MessageType, RoomMessageEventContent},
+    Client, Room,
+};
+use tracing::{error, info};
+
+use crate::get_backend;
+
+/// File in the state directory that marks the migration as done
+const MARKER_FILE: &str = "migrated-model-tags";
+
+/// Migrate every room once, the first time this version of chaz starts
+pub async fn migrate_once(client: &Client, state_dir: &Path) {
+    let marker = state_dir.join(MARKER_FILE);
+    if marker.exists() {
+        return;
+    }
+    let count = migrate_model_tags(client).await;
+    info!("Migrated the model of {} rooms to tags", count);
+    if let Err(e) = std::fs::write(&marker, "") {
+        error!("Unable to mark the model migration as done: {}", e);
+    }
+}
+
+/// Write the model set by `!chaz model` messages into the tags of every room
+///
+/// Returns the number of rooms that were migrated.
+pub async fn migrate_model_tags(client: &Client) -> usize {
+    let mut count = 0;
+    for room in client.joined_rooms() {
+        if migrate_room(&room).await {
+            count += 1;
+        }
+    }
+    count
+}
+
+/// Migrate a single room, returns true if a model tag was written
+///
+/// Rooms that already have a model tag are left alone.
+async fn migrate_room(room: &Room) -> bool {
+    let mut tags = Tags::new(room, "is.chaz.model").await;
+    if tags.get_value("default").is_some() {
+        return false;
+    }
+    let Some(model) = find_legacy_model(room).await else {
+        return false;
+    };
+    info!("Migrating model {} in {}", model, room.room_id());
+    tags.replace_kv("default", &model);
+    tags.sync().await;
+    true
+}
+
+/// Find the model that the room history selects
+///
+/// This is the most recent valid `!chaz model` message since the last `!chaz clear`.
+async fn find_legacy_model(room: &Room) -> Option<String> {
+    let backend = get_backend(room, None).await;
+    let mut options = MessagesOptions::backward();
+    while let Ok(batch) = room.messages(options).await {
+        for message in batch.chunk {
+            let Ok(Some(content)) = message
+                .event
+                .get_field::<RoomMessageEventContent>("content")
+            else {
+                continue;
+            };
+            let MessageType::Text(text_content) = content.msgtype else {
+                continue;
+            };
+            if text_content.body.starts_with("!chaz clear") {
+                return None;
+            }
+            if text_content.body.starts_with("!chaz model") {
+                if let Some(model) = text_content.body.split_whitespace().nth(2) {
+                    if backend.validate_model(model).await.is_ok() {
+                        return Some(model.to_string());
+                    }
+                }
+            }
+        }
+        options = MessagesOptions::backward().from(Some(batch.end?.as_str()));
+    }
+    None
+}
@@ -0,0 +1,214 @@
+/// Content moderation
+///
+/// Public instances can check the responses, and optionally the messages sent to chaz, before anything reaches
+/// the backend or the room. Text is matched against the keywords and regexes in the config, and sent to an OpenAI
+/// compatible moderation endpoint if one is set. Flagged text is handled according to the `action`, and is
+/// reported to the `admin_room`.
+use std::collections::BTreeMap;
+
+use matrix_sdk::{ruma::UserId, Room};
+use regex::Regex;
+use serde::Deserialize;
+use tracing::{error, warn};
+
+use crate::{get_config, notifications};
+
+/// Length of the excerpt of flagged text in the report to the admins
+const EXCERPT_LENGTH: usize = 200;
+
+/// What to do with flagged text
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Drop it without posting anything
+    Block,
+    /// Post a notice in its place
+    #[default]
+    Redact,
+    /// Let it through, only reporting it to the admins
+    Flag,
+}
+
+/// Configuration for moderation
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct ModerationConfig {
+    /// Words that flag the text, matched as whole words ignoring case
+    pub keywords: Option<Vec<String>>,
+    /// Regular expressions that flag the text
+    pub patterns: Option<Vec<String>>,
+    /// Base URL of an OpenAI compatible moderation API, e.g. https://api.openai.com/v1
+    pub api_base: Option<String>,
+    pub api_key: Option<String>,
+    /// Moderation model to request, e.g. omni-moderation-latest
+    pub model: Option<String>,
+    /// What to do with flagged text, defaults to redact
+    pub action: Option<Action>,
+    /// Check the messages sent to chaz as well as the responses
+    pub check_input: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct ModerationResponse {
+    results: Vec<ModerationResult>,
+}
+
+#[derive(Deserialize)]
+struct ModerationResult {
+    flagged: bool,
+    #[serde(default)]
+    categories: BTreeMap<String, bool>,
+}
+
+impl ModerationConfig {
+    /// Check the text, returning why it was flagged
+    ///
+    /// If the moderation endpoint fails the text is flagged, so nothing gets through unchecked.
+    async fn check(&self, text: &str) -> Option<String> {
+        for keyword in self.keywords.iter().flatten() {
+            let pattern = format!(r"(?i)\b{}\b", regex::escape(keyword));
+            if Regex::new(&pattern).is_ok_and(|regex| regex.is_match(text)) {
+                return Some(format!("keyword \"{}\"", keyword));
+            }
+        }
+        for pattern in self.patterns.iter().flatten() {
+            match Regex::new(pattern) {
+                Ok(regex) if regex.is_match(text) => {
+                    return Some(format!("pattern \"{}\"", pattern));
+                }
+                Ok(_) => {}
+                Err(e) => error!("Invalid moderation pattern {}: {}", pattern, e),
+            }
+        }
+        if self.api_base.is_some() {
+            return match self.check_endpoint(text).await {
+                Ok(reason) => reason,
+                Err(e) => {
+                    error!("Moderation endpoint failed: {}", e);
+                    Some(format!("the moderation endpoint failed: {}", e))
+                }
+            };
+        }
+        None
+    }
+
+    /// Ask the moderation endpoint about the text
+    async fn check_endpoint(&self, text: &str) -> Result<Option<String>, String> {
+        let api_base = self.api_base.as_deref().unwrap_or_default();
+        let mut request = serde_json::json!({ "input": text });
+        if let Some(model) = &self.model {
+            request["model"] = serde_json::Value::String(model.clone());
+        }
+        let mut request = reqwest::Client::new()
+            .post(format!("{}/moderations", api_base.trim_end_matches('/')))
+            .json(&request);
+        if let Some(api_key) = &self.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("{}: {}", status, body));
+        }
+        let response = response
+            .json::<ModerationResponse>()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(response
+            .results
+            .into_iter()
+            .find(|result| result.flagged)
+            .map(|result| {
+                let categories: Vec<String> = result
+                    .categories
+                    .into_iter()
+                    .filter(|(_, flagged)| *flagged)
+                    .map(|(category, _)| category)
+                    .collect();
+                if categories.is_empty() {
+                    "the moderation endpoint".to_string()
+                } else {
+                    format!("the moderation endpoint ({})", categories.join(", "))
+                }
+            }))
+    }
+}
+
+/// Check a response before it's posted, returning the action to take if it was flagged
+pub async fn check_response(room: &Room, text: &str) -> Option<Action> {
+    let config = get_config().moderation?;
+    let reason = config.check(text).await?;
+    let action = config.action.unwrap_or_default();
+    report(room, None, "response", &reason, action, text).await;
+    Some(action)
+}
+
+/// Check model output that's posted outside of a response, returning true if it can't be posted as it is
+///
+/// For output that has no notice to stand in for it, like a room name, both blocking and redacting drop it.
+pub async fn blocks_output(room: &Room, text: &str) -> bool {
+    matches!(
+        check_response(room, text).await,
+        Some(Action::Block | Action::Redact)
+    )
+}
+
+/// Check a response from an appservice persona, which has no client to notify the admins with
+pub async fn check_persona_response(room_id: &str, text: &str) -> Option<Action> {
+    let config = get_config().moderation?;
+    let reason = config.check(text).await?;
+    let action = config.action.unwrap_or_default();
+    warn!(
+        "Moderation: flagged a persona response in {}, flagged by {}",
+        room_id, reason
+    );
+    Some(action)
+}
+
+/// Check a message before it's sent to the backend, returning the action to take if it was flagged
+///
+/// Messages are only checked if `check_input` is set.
+pub async fn check_message(room: &Room, sender: &UserId, text: &str) -> Option<Action> {
+    let config = get_config().moderation?;
+    if !config.check_input.unwrap_or(false) {
+        return None;
+    }
+    let reason = config.check(text).await?;
+    let action = config.action.unwrap_or_default();
+    report(room, Some(sender), "message", &reason, action, text).await;
+    Some(action)
+}
+
+/// Log flagged text, and let the admins know about it
+async fn report(
+    room: &Room,
+    sender: Option<&UserId>,
+    kind: &str,
+    reason: &str,
+    action: Action,
+    text: &str,
+) {
+    let from = sender
+        .map(|sender| format!(" from {}", sender))
+        .unwrap_or_default();
+    let action = match action {
+        Action::Block => "blocked",
+        Action::Redact => "redacted",
+        Action::Flag => "let through",
+    };
+    let summary = format!(
+        "Moderation: {} a {}{} in {}, flagged by {}",
+        action,
+        kind,
+        from,
+        room.room_id(),
+        reason
+    );
+    warn!("{}", summary);
+    let mut excerpt: String = text.chars().take(EXCERPT_LENGTH).collect();
+    if excerpt.len() < text.len() {
+        excerpt.push('…');
+    }
+    let notice = format!("{}:\n> {}", summary, excerpt.replace('\n', "\n> "));
+    notifications::notify(&room.client(), &notice).await;
+}
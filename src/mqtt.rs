@@ -0,0 +1,372 @@
+/// MQTT integration
+///
+/// With `mqtt` set, chaz connects to an MQTT broker, e.g. the one used by Home Assistant or zigbee2mqtt, and
+/// follows the state topics of the configured entities. In the rooms listed in the config, the model is offered
+/// tools to read those states and to publish to the command topics, so users can ask "is the garage open?" or
+/// "turn off the porch light". The client is a minimal MQTT 3.1.1 one, over TCP or TLS, with QoS 0.
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use lazy_static::lazy_static;
+use matrix_sdk::Room;
+use reqwest::Url;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc,
+};
+use tokio_native_tls::{native_tls, TlsConnector};
+use tracing::{error, info};
+
+use crate::get_config;
+
+/// Seconds between the keep alive pings
+const KEEP_ALIVE: u16 = 60;
+
+/// Longest wait between reconnection attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Largest packet accepted from the broker
+const MAX_PACKET_SIZE: usize = 256 * 1024;
+
+/// Configuration for the MQTT integration
+#[derive(Debug, Deserialize, Clone)]
+pub struct MqttConfig {
+    /// URL of the broker, e.g. mqtt://localhost:1883, or mqtts://broker.example.com:8883 for TLS
+    pub broker: String,
+    /// Path to a PEM encoded CA bundle to trust for an mqtts:// broker, e.g. for a self-signed certificate
+    pub ca_bundle: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Client ID sent to the broker, defaults to chaz
+    pub client_id: Option<String>,
+    /// The things the model can see and control
+    pub entities: Vec<Entity>,
+    /// Room IDs where the model gets the home tools
+    pub rooms: Vec<String>,
+}
+
+/// Something in the home, with the topics it's read and controlled through
+#[derive(Debug, Deserialize, Clone)]
+pub struct Entity {
+    /// Name the model uses, e.g. "garage door"
+    pub name: String,
+    /// Topic the state is published to, the last message is the current state
+    pub state_topic: Option<String>,
+    /// Topic to publish commands to
+    pub command_topic: Option<String>,
+    /// What the entity is and the commands it takes, shown to the model, e.g. "send ON or OFF"
+    pub description: Option<String>,
+    /// The only payloads that can be published to the command topic, e.g. ["ON", "OFF"]
+    ///
+    /// Without it, the model can publish anything.
+    pub payloads: Option<Vec<String>>,
+}
+
+/// A connection to the broker, over TCP or TLS
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+lazy_static! {
+    /// The last message received on each state topic
+    static ref STATES: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+
+    /// Sends messages to the broker while connected, as (topic, payload)
+    static ref PUBLISHER: Mutex<Option<mpsc::UnboundedSender<(String, String)>>> = Mutex::new(None);
+}
+
+/// Check if the model gets the home tools in the room
+pub fn is_enabled(room: &Room) -> bool {
+    get_config()
+        .mqtt
+        .is_some_and(|mqtt| mqtt.rooms.iter().any(|id| id == room.room_id().as_str()))
+}
+
+/// Describe the state of every entity, or of the one with the name
+pub fn describe_states(name: Option<&str>) -> Result<String, String> {
+    let config = get_config().mqtt.ok_or("MQTT isn't configured")?;
+    let states = STATES.lock().unwrap();
+    let lines: Vec<String> = config
+        .entities
+        .iter()
+        .filter(|entity| name.is_none_or(|name| entity.name.eq_ignore_ascii_case(name)))
+        .map(|entity| {
+            let state = entity
+                .state_topic
+                .as_ref()
+                .and_then(|topic| states.get(topic))
+                .map_or("unknown", String::as_str);
+            let line = match &entity.description {
+                Some(description) => format!("{} ({}): {}", entity.name, description, state),
+                None => format!("{}: {}", entity.name, state),
+            };
+            match &entity.payloads {
+                Some(payloads) => format!("{}, takes {}", line, payloads.join(", ")),
+                None => line,
+            }
+        })
+        .collect();
+    if lines.is_empty() {
+        return Err(format!("no entity named {}", name.unwrap_or_default()));
+    }
+    Ok(lines.join("\n"))
+}
+
+/// Publish a command to an entity
+pub fn send_command(name: &str, payload: &str) -> Result<String, String> {
+    let config = get_config().mqtt.ok_or("MQTT isn't configured")?;
+    let entity = config
+        .entities
+        .iter()
+        .find(|entity| entity.name.eq_ignore_ascii_case(name))
+        .ok_or(format!("no entity named {}", name))?;
+    let topic = entity
+        .command_topic
+        .clone()
+        .ok_or(format!("{} can't be controlled", entity.name))?;
+    if let Some(payloads) = &entity.payloads {
+        if !payloads.iter().any(|allowed| allowed == payload) {
+            return Err(format!(
+                "{} only takes {}",
+                entity.name,
+                payloads.join(", ")
+            ));
+        }
+    }
+    PUBLISHER
+        .lock()
+        .unwrap()
+        .as_ref()
+        .ok_or("not connected to the MQTT broker")?
+        .send((topic, payload.to_string()))
+        .map_err(|_| "not connected to the MQTT broker")?;
+    info!("Sent {} to {}", payload, entity.name);
+    Ok(format!("Sent {} to {}", payload, entity.name))
+}
+
+/// Stay connected to the broker in the background, if `mqtt` is configured
+///
+/// The broker and the topics are read on startup, changing them needs a restart.
+pub fn start() {
+    let Some(config) = get_config().mqtt else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut backoff = Duration::from_secs(1);
+        loop {
+            if let Err(e) = run(&config).await {
+                error!("MQTT connection to {} failed: {}", config.broker, e);
+            }
+            // Retry quickly if the connection worked for a while
+            if PUBLISHER.lock().unwrap().take().is_some() {
+                backoff = Duration::from_secs(1);
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    });
+}
+
+/// Connect, subscribe, and handle the connection until it's closed
+async fn run(config: &MqttConfig) -> Result<(), String> {
+    let url = Url::parse(&config.broker).map_err(|e| e.to_string())?;
+    let host = url.host_str().ok_or("the broker URL has no host")?;
+    let stream: Box<dyn Connection> = match url.scheme() {
+        "mqtt" => Box::new(
+            TcpStream::connect((host, url.port().unwrap_or(1883)))
+                .await
+                .map_err(|e| e.to_string())?,
+        ),
+        "mqtts" => {
+            let stream = TcpStream::connect((host, url.port().unwrap_or(8883)))
+                .await
+                .map_err(|e| e.to_string())?;
+            Box::new(
+                tls_connector(config)?
+                    .connect(host, stream)
+                    .await
+                    .map_err(|e| e.to_string())?,
+            )
+        }
+        _ => return Err("only mqtt:// and mqtts:// brokers are supported".to_string()),
+    };
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    writer
+        .write_all(&connect_packet(config))
+        .await
+        .map_err(|e| e.to_string())?;
+    let (header, body) = read_packet(&mut reader).await?;
+    if header >> 4 != 2 || body.get(1) != Some(&0) {
+        return Err(format!("the broker refused the connection: {:?}", body));
+    }
+    let topics: Vec<&str> = config
+        .entities
+        .iter()
+        .filter_map(|entity| entity.state_topic.as_deref())
+        .collect();
+    if !topics.is_empty() {
+        writer
+            .write_all(&subscribe_packet(&topics))
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    info!("Connected to the MQTT broker {}", config.broker);
+
+    let (sender, mut outgoing) = mpsc::unbounded_channel();
+    *PUBLISHER.lock().unwrap() = Some(sender);
+    let mut incoming = tokio::spawn(async move {
+        loop {
+            let (header, body) = read_packet(&mut reader).await?;
+            if header >> 4 == 3 {
+                handle_publish(header, &body);
+            }
+        }
+    });
+    let mut ping = tokio::time::interval(Duration::from_secs(u64::from(KEEP_ALIVE) / 2));
+    loop {
+        let packet = tokio::select! {
+            result = &mut incoming => {
+                return result.map_err(|e| e.to_string()).and_then(|result: Result<(), String>| result);
+            }
+            _ = ping.tick() => vec![0xc0, 0],
+            Some((topic, payload)) = outgoing.recv() => publish_packet(&topic, &payload),
+        };
+        if let Err(e) = writer.write_all(&packet).await {
+            incoming.abort();
+            return Err(e.to_string());
+        }
+    }
+}
+
+/// Build the TLS connector for an mqtts:// broker, trusting the CA bundle if there's one
+fn tls_connector(config: &MqttConfig) -> Result<TlsConnector, String> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(ca_bundle) = &config.ca_bundle {
+        let pem = std::fs::read(ca_bundle)
+            .map_err(|e| format!("unable to read the CA bundle {}: {}", ca_bundle, e))?;
+        let certificate = native_tls::Certificate::from_pem(&pem).map_err(|e| e.to_string())?;
+        builder.add_root_certificate(certificate);
+    }
+    Ok(TlsConnector::from(
+        builder.build().map_err(|e| e.to_string())?,
+    ))
+}
+
+/// Record the state from a PUBLISH packet
+fn handle_publish(header: u8, body: &[u8]) {
+    let Some((topic, rest)) = read_string(body) else {
+        return;
+    };
+    // QoS 1 and 2 messages have a packet ID, but QoS 0 is all that's subscribed to
+    let payload = if header & 0x06 != 0 {
+        rest.get(2..).unwrap_or_default()
+    } else {
+        rest
+    };
+    let payload = String::from_utf8_lossy(payload).to_string();
+    STATES.lock().unwrap().insert(topic, payload);
+}
+
+/// Read a packet, returning the first byte of the fixed header and the rest of the packet
+async fn read_packet(reader: &mut (impl AsyncRead + Unpin)) -> Result<(u8, Vec<u8>), String> {
+    let header = reader.read_u8().await.map_err(|e| e.to_string())?;
+    let mut length = 0usize;
+    for shift in 0..4 {
+        let byte = reader.read_u8().await.map_err(|e| e.to_string())?;
+        length |= usize::from(byte & 0x7f) << (7 * shift);
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    if length > MAX_PACKET_SIZE {
+        return Err(format!("packet of {} bytes is too large", length));
+    }
+    let mut body = vec![0; length];
+    reader
+        .read_exact(&mut body)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok((header, body))
+}
+
+/// Split a length prefixed string off the start of the data
+fn read_string(data: &[u8]) -> Option<(String, &[u8])> {
+    let length = usize::from(u16::from_be_bytes([*data.first()?, *data.get(1)?]));
+    let string = data.get(2..2 + length)?;
+    Some((
+        String::from_utf8_lossy(string).to_string(),
+        &data[2 + length..],
+    ))
+}
+
+/// Build a packet from the first byte of the fixed header and the rest of the packet
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut length = body.len();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Append a length prefixed string
+fn push_string(data: &mut Vec<u8>, string: &str) {
+    data.extend_from_slice(&(string.len() as u16).to_be_bytes());
+    data.extend_from_slice(string.as_bytes());
+}
+
+fn connect_packet(config: &MqttConfig) -> Vec<u8> {
+    let mut body = Vec::new();
+    push_string(&mut body, "MQTT");
+    body.push(4);
+    // A password can only be sent with a username
+    let password = config
+        .password
+        .as_ref()
+        .filter(|_| config.username.is_some());
+    // Clean session, and the flags for the credentials that are sent
+    let mut flags = 0x02;
+    if config.username.is_some() {
+        flags |= 0x80;
+    }
+    if password.is_some() {
+        flags |= 0x40;
+    }
+    body.push(flags);
+    body.extend_from_slice(&KEEP_ALIVE.to_be_bytes());
+    push_string(&mut body, config.client_id.as_deref().unwrap_or("chaz"));
+    if let Some(username) = &config.username {
+        push_string(&mut body, username);
+    }
+    if let Some(password) = password {
+        push_string(&mut body, password);
+    }
+    packet(0x10, &body)
+}
+
+fn subscribe_packet(topics: &[&str]) -> Vec<u8> {
+    let mut body = 1u16.to_be_bytes().to_vec();
+    for topic in topics {
+        push_string(&mut body, topic);
+        body.push(0);
+    }
+    packet(0x82, &body)
+}
+
+fn publish_packet(topic: &str, payload: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    push_string(&mut body, topic);
+    body.extend_from_slice(payload.as_bytes());
+    packet(0x30, &body)
+}
@@ -0,0 +1,81 @@
+/// Member names
+///
+/// Remembers what each member of a room wants to be called, so the model addresses everyone consistently.
+/// Names stated with `!chaz name` take precedence over display names picked up from profile changes.
+/// They are stored in the room tags under `is.chaz.names`.
+use std::collections::BTreeMap;
+
+use headjack::Tags;
+use matrix_sdk::{
+    ruma::events::room::member::{MembershipState, OriginalSyncRoomMemberEvent},
+    Room,
+};
+
+/// Tag namespace for the names
+const NAMESPACE: &str = "is.chaz.names";
+
+/// Set the name a member prefers, or clear it with None
+pub async fn set_preferred(room: &Room, user: &str, name: Option<&str>) {
+    let mut tags = Tags::new(room, NAMESPACE).await;
+    let key = format!("{}.preferred", user);
+    match name {
+        Some(name) => tags.replace_kv(&key, name),
+        None => tags.remove_kv(&key),
+    }
+    tags.sync().await;
+}
+
+/// Record display name changes of the members
+pub async fn on_member_event(event: OriginalSyncRoomMemberEvent, room: Room) {
+    if event.content.membership != MembershipState::Join {
+        return;
+    }
+    let Some(name) = event.content.displayname else {
+        return;
+    };
+    let previous = event
+        .unsigned
+        .prev_content
+        .and_then(|content| content.displayname);
+    if previous.as_deref() == Some(name.as_str()) {
+        return;
+    }
+    let mut tags = Tags::new(&room, NAMESPACE).await;
+    let key = format!("{}.display", event.state_key);
+    if tags.get_value(&key).as_deref() != Some(name.as_str()) {
+        tags.replace_kv(&key, &name);
+        tags.sync().await;
+    }
+}
+
+/// Get the name for each known member of the room, preferring the ones they asked for
+pub async fn get_names(room: &Room) -> BTreeMap<String, String> {
+    let tags = Tags::new(room, NAMESPACE).await;
+    let kvs = tags.get_kvs();
+    let mut names = BTreeMap::new();
+    for (key, name) in &kvs {
+        if let Some(user) = key.strip_suffix(".display") {
+            names.entry(user.to_string()).or_insert(name.clone());
+        } else if let Some(user) = key.strip_suffix(".preferred") {
+            names.insert(user.to_string(), name.clone());
+        }
+    }
+    names
+}
+
+/// Instructions for the system prompt telling the model how to address each member
+pub async fn names_prompt(room: &Room) -> Option<String> {
+    let names = get_names(room).await;
+    if names.is_empty() {
+        return None;
+    }
+    let list = names
+        .iter()
+        .map(|(user, name)| format!("{} ({})", name, user))
+        .collect::<Vec<String>>()
+        .join(", ");
+    Some(format!(
+        "Always address the members of this conversation by these names: {}.",
+        list
+    ))
+}
@@ -0,0 +1,30 @@
+/// Notifications for the admins
+///
+/// What the operators should know about, like startup, errors, rate limits, invites, and moderation flags, is
+/// posted to the `admin_room` as notices.
+use matrix_sdk::{ruma::events::room::message::RoomMessageEventContent, Client};
+use tracing::error;
+
+use crate::get_admin_room;
+
+/// Post a notice to the admin room, if one is configured
+pub async fn notify(client: &Client, message: &str) {
+    let Some(room) = get_admin_room(client) else {
+        return;
+    };
+    if let Err(e) = room
+        .send(RoomMessageEventContent::notice_plain(format!(
+            "!chaz {}",
+            message
+        )))
+        .await
+    {
+        error!("Unable to post to the admin room: {}", e);
+    }
+}
+
+/// Post a notice to the admin room without waiting for it
+pub fn notify_later(client: &Client, message: String) {
+    let client = client.clone();
+    tokio::spawn(async move { notify(&client, &message).await });
+}
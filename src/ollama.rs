@@ -0,0 +1,356 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lazy_static::lazy_static;
+use openai_api_rs::v1::chat_completion::MessageRole;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+/// Ollama Backend
+///
+/// Talks directly to the Ollama REST API as a backend for chaz.
+use crate::{
+    backends::LLMBackend, capabilities::Capabilities, parse_duration, Backend, ChatContext,
+};
+
+lazy_static! {
+    /// The capabilities reported by the servers, by the URL of the server and the model
+    static ref PROBED: Mutex<HashMap<String, Capabilities>> = Mutex::new(HashMap::new());
+
+    /// The models pulled on each server, by its URL, with when they were queried
+    static ref PULLED: Mutex<HashMap<String, (Instant, Vec<String>)>> = Mutex::new(HashMap::new());
+}
+
+/// How long the models pulled on a server are reused by default
+const DEFAULT_MODELS_CACHE_TTL: Duration = Duration::from_secs(3600);
+
+/// The default location of a local Ollama server
+const DEFAULT_API_BASE: &str = "http://localhost:11434";
+
+/// Handle connections to an Ollama server
+pub struct Ollama {
+    /// Stores the full info given in the config file
+    backend: Backend,
+    /// Sends the requests, built once so they share its connections
+    client: reqwest::Client,
+}
+
+impl Ollama {
+    /// Create the backend, failing if its TLS, proxy, or header options are invalid
+    pub fn new(backend: &Backend) -> Result<Self, String> {
+        Ok(Ollama {
+            client: backend.http_client()?,
+            backend: backend.clone(),
+        })
+    }
+
+    /// Get the base URL of the Ollama server
+    fn api_base(&self) -> String {
+        self.backend
+            .api_base
+            .clone()
+            .unwrap_or(DEFAULT_API_BASE.to_string())
+            .trim_end_matches('/')
+            .to_string()
+    }
+
+    /// Get the models that have been pulled on the server
+    ///
+    /// The list is cached for the `models_cache_ttl`, so requests without a model don't each query the server.
+    async fn pulled_models(&self) -> Result<Vec<String>, String> {
+        let api_base = self.api_base();
+        let ttl = self
+            .backend
+            .models_cache_ttl
+            .as_deref()
+            .and_then(parse_duration)
+            .unwrap_or(DEFAULT_MODELS_CACHE_TTL);
+        if let Some((queried, models)) = PULLED.lock().unwrap().get(&api_base) {
+            if queried.elapsed() < ttl {
+                return Ok(models.clone());
+            }
+        }
+        let models = self.query_models().await?;
+        PULLED
+            .lock()
+            .unwrap()
+            .insert(api_base, (Instant::now(), models.clone()));
+        Ok(models)
+    }
+
+    /// Query the server for the models that have been pulled
+    async fn query_models(&self) -> Result<Vec<String>, String> {
+        let response = self
+            .client
+            .get(format!("{}/api/tags", self.api_base()))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?
+            .json::<TagsResponse>()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(response.models.into_iter().map(|m| m.name).collect())
+    }
+}
+
+/// Response from /api/tags
+#[derive(Deserialize)]
+struct TagsResponse {
+    models: Vec<TagsModel>,
+}
+
+#[derive(Deserialize)]
+struct TagsModel {
+    name: String,
+}
+
+/// Request for /api/chat
+#[derive(Serialize)]
+struct ChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    stream: bool,
+    options: ChatOptions,
+    /// JSON schema the response has to match
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<serde_json::Value>,
+}
+
+/// Generation options for /api/chat, unset options use the model's defaults
+#[derive(Serialize)]
+struct ChatOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    /// Maximum number of tokens to generate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_predict: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<Vec<String>>,
+}
+
+/// A single message in the Ollama chat format
+#[derive(Serialize, Deserialize)]
+struct ChatMessage {
+    role: String,
+    content: String,
+    /// Base64 encoded images, used by multimodal models
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+}
+
+/// Response from /api/chat
+#[derive(Deserialize)]
+struct ChatResponse {
+    message: ChatMessage,
+}
+
+/// Response from /api/show, with the details of a model
+#[derive(Deserialize)]
+struct ShowResponse {
+    /// What the model supports, e.g. "vision" and "tools", only sent by newer servers
+    capabilities: Option<Vec<String>>,
+    /// Metadata of the model, including "<architecture>.context_length"
+    #[serde(default)]
+    model_info: HashMap<String, serde_json::Value>,
+}
+
+#[async_trait]
+impl LLMBackend for Ollama {
+    /// List the models pulled on the Ollama host
+    ///
+    /// Falls back to the models listed in the config if the server can't be reached.
+    async fn list_models(&self) -> Vec<String> {
+        match self.pulled_models().await {
+            Ok(models) => models,
+            Err(_) => self
+                .backend
+                .models
+                .clone()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|model| model.name)
+                .collect(),
+        }
+    }
+
+    /// Get the default model for this backend
+    ///
+    /// It's the first model in the config, or the first model on the server.
+    async fn default_model(&self) -> Option<String> {
+        if let Some(models) = &self.backend.models {
+            if !models.is_empty() {
+                return Some(models[0].name.clone());
+            }
+        }
+        self.list_models().await.into_iter().next()
+    }
+
+    /// Execute a chat request with this backend
+    async fn execute(&self, context: &ChatContext) -> Result<String, String> {
+        let request = self.build_request(context, true).await?;
+        let response = self
+            .client
+            .post(format!("{}/api/chat", self.api_base()))
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("{}: {}", status, body));
+        }
+        let response = response
+            .json::<ChatResponse>()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(response.message.content)
+    }
+
+    /// Ask the server what the model supports
+    ///
+    /// The answers are cached, since they only change when the model is pulled again.
+    async fn probe_capabilities(&self, model: Option<&str>) -> Capabilities {
+        let Some(model) = self.model_name(model).await else {
+            return Capabilities::default();
+        };
+        let key = format!("{}/{}", self.api_base(), model);
+        if let Some(capabilities) = PROBED.lock().unwrap().get(&key) {
+            return capabilities.clone();
+        }
+        let capabilities = match self.show_model(&model).await {
+            Ok(show) => Capabilities {
+                supports_vision: show
+                    .capabilities
+                    .as_ref()
+                    .map(|c| c.iter().any(|c| c == "vision")),
+                supports_tools: show
+                    .capabilities
+                    .as_ref()
+                    .map(|c| c.iter().any(|c| c == "tools")),
+                supports_system: None,
+                max_context: show
+                    .model_info
+                    .iter()
+                    .find(|(key, _)| key.ends_with(".context_length"))
+                    .and_then(|(_, length)| length.as_u64())
+                    .map(|length| length as usize),
+            },
+            Err(e) => {
+                // Not cached, so it's asked again once the server is back
+                error!("Unable to get the capabilities of {}: {}", model, e);
+                return Capabilities::default();
+            }
+        };
+        PROBED.lock().unwrap().insert(key, capabilities.clone());
+        capabilities
+    }
+
+    /// The request body, without the images
+    async fn describe_request(&self, context: &ChatContext) -> Result<serde_json::Value, String> {
+        let request = self.build_request(context, false).await?;
+        let mut request = serde_json::to_value(request).map_err(|e| e.to_string())?;
+        request["media"] = context.media.len().into();
+        Ok(request)
+    }
+}
+
+impl Ollama {
+    /// Get the name of the model on the server, without the backend name, or the default model
+    async fn model_name(&self, model: Option<&str>) -> Option<String> {
+        let model_prefix = self.backend.name.clone().unwrap_or("ollama".to_string());
+        let model = model
+            .unwrap_or_default()
+            .trim_start_matches(&format!("{}:", model_prefix));
+        if model.is_empty() {
+            self.default_model().await
+        } else {
+            Some(model.to_string())
+        }
+    }
+
+    /// Get the details of a model from /api/show
+    async fn show_model(&self, model: &str) -> Result<ShowResponse, String> {
+        self.client
+            .post(format!("{}/api/show", self.api_base()))
+            .json(&serde_json::json!({ "model": model }))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?
+            .error_for_status()
+            .map_err(|e| e.to_string())?
+            .json::<ShowResponse>()
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Build the request for /api/chat, reading the media files into it if `read_images` is set
+    async fn build_request(
+        &self,
+        context: &ChatContext,
+        read_images: bool,
+    ) -> Result<ChatRequest, String> {
+        let model = self
+            .model_name(context.model.as_deref())
+            .await
+            .ok_or("No model available".to_string())?;
+        let params = context.params.clone().or(self.backend.model_params(&model));
+
+        let mut messages = Vec::new();
+        // Add the role
+        if let Some(role) = &context.role {
+            messages.push(ChatMessage {
+                role: "system".to_string(),
+                content: role.get_prompt(),
+                images: Vec::new(),
+            });
+        }
+        // Add all the messages, attaching the media files in the positions they were sent
+        let mut media = context.media.iter();
+        for message in &context.messages {
+            let mut images = Vec::new();
+            if message.attached_media && read_images {
+                if let Some(file) = media.next() {
+                    let data = tokio::fs::read(file.path())
+                        .await
+                        .map_err(|e| e.to_string())?;
+                    images.push(STANDARD.encode(data));
+                }
+            }
+            messages.push(ChatMessage {
+                role: match message.role {
+                    MessageRole::assistant => "assistant",
+                    MessageRole::system => "system",
+                    MessageRole::function => "tool",
+                    _ => "user",
+                }
+                .to_string(),
+                content: message.attributed_content(),
+                images,
+            });
+        }
+
+        Ok(ChatRequest {
+            options: ChatOptions {
+                temperature: params.temperature,
+                top_p: params.top_p,
+                num_predict: params.max_tokens,
+                frequency_penalty: params.frequency_penalty,
+                stop: params.stop,
+            },
+            model,
+            messages,
+            stream: false,
+            format: context.schema.clone(),
+        })
+    }
+}
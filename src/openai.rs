@@ -1,85 +1,417 @@
-use openai_api_rs::v1::{
-    api::OpenAIClient,
-    chat_completion::{self, ChatCompletionMessage, ChatCompletionRequest, MessageRole},
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use openai_api_rs::v1::chat_completion::{
+    self, ChatCompletionMessage, ChatCompletionRequest, ChatCompletionResponse, MessageRole,
+};
+use reqwest::header::HeaderMap;
+use serde::Deserialize;
+use tracing::{error, info};
+
 /// OpenAI Compatible Backend
 ///
 /// Communicates over the OpenAI API as a backend for chaz.
-use crate::{backends::LLMBackend, Backend, ChatContext};
+use crate::{
+    backends::LLMBackend,
+    capabilities::Capabilities,
+    parse_duration,
+    tools::{self, ToolName},
+    Backend, ChatContext,
+};
+
+/// Maximum number of rounds of tool calls for a single response
+const MAX_TOOL_ROUNDS: usize = 5;
+
+/// How long the models queried from a backend are reused by default
+const DEFAULT_MODELS_CACHE_TTL: Duration = Duration::from_secs(3600);
+
+/// How long the backend has to list its models
+const MODELS_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    /// The models served by each backend, by the API base, with when they were queried
+    static ref SERVED_MODELS: Mutex<HashMap<String, (Instant, Vec<String>)>> = Mutex::new(HashMap::new());
+}
+
+/// Response from the models API
+#[derive(Deserialize)]
+struct ModelsResponse {
+    data: Vec<ModelsData>,
+}
+
+#[derive(Deserialize)]
+struct ModelsData {
+    id: String,
+}
+
+/// Response from the embeddings API
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
 
 /// Handle connections to an OpenAI compatible backend
 pub struct OpenAI {
     /// Stores the full info given in the config file
     backend: Backend,
+    /// Sends the requests, built once so they share its connections
+    client: reqwest::Client,
+    /// Headers sent with every request, on top of the authorization
+    headers: HeaderMap,
+    /// Ask for the cost in the usage of each response, and add it to the context
+    report_cost: bool,
 }
 
 impl OpenAI {
-    pub fn new(backend: &Backend) -> Self {
-        OpenAI {
+    /// Create the backend, failing if its TLS, proxy, or header options are invalid
+    pub fn new(backend: &Backend) -> Result<Self, String> {
+        Ok(OpenAI {
+            client: backend.http_client()?,
             backend: backend.clone(),
+            headers: HeaderMap::new(),
+            report_cost: false,
+        })
+    }
+
+    /// Get the HTTP client the requests are sent with
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// Send these headers with every request
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Ask the backend for the cost of each request, for APIs that report it like OpenRouter
+    pub fn with_reported_cost(mut self) -> Self {
+        self.report_cost = true;
+        self
+    }
+
+    /// Get embeddings for a batch of inputs
+    ///
+    /// Returns one embedding per input, in the same order.
+    pub async fn embed(&self, model: &str, inputs: &[String]) -> Result<Vec<Vec<f32>>, String> {
+        let api_key = self.backend.api_key.clone().unwrap_or_default();
+        let api_base = match self.backend.api_base.clone() {
+            Some(base) => base,
+            None => return Err("API base doesn't exist".to_string()),
+        };
+        let response = self
+            .client
+            .post(format!("{}/embeddings", api_base.trim_end_matches('/')))
+            .headers(self.headers.clone())
+            .bearer_auth(api_key)
+            .json(&serde_json::json!({ "model": model, "input": inputs }))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("{}: {}", status, body));
+        }
+        let response = response
+            .json::<EmbeddingResponse>()
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut data = response.data;
+        data.sort_by_key(|d| d.index);
+        Ok(data.into_iter().map(|d| d.embedding).collect())
+    }
+
+    /// Get the models the backend serves, from GET /models, if `query_models` is set
+    ///
+    /// The list is cached for the `models_cache_ttl`. Errors are logged and give an empty list.
+    async fn served_models(&self) -> Vec<String> {
+        if !self.backend.query_models.unwrap_or(false) {
+            return Vec::new();
+        }
+        let Some(api_base) = self.backend.api_base.clone() else {
+            return Vec::new();
+        };
+        let ttl = self
+            .backend
+            .models_cache_ttl
+            .as_deref()
+            .and_then(parse_duration)
+            .unwrap_or(DEFAULT_MODELS_CACHE_TTL);
+        if let Some((queried, models)) = SERVED_MODELS.lock().unwrap().get(&api_base) {
+            if queried.elapsed() < ttl {
+                return models.clone();
+            }
+        }
+        match self.query_models(&api_base).await {
+            Ok(models) => {
+                SERVED_MODELS
+                    .lock()
+                    .unwrap()
+                    .insert(api_base, (Instant::now(), models.clone()));
+                models
+            }
+            Err(e) => {
+                error!("Unable to list the models of {}: {}", api_base, e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Ask the backend for the models it serves
+    async fn query_models(&self, api_base: &str) -> Result<Vec<String>, String> {
+        let mut request = self
+            .client
+            .get(format!("{}/models", api_base.trim_end_matches('/')))
+            .headers(self.headers.clone())
+            .timeout(MODELS_TIMEOUT);
+        if let Some(api_key) = &self.backend.api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let response = request
+            .send()
+            .await
+            .map_err(|e| e.to_string())?
+            .error_for_status()
+            .map_err(|e| e.to_string())?
+            .json::<ModelsResponse>()
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut models: Vec<String> = response.data.into_iter().map(|m| m.id).collect();
+        models.sort();
+        Ok(models)
+    }
+
+    /// The body of a chat completion request, with the options the typed request can't hold
+    fn request_body(&self, request: &ChatCompletionRequest) -> Result<serde_json::Value, String> {
+        let mut body = serde_json::to_value(request).map_err(|e| e.to_string())?;
+        if self.report_cost {
+            body["usage"] = serde_json::json!({ "include": true });
+        }
+        Ok(body)
+    }
+
+    /// Add the cost reported in the usage of a response to the context
+    fn add_reported_cost(&self, context: &ChatContext, response: &serde_json::Value) {
+        if !self.report_cost {
+            return;
+        }
+        if let Some(cost) = response["usage"]["cost"].as_f64() {
+            *context.reported_cost.lock().unwrap().get_or_insert(0.0) += cost;
+        }
+    }
+
+    /// Send a chat completion request to the backend
+    ///
+    /// Returns an error if the backend is misconfigured or the server responds with an error.
+    async fn send_request(
+        &self,
+        request: &ChatCompletionRequest,
+    ) -> Result<reqwest::Response, String> {
+        let api_key = match self.backend.api_key.clone() {
+            Some(key) => key,
+            None => return Err("API key doesn't exist".to_string()),
+        };
+        let api_base = match self.backend.api_base.clone() {
+            Some(base) => base,
+            None => return Err("API base doesn't exist".to_string()),
+        };
+
+        let response = self
+            .client
+            .post(format!(
+                "{}/chat/completions",
+                api_base.trim_end_matches('/')
+            ))
+            .headers(self.headers.clone())
+            .bearer_auth(api_key)
+            .json(&self.request_body(request)?)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("{}: {}", status, body));
         }
+        Ok(response)
     }
 }
 
+#[async_trait]
 impl LLMBackend for OpenAI {
     /// List the models available to this backend
     ///
-    /// We can't query this, so it's just read from the config.
-    fn list_models(&self) -> Vec<String> {
-        // TODO: Embed a list of known models by backend ala https://github.com/sigoden/aichat/blob/main/models.yaml
+    /// The models in the config come first, followed by the ones the backend serves if `query_models` is set.
+    async fn list_models(&self) -> Vec<String> {
         let mut models = Vec::new();
         for model in &self.backend.models.clone().unwrap_or_default() {
             models.push(model.name.clone());
         }
+        for model in self.served_models().await {
+            if !models.contains(&model) {
+                models.push(model);
+            }
+        }
         models
     }
 
     /// Get the default model for this backend
     ///
-    /// It's the first model in the list
-    fn default_model(&self) -> Option<String> {
+    /// It's the first model in the config, or the first model the backend serves
+    async fn default_model(&self) -> Option<String> {
         if let Some(models) = &self.backend.models {
             if !models.is_empty() {
                 return Some(models[0].name.clone());
             }
         }
-        None
+        self.served_models().await.into_iter().next()
+    }
+
+    /// The OpenAI API supports tools, other OpenAI compatible servers have to declare it in the config
+    async fn probe_capabilities(&self, _model: Option<&str>) -> Capabilities {
+        let is_openai = self
+            .backend
+            .api_base
+            .as_deref()
+            .and_then(|base| reqwest::Url::parse(base).ok())
+            .is_some_and(|url| url.host_str() == Some("api.openai.com"));
+        Capabilities {
+            supports_tools: is_openai.then_some(true),
+            ..Default::default()
+        }
     }
 
     /// Execute a chat request with this backend
+    ///
+    /// Tool calls from the model are run and their results sent back, until the model answers.
     async fn execute(&self, context: &ChatContext) -> Result<String, String> {
-        let api_key = match self.backend.api_key.clone() {
-            Some(key) => key,
-            None => return Err("API key doesn't exist".to_string()),
-        };
-        let api_base = match self.backend.api_base.clone() {
-            Some(base) => base,
-            None => return Err("API base doesn't exist".to_string()),
-        };
+        let mut request =
+            convert_to_chatcompletionrequest(context, &self.backend, &self.default_model().await);
+
+        for _ in 0..MAX_TOOL_ROUNDS {
+            let response = self
+                .send_request(&request)
+                .await?
+                .json::<serde_json::Value>()
+                .await
+                .map_err(|e| e.to_string())?;
+            self.add_reported_cost(context, &response);
+            let response = serde_json::from_value::<ChatCompletionResponse>(response)
+                .map_err(|e| e.to_string())?;
+            let message = response
+                .choices
+                .into_iter()
+                .next()
+                .ok_or("No response returned".to_string())?
+                .message;
 
-        let client = OpenAIClient::new_with_endpoint(api_base, api_key);
-        let model_prefix = self.backend.name.clone().unwrap_or("openai".to_string());
+            let tool_calls = message.tool_calls.unwrap_or_default();
+            if tool_calls.is_empty() {
+                return Ok(message
+                    .content
+                    .unwrap_or("Error retrieving response".to_string()));
+            }
+            // Keep the calls in the conversation, followed by their results
+            request.messages.push(ChatCompletionMessage {
+                role: MessageRole::assistant,
+                content: chat_completion::Content::Text(message.content.unwrap_or_default()),
+                name: None,
+                tool_calls: Some(tool_calls.clone()),
+                tool_call_id: None,
+            });
+            for call in tool_calls {
+                info!(
+                    "Tool call: {} {}",
+                    call.function.name.as_deref().unwrap_or_default(),
+                    call.function.arguments.as_deref().unwrap_or_default()
+                );
+                let result = tools::run(&context.tools, &call).await;
+                request.messages.push(ChatCompletionMessage {
+                    role: MessageRole::tool,
+                    content: chat_completion::Content::Text(result),
+                    name: None,
+                    tool_calls: None,
+                    tool_call_id: Some(call.id),
+                });
+            }
+        }
+        Err("Too many tool calls without an answer".to_string())
+    }
+
+    /// The chat completion request, as sent before any tool calls
+    async fn describe_request(&self, context: &ChatContext) -> Result<serde_json::Value, String> {
         let request =
-            convert_to_chatcompletionrequest(context, &model_prefix, &self.default_model());
+            convert_to_chatcompletionrequest(context, &self.backend, &self.default_model().await);
+        self.request_body(&request)
+    }
 
-        let response = client.chat_completion(request).await;
+    /// Execute a chat request, streaming the response into `output`
+    ///
+    /// Uses the streaming chat completion API, reading the server-sent events as they arrive.
+    async fn execute_streaming(
+        &self,
+        context: &ChatContext,
+        output: &Mutex<String>,
+    ) -> Result<String, String> {
+        // Tool calls are handled between requests, so only the final answer could be streamed
+        if !context.tools.is_empty() {
+            let response = self.execute(context).await?;
+            output.lock().unwrap().push_str(&response);
+            return Ok(response);
+        }
+        let mut request =
+            convert_to_chatcompletionrequest(context, &self.backend, &self.default_model().await);
+        request.stream = Some(true);
 
-        let response = response.map_err(|e| e.to_string())?;
+        let mut response = self.send_request(&request).await?;
 
-        Ok(response.choices[0]
-            .message
-            .content
-            .clone()
-            .unwrap_or("Error retrieving response".to_string()))
+        // Each event is a line of the form `data: <json>`, possibly split across chunks
+        // The bytes are only decoded once a line is complete, a chunk can end in the middle of a character
+        let mut buffer = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+            buffer.extend_from_slice(&chunk);
+            while let Some(newline) = buffer.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=newline).collect();
+                let line = String::from_utf8_lossy(&line);
+                let Some(data) = line.trim().strip_prefix("data:") else {
+                    continue;
+                };
+                let data = data.trim();
+                if data == "[DONE]" {
+                    return Ok(output.lock().unwrap().clone());
+                }
+                let Ok(event) = serde_json::from_str::<serde_json::Value>(data) else {
+                    continue;
+                };
+                if let Some(content) = event["choices"][0]["delta"]["content"].as_str() {
+                    output.lock().unwrap().push_str(content);
+                }
+                // The usage comes in the last event
+                self.add_reported_cost(context, &event);
+            }
+        }
+        Ok(output.lock().unwrap().clone())
     }
 }
 
 fn convert_to_chatcompletionrequest(
     context: &ChatContext,
-    model_prefix: &String,
+    backend: &Backend,
     default_model: &Option<String>,
 ) -> ChatCompletionRequest {
+    let model_prefix = backend.name.clone().unwrap_or("openai".to_string());
     let mut messages = Vec::new();
     // Add the role
     if let Some(role) = &context.role {
@@ -96,7 +428,7 @@ fn convert_to_chatcompletionrequest(
         messages.push(ChatCompletionMessage {
             role: message.role.clone(),
             content: chat_completion::Content::Text(message.content.clone()),
-            name: None,
+            name: message.sender.as_deref().map(sanitize_name),
             tool_calls: None,
             tool_call_id: None,
         });
@@ -110,5 +442,37 @@ fn convert_to_chatcompletionrequest(
         model = default_model.clone().unwrap_or_default();
     }
 
-    ChatCompletionRequest::new(model, messages)
+    let params = context.params.clone().or(backend.model_params(&model));
+    let mut request = ChatCompletionRequest::new(model, messages);
+    request.temperature = params.temperature;
+    request.top_p = params.top_p;
+    request.max_tokens = params.max_tokens;
+    request.frequency_penalty = params.frequency_penalty;
+    request.stop = params.stop;
+    if !context.tools.is_empty() {
+        request.tools = Some(context.tools.iter().map(ToolName::definition).collect());
+    }
+    if let Some(schema) = &context.schema {
+        request.response_format = Some(serde_json::json!({
+            "type": "json_schema",
+            "json_schema": { "name": "response", "schema": schema },
+        }));
+    }
+    request
+}
+
+/// Convert a display name into a valid name for the OpenAI API
+///
+/// Names may only contain letters, numbers, underscores and dashes, and are limited to 64 characters.
+fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(64)
+        .collect()
 }
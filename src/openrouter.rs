@@ -0,0 +1,293 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use reqwest::header::{HeaderMap, HeaderValue};
+use serde::Deserialize;
+use tracing::error;
+
+/// OpenRouter Backend
+///
+/// OpenRouter speaks the OpenAI API, so requests go through the OpenAI compatible backend, with the headers
+/// OpenRouter uses to attribute apps and the cost of each request asked for in the response. The models and their
+/// prices come from the public catalogue, so `!chaz list` shows everything available, and the prices and
+/// capabilities don't need to be set in the config.
+use crate::{
+    backends::LLMBackend, capabilities::Capabilities, cost::Pricing, openai::OpenAI,
+    parse_duration, Backend, ChatContext, Model,
+};
+
+/// The OpenRouter API
+const DEFAULT_API_BASE: &str = "https://openrouter.ai/api/v1";
+
+/// Model used when none are set in the config, OpenRouter picks one for each request
+const DEFAULT_MODEL: &str = "openrouter/auto";
+
+/// Sent as the HTTP-Referer, identifying the app to OpenRouter
+const APP_URL: &str = "https://github.com/arcuru/chaz";
+
+/// Sent as the X-Title, the name the app is shown with on OpenRouter
+const APP_TITLE: &str = "chaz";
+
+/// How long the catalogue is reused by default
+const DEFAULT_CATALOGUE_TTL: Duration = Duration::from_secs(3600);
+
+/// How long OpenRouter has to send the catalogue
+const CATALOGUE_TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    /// The catalogue of each API base, with when it was fetched
+    static ref CATALOGUES: Mutex<HashMap<String, (Instant, Vec<CatalogueModel>)>> = Mutex::new(HashMap::new());
+}
+
+/// Response from /models
+#[derive(Deserialize)]
+struct CatalogueResponse {
+    data: Vec<CatalogueModel>,
+}
+
+#[derive(Deserialize, Clone)]
+struct CatalogueModel {
+    id: String,
+    context_length: Option<usize>,
+    pricing: Option<CataloguePricing>,
+    architecture: Option<Architecture>,
+    supported_parameters: Option<Vec<String>>,
+}
+
+/// Prices of a model, per token, as decimal strings
+#[derive(Deserialize, Clone)]
+struct CataloguePricing {
+    prompt: String,
+    completion: String,
+}
+
+#[derive(Deserialize, Clone)]
+struct Architecture {
+    input_modalities: Option<Vec<String>>,
+}
+
+impl CatalogueModel {
+    /// The prices per million tokens, unless they vary like for the auto router
+    fn pricing(&self) -> Option<Pricing> {
+        let pricing = self.pricing.as_ref()?;
+        let input = pricing.prompt.parse::<f64>().ok()?;
+        let output = pricing.completion.parse::<f64>().ok()?;
+        if input < 0.0 || output < 0.0 {
+            return None;
+        }
+        Some(Pricing {
+            input: input * 1_000_000.0,
+            output: output * 1_000_000.0,
+        })
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            supports_vision: self
+                .architecture
+                .as_ref()
+                .and_then(|a| a.input_modalities.as_ref())
+                .map(|m| m.iter().any(|m| m == "image")),
+            supports_tools: self
+                .supported_parameters
+                .as_ref()
+                .map(|p| p.iter().any(|p| p == "tools")),
+            supports_system: None,
+            max_context: self.context_length,
+        }
+    }
+}
+
+/// Handle connections to OpenRouter
+pub struct OpenRouter {
+    /// Stores the full info given in the config file, with the defaults filled in
+    backend: Backend,
+    /// Sends the chat requests
+    openai: OpenAI,
+}
+
+impl OpenRouter {
+    /// Create the backend, failing if its TLS, proxy, or header options are invalid
+    pub fn new(backend: &Backend) -> Result<Self, String> {
+        let mut backend = backend.clone();
+        backend.api_base.get_or_insert(DEFAULT_API_BASE.to_string());
+        if backend.models.as_ref().is_none_or(Vec::is_empty) {
+            backend.models = Some(vec![Model {
+                name: DEFAULT_MODEL.to_string(),
+                input_cost: None,
+                output_cost: None,
+                params: Default::default(),
+                capabilities: None,
+            }]);
+        }
+        // Headers set in the config win, they're sent with every request by the HTTP client
+        let mut headers = HeaderMap::new();
+        for (name, value) in [("HTTP-Referer", APP_URL), ("X-Title", APP_TITLE)] {
+            let configured = backend
+                .headers
+                .iter()
+                .flatten()
+                .any(|(header, _)| header.eq_ignore_ascii_case(name));
+            if !configured {
+                headers.insert(name, HeaderValue::from_static(value));
+            }
+        }
+        // The catalogue is listed here, the OpenAI backend only sends the requests
+        let mut requests = backend.clone();
+        requests.query_models = Some(false);
+        // The OpenAI backend strips its name from the models, which has to be this one's, not "openai"
+        requests.name = Some(backend.get_name());
+        Ok(OpenRouter {
+            openai: OpenAI::new(&requests)?
+                .with_headers(headers)
+                .with_reported_cost(),
+            backend,
+        })
+    }
+
+    fn api_base(&self) -> String {
+        self.backend
+            .api_base
+            .clone()
+            .unwrap_or(DEFAULT_API_BASE.to_string())
+            .trim_end_matches('/')
+            .to_string()
+    }
+
+    /// Get the name OpenRouter knows the model by, without the name of the backend
+    fn model_name(&self, model: Option<&str>) -> Option<String> {
+        let model = match model {
+            Some(model) => model
+                .strip_prefix(&format!("{}:", self.backend.get_name()))
+                .unwrap_or(model),
+            None => self.backend.model_config(None)?.name.as_str(),
+        };
+        Some(model.to_string())
+    }
+
+    /// Get the catalogue, fetching it if it's missing or older than the `models_cache_ttl`
+    ///
+    /// Errors are logged and give an empty catalogue.
+    async fn catalogue(&self) -> Vec<CatalogueModel> {
+        if !self.backend.query_models.unwrap_or(true) {
+            return Vec::new();
+        }
+        let api_base = self.api_base();
+        let ttl = self
+            .backend
+            .models_cache_ttl
+            .as_deref()
+            .and_then(parse_duration)
+            .unwrap_or(DEFAULT_CATALOGUE_TTL);
+        if let Some((fetched, catalogue)) = CATALOGUES.lock().unwrap().get(&api_base) {
+            if fetched.elapsed() < ttl {
+                return catalogue.clone();
+            }
+        }
+        match self.fetch_catalogue(&api_base).await {
+            Ok(catalogue) => {
+                CATALOGUES
+                    .lock()
+                    .unwrap()
+                    .insert(api_base, (Instant::now(), catalogue.clone()));
+                catalogue
+            }
+            Err(e) => {
+                error!("Unable to fetch the OpenRouter catalogue: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Fetch the public catalogue of models
+    async fn fetch_catalogue(&self, api_base: &str) -> Result<Vec<CatalogueModel>, String> {
+        let response = self
+            .openai
+            .client()
+            .get(format!("{}/models", api_base))
+            .timeout(CATALOGUE_TIMEOUT)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?
+            .error_for_status()
+            .map_err(|e| e.to_string())?
+            .json::<CatalogueResponse>()
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut catalogue = response.data;
+        catalogue.sort_by(|a, b| a.id.cmp(&b.id));
+        Ok(catalogue)
+    }
+}
+
+#[async_trait]
+impl LLMBackend for OpenRouter {
+    /// List the models in the config, followed by the catalogue
+    async fn list_models(&self) -> Vec<String> {
+        let mut models: Vec<String> = self
+            .backend
+            .models
+            .iter()
+            .flatten()
+            .map(|m| m.name.clone())
+            .collect();
+        for model in self.catalogue().await {
+            if !models.contains(&model.id) {
+                models.push(model.id);
+            }
+        }
+        models
+    }
+
+    /// Get the first model in the config, or the auto router
+    async fn default_model(&self) -> Option<String> {
+        self.model_name(None)
+    }
+
+    async fn execute(&self, context: &ChatContext) -> Result<String, String> {
+        self.openai.execute(context).await
+    }
+
+    async fn describe_request(&self, context: &ChatContext) -> Result<serde_json::Value, String> {
+        self.openai.describe_request(context).await
+    }
+
+    /// Read the capabilities from the catalogue
+    async fn probe_capabilities(&self, model: Option<&str>) -> Capabilities {
+        let Some(model) = self.model_name(model) else {
+            return Capabilities::default();
+        };
+        self.catalogue()
+            .await
+            .iter()
+            .find(|m| m.id == model)
+            .map(CatalogueModel::capabilities)
+            .unwrap_or_default()
+    }
+
+    /// Read the prices from the catalogue, if it's been fetched
+    fn pricing(&self, model: Option<&str>) -> Option<Pricing> {
+        let model = self.model_name(model)?;
+        CATALOGUES
+            .lock()
+            .unwrap()
+            .get(&self.api_base())?
+            .1
+            .iter()
+            .find(|m| m.id == model)
+            .and_then(CatalogueModel::pricing)
+    }
+
+    async fn execute_streaming(
+        &self,
+        context: &ChatContext,
+        output: &Mutex<String>,
+    ) -> Result<String, String> {
+        self.openai.execute_streaming(context, output).await
+    }
+}
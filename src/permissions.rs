@@ -0,0 +1,102 @@
+/// Room permissions
+///
+/// Checks based on the sender's power level in the room, on top of the allow_list.
+/// The `permissions` config sets the power level needed for each command, and room admins can restrict who uses
+/// chaz at all in their room with `!chaz access`, stored in the room tags under `is.chaz.access`.
+use headjack::Tags;
+use matrix_sdk::{
+    ruma::{events::room::message::RoomMessageEventContent, OwnedUserId},
+    Room,
+};
+use regex::Regex;
+
+use crate::{commands::Args, error::ChazError, get_config, i18n, is_admin};
+
+/// Power level of a room admin in Matrix
+const ROOM_ADMIN_LEVEL: i64 = 100;
+
+/// Tag namespace for the access rule of a room
+pub const ACCESS_NAMESPACE: &str = "is.chaz.access";
+
+/// Get the power level of a user in the room, 0 if they aren't a member
+pub async fn power_level(room: &Room, user: &OwnedUserId) -> i64 {
+    room.get_member_no_sync(user)
+        .await
+        .ok()
+        .flatten()
+        .map(|member| member.power_level())
+        .unwrap_or(0)
+}
+
+/// Check if a user is an admin of the room, bot admins count as admins of every room
+pub async fn is_room_admin(room: &Room, user: &OwnedUserId) -> bool {
+    is_admin(user) || power_level(room, user).await >= ROOM_ADMIN_LEVEL
+}
+
+/// Get the power level a user is missing to run a command, or None if they can run it
+///
+/// Bot admins can run every command.
+pub async fn missing_level(room: &Room, user: &OwnedUserId, command: &str) -> Option<i64> {
+    let level = *get_config().permissions?.get(command)?;
+    if is_admin(user) || power_level(room, user).await >= level {
+        return None;
+    }
+    Some(level)
+}
+
+/// Check if the access rule of the room lets a user use chaz
+///
+/// The rule is either a power level or a regex of user IDs. Room admins can always use chaz, so they can't lock
+/// themselves out.
+pub async fn room_allows(room: &Room, user: &OwnedUserId) -> bool {
+    let Some(rule) = Tags::new(room, ACCESS_NAMESPACE).await.get_value("rule") else {
+        return true;
+    };
+    is_admin(user) || rule_allows(&rule, user.as_str(), power_level(room, user).await)
+}
+
+/// Check the access rule of a room against a user with the given power level
+pub fn rule_allows(rule: &str, user: &str, level: i64) -> bool {
+    if level >= ROOM_ADMIN_LEVEL {
+        return true;
+    }
+    match rule.parse::<i64>() {
+        Ok(needed) => level >= needed,
+        Err(_) => Regex::new(rule).is_ok_and(|regex| regex.is_match(user)),
+    }
+}
+
+/// Show or set who can use chaz in the room, `!chaz access [set <regex|power level>|clear]`
+pub async fn access(sender: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let mut tags = Tags::new(&room, ACCESS_NAMESPACE).await;
+    let response = match args.subcommand() {
+        None => match tags.get_value("rule") {
+            Some(rule) if rule.parse::<i64>().is_ok() => {
+                i18n::notice_text(&room, "access-level", &[("level", &rule)]).await
+            }
+            Some(rule) => i18n::notice_text(&room, "access-regex", &[("regex", &rule)]).await,
+            None => i18n::notice_text(&room, "access-everyone", &[]).await,
+        },
+        Some(_) if !is_room_admin(&room, &sender).await => {
+            i18n::notice_text(&room, "access-forbidden", &[]).await
+        }
+        Some("set") => {
+            let rule = &args["rule"];
+            if rule.parse::<i64>().is_err() && Regex::new(rule).is_err() {
+                i18n::notice_text(&room, "access-invalid", &[("rule", rule)]).await
+            } else {
+                tags.replace_kv("rule", rule);
+                tags.sync().await;
+                i18n::notice_text(&room, "access-set", &[("rule", rule)]).await
+            }
+        }
+        _ => {
+            tags.remove_kv("rule");
+            tags.sync().await;
+            i18n::notice_text(&room, "access-everyone", &[]).await
+        }
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,185 @@
+/// Polls
+///
+/// `!chaz poll` posts a native Matrix poll. With `disambiguation_polls` set, the model is also told it can answer
+/// an ambiguous request with a poll of the interpretations instead of guessing. chaz posts that poll, and once the
+/// user who asked votes, it ends the poll and answers the request with their choice. The open polls are kept in
+/// memory and are lost on restart.
+use std::{collections::HashMap, sync::Mutex};
+
+use lazy_static::lazy_static;
+use matrix_sdk::{
+    ruma::{
+        events::poll::{
+            unstable_end::UnstablePollEndEventContent,
+            unstable_response::OriginalSyncUnstablePollResponseEvent,
+            unstable_start::{
+                NewUnstablePollStartEventContent, UnstablePollAnswer, UnstablePollAnswers,
+                UnstablePollStartContentBlock, UnstablePollStartEventContent,
+            },
+        },
+        OwnedEventId, OwnedRoomId, OwnedUserId,
+    },
+    Room,
+};
+use openai_api_rs::v1::chat_completion::MessageRole;
+use tracing::info;
+
+use crate::{
+    backends::Message, commands::Args, error::ChazError, get_context, i18n, respond, shutdown,
+};
+
+/// Start of a response that asks for a poll instead of answering
+const PREFIX: &str = "POLL:";
+
+/// Added to the role when `disambiguation_polls` is on
+pub const INSTRUCTIONS: &str = "If the request is ambiguous and the answer depends on which interpretation is meant, respond with only a single line like `POLL: Which one did you mean? | first interpretation | second interpretation`, with between 2 and 5 short options, and nothing else. Otherwise answer normally.";
+
+/// Most options a poll can have, the limit of the spec
+const MAX_OPTIONS: usize = 20;
+
+/// A poll chaz asked to disambiguate a request
+struct Pending {
+    room: OwnedRoomId,
+    /// The user whose vote is used
+    asker: OwnedUserId,
+    question: String,
+    options: Vec<String>,
+}
+
+lazy_static! {
+    /// The disambiguation polls waiting for a vote, by the ID of the poll
+    static ref PENDING: Mutex<HashMap<OwnedEventId, Pending>> = Mutex::new(HashMap::new());
+}
+
+/// Split `question | option | option` into the question and the options
+fn parse(text: &str) -> Option<(String, Vec<String>)> {
+    let mut parts = text
+        .split('|')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+        .map(str::to_string);
+    let question = parts.next()?;
+    let options: Vec<String> = parts.collect();
+    if options.len() < 2 || options.len() > MAX_OPTIONS {
+        return None;
+    }
+    Some((question, options))
+}
+
+/// Get the poll a response asks for, if it's only a poll
+pub fn requested(response: &str) -> Option<(String, Vec<String>)> {
+    let response = response.trim();
+    if response.lines().count() != 1 {
+        return None;
+    }
+    parse(response.strip_prefix(PREFIX)?)
+}
+
+/// Post a poll, returning its ID
+async fn send(room: &Room, question: &str, options: &[String]) -> Result<OwnedEventId, ChazError> {
+    let answers: Vec<UnstablePollAnswer> = options
+        .iter()
+        .enumerate()
+        .map(|(i, option)| UnstablePollAnswer::new((i + 1).to_string(), option))
+        .collect();
+    let answers = UnstablePollAnswers::try_from(answers)
+        .map_err(|e| ChazError::InvalidEvent(e.to_string()))?;
+    // The text is shown by clients that don't support polls
+    let fallback = std::iter::once(question.to_string())
+        .chain(
+            options
+                .iter()
+                .enumerate()
+                .map(|(i, option)| format!("{}. {}", i + 1, option)),
+        )
+        .collect::<Vec<String>>()
+        .join("\n");
+    let content: UnstablePollStartEventContent = NewUnstablePollStartEventContent::plain_text(
+        fallback,
+        UnstablePollStartContentBlock::new(question, answers),
+    )
+    .into();
+    let response = room.send(content).await?;
+    Ok(response.event_id)
+}
+
+/// Post a poll to disambiguate a request, and answer it once the user votes
+pub async fn ask(
+    room: &Room,
+    asker: &OwnedUserId,
+    question: String,
+    options: Vec<String>,
+) -> Result<(), ChazError> {
+    let poll = send(room, &question, &options).await?;
+    info!("Asked {} to disambiguate with poll {}", asker, poll);
+    PENDING.lock().unwrap().insert(
+        poll,
+        Pending {
+            room: room.room_id().to_owned(),
+            asker: asker.clone(),
+            question,
+            options,
+        },
+    );
+    Ok(())
+}
+
+/// Post a poll, `!chaz poll <question> | <option> | <option>...`
+pub async fn poll(_: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    match parse(&args["poll"]) {
+        Some((question, options)) => {
+            send(&room, &question, &options).await?;
+        }
+        None => {
+            let limit = MAX_OPTIONS.to_string();
+            room.send(i18n::notice(&room, "poll-usage", &[("limit", &limit)]).await)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Answer a disambiguated request once the user who asked it votes
+pub async fn on_response(event: OriginalSyncUnstablePollResponseEvent, room: Room) {
+    let poll = event.content.relates_to.event_id;
+    let selected = event.content.poll_response.answers.first();
+    let (pending, choice) = {
+        let mut pending = PENDING.lock().unwrap();
+        let Some(open) = pending.get(&poll) else {
+            return;
+        };
+        if open.asker != event.sender || open.room != room.room_id() {
+            return;
+        }
+        // Retracting a vote sends no answers
+        let Some(choice) = selected
+            .and_then(|id| id.parse::<usize>().ok())
+            .and_then(|index| open.options.get(index.wrapping_sub(1)))
+            .cloned()
+        else {
+            return;
+        };
+        (pending.remove(&poll).unwrap(), choice)
+    };
+    let _in_flight = shutdown::track();
+    let result = async {
+        room.send(UnstablePollEndEventContent::new(
+            format!("The poll has ended. {} chose: {}", pending.asker, choice),
+            poll.clone(),
+        ))
+        .await?;
+        let mut context = get_context(&room).await?;
+        context.messages.push(Message::new(
+            MessageRole::user,
+            format!(
+                "For \"{}\", I meant \"{}\". Answer my earlier request with that.",
+                pending.question, choice
+            ),
+        ));
+        respond(&room, &pending.asker, context, None).await
+    }
+    .await;
+    if let Err(e) = result {
+        crate::error::report(&room.client(), "a poll response", &e).await;
+    }
+}
@@ -0,0 +1,248 @@
+/// User preferences
+///
+/// Defaults set with `!chaz prefs` that follow a user across rooms: the model, the language and length of the
+/// responses, and whether they're formatted with markdown. They're applied to the context of every response to the
+/// user, and stored in the bot's global account data under `is.chaz.preferences`, keyed by user ID.
+use std::{collections::BTreeMap, sync::Mutex};
+
+use headjack::Tags;
+use lazy_static::lazy_static;
+use matrix_sdk::{
+    ruma::{events::room::message::RoomMessageEventContent, OwnedUserId},
+    Client, Room,
+};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{
+    account_data, backends::ChatContext, commands::Args, error::ChazError, format::Format, i18n,
+    language, role::RoleDetails, space, workspace,
+};
+
+/// Account data type the preferences are stored in
+const ACCOUNT_DATA_TYPE: &str = "is.chaz.preferences";
+
+/// The preferences of a user
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct Preferences {
+    /// Model used in rooms that haven't chosen one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Language to respond in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    /// Length of the responses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub length: Option<Length>,
+    /// Formatting of the responses
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<Format>,
+}
+
+/// Length of the responses
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Length {
+    Short,
+    Medium,
+    Long,
+}
+
+/// The stored preferences of all users
+#[derive(Serialize, Deserialize, Default)]
+struct StoredPreferences {
+    users: BTreeMap<String, Preferences>,
+}
+
+lazy_static! {
+    /// Preferences loaded from the account data
+    static ref PREFERENCES: Mutex<Option<BTreeMap<String, Preferences>>> = Mutex::new(None);
+}
+
+/// Get the preferences of all users, loading them if needed
+async fn load_all(client: &Client) -> Result<BTreeMap<String, Preferences>, String> {
+    if let Some(preferences) = PREFERENCES.lock().unwrap().as_ref() {
+        return Ok(preferences.clone());
+    }
+    let preferences = account_data::get_global::<StoredPreferences>(client, ACCOUNT_DATA_TYPE)
+        .await?
+        .unwrap_or_default()
+        .users;
+    *PREFERENCES.lock().unwrap() = Some(preferences.clone());
+    Ok(preferences)
+}
+
+/// Get the preferences of a user
+pub async fn get(client: &Client, user: &OwnedUserId) -> Preferences {
+    match load_all(client).await {
+        Ok(preferences) => preferences.get(user.as_str()).cloned().unwrap_or_default(),
+        Err(e) => {
+            error!("Unable to load the preferences: {}", e);
+            Preferences::default()
+        }
+    }
+}
+
+/// Save the preferences of a user
+async fn save(client: &Client, user: &OwnedUserId, preferences: Preferences) -> Result<(), String> {
+    let mut users = load_all(client).await?;
+    if preferences.model.is_none()
+        && preferences.language.is_none()
+        && preferences.length.is_none()
+        && preferences.format.is_none()
+    {
+        users.remove(user.as_str());
+    } else {
+        users.insert(user.to_string(), preferences);
+    }
+    let stored = StoredPreferences { users };
+    if serde_json::to_string(&stored).map_or(0, |json| json.len()) > account_data::MAX_SIZE {
+        return Err("there's no room to store more preferences".to_string());
+    }
+    account_data::set_global(client, ACCOUNT_DATA_TYPE, &stored).await?;
+    *PREFERENCES.lock().unwrap() = Some(stored.users);
+    Ok(())
+}
+
+impl Preferences {
+    /// Set a preference by its key, or unset it with None
+    fn set(&mut self, key: &str, value: Option<&str>) -> Result<(), String> {
+        match key {
+            "model" => self.model = value.map(str::to_string),
+            "language" => self.language = value.map(str::to_string),
+            "length" => {
+                self.length = match value {
+                    None => None,
+                    Some("short") => Some(Length::Short),
+                    Some("medium") => Some(Length::Medium),
+                    Some("long") => Some(Length::Long),
+                    Some(_) => return Err("length is short, medium, or long".to_string()),
+                }
+            }
+            "format" => {
+                self.format = match value.map(Format::parse) {
+                    None => None,
+                    Some(Some(format)) => Some(format),
+                    Some(None) => return Err("format is markdown, plain, or notice".to_string()),
+                }
+            }
+            _ => {
+                return Err(format!(
+                "unknown preference {}, the preferences are model, language, length, and format",
+                key
+            ))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Show, set, or unset the sender's preferences
+///
+/// `!chaz prefs [set <key> <value>|unset <key>]`
+pub async fn prefs(sender: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let client = room.client();
+    let mut preferences = get(&client, &sender).await;
+    if args.subcommand().is_none() {
+        let language = language::get(&room).await;
+        room.send(RoomMessageEventContent::notice_plain(describe(
+            &preferences,
+            language.as_deref(),
+        )))
+        .await?;
+        return Ok(());
+    }
+    let key = &args["key"];
+    let value = args.get("value").map(str::to_string);
+    let result = match preferences.set(key, value.as_deref()) {
+        Ok(()) => save(&client, &sender, preferences).await,
+        Err(e) => Err(e),
+    };
+    let response = match (result, value) {
+        (Ok(()), Some(value)) => {
+            let args = [("key", key), ("value", value.as_str())];
+            i18n::notice_text(&room, "preference-set", &args).await
+        }
+        (Ok(()), None) => i18n::notice_text(&room, "preference-unset", &[("key", key)]).await,
+        (Err(e), _) => i18n::error_text(&room, &e).await,
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// Describe the preferences of a user
+fn describe(preferences: &Preferences, language: Option<&str>) -> String {
+    let mut lines = Vec::new();
+    if let Some(model) = &preferences.model {
+        lines.push(format!("model: {}", model));
+    }
+    if let Some(language) = &preferences.language {
+        lines.push(format!("language: {}", language));
+    }
+    if let Some(length) = preferences.length {
+        let length = match length {
+            Length::Short => "short",
+            Length::Medium => "medium",
+            Length::Long => "long",
+        };
+        lines.push(format!("length: {}", length));
+    }
+    if let Some(format) = preferences.format {
+        lines.push(format!("format: {}", format.name()));
+    }
+    let response = if lines.is_empty() {
+        i18n::translate(language, "preferences-empty", &[])
+    } else {
+        let preferences = lines.join("\n");
+        i18n::translate(language, "preferences", &[("preferences", &preferences)])
+    };
+    format!("!chaz {}", response)
+}
+
+/// Apply the sender's preferences to the context
+///
+/// The preferred model is only used in rooms that haven't chosen a model, and where the space allows it.
+pub async fn apply(room: &Room, preferences: &Preferences, context: &mut ChatContext) {
+    if let Some(model) = &preferences.model {
+        let tags = Tags::new(room, "is.chaz.model").await;
+        let workspace_model = workspace::settings(room, &workspace::current(room).await)
+            .await
+            .and_then(|settings| settings.get_value("model"));
+        if tags.get_value("default").is_none()
+            && tags.get_value("locked").is_none()
+            && workspace_model.is_none()
+            && space::settings(room).await.allows_model(model)
+        {
+            context.model = Some(model.clone());
+        }
+    }
+    let mut instructions = Vec::new();
+    if let Some(language) = &preferences.language {
+        instructions.push(format!("Respond in {}.", language));
+    }
+    match preferences.length {
+        Some(Length::Short) => {
+            instructions.push("Keep your responses brief, a few sentences at most.".to_string())
+        }
+        Some(Length::Long) => {
+            instructions.push("Give detailed and thorough responses.".to_string())
+        }
+        _ => {}
+    }
+    if instructions.is_empty() {
+        return;
+    }
+    let instructions = instructions.join(" ");
+    match context.role.as_mut() {
+        Some(role) => role.append_prompt(&instructions),
+        None => {
+            context.role = Some(RoleDetails::new(
+                "preferences",
+                None,
+                Some(instructions),
+                None,
+            ))
+        }
+    }
+}
@@ -0,0 +1,80 @@
+/// System prompt inspection and override
+///
+/// `!chaz prompt` shows the system prompt chaz will send in the room, which is the role with everything added to
+/// it: what the members want to be called, the room's memories, and the summary of the earlier conversation.
+/// `!chaz prompt set <text>` replaces the role with a prompt for this room only, without defining a named role,
+/// and `!chaz prompt clear` goes back to the role. The prompt is kept in the room tags.
+use headjack::Tags;
+use matrix_sdk::{
+    ruma::{events::room::message::RoomMessageEventContent, OwnedUserId},
+    Room,
+};
+use openai_api_rs::v1::chat_completion::MessageRole;
+
+use crate::{commands::Args, error::ChazError, get_context, i18n};
+
+/// Tag namespace for the prompt
+const NAMESPACE: &str = "is.chaz.prompt";
+
+/// Tag key of the prompt
+const KEY: &str = "override";
+
+/// Name of the role made from the prompt
+pub const ROLE_NAME: &str = "prompt";
+
+/// Get the prompt set for the room, if there is one
+pub async fn get(room: &Room) -> Option<String> {
+    Tags::new(room, NAMESPACE).await.get_value(KEY)
+}
+
+/// Show, set, or clear the system prompt of the room, `!chaz prompt [set <text>|clear]`
+pub async fn prompt(_: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let mut tags = Tags::new(&room, NAMESPACE).await;
+    let response = match args.subcommand() {
+        None => describe(&room).await?,
+        Some("set") => {
+            tags.replace_kv(KEY, &args["text"]);
+            tags.sync().await;
+            i18n::notice_text(&room, "prompt-set", &[]).await
+        }
+        _ => match tags.get_value(KEY) {
+            Some(_) => {
+                tags.remove_kv(KEY);
+                tags.sync().await;
+                i18n::notice_text(&room, "prompt-cleared", &[]).await
+            }
+            None => i18n::notice_text(&room, "prompt-not-set", &[]).await,
+        },
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// Describe the system prompt and the system messages at the start of the context
+async fn describe(room: &Room) -> Result<String, ChazError> {
+    let context = get_context(room).await?;
+    let mut parts = Vec::new();
+    if let Some(role) = &context.role {
+        let prompt = role.get_prompt();
+        parts.push(if role.name == ROLE_NAME {
+            i18n::tr(room, "prompt-room", &[("prompt", &prompt)]).await
+        } else {
+            let args = [("role", role.name.as_str()), ("prompt", prompt.as_str())];
+            i18n::tr(room, "prompt-role", &args).await
+        });
+    }
+    // Memories and summaries are added as system messages before the conversation
+    for message in context
+        .messages
+        .iter()
+        .take_while(|message| message.role == MessageRole::system)
+    {
+        parts.push(message.content.clone());
+    }
+    Ok(if parts.is_empty() {
+        i18n::notice_text(room, "prompt-none", &[]).await
+    } else {
+        format!("!chaz {}", parts.join("\n\n"))
+    })
+}
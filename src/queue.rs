@@ -0,0 +1,206 @@
+/// Question queue for offline backends
+///
+/// When the backend is unreachable, times out, or has a server error, questions are queued instead of answered
+/// with an error. A background task retries the oldest one, and once it goes through the backend is back, so the
+/// rest of the queue is answered too. A question that keeps failing is moved to the back, so it can't hold up the
+/// others, and is answered with the error after a few attempts. The queue is kept in memory and is lost on restart.
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use matrix_sdk::ruma::{OwnedEventId, OwnedRoomId, OwnedUserId};
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::{
+    accounts, generate, get_config, get_context_at, i18n, parse_duration, post_response, shutdown,
+};
+
+/// Configuration for the question queue
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct QueueConfig {
+    /// Maximum number of queued questions, 20 by default
+    pub size: Option<usize>,
+    /// How often to retry the backend, e.g. "1m", the default
+    pub retry_interval: Option<String>,
+    /// How long a question waits before it's dropped, e.g. "1h", the default
+    pub max_wait: Option<String>,
+}
+
+/// Number of times a question is retried before it's answered with the error
+const MAX_ATTEMPTS: u32 = 10;
+
+/// A question waiting for the backend
+struct Pending {
+    room: OwnedRoomId,
+    sender: OwnedUserId,
+    prompt: OwnedEventId,
+    queued_at: Instant,
+    attempts: u32,
+}
+
+lazy_static! {
+    static ref QUEUE: Mutex<VecDeque<Pending>> = Mutex::new(VecDeque::new());
+}
+
+/// Queue a question, returning the number of questions waiting
+///
+/// Returns None if queueing is disabled or the queue is full.
+pub fn push(room: OwnedRoomId, sender: OwnedUserId, prompt: OwnedEventId) -> Option<usize> {
+    let config = get_config().offline_queue?;
+    let mut queue = QUEUE.lock().unwrap();
+    if queue.len() >= config.size.unwrap_or(20) {
+        return None;
+    }
+    queue.push_back(Pending {
+        room,
+        sender,
+        prompt,
+        queued_at: Instant::now(),
+        attempts: 0,
+    });
+    Some(queue.len())
+}
+
+/// Check if a backend error means the backend is down, rather than that the question can't be answered
+///
+/// The backends return their errors as text, so this looks for the reqwest messages of failed connections and
+/// timeouts, and for the HTTP status the backends start their errors with.
+pub fn is_unavailable(error: &str) -> bool {
+    let server_error = error
+        .get(..3)
+        .and_then(|status| status.parse::<u16>().ok())
+        .is_some_and(|status| (500..600).contains(&status));
+    let error = error.to_lowercase();
+    server_error
+        || error.contains("error sending request")
+        || error.contains("timed out")
+        || error.contains("didn't respond within")
+        || error.contains("connection")
+}
+
+/// Describe the queued questions, for the admins
+pub fn report(language: Option<&str>) -> String {
+    let queue = QUEUE.lock().unwrap();
+    if queue.is_empty() {
+        return format!("!chaz {}", i18n::translate(language, "queue-empty", &[]));
+    }
+    let count = queue.len().to_string();
+    let mut response = format!(
+        "!chaz {}",
+        i18n::translate(language, "queue-report", &[("count", &count)])
+    );
+    for pending in queue.iter() {
+        let waiting = pending.queued_at.elapsed().as_secs().to_string();
+        let args = [
+            ("user", pending.sender.as_str()),
+            ("room", pending.room.as_str()),
+            ("seconds", &waiting),
+        ];
+        response.push_str(&format!(
+            "\n- {}",
+            i18n::translate(language, "queue-entry", &args)
+        ));
+    }
+    response
+}
+
+/// Retry the queued questions in the background, for all the accounts
+pub fn start() {
+    tokio::spawn(async move {
+        loop {
+            let retry_interval = get_config()
+                .offline_queue
+                .and_then(|config| config.retry_interval)
+                .and_then(|interval| parse_duration(&interval))
+                .unwrap_or(Duration::from_secs(60));
+            tokio::time::sleep(retry_interval).await;
+            drop_expired().await;
+            while retry_next().await {}
+        }
+    });
+}
+
+/// Drop the questions that have waited too long, letting their senders know
+async fn drop_expired() {
+    let max_wait = get_config()
+        .offline_queue
+        .and_then(|config| config.max_wait)
+        .and_then(|max_wait| parse_duration(&max_wait))
+        .unwrap_or(Duration::from_secs(3600));
+    let expired: VecDeque<Pending> = {
+        let mut queue = QUEUE.lock().unwrap();
+        let (expired, waiting) = queue
+            .drain(..)
+            .partition(|pending| pending.queued_at.elapsed() > max_wait);
+        *queue = waiting;
+        expired
+    };
+    for pending in expired {
+        let Some(room) = accounts::get_room(&pending.room) else {
+            continue;
+        };
+        let notice =
+            i18n::notice(&room, "queue-expired", &[("user", pending.sender.as_str())]).await;
+        if let Err(e) = room.send(notice).await {
+            error!("Unable to send the expired question notice: {}", e);
+        }
+    }
+}
+
+/// Try to answer the oldest question
+///
+/// Returns true if it was answered, and the next one should be tried. If the backend is still down the question
+/// goes to the back of the queue.
+async fn retry_next() -> bool {
+    let Some((room_id, sender, prompt)) = QUEUE.lock().unwrap().front().map(|pending| {
+        (
+            pending.room.clone(),
+            pending.sender.clone(),
+            pending.prompt.clone(),
+        )
+    }) else {
+        return false;
+    };
+    let Some(room) = accounts::get_room(&room_id) else {
+        QUEUE.lock().unwrap().pop_front();
+        return true;
+    };
+    let _in_flight = shutdown::track();
+    let context = match get_context_at(&room, Some(&prompt)).await {
+        Ok(context) => context,
+        Err(e) => {
+            error!(
+                "Dropping a queued question, unable to get its context: {}",
+                e
+            );
+            QUEUE.lock().unwrap().pop_front();
+            return true;
+        }
+    };
+    let (result, style) = generate(&room, &sender, context).await;
+    let unavailable = result.as_ref().is_err_and(|e| is_unavailable(e));
+    let Some(mut pending) = QUEUE.lock().unwrap().pop_front() else {
+        return false;
+    };
+    if unavailable {
+        pending.attempts += 1;
+        if pending.attempts < MAX_ATTEMPTS {
+            QUEUE.lock().unwrap().push_back(pending);
+            return false;
+        }
+        error!(
+            "Answering a queued question from {} with the error after {} attempts",
+            sender, MAX_ATTEMPTS
+        );
+    } else {
+        info!("Answering a queued question from {}", sender);
+    }
+    if let Err(e) = post_response(&room, &sender, result, style, Some(&prompt)).await {
+        error!("Unable to post the answer to a queued question: {}", e);
+    }
+    !unavailable
+}
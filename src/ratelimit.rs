@@ -0,0 +1,163 @@
+/// Rate limits
+///
+/// A token bucket for each user and each room: every message takes a token, and the tokens come back at
+/// `messages_per_hour`, up to `burst` of them. So a user can ask a few questions in a row, but not flood a room.
+/// The buckets are saved in the state directory, so a restart doesn't reset them.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{session, util::read_state};
+
+/// Configuration for the rate limits
+///
+/// Unset limits aren't enforced, and the burst defaults to the messages per hour.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RateLimitConfig {
+    /// Messages each user can send per hour
+    pub messages_per_hour: Option<f64>,
+    /// Messages each user can send in a row
+    pub burst: Option<f64>,
+    /// Messages per hour in each room, from all its users
+    pub room_messages_per_hour: Option<f64>,
+    /// Messages in a row in each room
+    pub room_burst: Option<f64>,
+}
+
+/// The tokens left for a user or room
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    /// Seconds since the epoch when the tokens were last counted
+    updated: f64,
+}
+
+/// A rate limit, in tokens per second
+#[derive(Clone, Copy)]
+struct Limit {
+    rate: f64,
+    burst: f64,
+}
+
+impl Limit {
+    fn new(per_hour: Option<f64>, burst: Option<f64>) -> Option<Limit> {
+        let per_hour = per_hour.filter(|per_hour| *per_hour > 0.0)?;
+        Some(Limit {
+            rate: per_hour / 3600.0,
+            burst: burst.unwrap_or(per_hour).max(1.0),
+        })
+    }
+}
+
+impl Bucket {
+    /// Add the tokens that came back since the last count
+    fn refill(&mut self, limit: Limit, now: f64) {
+        let elapsed = (now - self.updated).max(0.0);
+        self.tokens = (self.tokens + elapsed * limit.rate).min(limit.burst);
+        self.updated = now;
+    }
+
+    /// Seconds until there's a token to take
+    fn wait(&self, limit: Limit) -> f64 {
+        ((1.0 - self.tokens) / limit.rate).max(0.0)
+    }
+}
+
+struct Buckets {
+    path: PathBuf,
+    buckets: HashMap<String, Bucket>,
+}
+
+lazy_static! {
+    static ref BUCKETS: Mutex<Option<Buckets>> = Mutex::new(None);
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+/// Load the saved buckets from the state directory
+pub fn init(state_dir: &Path) -> anyhow::Result<()> {
+    let path = state_dir.join("ratelimit.json");
+    let buckets = read_state(&path)?;
+    *BUCKETS.lock().unwrap() = Some(Buckets { path, buckets });
+    Ok(())
+}
+
+/// Take a token for a message from the user in the room
+///
+/// Returns the reason, with how long to wait, if the user or the room is out of tokens. No token is taken then.
+pub fn check(user: &str, room: &str, config: &RateLimitConfig) -> Result<(), String> {
+    let limits = [
+        (
+            user.to_string(),
+            Limit::new(config.messages_per_hour, config.burst),
+            "you're",
+        ),
+        (
+            room.to_string(),
+            Limit::new(config.room_messages_per_hour, config.room_burst),
+            "this room is",
+        ),
+    ];
+    let mut buckets = BUCKETS.lock().unwrap();
+    let Some(buckets) = buckets.as_mut() else {
+        return Ok(());
+    };
+    let now = now();
+    let mut taken = Vec::new();
+    for (key, limit, who) in limits {
+        let Some(limit) = limit else {
+            continue;
+        };
+        let mut bucket = buckets.buckets.get(&key).copied().unwrap_or(Bucket {
+            tokens: limit.burst,
+            updated: now,
+        });
+        bucket.refill(limit, now);
+        if bucket.tokens < 1.0 {
+            return Err(format!(
+                "{} sending messages too quickly, try again in {}",
+                who,
+                describe_wait(bucket.wait(limit))
+            ));
+        }
+        bucket.tokens -= 1.0;
+        taken.push((key, bucket));
+    }
+    if taken.is_empty() {
+        return Ok(());
+    }
+    buckets.buckets.extend(taken);
+    save(buckets);
+    Ok(())
+}
+
+/// Describe a wait in seconds, rounded up to the minute
+fn describe_wait(seconds: f64) -> String {
+    let minutes = (seconds / 60.0).ceil() as u64;
+    match minutes {
+        0 | 1 => "a minute".to_string(),
+        minutes => format!("{} minutes", minutes),
+    }
+}
+
+/// Write the buckets to disk
+fn save(buckets: &Buckets) {
+    let result = serde_json::to_string(&buckets.buckets)
+        .map_err(anyhow::Error::from)
+        .and_then(|contents| Ok(session::write(&buckets.path, &contents)?));
+    if let Err(e) = result {
+        error!("Unable to save the rate limits: {}", e);
+    }
+}
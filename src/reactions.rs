@@ -0,0 +1,126 @@
+/// Reactions to responses
+///
+/// Reacting to a chaz response with 🔁 regenerates it in place, and 👍 or 👎 records feedback on it.
+/// Feedback is appended to `feedback.jsonl` in the state directory when `feedback_log` is enabled.
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use matrix_sdk::{
+    ruma::{events::reaction::OriginalSyncReactionEvent, EventId},
+    Room,
+};
+use serde::Serialize;
+use tracing::error;
+
+use crate::{admin, edit_response, get_config, is_allowed, permissions, responses};
+
+lazy_static! {
+    /// Path of the feedback log
+    static ref FEEDBACK_LOG: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// A line of the feedback log
+#[derive(Serialize)]
+struct Feedback<'a> {
+    /// Seconds since the epoch
+    time: u64,
+    room: &'a str,
+    sender: &'a str,
+    rating: &'a str,
+    prompt: &'a str,
+    response: &'a str,
+    prompt_text: Option<String>,
+    response_text: Option<String>,
+}
+
+/// Set the location of the feedback log
+pub fn init(state_dir: &Path) {
+    *FEEDBACK_LOG.lock().unwrap() = Some(state_dir.join("feedback.jsonl"));
+}
+
+/// Strip the variation selector and skin tone from an emoji, so that all variants match
+fn normalize(key: &str) -> String {
+    key.chars()
+        .filter(|c| !matches!(*c as u32, 0xFE0F | 0x1F3FB..=0x1F3FF))
+        .collect()
+}
+
+/// Handle a reaction to one of chaz's responses
+pub async fn on_reaction(event: OriginalSyncReactionEvent, room: Room) {
+    let sender = event.sender;
+    if Some(sender.as_ref()) == room.client().user_id()
+        || !is_allowed(&sender)
+        || admin::is_blocked(sender.as_str())
+        || !permissions::room_allows(&room, &sender).await
+    {
+        return;
+    }
+    let response = event.content.relates_to.event_id;
+    let Some(prompt) = responses::prompt_for(&response) else {
+        return;
+    };
+    match normalize(&event.content.relates_to.key).as_str() {
+        "🔁" => {
+            if let Err(e) = edit_response(&room, &sender, &prompt).await {
+                crate::error::report(&room.client(), "a regenerate reaction", &e).await;
+            }
+        }
+        "👍" => log_feedback(&room, sender.as_str(), "up", &prompt, &response).await,
+        "👎" => log_feedback(&room, sender.as_str(), "down", &prompt, &response).await,
+        _ => {}
+    }
+}
+
+/// Get the text of a message
+async fn message_text(room: &Room, event_id: &EventId) -> Option<String> {
+    let event = room.event(event_id).await.ok()?;
+    let event: serde_json::Value = event.event.deserialize_as().ok()?;
+    event["content"]["body"].as_str().map(str::to_string)
+}
+
+/// Append feedback to the log, if it's enabled
+async fn log_feedback(
+    room: &Room,
+    sender: &str,
+    rating: &str,
+    prompt: &EventId,
+    response: &EventId,
+) {
+    if !get_config().feedback_log.unwrap_or(false) {
+        return;
+    }
+    let Some(path) = FEEDBACK_LOG.lock().unwrap().clone() else {
+        return;
+    };
+    let feedback = Feedback {
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        room: room.room_id().as_str(),
+        sender,
+        rating,
+        prompt: prompt.as_str(),
+        response: response.as_str(),
+        prompt_text: message_text(room, prompt).await,
+        response_text: message_text(room, response).await,
+    };
+    let result = serde_json::to_string(&feedback)
+        .map_err(anyhow::Error::from)
+        .and_then(|line| {
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)?;
+            writeln!(file, "{}", line)?;
+            Ok(())
+        });
+    if let Err(e) = result {
+        error!("Unable to log feedback: {}", e);
+    }
+}
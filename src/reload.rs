@@ -0,0 +1,92 @@
+/// Config hot reload
+///
+/// The config file is read again when it or the files in the `roles_dir` change on disk, or chaz receives SIGHUP
+/// on Unix, without a restart or re-sync. Only settings that are read while handling messages take effect, see
+/// `reload_config`.
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use tracing::warn;
+
+use crate::{get_config, reload_config};
+
+/// How often the config file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Watch for config changes and SIGHUP in the background
+pub fn watch(path: PathBuf) {
+    tokio::spawn(async move {
+        let mut hangup = hangup();
+        let mut last_modified = modified(&path);
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                Some(()) = async { hangup.as_mut()?.recv().await } => {}
+                _ = interval.tick() => {
+                    let current = modified(&path);
+                    if current == last_modified {
+                        continue;
+                    }
+                    last_modified = current;
+                }
+            }
+            if let Err(e) = reload_config() {
+                warn!("Keeping the previous config, the new one is invalid: {}", e);
+            }
+        }
+    });
+}
+
+/// Listen for SIGHUP
+#[cfg(unix)]
+fn hangup() -> Option<tokio::signal::unix::Signal> {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(e) => {
+            tracing::error!("Unable to listen for SIGHUP: {}", e);
+            None
+        }
+    }
+}
+
+/// Other platforms have no SIGHUP, only the changes on disk reload the config
+#[cfg(not(unix))]
+fn hangup() -> Option<Hangup> {
+    None
+}
+
+/// Stands in for the SIGHUP signal where there's none
+#[cfg(not(unix))]
+struct Hangup;
+
+#[cfg(not(unix))]
+impl Hangup {
+    async fn recv(&mut self) -> Option<()> {
+        None
+    }
+}
+
+/// When the config file and each file in the roles_dir were last modified
+///
+/// Files added to or removed from the roles_dir change the list too.
+fn modified(path: &Path) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut files = vec![(path.to_path_buf(), modified(path))];
+    if let Some(roles_dir) = get_config().roles_dir {
+        let mut roles: Vec<PathBuf> = std::fs::read_dir(roles_dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .collect();
+        roles.sort();
+        files.extend(roles.into_iter().map(|role| {
+            let time = modified(&role);
+            (role, time)
+        }));
+    }
+    files
+}
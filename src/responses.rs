@@ -0,0 +1,96 @@
+/// Response tracking
+///
+/// Remembers which message chaz sent in response to each prompt, so that edits and redactions of the prompt
+/// can be applied to the response as well. The mapping is saved in the state directory.
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use lazy_static::lazy_static;
+use matrix_sdk::ruma::{EventId, OwnedEventId};
+use tracing::error;
+
+use crate::{session, util::read_state};
+
+/// Maximum number of prompts to remember, older ones are forgotten first
+const MAX_RESPONSES: usize = 1000;
+
+/// Responses for each prompt, oldest first
+struct Responses {
+    path: PathBuf,
+    entries: VecDeque<(OwnedEventId, OwnedEventId)>,
+}
+
+lazy_static! {
+    static ref RESPONSES: Mutex<Option<Responses>> = Mutex::new(None);
+}
+
+/// Load the saved responses from the state directory
+pub fn init(state_dir: &Path) -> anyhow::Result<()> {
+    let path = state_dir.join("responses.json");
+    let entries = read_state(&path)?;
+    *RESPONSES.lock().unwrap() = Some(Responses { path, entries });
+    Ok(())
+}
+
+/// Get the response chaz sent for a prompt
+pub fn get(prompt: &EventId) -> Option<OwnedEventId> {
+    RESPONSES
+        .lock()
+        .unwrap()
+        .as_ref()?
+        .entries
+        .iter()
+        .find(|(p, _)| p == prompt)
+        .map(|(_, response)| response.clone())
+}
+
+/// Get the prompt that a response answered
+pub fn prompt_for(response: &EventId) -> Option<OwnedEventId> {
+    RESPONSES
+        .lock()
+        .unwrap()
+        .as_ref()?
+        .entries
+        .iter()
+        .find(|(_, r)| r == response)
+        .map(|(prompt, _)| prompt.clone())
+}
+
+/// Remember the response chaz sent for a prompt
+pub fn record(prompt: &EventId, response: &EventId) {
+    let mut responses = RESPONSES.lock().unwrap();
+    let Some(responses) = responses.as_mut() else {
+        return;
+    };
+    responses.entries.retain(|(p, _)| p != prompt);
+    responses
+        .entries
+        .push_back((prompt.to_owned(), response.to_owned()));
+    while responses.entries.len() > MAX_RESPONSES {
+        responses.entries.pop_front();
+    }
+    save(responses);
+}
+
+/// Forget a prompt, returning the response chaz sent for it
+pub fn remove(prompt: &EventId) -> Option<OwnedEventId> {
+    let mut responses = RESPONSES.lock().unwrap();
+    let responses = responses.as_mut()?;
+    let index = responses.entries.iter().position(|(p, _)| p == prompt)?;
+    let (_, response) = responses.entries.remove(index)?;
+    save(responses);
+    Some(response)
+}
+
+/// Write the responses to disk
+fn save(responses: &Responses) {
+    let result = serde_json::to_string(&responses.entries)
+        .map_err(anyhow::Error::from)
+        .and_then(|contents| Ok(session::write(&responses.path, &contents)?));
+    if let Err(e) = result {
+        error!("Unable to save responses: {}", e);
+    }
+}
@@ -5,8 +5,12 @@
 
 use serde::de::{self, Deserializer, Unexpected, Visitor};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::fmt;
+use std::path::{Path, PathBuf};
+use tracing::error;
 
+/// A role, the system prompt and example conversation given to the model
 #[derive(Debug, Deserialize, Clone)]
 pub struct RoleDetails {
     /// Name of the role, used to reference it
@@ -17,6 +21,35 @@ pub struct RoleDetails {
     prompt: Option<String>,
     /// Example Conversations
     example: Option<Vec<Message>>,
+    /// Translations of the role, keyed by language code, e.g. "fr" or "de"
+    translations: Option<HashMap<String, RoleTranslation>>,
+    /// Steps applied to the responses, in order
+    postprocess: Option<Vec<PostProcess>>,
+}
+
+/// A step cleaning up the responses of a role
+///
+/// Models often wrap their output in code blocks despite being told not to, which gets in the way of roles that
+/// should only return a command.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PostProcess {
+    /// Remove the code fences, keeping everything else
+    StripFences,
+    /// Keep only the contents of the first code block, if there is one
+    ExtractCode,
+    /// Trim the whitespace around the response
+    Trim,
+}
+
+/// The text of a role in another language
+///
+/// Anything left out falls back to the untranslated role.
+#[derive(Debug, Deserialize, Clone)]
+pub struct RoleTranslation {
+    description: Option<String>,
+    prompt: Option<String>,
+    example: Option<Vec<Message>>,
 }
 
 impl RoleDetails {
@@ -31,7 +64,35 @@ impl RoleDetails {
             description,
             prompt,
             example,
+            translations: None,
+            postprocess: None,
+        }
+    }
+
+    /// Use the translation for a language, if the role has one
+    ///
+    /// A regional language like "fr-CA" falls back to the translation for "fr".
+    pub fn localized(mut self, language: &str) -> Self {
+        let Some(translations) = self.translations.take() else {
+            return self;
+        };
+        let language = language.to_lowercase();
+        let base = language.split(['-', '_']).next().unwrap_or_default();
+        let translation = translations
+            .iter()
+            .find(|(code, _)| code.to_lowercase() == language)
+            .or_else(|| {
+                translations
+                    .iter()
+                    .find(|(code, _)| code.to_lowercase() == base)
+            });
+        if let Some((_, translation)) = translation {
+            let translation = translation.clone();
+            self.description = translation.description.or(self.description);
+            self.prompt = translation.prompt.or(self.prompt);
+            self.example = translation.example.or(self.example);
         }
+        self
     }
 
     pub fn get_prompt(&self) -> String {
@@ -40,6 +101,59 @@ impl RoleDetails {
         }
         "".to_string()
     }
+
+    /// Get the description, if there is one
+    pub fn get_description(&self) -> Option<String> {
+        self.description.clone()
+    }
+
+    /// Clean up a response with the post-processing steps of the role
+    pub fn postprocess(&self, response: String) -> String {
+        self.postprocess
+            .iter()
+            .flatten()
+            .fold(response, |response, step| match step {
+                PostProcess::StripFences => strip_fences(&response),
+                PostProcess::ExtractCode => extract_code(&response).unwrap_or(response),
+                PostProcess::Trim => response.trim().to_string(),
+            })
+    }
+
+    /// Append extra instructions to the system prompt
+    pub fn append_prompt(&mut self, extra: &str) {
+        self.prompt = match &self.prompt {
+            Some(prompt) if !prompt.is_empty() => Some(format!("{}\n\n{}", prompt, extra)),
+            _ => Some(extra.to_string()),
+        };
+    }
+}
+
+/// Check if a line opens or closes a code block
+fn is_fence(line: &str) -> bool {
+    line.trim_start().starts_with("```")
+}
+
+/// Remove the lines opening and closing code blocks
+fn strip_fences(response: &str) -> String {
+    response
+        .lines()
+        .filter(|line| !is_fence(line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Get the contents of the first code block
+///
+/// A block that's never closed runs to the end of the response.
+fn extract_code(response: &str) -> Option<String> {
+    let mut lines = response.lines().skip_while(|line| !is_fence(line));
+    lines.next()?;
+    Some(
+        lines
+            .take_while(|line| !is_fence(line))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    )
 }
 
 /// A single message in a conversation
@@ -98,7 +212,7 @@ impl<'de> Deserialize<'de> for MessageRole {
 
 /// Print details of a given role
 #[allow(dead_code)]
-pub fn print_role(
+pub(crate) fn print_role(
     role: Option<String>,
     role_list: Option<Vec<RoleDetails>>,
     default_roles: Option<Vec<RoleDetails>>,
@@ -120,14 +234,6 @@ pub fn print_role(
     }
 }
 
-/// Return all the role names defines in the input.
-pub fn get_role_names(roles: Option<Vec<RoleDetails>>) -> Vec<String> {
-    match roles {
-        Some(role_details) => role_details.into_iter().map(|role| role.name).collect(),
-        None => Vec::new(),
-    }
-}
-
 /// Get the role details from the role name
 pub fn get_role(
     role: Option<String>,
@@ -154,8 +260,101 @@ pub fn get_role(
     None
 }
 
+/// Load the roles in a directory
+///
+/// YAML files (`.yaml` or `.yml`) hold a role written like in the config, or a list of them. Markdown files
+/// (`.md`) are the prompt, with the other fields in an optional YAML front matter. The name defaults to the name of
+/// the file. Files that can't be read or parsed are skipped.
+pub fn load_dir(dir: &Path) -> Vec<RoleDetails> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Unable to read the roles in {}: {}", dir.display(), e);
+            return Vec::new();
+        }
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .collect();
+    paths.sort();
+    let mut roles = Vec::new();
+    for path in paths {
+        match load_file(&path) {
+            Ok(file_roles) => roles.extend(file_roles),
+            Err(e) => error!("Unable to load the roles in {}: {}", path.display(), e),
+        }
+    }
+    roles
+}
+
+/// Load the roles in a file, none if it isn't a role file
+fn load_file(path: &Path) -> Result<Vec<RoleDetails>, String> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) else {
+        return Ok(Vec::new());
+    };
+    if !matches!(extension, "yaml" | "yml" | "md") {
+        return Ok(Vec::new());
+    }
+    let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let values = if extension == "md" {
+        let (front_matter, prompt) = split_front_matter(&contents);
+        let mut value: serde_yaml::Value = match front_matter {
+            Some(front_matter) => serde_yaml::from_str(front_matter).map_err(|e| e.to_string())?,
+            None => serde_yaml::Value::Null,
+        };
+        if value.is_null() {
+            value = serde_yaml::Value::Mapping(Default::default());
+        }
+        if let Some(mapping) = value.as_mapping_mut() {
+            mapping.insert("prompt".into(), prompt.trim().into());
+        }
+        vec![value]
+    } else {
+        match serde_yaml::from_str(&contents).map_err(|e| e.to_string())? {
+            serde_yaml::Value::Sequence(roles) => roles,
+            value => vec![value],
+        }
+    };
+    values
+        .into_iter()
+        .map(|mut value| {
+            let mapping = value
+                .as_mapping_mut()
+                .ok_or("a role has to be a mapping of its fields")?;
+            if !mapping.contains_key("name") {
+                mapping.insert("name".into(), name.into());
+            }
+            serde_yaml::from_value(value).map_err(|e| e.to_string())
+        })
+        .collect()
+}
+
+/// Split the YAML front matter between `---` lines off the start of a Markdown file
+fn split_front_matter(contents: &str) -> (Option<&str>, &str) {
+    let Some(rest) = contents
+        .strip_prefix("---\n")
+        .or_else(|| contents.strip_prefix("---\r\n"))
+    else {
+        return (None, contents);
+    };
+    match rest.find("\n---") {
+        Some(end) => {
+            let body = rest[end + 4..]
+                .split_once('\n')
+                .map_or("", |(_, body)| body);
+            (Some(&rest[..end]), body)
+        }
+        None => (None, contents),
+    }
+}
+
 /// Prepends the role prompt to the message
-pub fn prepend_role(message: String, role_details: &RoleDetails) -> String {
+pub(crate) fn prepend_role(message: String, role_details: &RoleDetails) -> String {
     let mut role_prompt = role_details.prompt.clone().unwrap_or("".to_string());
     if !role_prompt.is_empty() {
         role_prompt = format!("SYSTEM: {}", role_prompt);
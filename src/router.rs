@@ -0,0 +1,205 @@
+/// Model router
+///
+/// Selecting the `router` model with `!chaz model router` has chaz pick a model for each request instead. The
+/// routes in the config are checked in order: a route for code, for long contexts, or for some keywords is used
+/// when its rules match the request. If none match and a routing `prompt` is set, a cheap classifier model picks
+/// one of the routes by their descriptions. Otherwise the `default` model is used. Routes to models that can't
+/// see the images or call the tools in the request are skipped. The pick is logged and shown by `!chaz status`.
+use std::{collections::HashMap, sync::Mutex};
+
+use lazy_static::lazy_static;
+use matrix_sdk::{ruma::OwnedRoomId, Room};
+use openai_api_rs::v1::chat_completion::MessageRole;
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::{
+    backends::{BackendManager, GenerationParams, Message},
+    context, get_backend, get_config, mqtt, ChatContext,
+};
+
+/// Name of the pseudo-model that turns on the router
+pub const MODEL: &str = "router";
+
+/// Most characters of the request shown to the classifier
+const CLASSIFIER_INPUT_LIMIT: usize = 2000;
+
+/// Configuration for the model router
+#[derive(Debug, Deserialize, Clone)]
+pub struct RouterConfig {
+    /// Model used when no route is picked
+    pub default: String,
+    /// Model that picks a route when no rules match, defaults to the chat_summary_model
+    pub classifier_model: Option<String>,
+    /// Instructions for the classifier, it's only asked when this is set
+    pub prompt: Option<String>,
+    /// The routes, checked in order
+    #[serde(default)]
+    pub routes: Vec<Route>,
+}
+
+/// A model and the requests it's used for
+#[derive(Debug, Deserialize, Clone)]
+pub struct Route {
+    pub model: String,
+    /// What the model is good at, shown to the classifier
+    pub description: Option<String>,
+    /// Use the model for requests containing code
+    pub code: Option<bool>,
+    /// Use the model when the context has at least this many tokens
+    pub min_tokens: Option<usize>,
+    /// Use the model for requests containing any of these words
+    #[serde(default)]
+    pub keywords: Vec<String>,
+}
+
+impl Route {
+    /// Check the rules of the route, a route without rules never matches
+    fn matches(&self, request: &str, tokens: usize) -> bool {
+        if self.code.is_none() && self.min_tokens.is_none() && self.keywords.is_empty() {
+            return false;
+        }
+        let request = request.to_lowercase();
+        self.code
+            .is_none_or(|code| looks_like_code(&request) == code)
+            && self.min_tokens.is_none_or(|min| tokens >= min)
+            && (self.keywords.is_empty()
+                || self
+                    .keywords
+                    .iter()
+                    .any(|keyword| request.contains(&keyword.to_lowercase())))
+    }
+}
+
+lazy_static! {
+    /// The last model picked in each room, with the reason
+    static ref SELECTIONS: Mutex<HashMap<OwnedRoomId, (String, String)>> = Mutex::new(HashMap::new());
+}
+
+/// Check if a request has code in it
+fn looks_like_code(request: &str) -> bool {
+    if request.contains("```") {
+        return true;
+    }
+    let code_lines = request
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| line.ends_with(';') || line.ends_with('{') || line.ends_with('}'))
+        .count();
+    code_lines >= 3
+}
+
+/// Describe the last model picked in the room
+pub fn last_selection(room: &Room) -> Option<String> {
+    SELECTIONS
+        .lock()
+        .unwrap()
+        .get(room.room_id())
+        .map(|(model, reason)| format!("{} ({})", model, reason))
+}
+
+/// Replace the `router` model in the context with the model picked for the request
+pub async fn route(room: &Room, context: &mut ChatContext) {
+    if context.model.as_deref() != Some(MODEL) {
+        return;
+    }
+    let Some(config) = get_config().router else {
+        // Let the backend use its default model
+        context.model = None;
+        return;
+    };
+    let request = context
+        .messages
+        .iter()
+        .rev()
+        .find(|message| message.role == MessageRole::user)
+        .map(|message| message.content.clone())
+        .unwrap_or_default();
+    let tokens = context::estimate_context_tokens(context);
+    // Only route to the models that can answer with the images and tools in the request
+    let backend = get_backend(room, None).await;
+    let media = !context.media.is_empty();
+    let tools = !get_config().tools.unwrap_or_default().is_empty() || mqtt::is_enabled(room);
+    let mut routes = Vec::new();
+    for route in &config.routes {
+        let capabilities = backend.capabilities(Some(&route.model)).await;
+        if capabilities.can_answer(media, tools) {
+            routes.push(route);
+        }
+    }
+    let (model, reason) = match routes.iter().find(|route| route.matches(&request, tokens)) {
+        Some(route) => (route.model.clone(), "matched its rules".to_string()),
+        None => match classify(&backend, &config, &routes, &request, tokens).await {
+            Some(model) => (model, "picked by the classifier".to_string()),
+            None => (config.default.clone(), "default".to_string()),
+        },
+    };
+    info!(
+        "Routed the request in {} to {}: {}",
+        room.room_id(),
+        model,
+        reason
+    );
+    SELECTIONS
+        .lock()
+        .unwrap()
+        .insert(room.room_id().to_owned(), (model.clone(), reason));
+    context.model = Some(model);
+}
+
+/// Ask the classifier model to pick a route, if there's a routing prompt
+async fn classify(
+    backend: &BackendManager,
+    config: &RouterConfig,
+    routes: &[&Route],
+    request: &str,
+    tokens: usize,
+) -> Option<String> {
+    let prompt = config.prompt.as_ref()?;
+    if routes.is_empty() {
+        return None;
+    }
+    let descriptions: Vec<String> = routes
+        .iter()
+        .map(|route| match &route.description {
+            Some(description) => format!("{}: {}", route.model, description),
+            None => route.model.clone(),
+        })
+        .collect();
+    let request: String = request.chars().take(CLASSIFIER_INPUT_LIMIT).collect();
+    let instructions = [
+        prompt.clone(),
+        format!("The models are:\n{}", descriptions.join("\n")),
+        format!("The conversation has about {} tokens.", tokens),
+        "Respond with only the name of the model that should answer this request, or DEFAULT if none fit."
+            .to_string(),
+        format!("The request:\n{}", request),
+    ]
+    .join("\n\n");
+    let context = ChatContext {
+        messages: vec![Message::new(MessageRole::user, instructions)],
+        model: config
+            .classifier_model
+            .clone()
+            .or(get_config().chat_summary_model),
+        role: None,
+        media: Vec::new(),
+        tools: Vec::new(),
+        schema: None,
+        params: GenerationParams::default(),
+        reported_cost: Default::default(),
+    };
+    match backend.execute(&context).await {
+        Ok(choice) => {
+            let choice = choice.trim().trim_matches('`');
+            routes
+                .iter()
+                .find(|route| route.model.eq_ignore_ascii_case(choice))
+                .map(|route| route.model.clone())
+        }
+        Err(e) => {
+            error!("Model router classifier failed: {}", e);
+            None
+        }
+    }
+}
@@ -0,0 +1,459 @@
+/// Reminders and scheduled prompts
+///
+/// `!chaz remind` posts a reminder once, and `!chaz schedule` runs a prompt through the backend on a cron schedule,
+/// e.g. for a daily standup summary. The jobs are saved in the state directory, and a background task runs them
+/// when they're due. All times are in UTC.
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use matrix_sdk::{
+    ruma::{events::room::message::RoomMessageEventContent, OwnedRoomId, OwnedUserId, RoomId},
+    Client, Room,
+};
+use openai_api_rs::v1::chat_completion::MessageRole;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, Instrument};
+
+use crate::{
+    accounts, admin,
+    backends::Message,
+    commands::Args,
+    error::ChazError,
+    generate, get_context, i18n, is_admin, is_allowed, language, logging, parse_duration,
+    post_response, rate_limit, session, shutdown,
+    util::{civil_from_days, format_utc, read_state},
+};
+
+/// How often the scheduler checks for jobs that are due
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Maximum number of jobs each user can have
+const MAX_JOBS_PER_USER: usize = 20;
+
+/// What a job does when it's due
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JobKind {
+    /// Post the text once
+    Reminder { text: String },
+    /// Run the prompt through the backend on every run of the cron schedule
+    Prompt { cron: String, prompt: String },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Job {
+    id: u64,
+    room: OwnedRoomId,
+    sender: OwnedUserId,
+    /// Seconds since the epoch
+    next_run: u64,
+    #[serde(flatten)]
+    kind: JobKind,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Jobs {
+    next_id: u64,
+    jobs: Vec<Job>,
+}
+
+struct ScheduleFile {
+    path: PathBuf,
+    jobs: Jobs,
+}
+
+lazy_static! {
+    static ref SCHEDULE: Mutex<Option<ScheduleFile>> = Mutex::new(None);
+}
+
+/// Seconds since the epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// A cron schedule, with the usual 5 fields: minute, hour, day of month, month, and day of week
+struct Cron {
+    minutes: [bool; 60],
+    hours: [bool; 24],
+    days: [bool; 32],
+    months: [bool; 13],
+    weekdays: [bool; 7],
+    /// Whether the day of month and day of week are restricted, if both are a day matches either
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl Cron {
+    /// Parse a schedule like "0 9 * * 1-5"
+    ///
+    /// Each field can be `*`, a number, a range like `1-5`, or a list of those, and each can have a step like `*/15`.
+    fn parse(schedule: &str) -> Result<Cron, String> {
+        let fields: Vec<&str> = schedule.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err("a cron schedule has 5 fields: minute hour day month weekday".to_string());
+        };
+        let mut cron = Cron {
+            minutes: [false; 60],
+            hours: [false; 24],
+            days: [false; 32],
+            months: [false; 13],
+            weekdays: [false; 7],
+            days_restricted: days != "*",
+            weekdays_restricted: weekdays != "*",
+        };
+        parse_field(minutes, 0, 59, &mut cron.minutes)?;
+        parse_field(hours, 0, 23, &mut cron.hours)?;
+        parse_field(days, 1, 31, &mut cron.days)?;
+        parse_field(months, 1, 12, &mut cron.months)?;
+        // Sunday can be 0 or 7
+        let mut weekday_values = [false; 8];
+        parse_field(weekdays, 0, 7, &mut weekday_values)?;
+        for (day, value) in weekday_values.iter().enumerate() {
+            cron.weekdays[day % 7] |= value;
+        }
+        Ok(cron)
+    }
+
+    fn matches_day(&self, day: usize, weekday: usize) -> bool {
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => self.days[day] || self.weekdays[weekday],
+            _ => self.days[day] && self.weekdays[weekday],
+        }
+    }
+
+    /// Find the next run after the given time, within a year
+    fn next_after(&self, seconds: u64) -> Option<u64> {
+        let mut time = (seconds / 60 + 1) * 60;
+        let end = time + 366 * 86400;
+        while time < end {
+            let days = (time / 86400) as i64;
+            let (_, month, day) = civil_from_days(days);
+            // The epoch was a Thursday
+            let weekday = (days + 4).rem_euclid(7) as usize;
+            if !self.months[month as usize] || !self.matches_day(day as usize, weekday) {
+                time = (days as u64 + 1) * 86400;
+                continue;
+            }
+            let hour = (time % 86400 / 3600) as usize;
+            if !self.hours[hour] {
+                time = (time / 3600 + 1) * 3600;
+                continue;
+            }
+            if self.minutes[(time % 3600 / 60) as usize] {
+                return Some(time);
+            }
+            time += 60;
+        }
+        None
+    }
+}
+
+/// Parse a single cron field into the allowed values
+fn parse_field(field: &str, min: usize, max: usize, values: &mut [bool]) -> Result<(), String> {
+    let invalid = || format!("invalid cron field \"{}\"", field);
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<usize>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (
+                start.parse().map_err(|_| invalid())?,
+                end.parse().map_err(|_| invalid())?,
+            )
+        } else {
+            let value = range.parse().map_err(|_| invalid())?;
+            // A single value with a step runs from there to the end
+            (value, if part.contains('/') { max } else { value })
+        };
+        if step == 0 || start < min || end > max || start > end {
+            return Err(invalid());
+        }
+        for value in (start..=end).step_by(step) {
+            values[value] = true;
+        }
+    }
+    Ok(())
+}
+
+/// Parse the time of a reminder, either a delay like "30m" or a time of day like "14:30"
+fn parse_time(input: &str) -> Option<u64> {
+    if let Some(delay) = parse_duration(input) {
+        return Some(now() + delay.as_secs());
+    }
+    let (hour, minute) = input.split_once(':')?;
+    let (hour, minute) = (hour.parse::<u64>().ok()?, minute.parse::<u64>().ok()?);
+    if hour > 23 || minute > 59 {
+        return None;
+    }
+    let now = now();
+    let today = now - now % 86400 + hour * 3600 + minute * 60;
+    Some(if today > now { today } else { today + 86400 })
+}
+
+/// Load the saved jobs from the state directory
+pub fn init(state_dir: &Path) -> anyhow::Result<()> {
+    let path = state_dir.join("schedule.json");
+    let jobs = read_state(&path)?;
+    *SCHEDULE.lock().unwrap() = Some(ScheduleFile { path, jobs });
+    Ok(())
+}
+
+/// Change the jobs and save them
+fn update<T>(change: impl FnOnce(&mut Jobs) -> T) -> Option<T> {
+    let mut schedule = SCHEDULE.lock().unwrap();
+    let schedule = schedule.as_mut()?;
+    let result = change(&mut schedule.jobs);
+    let saved = serde_json::to_string(&schedule.jobs)
+        .map_err(anyhow::Error::from)
+        .and_then(|contents| Ok(session::write(&schedule.path, &contents)?));
+    if let Err(e) = saved {
+        error!("Unable to save the schedule: {}", e);
+    }
+    Some(result)
+}
+
+/// Add a job, returning its ID
+fn add(room: &RoomId, sender: &OwnedUserId, next_run: u64, kind: JobKind) -> Result<u64, String> {
+    update(|jobs| {
+        if jobs.jobs.iter().filter(|job| &job.sender == sender).count() >= MAX_JOBS_PER_USER {
+            return Err(format!(
+                "you already have {} scheduled jobs, cancel one first",
+                MAX_JOBS_PER_USER
+            ));
+        }
+        jobs.next_id += 1;
+        jobs.jobs.push(Job {
+            id: jobs.next_id,
+            room: room.to_owned(),
+            sender: sender.clone(),
+            next_run,
+            kind,
+        });
+        Ok(jobs.next_id)
+    })
+    .unwrap_or(Err("the schedule isn't loaded".to_string()))
+}
+
+/// Remove the jobs of a room chaz left
+pub fn forget_room(room: &RoomId) {
+    update(|jobs| jobs.jobs.retain(|job| job.room != room));
+}
+
+/// Set a reminder, `!chaz remind <time> <text>`
+pub async fn remind(sender: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let text = args["text"].to_string();
+    let response = match parse_time(&args["time"]) {
+        None => i18n::notice_text(&room, "reminder-time", &[]).await,
+        Some(time) => match add(room.room_id(), &sender, time, JobKind::Reminder { text }) {
+            Ok(id) => {
+                let (id, time) = (id.to_string(), format_utc(time));
+                i18n::notice_text(&room, "reminder-set", &[("id", &id), ("time", &time)]).await
+            }
+            Err(e) => i18n::error_text(&room, &e).await,
+        },
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// Usage of `!chaz schedule`
+pub const SCHEDULE_USAGE: &str = "[<minute> <hour> <day> <month> <weekday> <prompt>|cancel <id>]";
+
+/// Schedule a prompt, `!chaz schedule <cron> <prompt>`, or list and cancel the jobs of the room
+pub async fn schedule(sender: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let fields = ["minute", "hour", "day", "month", "weekday"].map(|field| args.get(field));
+    let language = language::get(&room).await;
+    let language = language.as_deref();
+    let response = match (args.subcommand(), fields, args.get("prompt")) {
+        (Some("cancel"), _, _) => cancel(&sender, room.room_id(), &args["id"], language),
+        (_, [None, ..], _) => list(room.room_id(), language),
+        (_, [Some(minute), Some(hour), Some(day), Some(month), Some(weekday)], Some(prompt)) => {
+            let cron = [minute, hour, day, month, weekday].join(" ");
+            let prompt = prompt.to_string();
+            match Cron::parse(&cron).and_then(|schedule| {
+                schedule
+                    .next_after(now())
+                    .ok_or("the schedule never runs".to_string())
+            }) {
+                Ok(next_run) => {
+                    match add(
+                        room.room_id(),
+                        &sender,
+                        next_run,
+                        JobKind::Prompt { cron, prompt },
+                    ) {
+                        Ok(id) => {
+                            let (id, time) = (id.to_string(), format_utc(next_run));
+                            let args = [("id", id.as_str()), ("time", time.as_str())];
+                            i18n::notice_text(&room, "schedule-added", &args).await
+                        }
+                        Err(e) => i18n::error_text(&room, &e).await,
+                    }
+                }
+                Err(e) => i18n::error_text(&room, &e).await,
+            }
+        }
+        _ => i18n::notice_text(&room, "schedule-usage", &[("usage", SCHEDULE_USAGE)]).await,
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// Describe the jobs of a room
+fn list(room: &RoomId, language: Option<&str>) -> String {
+    let schedule = SCHEDULE.lock().unwrap();
+    let jobs: Vec<&Job> = schedule
+        .as_ref()
+        .map(|schedule| {
+            schedule
+                .jobs
+                .jobs
+                .iter()
+                .filter(|job| job.room == room)
+                .collect()
+        })
+        .unwrap_or_default();
+    if jobs.is_empty() {
+        return format!("!chaz {}", i18n::translate(language, "schedule-empty", &[]));
+    }
+    let mut response = format!("!chaz {}", i18n::translate(language, "schedule", &[]));
+    for job in jobs {
+        response.push_str(&match &job.kind {
+            JobKind::Reminder { text } => format!(
+                "\n- {}: reminder for {} at {}: {}",
+                job.id,
+                job.sender,
+                format_utc(job.next_run),
+                text
+            ),
+            JobKind::Prompt { cron, prompt } => format!(
+                "\n- {}: \"{}\" by {}, next at {}: {}",
+                job.id,
+                cron,
+                job.sender,
+                format_utc(job.next_run),
+                prompt
+            ),
+        });
+    }
+    response
+}
+
+/// Cancel a job in the room, only its creator or an admin can cancel it
+fn cancel(sender: &OwnedUserId, room: &RoomId, id: &str, language: Option<&str>) -> String {
+    let Ok(id) = id.parse::<u64>() else {
+        return format!(
+            "!chaz {}",
+            i18n::translate(language, "schedule-cancel-usage", &[])
+        );
+    };
+    let cancelled = update(|jobs| {
+        let position = jobs.jobs.iter().position(|job| {
+            job.id == id && job.room == room && (&job.sender == sender || is_admin(sender))
+        })?;
+        Some(jobs.jobs.remove(position))
+    })
+    .flatten();
+    let key = match cancelled {
+        Some(_) => "schedule-cancelled",
+        None => "schedule-unknown",
+    };
+    format!(
+        "!chaz {}",
+        i18n::translate(language, key, &[("id", &id.to_string())])
+    )
+}
+
+/// Run the jobs in the background when they're due
+pub fn start(client: Client) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+            let now = now();
+            // Reminders are done after one run, prompts move on to their next run
+            let due = update(|jobs| {
+                let due: Vec<Job> = jobs
+                    .jobs
+                    .iter()
+                    .filter(|job| job.next_run <= now)
+                    .cloned()
+                    .collect();
+                jobs.jobs.retain_mut(|job| {
+                    if job.next_run > now {
+                        return true;
+                    }
+                    match &job.kind {
+                        JobKind::Reminder { .. } => false,
+                        JobKind::Prompt { cron, .. } => {
+                            match Cron::parse(cron).ok().and_then(|cron| cron.next_after(now)) {
+                                Some(next_run) => {
+                                    job.next_run = next_run;
+                                    true
+                                }
+                                None => false,
+                            }
+                        }
+                    }
+                });
+                due
+            })
+            .unwrap_or_default();
+            for job in due {
+                run(&client, job).await;
+            }
+        }
+    });
+}
+
+/// Run a job that's due
+async fn run(client: &Client, job: Job) {
+    let Some(room) = accounts::get_room(&job.room) else {
+        return;
+    };
+    if !is_allowed(&job.sender) || admin::is_blocked(job.sender.as_str()) {
+        return;
+    }
+    let _in_flight = shutdown::track();
+    let span = logging::request_span(&room, &job.sender);
+    let result = async {
+        match job.kind {
+            JobKind::Reminder { text } => {
+                room.send(RoomMessageEventContent::text_plain(format!(
+                    "Reminder for {}: {}",
+                    job.sender, text
+                )))
+                .await?;
+            }
+            JobKind::Prompt { prompt, .. } => {
+                if rate_limit(&room, &job.sender).await {
+                    return Ok(());
+                }
+                info!("Running scheduled prompt {} from {}", job.id, job.sender);
+                let mut context = get_context(&room).await?;
+                context
+                    .messages
+                    .push(Message::new(MessageRole::user, prompt));
+                let (result, style) = generate(&room, &job.sender, context).await;
+                post_response(&room, &job.sender, result, style, None).await?;
+            }
+        }
+        Ok::<(), ChazError>(())
+    }
+    .instrument(span)
+    .await;
+    if let Err(e) = result {
+        crate::error::report(client, &format!("scheduled job {}", job.id), &e).await;
+    }
+}
@@ -0,0 +1,229 @@
+/// Secret providers
+///
+/// Secrets can be kept out of the config file and the state directory. Config values reference them with
+/// `${secret:<name>}`, and the passphrase of the encryption store is moved out of the session file into the
+/// provider set in `secrets`. The providers are environment variables (`CHAZ_SECRET_<NAME>`), a directory of
+/// files only readable by chaz, or the OS keyring: the Secret Service on Linux, the Keychain on macOS, and the
+/// Credential Manager on Windows.
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::session;
+
+/// Name of the encryption store passphrase in the provider
+const PASSPHRASE: &str = "store_passphrase";
+
+/// Service the secrets are stored under in the keyring
+const KEYRING_SERVICE: &str = "chaz";
+
+/// Where secrets are kept
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    /// `CHAZ_SECRET_<NAME>` environment variables
+    #[default]
+    Env,
+    /// Files named after the secret in a directory, readable only by chaz
+    File,
+    /// The OS keyring
+    Keyring,
+}
+
+/// Configuration for the secret provider
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct SecretsConfig {
+    pub provider: Option<Provider>,
+    /// Directory of the secret files, for the file provider
+    pub path: Option<String>,
+}
+
+impl SecretsConfig {
+    /// Read a secret
+    pub fn get(&self, name: &str) -> anyhow::Result<String> {
+        match self.provider.unwrap_or_default() {
+            Provider::Env => std::env::var(env_name(name))
+                .with_context(|| format!("environment variable {} is not set", env_name(name))),
+            Provider::File => {
+                let path = self.file(name)?;
+                check_private(&path)?;
+                Ok(std::fs::read_to_string(&path)?.trim().to_string())
+            }
+            Provider::Keyring => keyring_get(name),
+        }
+    }
+
+    /// Store a secret, if the provider can be written to
+    fn set(&self, name: &str, value: &str) -> anyhow::Result<()> {
+        match self.provider.unwrap_or_default() {
+            Provider::Env => Err(anyhow!(
+                "set {} to move it out of the session file",
+                env_name(name)
+            )),
+            Provider::File => write_private(&self.file(name)?, value),
+            Provider::Keyring => keyring_set(name, value),
+        }
+    }
+
+    /// Path of a secret for the file provider
+    fn file(&self, name: &str) -> anyhow::Result<PathBuf> {
+        let dir = self
+            .path
+            .as_ref()
+            .ok_or(anyhow!("the file secret provider needs a path"))?;
+        Ok(Path::new(dir).join(name))
+    }
+}
+
+/// Environment variable of a secret, e.g. `CHAZ_SECRET_STORE_PASSPHRASE`
+fn env_name(name: &str) -> String {
+    format!(
+        "CHAZ_SECRET_{}",
+        name.to_uppercase()
+            .replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+    )
+}
+
+/// Check that only chaz can read a secret file
+#[cfg(unix)]
+fn check_private(path: &Path) -> anyhow::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = std::fs::metadata(path)
+        .with_context(|| format!("unable to read {}", path.display()))?
+        .permissions()
+        .mode();
+    if mode & 0o077 != 0 {
+        return Err(anyhow!(
+            "{} can be read by other users, run chmod 600 on it",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Check that only chaz can read a secret file
+///
+/// Other platforms don't have the Unix permissions, the file is protected by the ACLs of its directory.
+#[cfg(not(unix))]
+fn check_private(path: &Path) -> anyhow::Result<()> {
+    std::fs::metadata(path).with_context(|| format!("unable to read {}", path.display()))?;
+    Ok(())
+}
+
+/// Write a secret file that only chaz can read
+fn write_private(path: &Path, value: &str) -> anyhow::Result<()> {
+    let mut dir = std::fs::DirBuilder::new();
+    let mut file = std::fs::OpenOptions::new();
+    file.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+        dir.mode(0o700);
+        file.mode(0o600);
+    }
+    if let Some(parent) = path.parent() {
+        dir.recursive(true).create(parent)?;
+    }
+    file.open(path)?.write_all(value.as_bytes())?;
+    Ok(())
+}
+
+/// Read a secret from the keyring
+fn keyring_get(name: &str) -> anyhow::Result<String> {
+    match keyring::Entry::new(KEYRING_SERVICE, name)?.get_password() {
+        Ok(value) if !value.is_empty() => Ok(value),
+        Ok(_) | Err(keyring::Error::NoEntry) => Err(anyhow!("{} is not in the keyring", name)),
+        Err(e) => Err(anyhow!("unable to read {} from the keyring: {}", name, e)),
+    }
+}
+
+/// Store a secret in the keyring
+fn keyring_set(name: &str, value: &str) -> anyhow::Result<()> {
+    keyring::Entry::new(KEYRING_SERVICE, name)?
+        .set_password(value)
+        .with_context(|| format!("unable to store {} in the keyring", name))
+}
+
+/// Read a session file as JSON
+fn read_session(session_file: &Path) -> Option<serde_json::Value> {
+    serde_json::from_str(&std::fs::read_to_string(session_file).ok()?).ok()
+}
+
+/// The store passphrase written back to the session file for the login, removed again when this is dropped
+pub struct RestoredPassphrase {
+    config: SecretsConfig,
+    session_file: PathBuf,
+}
+
+impl Drop for RestoredPassphrase {
+    fn drop(&mut self) {
+        scrub_passphrase(&self.config, &self.session_file);
+    }
+}
+
+/// Put the store passphrase in the session file for the login, or move it to the provider
+///
+/// Headjack only reads the passphrase from the session file, so it's written back for the login and removed
+/// again when the returned guard is dropped, even if the login panics. A passphrase that's already in the file is
+/// stored in the provider, which also cleans up after a login that was killed before it could remove it.
+pub fn restore_passphrase(config: &SecretsConfig, session_file: &Path) -> RestoredPassphrase {
+    let restored = RestoredPassphrase {
+        config: config.clone(),
+        session_file: session_file.to_path_buf(),
+    };
+    let Some(mut session) = read_session(session_file) else {
+        return restored;
+    };
+    if let Some(passphrase) = session["client_session"]["passphrase"].as_str() {
+        if config.get(PASSPHRASE).ok().as_deref() == Some(passphrase) {
+            return restored;
+        }
+        match config.set(PASSPHRASE, passphrase) {
+            Ok(()) => info!("Moved the store passphrase out of the session file"),
+            Err(e) => warn!("Keeping the store passphrase in the session file: {}", e),
+        }
+        return restored;
+    }
+    let passphrase = match config.get(PASSPHRASE) {
+        Ok(passphrase) => passphrase,
+        Err(e) => {
+            warn!("Unable to read the store passphrase: {}", e);
+            return restored;
+        }
+    };
+    session["client_session"]["passphrase"] = serde_json::Value::String(passphrase);
+    if let Err(e) = session::write(session_file, &session.to_string()) {
+        warn!("Unable to restore the store passphrase: {}", e);
+    }
+    restored
+}
+
+/// Remove the store passphrase from the session file and its backups, once the provider has it
+fn scrub_passphrase(config: &SecretsConfig, session_file: &Path) {
+    let Ok(stored) = config.get(PASSPHRASE) else {
+        return;
+    };
+    for path in std::iter::once(session_file.to_path_buf()).chain(session::backups(session_file)) {
+        let Some(mut session) = read_session(&path) else {
+            continue;
+        };
+        if session["client_session"]["passphrase"].as_str() != Some(&stored) {
+            continue;
+        }
+        if let Some(client_session) = session["client_session"].as_object_mut() {
+            client_session.remove("passphrase");
+        }
+        if let Err(e) = session::write(&path, &session.to_string()) {
+            warn!(
+                "Unable to remove the store passphrase from {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
@@ -0,0 +1,102 @@
+/// Session file safety
+///
+/// The session file holds the login and the passphrase of the encryption store, so losing it means logging in
+/// again with a new device and losing the encryption keys. It's only written atomically, and the last few
+/// good versions are kept as `session.bak.<n>` to restore from if it's ever corrupted.
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use tracing::{error, warn};
+
+/// Number of backups kept of the session file
+const BACKUPS: usize = 3;
+
+/// Path of a backup, `session.bak.1` is the newest
+fn backup_file(session_file: &Path, index: usize) -> PathBuf {
+    session_file.with_extension(format!("bak.{}", index))
+}
+
+/// Paths of the backups that exist, newest first
+pub fn backups(session_file: &Path) -> Vec<PathBuf> {
+    (1..=BACKUPS)
+        .map(|index| backup_file(session_file, index))
+        .filter(|backup| backup.exists())
+        .collect()
+}
+
+/// Check if a file holds a session that can be restored
+fn is_valid(path: &Path) -> bool {
+    std::fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| serde_json::from_str::<serde_json::Value>(&contents).ok())
+        .is_some_and(|session| {
+            session["client_session"].is_object() && session["user_session"].is_object()
+        })
+}
+
+/// Write a file atomically
+///
+/// The contents are written to a temporary file first so a crash can't leave a partial file behind, and flushed
+/// to disk before the rename so a power loss can't leave an empty one.
+pub fn write(path: &Path, contents: &str) -> std::io::Result<()> {
+    let temp_file = path.with_extension("tmp");
+    let mut file = File::create(&temp_file)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(temp_file, path)?;
+    // The rename is only durable once the directory is flushed too
+    if let Some(dir) = path.parent().and_then(|dir| File::open(dir).ok()) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// Check the session file before logging in
+///
+/// A valid session is added to the backups, and a corrupted one is replaced by the newest valid backup.
+pub fn check(session_file: &Path) {
+    if !session_file.exists() {
+        return;
+    }
+    if is_valid(session_file) {
+        if let Err(e) = backup(session_file) {
+            error!("Unable to back up the session: {}", e);
+        }
+        return;
+    }
+    let Some(backup) = (1..=BACKUPS)
+        .map(|index| backup_file(session_file, index))
+        .find(|backup| is_valid(backup))
+    else {
+        error!("The session file is corrupted and there is no backup to restore");
+        return;
+    };
+    warn!(
+        "The session file is corrupted, restoring it from {}",
+        backup.display()
+    );
+    let result =
+        std::fs::read_to_string(&backup).and_then(|contents| write(session_file, &contents));
+    if let Err(e) = result {
+        error!("Unable to restore the session: {}", e);
+    }
+}
+
+/// Rotate the backups and add the current session, unless it's unchanged since the last backup
+fn backup(session_file: &Path) -> std::io::Result<()> {
+    let contents = std::fs::read_to_string(session_file)?;
+    let newest = backup_file(session_file, 1);
+    if std::fs::read_to_string(&newest).is_ok_and(|backup| backup == contents) {
+        return Ok(());
+    }
+    for index in (1..BACKUPS).rev() {
+        let from = backup_file(session_file, index);
+        if from.exists() {
+            std::fs::rename(from, backup_file(session_file, index + 1))?;
+        }
+    }
+    write(&newest, &contents)
+}
@@ -0,0 +1,88 @@
+/// Graceful shutdown
+///
+/// On SIGTERM or SIGINT, or Ctrl-C on other platforms, chaz stops taking new messages and commands, and waits for the ones it's already handling
+/// to be answered, up to the `shutdown_timeout`, before it exits. The sync token and the usage are saved as they
+/// change, so nothing is lost by stopping the sync once the handlers are done.
+use std::{
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use tracing::error;
+
+/// How often the handlers are checked while draining
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Messages and commands being handled
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Set once a shutdown signal is received
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// Marks a message or command as being handled until it's dropped
+pub struct InFlight;
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Track a message or command, so shutting down waits for it
+pub fn track() -> InFlight {
+    IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+    InFlight
+}
+
+/// Check if chaz is shutting down, new messages and commands are ignored then
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::SeqCst)
+}
+
+/// Number of messages and commands being handled
+pub fn in_flight() -> usize {
+    IN_FLIGHT.load(Ordering::SeqCst)
+}
+
+/// Wait for SIGTERM or SIGINT, then stop taking new messages
+#[cfg(unix)]
+pub async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let (mut terminate, mut interrupt) = match (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) {
+        (Ok(terminate), Ok(interrupt)) => (terminate, interrupt),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Unable to listen for shutdown signals: {}", e);
+            return std::future::pending().await;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
+    DRAINING.store(true, Ordering::SeqCst);
+}
+
+/// Wait for Ctrl-C, then stop taking new messages
+#[cfg(not(unix))]
+pub async fn wait_for_signal() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!("Unable to listen for shutdown signals: {}", e);
+        return std::future::pending().await;
+    }
+    DRAINING.store(true, Ordering::SeqCst);
+}
+
+/// Wait for the messages and commands being handled to finish
+///
+/// Returns false if some were still running after the timeout.
+pub async fn drain(timeout: Duration) -> bool {
+    let finished = async {
+        while in_flight() > 0 {
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    };
+    tokio::time::timeout(timeout, finished).await.is_ok()
+}
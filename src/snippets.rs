@@ -0,0 +1,178 @@
+/// Prompt snippets
+///
+/// Reusable prompt fragments shared by everyone in a room. `!chaz snippet save <name>` in reply to a message saves
+/// that message, and `!chaz snippet use <name>` sends it to the model with the context of the room. Snippets are
+/// kept in the room state as `is.chaz.snippet` events keyed by name, so the whole room sees the same library, and
+/// chaz needs permission to send state events to save them.
+use matrix_sdk::{
+    deserialized_responses::RawAnySyncOrStrippedState,
+    room::MessagesOptions,
+    ruma::{
+        events::{room::message::RoomMessageEventContent, StateEventType},
+        OwnedEventId, OwnedUserId, UInt, UserId,
+    },
+    Room,
+};
+use openai_api_rs::v1::chat_completion::MessageRole;
+use serde_json::Value;
+use tracing::error;
+
+use crate::{
+    backends::Message,
+    commands::{split_words, Args},
+    error::ChazError,
+    get_context, i18n, rate_limit, respond,
+};
+
+/// Type of the state events the snippets are stored in
+const EVENT_TYPE: &str = "is.chaz.snippet";
+
+/// How many recent events are searched for the command, to find the message it replies to
+const SEARCH_LIMIT: u32 = 20;
+
+/// Save, use, delete, or list the snippets, `!chaz snippet [save|use|delete <name>]`
+pub async fn snippet(sender: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let name = &args["name"];
+    let rest = args.get("text");
+    let response = match args.subcommand() {
+        None => {
+            let names = list(&room).await;
+            if names.is_empty() {
+                i18n::notice_text(&room, "snippets-empty", &[]).await
+            } else {
+                let names = names.join("\n");
+                i18n::notice_text(&room, "snippets", &[("snippets", &names)]).await
+            }
+        }
+        Some("save") => {
+            // The text after the name is saved, otherwise the message the command replies to
+            let body = match rest {
+                Some(rest) => Some(rest.to_string()),
+                None => replied_to(&room, &sender, name).await,
+            };
+            match body {
+                Some(body) => match save(&room, name, &body).await {
+                    Ok(()) => i18n::notice_text(&room, "snippet-saved", &[("name", name)]).await,
+                    Err(e) => {
+                        let error = e.to_string();
+                        i18n::notice_text(&room, "snippet-save-failed", &[("error", &error)]).await
+                    }
+                },
+                None => i18n::notice_text(&room, "snippet-save-usage", &[]).await,
+            }
+        }
+        Some("use") => match get(&room, name).await {
+            Some(body) => {
+                if rate_limit(&room, &sender).await {
+                    return Ok(());
+                }
+                // Anything after the name is added to the snippet
+                let prompt = match rest {
+                    Some(rest) => format!("{}\n\n{}", body, rest),
+                    None => body,
+                };
+                let mut context = get_context(&room).await?;
+                context
+                    .messages
+                    .push(Message::new(MessageRole::user, prompt));
+                return respond(&room, &sender, context, None).await;
+            }
+            None => i18n::notice_text(&room, "snippet-unknown", &[("name", name)]).await,
+        },
+        _ => match get(&room, name).await {
+            // State events can't be removed, a snippet without a body is deleted
+            Some(_) => match save_content(&room, name, serde_json::json!({})).await {
+                Ok(()) => i18n::notice_text(&room, "snippet-deleted", &[("name", name)]).await,
+                Err(e) => {
+                    let error = e.to_string();
+                    i18n::notice_text(&room, "snippet-delete-failed", &[("error", &error)]).await
+                }
+            },
+            None => i18n::notice_text(&room, "snippet-unknown", &[("name", name)]).await,
+        },
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// Get the text of a snippet
+async fn get(room: &Room, name: &str) -> Option<String> {
+    let event = room
+        .get_state_event(StateEventType::from(EVENT_TYPE), name)
+        .await
+        .ok()??;
+    deserialize(event)?["content"]["body"]
+        .as_str()
+        .map(ToString::to_string)
+}
+
+/// Get the names of the snippets in the room
+async fn list(room: &Room) -> Vec<String> {
+    let events = match room
+        .get_state_events(StateEventType::from(EVENT_TYPE))
+        .await
+    {
+        Ok(events) => events,
+        Err(e) => {
+            error!("Unable to get the snippets of {}: {}", room.room_id(), e);
+            return Vec::new();
+        }
+    };
+    let mut names: Vec<String> = events
+        .into_iter()
+        .filter_map(|event| {
+            let event = deserialize(event)?;
+            event["content"]["body"].as_str()?;
+            Some(event["state_key"].as_str()?.to_string())
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+/// Save a snippet in the room state
+async fn save(room: &Room, name: &str, body: &str) -> Result<(), String> {
+    save_content(room, name, serde_json::json!({ "body": body })).await
+}
+
+async fn save_content(room: &Room, name: &str, content: Value) -> Result<(), String> {
+    room.send_state_event_raw(EVENT_TYPE, name, content)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Get the body of the message that `!chaz snippet save <name>` replies to
+///
+/// The command is found among the most recent messages from the sender.
+async fn replied_to(room: &Room, sender: &UserId, name: &str) -> Option<String> {
+    let mut options = MessagesOptions::backward();
+    options.limit = UInt::from(SEARCH_LIMIT);
+    let messages = room.messages(options).await.ok()?;
+    let reply_to = messages.chunk.iter().find_map(|event| {
+        let event = event.event.deserialize_as::<Value>().ok()?;
+        let body = event["content"]["body"].as_str()?;
+        let (words, rest) = split_words(body, 4);
+        if event["sender"] != sender.as_str()
+            || !rest.is_empty()
+            || words[..] != ["!chaz", "snippet", "save", name]
+        {
+            return None;
+        }
+        event["content"]["m.relates_to"]["m.in_reply_to"]["event_id"]
+            .as_str()
+            .and_then(|id| OwnedEventId::try_from(id).ok())
+    })?;
+    let event = room.event(&reply_to).await.ok()?;
+    let event = event.event.deserialize_as::<Value>().ok()?;
+    event["content"]["body"].as_str().map(ToString::to_string)
+}
+
+/// Get the JSON of a state event
+fn deserialize(event: RawAnySyncOrStrippedState) -> Option<Value> {
+    match event {
+        RawAnySyncOrStrippedState::Sync(raw) => raw.deserialize_as().ok(),
+        RawAnySyncOrStrippedState::Stripped(raw) => raw.deserialize_as().ok(),
+    }
+}
@@ -0,0 +1,125 @@
+/// Space settings
+///
+/// A Matrix Space can hold an `is.chaz.config` state event with settings for all of its rooms, so they can be
+/// managed in one place. Rooms inherit the settings of their parent spaces, with nearer spaces taking priority,
+/// and the settings of the room itself override both. Chaz has to be a member of the space to read them.
+use matrix_sdk::{
+    deserialized_responses::RawAnySyncOrStrippedState,
+    ruma::{events::StateEventType, OwnedRoomId},
+    Room,
+};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::usage::QuotaConfig;
+
+/// Type of the state event holding the settings
+const EVENT_TYPE: &str = "is.chaz.config";
+
+/// How many levels of parent spaces are searched
+const MAX_DEPTH: usize = 3;
+
+/// Settings shared by the rooms of a space
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct SpaceConfig {
+    /// Default role
+    pub role: Option<String>,
+    /// Default model
+    pub model: Option<String>,
+    /// Models that can be used in the rooms, any model if unset
+    pub models: Option<Vec<String>>,
+    /// Token limit of the context
+    pub context_token_limit: Option<usize>,
+    /// How long a conversation lasts without messages
+    pub context_ttl: Option<String>,
+    /// Per-account quotas
+    pub quotas: Option<QuotaConfig>,
+}
+
+impl SpaceConfig {
+    /// Fill in the settings this one leaves unset
+    fn inherit(self, parent: SpaceConfig) -> SpaceConfig {
+        SpaceConfig {
+            role: self.role.or(parent.role),
+            model: self.model.or(parent.model),
+            models: self.models.or(parent.models),
+            context_token_limit: self.context_token_limit.or(parent.context_token_limit),
+            context_ttl: self.context_ttl.or(parent.context_ttl),
+            quotas: self.quotas.or(parent.quotas),
+        }
+    }
+
+    /// Check if a model can be used
+    pub fn allows_model(&self, model: &str) -> bool {
+        self.models
+            .as_ref()
+            .is_none_or(|models| models.iter().any(|m| m == model))
+    }
+}
+
+/// Get the settings a room inherits from its spaces
+pub async fn settings(room: &Room) -> SpaceConfig {
+    let mut settings = SpaceConfig::default();
+    let mut rooms = vec![room.clone()];
+    for _ in 0..MAX_DEPTH {
+        let mut parents = Vec::new();
+        for room in &rooms {
+            for parent_id in parent_ids(room).await {
+                let Some(parent) = room.client().get_room(&parent_id) else {
+                    continue;
+                };
+                if let Some(config) = read_config(&parent).await {
+                    settings = settings.inherit(config);
+                }
+                parents.push(parent);
+            }
+        }
+        if parents.is_empty() {
+            break;
+        }
+        rooms = parents;
+    }
+    settings
+}
+
+/// Get the IDs of the spaces a room is in
+async fn parent_ids(room: &Room) -> Vec<OwnedRoomId> {
+    let Ok(events) = room.get_state_events(StateEventType::SpaceParent).await else {
+        return Vec::new();
+    };
+    events
+        .into_iter()
+        .filter_map(|event| {
+            let event = deserialize(event)?;
+            // A parent event without content has been removed
+            if event["content"].as_object().is_none_or(|c| c.is_empty()) {
+                return None;
+            }
+            OwnedRoomId::try_from(event["state_key"].as_str()?).ok()
+        })
+        .collect()
+}
+
+/// Read the settings stored in a space
+async fn read_config(space: &Room) -> Option<SpaceConfig> {
+    let event = space
+        .get_state_event(StateEventType::from(EVENT_TYPE), "")
+        .await
+        .ok()??;
+    let event = deserialize(event)?;
+    match serde_json::from_value(event["content"].clone()) {
+        Ok(config) => Some(config),
+        Err(e) => {
+            warn!("Invalid {} in {}: {}", EVENT_TYPE, space.room_id(), e);
+            None
+        }
+    }
+}
+
+/// Get the JSON of a state event
+fn deserialize(event: RawAnySyncOrStrippedState) -> Option<serde_json::Value> {
+    match event {
+        RawAnySyncOrStrippedState::Sync(raw) => raw.deserialize_as().ok(),
+        RawAnySyncOrStrippedState::Stripped(raw) => raw.deserialize_as().ok(),
+    }
+}
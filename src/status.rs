@@ -0,0 +1,107 @@
+/// Service status tracking
+///
+/// Tracks repeated backend failures and shows a status banner in the room topic of DMs while degraded,
+/// so users see that something is wrong before they hit an error.
+use std::{collections::HashMap, sync::Mutex};
+
+use lazy_static::lazy_static;
+use matrix_sdk::Client;
+use tracing::{error, info};
+
+/// Prefix of the banner appended to room topics
+const BANNER_PREFIX: &str = " ⚠ chaz degraded:";
+
+lazy_static! {
+    /// Consecutive failures for each backend
+    static ref BACKEND_FAILURES: Mutex<HashMap<String, u32>> = Mutex::new(HashMap::new());
+
+    /// Backends currently considered degraded, with the reason
+    static ref DEGRADED: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// Record the result of a request to a backend
+///
+/// Once a backend fails `threshold` times in a row it is marked as degraded, and the banner is updated.
+/// A single success clears it again.
+pub async fn record_result(
+    client: &Client,
+    backend: &str,
+    result: &Result<String, String>,
+    threshold: u32,
+) {
+    let changed = match result {
+        Ok(_) => {
+            BACKEND_FAILURES.lock().unwrap().remove(backend);
+            DEGRADED.lock().unwrap().remove(backend).is_some()
+        }
+        Err(e) => {
+            let failures = {
+                let mut failures = BACKEND_FAILURES.lock().unwrap();
+                let count = failures.entry(backend.to_string()).or_insert(0);
+                *count += 1;
+                *count
+            };
+            if failures >= threshold {
+                let reason = if e.contains("429") || e.to_lowercase().contains("quota") {
+                    format!("{} quota exhausted", backend)
+                } else {
+                    format!("{} unavailable", backend)
+                };
+                DEGRADED
+                    .lock()
+                    .unwrap()
+                    .insert(backend.to_string(), reason.clone())
+                    .as_ref()
+                    != Some(&reason)
+            } else {
+                false
+            }
+        }
+    };
+    if changed {
+        update_banner(client).await;
+    }
+}
+
+/// Get the current status, or None if everything is working
+pub fn current_status() -> Option<String> {
+    let degraded = DEGRADED.lock().unwrap();
+    if degraded.is_empty() {
+        None
+    } else {
+        let mut reasons: Vec<&String> = degraded.values().collect();
+        reasons.sort();
+        Some(
+            reasons
+                .into_iter()
+                .cloned()
+                .collect::<Vec<String>>()
+                .join(", "),
+        )
+    }
+}
+
+/// Update the banner in the topic of every DM room
+async fn update_banner(client: &Client) {
+    let status = current_status();
+    info!("Status changed: {}", status.as_deref().unwrap_or("ok"));
+    for room in client.joined_rooms() {
+        let is_direct = room.is_direct().await.unwrap_or(false) || room.joined_members_count() < 3;
+        if !is_direct {
+            continue;
+        }
+        let topic = room.topic().unwrap_or_default();
+        let base = topic
+            .split_once(BANNER_PREFIX)
+            .map_or(topic.as_str(), |(base, _)| base);
+        let new_topic = match &status {
+            Some(status) => format!("{}{} {}", base, BANNER_PREFIX, status),
+            None => base.to_string(),
+        };
+        if new_topic != topic {
+            if let Err(e) = room.set_room_topic(&new_topic).await {
+                error!("Unable to set status in {}: {}", room.room_id(), e);
+            }
+        }
+    }
+}
@@ -0,0 +1,245 @@
+/// Structured output
+///
+/// `!chaz json <schema> <prompt>` answers the prompt with JSON matching one of the `json_schemas` in the config,
+/// so chaz can extract data from the conversation for other tools. The schema is sent to the backends that support
+/// structured output, and the response is checked against it anyway. If it doesn't match, the model is told why
+/// and asked again, a few times at most.
+use std::time::Instant;
+
+use matrix_sdk::{
+    ruma::{events::room::message::RoomMessageEventContent, OwnedUserId},
+    Room,
+};
+use openai_api_rs::v1::chat_completion::MessageRole;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{
+    activity, backends::Message, commands::Args, error::ChazError, format, get_backend, get_config,
+    get_context, i18n, moderate_message, moderation, rate_limit, record_tokens, role::RoleDetails,
+    router,
+};
+
+/// Times the model is asked again after a response that doesn't match the schema
+const MAX_RETRIES: usize = 2;
+
+/// A JSON schema responses can be asked to follow
+#[derive(Debug, Deserialize, Clone)]
+pub struct JsonSchema {
+    pub name: String,
+    pub description: Option<String>,
+    /// The schema, written in YAML or JSON
+    pub schema: Value,
+}
+
+/// Answer a prompt with JSON, `!chaz json <schema> <prompt>`, or list the schemas
+pub async fn json(sender: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let schemas = get_config().json_schemas.unwrap_or_default();
+    let Some(name) = args.get("schema") else {
+        let response = if schemas.is_empty() {
+            i18n::notice_text(&room, "schemas-empty", &[]).await
+        } else {
+            let list = schemas
+                .iter()
+                .map(|schema| match &schema.description {
+                    Some(description) => format!("{} - {}", schema.name, description),
+                    None => schema.name.clone(),
+                })
+                .collect::<Vec<String>>()
+                .join("\n");
+            i18n::notice_text(&room, "schemas", &[("schemas", &list)]).await
+        };
+        room.send(RoomMessageEventContent::notice_plain(response))
+            .await?;
+        return Ok(());
+    };
+    let Some(schema) = schemas.into_iter().find(|schema| schema.name == name) else {
+        room.send(i18n::notice(&room, "schema-unknown", &[("name", name)]).await)
+            .await?;
+        return Ok(());
+    };
+    let Some(prompt) = args.get("prompt") else {
+        room.send(i18n::notice(&room, "schema-usage", &[]).await)
+            .await?;
+        return Ok(());
+    };
+    if rate_limit(&room, &sender).await || moderate_message(&room, &sender, prompt).await? {
+        return Ok(());
+    }
+
+    let mut context = get_context(&room).await?;
+    let instructions = format!(
+        "Respond with only a JSON value that matches this JSON schema, without any other text or code fences:\n{}",
+        schema.schema
+    );
+    match context.role.as_mut() {
+        Some(role) => role.append_prompt(&instructions),
+        None => context.role = Some(RoleDetails::new("json", None, Some(instructions), None)),
+    }
+    context.schema = Some(schema.schema.clone());
+    context
+        .messages
+        .push(Message::new(MessageRole::user, prompt.to_string()));
+    router::route(&room, &mut context).await;
+    let backend = get_backend(&room, Some(&sender)).await;
+    let mut attempt = 0;
+    let content = loop {
+        let started = Instant::now();
+        let result = activity::while_typing(&room, backend.execute(&context)).await;
+        record_tokens(
+            room.room_id(),
+            &sender,
+            &backend,
+            &context,
+            &result,
+            started,
+        );
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                let error = e.replace('\n', " ");
+                break i18n::notice(&room, "error", &[("message", &error)]).await;
+            }
+        };
+        let problem = match parse(&response) {
+            Ok(value) => match validate(&value, &schema.schema, "$") {
+                Ok(()) => {
+                    let pretty = serde_json::to_string_pretty(&value).unwrap_or(response);
+                    match moderation::check_response(&room, &pretty).await {
+                        Some(moderation::Action::Block) => return Ok(()),
+                        Some(moderation::Action::Redact) => {
+                            break i18n::notice(&room, "moderated-response", &[]).await;
+                        }
+                        _ => {}
+                    }
+                    break format::get(&room)
+                        .await
+                        .content(format!("```json\n{}\n```", pretty));
+                }
+                Err(problem) => problem,
+            },
+            Err(problem) => problem,
+        };
+        if attempt == MAX_RETRIES {
+            let tries = (attempt + 1).to_string();
+            let args = [
+                ("schema", schema.name.as_str()),
+                ("tries", tries.as_str()),
+                ("problem", problem.as_str()),
+            ];
+            break i18n::notice(&room, "schema-mismatch", &args).await;
+        }
+        attempt += 1;
+        context
+            .messages
+            .push(Message::new(MessageRole::assistant, response));
+        context.messages.push(Message::new(
+            MessageRole::user,
+            format!(
+                "That doesn't match the schema: {}. Respond again with only the corrected JSON.",
+                problem
+            ),
+        ));
+    };
+    room.send(content).await?;
+    Ok(())
+}
+
+/// Parse the JSON in a response, ignoring a code fence around it
+fn parse(response: &str) -> Result<Value, String> {
+    let trimmed = response.trim();
+    let json = trimmed
+        .strip_prefix("```json")
+        .or_else(|| trimmed.strip_prefix("```"))
+        .and_then(|inner| inner.strip_suffix("```"))
+        .unwrap_or(trimmed);
+    serde_json::from_str(json.trim()).map_err(|e| format!("it isn't valid JSON, {}", e))
+}
+
+/// Check a value against the common keywords of a JSON schema
+///
+/// Supports `type`, `enum`, `const`, `properties`, `required`, `additionalProperties: false`, `items`,
+/// `minItems`, and `maxItems`. Other keywords are ignored, so anything they'd reject still passes.
+fn validate(value: &Value, schema: &Value, path: &str) -> Result<(), String> {
+    if let Some(types) = schema.get("type") {
+        let types: Vec<&str> = match types {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !types.is_empty() && !types.iter().any(|name| has_type(value, name)) {
+            return Err(format!("{} should be {}", path, types.join(" or ")));
+        }
+    }
+    if let Some(options) = schema.get("enum").and_then(Value::as_array) {
+        if !options.contains(value) {
+            return Err(format!(
+                "{} should be one of {}",
+                path,
+                Value::from(options.clone())
+            ));
+        }
+    }
+    if let Some(constant) = schema.get("const") {
+        if constant != value {
+            return Err(format!("{} should be {}", path, constant));
+        }
+    }
+    if let Some(object) = value.as_object() {
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for required in schema
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+        {
+            if !object.contains_key(required) {
+                return Err(format!("{} is missing {}", path, required));
+            }
+        }
+        for (key, property) in object {
+            match properties.and_then(|properties| properties.get(key)) {
+                Some(property_schema) => {
+                    validate(property, property_schema, &format!("{}.{}", path, key))?
+                }
+                None if schema.get("additionalProperties") == Some(&Value::Bool(false)) => {
+                    return Err(format!("{} has the unexpected property {}", path, key));
+                }
+                None => {}
+            }
+        }
+    }
+    if let Some(array) = value.as_array() {
+        if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+            if (array.len() as u64) < min {
+                return Err(format!("{} should have at least {} items", path, min));
+            }
+        }
+        if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+            if array.len() as u64 > max {
+                return Err(format!("{} should have at most {} items", path, max));
+            }
+        }
+        if let Some(items) = schema.get("items") {
+            for (index, item) in array.iter().enumerate() {
+                validate(item, items, &format!("{}[{}]", path, index))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check if a value has a JSON schema type
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => true,
+    }
+}
@@ -0,0 +1,193 @@
+/// Syncing with the homeserver
+///
+/// Chaz runs its own sync loop instead of the one in headjack so that it can filter out the events it never uses.
+/// On accounts in many busy rooms, receipts, typing notifications, and presence make up most of every sync.
+///
+/// The sync token is saved in the state store instead of rewriting the session file each time, at most every few
+/// seconds and once more on shutdown. A token that's a little behind only means the initial sync after a crash
+/// goes over some events again, and the handlers aren't registered yet then so nothing is answered twice.
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use matrix_sdk::{
+    config::SyncSettings,
+    ruma::{
+        api::client::filter::{Filter, FilterDefinition, RoomEventFilter},
+        OwnedUserId,
+    },
+    Client, LoopCtrl,
+};
+use serde::Deserialize;
+use tracing::error;
+
+use crate::{health, history};
+
+/// Configuration for the sync filter
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct SyncFilterConfig {
+    /// Receive presence updates, off by default
+    pub presence: Option<bool>,
+    /// Receive read receipts and typing notifications, off by default
+    pub ephemeral: Option<bool>,
+    /// Additional event types to leave out of the room timelines
+    pub exclude_types: Option<Vec<String>>,
+}
+
+/// Build the filter used for every sync
+pub fn build_filter(config: &SyncFilterConfig) -> FilterDefinition {
+    // Enable room members lazy-loading, chaz only needs the members that are sending messages
+    // See <https://spec.matrix.org/v1.6/client-server-api/#lazy-loading-room-members>.
+    let mut filter = FilterDefinition::with_lazy_loading();
+    filter.room.timeline.lazy_load_options = filter.room.state.lazy_load_options;
+    if !config.presence.unwrap_or(false) {
+        filter.presence = Filter::ignore_all();
+    }
+    if !config.ephemeral.unwrap_or(false) {
+        filter.room.ephemeral = RoomEventFilter::ignore_all();
+    }
+    filter.room.timeline.not_types = config.exclude_types.clone().unwrap_or_default();
+    filter
+}
+
+/// Key of the sync token in the state store
+const SYNC_TOKEN_KEY: &[u8] = b"is.chaz.sync_token";
+
+/// Minimum time between saving the sync token
+const PERSIST_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The sync token of an account that hasn't been saved yet, and when one was last saved
+#[derive(Default)]
+struct PendingToken {
+    token: Option<String>,
+    last_saved: Option<Instant>,
+}
+
+lazy_static! {
+    static ref PENDING: Mutex<HashMap<Option<OwnedUserId>, PendingToken>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Sync settings starting from the saved token
+async fn sync_settings(
+    client: &Client,
+    session_file: &Path,
+    filter: FilterDefinition,
+) -> SyncSettings {
+    let mut sync_settings = SyncSettings::default().filter(filter.into());
+    if let Some(sync_token) = load_sync_token(client, session_file).await {
+        sync_settings = sync_settings.token(sync_token);
+    }
+    sync_settings
+}
+
+/// Sync to the current state of the homeserver
+///
+/// Retries until the sync succeeds.
+pub async fn initial_sync(
+    client: &Client,
+    session_file: &Path,
+    filter: FilterDefinition,
+) -> anyhow::Result<()> {
+    let sync_settings = sync_settings(client, session_file, filter).await;
+    loop {
+        match client.sync_once(sync_settings.clone()).await {
+            Ok(response) => {
+                history::check_gaps(&response);
+                health::record_sync();
+                persist_sync_token(client, response.next_batch).await?;
+                return Ok(());
+            }
+            Err(error) => {
+                error!("An error occurred during initial sync: {error}");
+                error!("Trying again…");
+            }
+        }
+    }
+}
+
+/// Sync forever, only returns on error
+pub async fn run(
+    client: &Client,
+    session_file: &Path,
+    filter: FilterDefinition,
+) -> anyhow::Result<()> {
+    let sync_settings = sync_settings(client, session_file, filter).await;
+    client
+        .sync_with_result_callback(sync_settings, |sync_result| async move {
+            let response = sync_result?;
+            history::check_gaps(&response);
+            health::record_sync();
+
+            // Persist the token to be able to restore our session
+            queue_sync_token(client, response.next_batch).await?;
+
+            Ok(LoopCtrl::Continue)
+        })
+        .await?;
+    Ok(())
+}
+
+/// Read the saved sync token
+///
+/// Sessions from older versions kept the token in the session file, so that's used if the store has none.
+async fn load_sync_token(client: &Client, session_file: &Path) -> Option<String> {
+    match client.store().get_custom_value(SYNC_TOKEN_KEY).await {
+        Ok(Some(sync_token)) => return String::from_utf8(sync_token).ok(),
+        Ok(None) => {}
+        Err(e) => error!("Unable to read the sync token: {e}"),
+    }
+    let session: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(session_file).ok()?).ok()?;
+    session["sync_token"].as_str().map(str::to_string)
+}
+
+/// Save the sync token, unless one was saved recently
+async fn queue_sync_token(client: &Client, sync_token: String) -> matrix_sdk::Result<()> {
+    let due = {
+        let mut pending = PENDING.lock().unwrap();
+        let pending = pending.entry(client.user_id().map(Into::into)).or_default();
+        pending.token = Some(sync_token);
+        pending
+            .last_saved
+            .is_none_or(|last_saved| last_saved.elapsed() >= PERSIST_INTERVAL)
+    };
+    if due {
+        flush(client).await?;
+    }
+    Ok(())
+}
+
+/// Save the latest sync token if it hasn't been yet, e.g. before shutting down
+pub async fn flush(client: &Client) -> matrix_sdk::Result<()> {
+    let user_id: Option<OwnedUserId> = client.user_id().map(Into::into);
+    let Some(sync_token) = PENDING
+        .lock()
+        .unwrap()
+        .get_mut(&user_id)
+        .and_then(|pending| pending.token.take())
+    else {
+        return Ok(());
+    };
+    persist_sync_token(client, sync_token).await?;
+    PENDING
+        .lock()
+        .unwrap()
+        .entry(user_id)
+        .or_default()
+        .last_saved = Some(Instant::now());
+    Ok(())
+}
+
+/// Save the sync token into the state store
+async fn persist_sync_token(client: &Client, sync_token: String) -> matrix_sdk::Result<()> {
+    client
+        .store()
+        .set_custom_value(SYNC_TOKEN_KEY, sync_token.into_bytes())
+        .await?;
+    Ok(())
+}
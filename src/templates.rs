@@ -0,0 +1,216 @@
+/// Prompt templates
+///
+/// Users save prompts they use often with `!chaz template save`, with `{1}`, `{2}`, ... placeholders for the
+/// arguments, and run them in any room with `!chaz template run`. They're stored in the bot's global account
+/// data under `is.chaz.templates`, keyed by user ID.
+use std::{collections::BTreeMap, sync::Mutex};
+
+use lazy_static::lazy_static;
+use matrix_sdk::{
+    ruma::{events::room::message::RoomMessageEventContent, OwnedUserId},
+    Client, Room,
+};
+use openai_api_rs::v1::chat_completion::MessageRole;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    account_data,
+    backends::Message,
+    commands::{split_words, Args},
+    error::ChazError,
+    get_context, i18n, rate_limit, respond,
+};
+
+/// Account data type the templates are stored in
+const ACCOUNT_DATA_TYPE: &str = "is.chaz.templates";
+
+/// Maximum number of templates a user can save
+const MAX_TEMPLATES: usize = 50;
+
+/// The stored templates of all users, by user and then by name
+#[derive(Serialize, Deserialize, Default)]
+struct StoredTemplates {
+    users: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+lazy_static! {
+    /// Templates loaded from the account data
+    static ref TEMPLATES: Mutex<Option<BTreeMap<String, BTreeMap<String, String>>>> = Mutex::new(None);
+}
+
+/// Get the templates of all users, loading them if needed
+async fn load_all(client: &Client) -> Result<BTreeMap<String, BTreeMap<String, String>>, String> {
+    if let Some(templates) = TEMPLATES.lock().unwrap().as_ref() {
+        return Ok(templates.clone());
+    }
+    let templates = account_data::get_global::<StoredTemplates>(client, ACCOUNT_DATA_TYPE)
+        .await?
+        .unwrap_or_default()
+        .users;
+    *TEMPLATES.lock().unwrap() = Some(templates.clone());
+    Ok(templates)
+}
+
+/// Change the templates of a user and save them
+async fn update(
+    client: &Client,
+    user: &OwnedUserId,
+    change: impl FnOnce(&mut BTreeMap<String, String>),
+) -> Result<(), String> {
+    let mut users = load_all(client).await?;
+    let templates = users.entry(user.to_string()).or_default();
+    change(templates);
+    if templates.len() > MAX_TEMPLATES {
+        return Err(format!(
+            "you can save up to {} templates, delete some first",
+            MAX_TEMPLATES
+        ));
+    }
+    if templates.is_empty() {
+        users.remove(user.as_str());
+    }
+    let stored = StoredTemplates { users };
+    if serde_json::to_string(&stored).map_or(0, |json| json.len()) > account_data::MAX_SIZE {
+        return Err("there's no room to store more templates".to_string());
+    }
+    account_data::set_global(client, ACCOUNT_DATA_TYPE, &stored).await?;
+    *TEMPLATES.lock().unwrap() = Some(stored.users);
+    Ok(())
+}
+
+/// Save, run, list, or delete the sender's templates
+///
+/// `!chaz template [save <name> <prompt>|run <name> [<args>]|delete <name>]`
+pub async fn template(sender: OwnedUserId, args: Args, room: Room) -> Result<(), ChazError> {
+    let client = room.client();
+    let name = &args["name"];
+    let response = match args.subcommand() {
+        None => match load_all(&client).await {
+            Ok(users) => match users.get(sender.as_str()) {
+                Some(templates) => {
+                    let templates = templates
+                        .iter()
+                        .map(|(name, prompt)| format!("- {}: {}", name, prompt))
+                        .collect::<Vec<String>>()
+                        .join("\n");
+                    i18n::notice_text(&room, "templates", &[("templates", &templates)]).await
+                }
+                None => i18n::notice_text(&room, "templates-empty", &[]).await,
+            },
+            Err(e) => i18n::error_text(&room, &e).await,
+        },
+        Some("save") => {
+            let prompt = args["prompt"].to_string();
+            let result = update(&client, &sender, |templates| {
+                templates.insert(name.to_string(), prompt);
+            })
+            .await;
+            match result {
+                Ok(()) => i18n::notice_text(&room, "template-saved", &[("name", name)]).await,
+                Err(e) => i18n::error_text(&room, &e).await,
+            }
+        }
+        Some("delete") => {
+            let mut found = false;
+            let result = update(&client, &sender, |templates| {
+                found = templates.remove(name).is_some();
+            })
+            .await;
+            match result {
+                Ok(()) if found => {
+                    i18n::notice_text(&room, "template-deleted", &[("name", name)]).await
+                }
+                Ok(()) => i18n::notice_text(&room, "template-unknown", &[("name", name)]).await,
+                Err(e) => i18n::error_text(&room, &e).await,
+            }
+        }
+        _ => {
+            let template = match load_all(&client).await {
+                Ok(users) => users
+                    .get(sender.as_str())
+                    .and_then(|templates| templates.get(name))
+                    .cloned(),
+                Err(e) => {
+                    room.send(RoomMessageEventContent::notice_plain(
+                        i18n::error_text(&room, &e).await,
+                    ))
+                    .await?;
+                    return Ok(());
+                }
+            };
+            match template {
+                Some(template) => {
+                    let args = args.get("args").unwrap_or_default();
+                    return run(&room, &sender, &template, args).await;
+                }
+                None => i18n::notice_text(&room, "template-unknown", &[("name", name)]).await,
+            }
+        }
+    };
+    room.send(RoomMessageEventContent::notice_plain(response))
+        .await?;
+    Ok(())
+}
+
+/// Fill in the placeholders of a template
+///
+/// Each `{n}` is replaced by the nth argument, and the highest one takes the rest of the text so it can hold a
+/// block of code or a paragraph. A template without placeholders gets the text appended.
+/// Returns the number of arguments needed if there aren't enough.
+fn fill(template: &str, text: &str) -> Result<String, usize> {
+    let placeholder = Regex::new(r"\{(\d+)\}").unwrap();
+    let count = placeholder
+        .captures_iter(template)
+        .filter_map(|captures| captures[1].parse::<usize>().ok())
+        .filter(|n| *n >= 1)
+        .max()
+        .unwrap_or(0);
+    if count == 0 {
+        return Ok(if text.is_empty() {
+            template.to_string()
+        } else {
+            format!("{}\n\n{}", template, text)
+        });
+    }
+    let (mut args, rest) = split_words(text, count - 1);
+    if !rest.is_empty() {
+        args.push(rest);
+    }
+    if args.len() < count {
+        return Err(count);
+    }
+    let filled = placeholder.replace_all(template, |captures: &regex::Captures| {
+        match captures[1].parse::<usize>() {
+            Ok(n) if n >= 1 => args[n - 1].to_string(),
+            _ => captures[0].to_string(),
+        }
+    });
+    Ok(filled.into_owned())
+}
+
+/// Run a template with the room's context, like a message to chaz
+async fn run(
+    room: &Room,
+    sender: &OwnedUserId,
+    template: &str,
+    text: &str,
+) -> Result<(), ChazError> {
+    let prompt = match fill(template, text) {
+        Ok(prompt) => prompt,
+        Err(count) => {
+            let count = count.to_string();
+            room.send(i18n::notice(room, "template-arguments", &[("count", &count)]).await)
+                .await?;
+            return Ok(());
+        }
+    };
+    if rate_limit(room, sender).await {
+        return Ok(());
+    }
+    let mut context = get_context(room).await?;
+    context
+        .messages
+        .push(Message::new(MessageRole::user, prompt));
+    respond(room, sender, context, None).await
+}
@@ -0,0 +1,653 @@
+/// Tools for function calling
+///
+/// Backends that support OpenAI function calling are offered the tools enabled in the config. When the model calls
+/// one, chaz runs it and sends the result back so the model can use it in its answer.
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use openai_api_rs::v1::{
+    chat_completion::{self, ToolCall, ToolType},
+    types::{Function, FunctionParameters, JSONSchemaDefine, JSONSchemaType},
+};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{mqtt, util::format_utc};
+
+/// Maximum number of characters of a fetched page returned to the model
+const WEB_FETCH_LIMIT: usize = 8000;
+
+/// Maximum number of bytes of a fetched page that are read, the rest is never downloaded
+const WEB_FETCH_MAX_BYTES: usize = 512 * 1024;
+
+/// Maximum number of redirects followed when fetching a page
+const WEB_FETCH_MAX_REDIRECTS: usize = 5;
+
+/// How long connecting to the server of a page can take
+const WEB_FETCH_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long fetching a page can take, including reading the body
+const WEB_FETCH_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// The tools that can be enabled in the config
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolName {
+    /// The current date and time
+    Time,
+    /// Evaluate an arithmetic expression
+    Calculator,
+    /// Fetch a web page as text
+    WebFetch,
+    /// Read the state of the MQTT entities, offered in the rooms set in `mqtt`
+    #[serde(skip_deserializing)]
+    HomeState,
+    /// Send a command to an MQTT entity, offered in the rooms set in `mqtt`
+    #[serde(skip_deserializing)]
+    HomeControl,
+}
+
+/// A tool the model can call
+pub trait Tool {
+    /// Name the model uses to call the tool
+    fn name(&self) -> &'static str;
+    /// Description shown to the model
+    fn description(&self) -> &'static str;
+    /// JSON schema for the arguments
+    fn parameters(&self) -> FunctionParameters;
+    /// Run the tool with the arguments from the model
+    async fn call(&self, arguments: &serde_json::Value) -> Result<String, String>;
+}
+
+impl ToolName {
+    /// The definition of the tool sent with the request
+    pub fn definition(&self) -> chat_completion::Tool {
+        match self {
+            ToolName::Time => definition(&CurrentTime),
+            ToolName::Calculator => definition(&Calculator),
+            ToolName::WebFetch => definition(&WebFetch),
+            ToolName::HomeState => definition(&HomeState),
+            ToolName::HomeControl => definition(&HomeControl),
+        }
+    }
+
+    async fn call(&self, arguments: &serde_json::Value) -> Result<String, String> {
+        match self {
+            ToolName::Time => CurrentTime.call(arguments).await,
+            ToolName::Calculator => Calculator.call(arguments).await,
+            ToolName::WebFetch => WebFetch.call(arguments).await,
+            ToolName::HomeState => HomeState.call(arguments).await,
+            ToolName::HomeControl => HomeControl.call(arguments).await,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ToolName::Time => CurrentTime.name(),
+            ToolName::Calculator => Calculator.name(),
+            ToolName::WebFetch => WebFetch.name(),
+            ToolName::HomeState => HomeState.name(),
+            ToolName::HomeControl => HomeControl.name(),
+        }
+    }
+}
+
+/// Build the definition sent to the model
+fn definition(tool: &impl Tool) -> chat_completion::Tool {
+    chat_completion::Tool {
+        r#type: ToolType::Function,
+        function: Function {
+            name: tool.name().to_string(),
+            description: Some(tool.description().to_string()),
+            parameters: tool.parameters(),
+        },
+    }
+}
+
+/// Run a tool call from the model, returning the result to send back
+///
+/// Errors are returned to the model as the result, so it can recover or explain.
+pub async fn run(tools: &[ToolName], call: &ToolCall) -> String {
+    let name = call.function.name.clone().unwrap_or_default();
+    let Some(tool) = tools.iter().find(|tool| tool.name() == name) else {
+        return format!("Error: unknown tool {}", name);
+    };
+    let arguments = match serde_json::from_str(call.function.arguments.as_deref().unwrap_or("{}")) {
+        Ok(arguments) => arguments,
+        Err(e) => return format!("Error: invalid arguments: {}", e),
+    };
+    match tool.call(&arguments).await {
+        Ok(result) => result,
+        Err(e) => format!("Error: {}", e),
+    }
+}
+
+/// Parameters with a single required string argument
+fn string_parameter(name: &str, description: &str) -> FunctionParameters {
+    let mut properties = HashMap::new();
+    properties.insert(
+        name.to_string(),
+        Box::new(JSONSchemaDefine {
+            schema_type: Some(JSONSchemaType::String),
+            description: Some(description.to_string()),
+            ..Default::default()
+        }),
+    );
+    FunctionParameters {
+        schema_type: JSONSchemaType::Object,
+        properties: Some(properties),
+        required: Some(vec![name.to_string()]),
+    }
+}
+
+/// Get a string argument from the model
+fn string_argument<'a>(arguments: &'a serde_json::Value, name: &str) -> Result<&'a str, String> {
+    arguments[name]
+        .as_str()
+        .ok_or(format!("missing argument {}", name))
+}
+
+/// Get the current date and time in UTC
+struct CurrentTime;
+
+impl Tool for CurrentTime {
+    fn name(&self) -> &'static str {
+        "current_time"
+    }
+
+    fn description(&self) -> &'static str {
+        "Get the current date and time in UTC"
+    }
+
+    fn parameters(&self) -> FunctionParameters {
+        FunctionParameters {
+            schema_type: JSONSchemaType::Object,
+            properties: Some(HashMap::new()),
+            required: None,
+        }
+    }
+
+    async fn call(&self, _: &serde_json::Value) -> Result<String, String> {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| e.to_string())?
+            .as_secs();
+        Ok(format_utc(seconds))
+    }
+}
+
+/// Evaluate an arithmetic expression
+struct Calculator;
+
+impl Tool for Calculator {
+    fn name(&self) -> &'static str {
+        "calculator"
+    }
+
+    fn description(&self) -> &'static str {
+        "Evaluate an arithmetic expression with +, -, *, /, %, ^ and parentheses"
+    }
+
+    fn parameters(&self) -> FunctionParameters {
+        string_parameter("expression", "The expression to evaluate, e.g. (2 + 3) * 4")
+    }
+
+    async fn call(&self, arguments: &serde_json::Value) -> Result<String, String> {
+        let expression = string_argument(arguments, "expression")?;
+        // The parser is recursive, so keep deeply nested input from overflowing the stack
+        if expression.len() > 1000 {
+            return Err("the expression is too long".to_string());
+        }
+        let mut parser = ExpressionParser {
+            input: expression.chars().filter(|c| !c.is_whitespace()).collect(),
+            position: 0,
+        };
+        let value = parser.expression()?;
+        if parser.position < parser.input.len() {
+            return Err(format!(
+                "unexpected '{}' in the expression",
+                parser.input[parser.position]
+            ));
+        }
+        Ok(value.to_string())
+    }
+}
+
+/// Recursive descent parser for arithmetic expressions
+struct ExpressionParser {
+    input: Vec<char>,
+    position: usize,
+}
+
+impl ExpressionParser {
+    fn peek(&self) -> Option<char> {
+        self.input.get(self.position).copied()
+    }
+
+    /// expression = term (('+' | '-') term)*
+    fn expression(&mut self) -> Result<f64, String> {
+        let mut value = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.position += 1;
+            let rhs = self.term()?;
+            value = if op == '+' { value + rhs } else { value - rhs };
+        }
+        Ok(value)
+    }
+
+    /// term = power (('*' | '/' | '%') power)*
+    fn term(&mut self) -> Result<f64, String> {
+        let mut value = self.power()?;
+        while let Some(op @ ('*' | '/' | '%')) = self.peek() {
+            self.position += 1;
+            let rhs = self.power()?;
+            value = match op {
+                '*' => value * rhs,
+                '/' => value / rhs,
+                _ => value % rhs,
+            };
+        }
+        Ok(value)
+    }
+
+    /// power = unary ('^' power)?
+    fn power(&mut self) -> Result<f64, String> {
+        let base = self.unary()?;
+        if self.peek() == Some('^') {
+            self.position += 1;
+            return Ok(base.powf(self.power()?));
+        }
+        Ok(base)
+    }
+
+    /// unary = '-' unary | atom
+    fn unary(&mut self) -> Result<f64, String> {
+        if self.peek() == Some('-') {
+            self.position += 1;
+            return Ok(-self.unary()?);
+        }
+        self.atom()
+    }
+
+    /// atom = number | '(' expression ')'
+    fn atom(&mut self) -> Result<f64, String> {
+        if self.peek() == Some('(') {
+            self.position += 1;
+            let value = self.expression()?;
+            if self.peek() != Some(')') {
+                return Err("missing ')'".to_string());
+            }
+            self.position += 1;
+            return Ok(value);
+        }
+        let start = self.position;
+        while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+            self.position += 1;
+        }
+        let number: String = self.input[start..self.position].iter().collect();
+        number.parse().map_err(|_| match self.peek() {
+            Some(c) => format!("unexpected '{}' in the expression", c),
+            None => "unexpected end of the expression".to_string(),
+        })
+    }
+}
+
+/// Fetch a web page as text
+struct WebFetch;
+
+impl Tool for WebFetch {
+    fn name(&self) -> &'static str {
+        "web_fetch"
+    }
+
+    fn description(&self) -> &'static str {
+        "Fetch a web page and return its text content"
+    }
+
+    fn parameters(&self) -> FunctionParameters {
+        string_parameter("url", "The http or https URL to fetch")
+    }
+
+    async fn call(&self, arguments: &serde_json::Value) -> Result<String, String> {
+        let url = string_argument(arguments, "url")?;
+        let mut url = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+        // The whole fetch is limited, including the redirects and reading the body
+        tokio::time::timeout(WEB_FETCH_TIMEOUT, async {
+            for _ in 0..=WEB_FETCH_MAX_REDIRECTS {
+                let mut response = fetch_public(&url).await?;
+                if response.status().is_redirection() {
+                    let location = response
+                        .headers()
+                        .get(reqwest::header::LOCATION)
+                        .and_then(|value| value.to_str().ok())
+                        .ok_or("redirect without a location")?;
+                    url = url.join(location).map_err(|e| e.to_string())?;
+                    continue;
+                }
+                if !response.status().is_success() {
+                    return Err(response.status().to_string());
+                }
+                let is_html = response
+                    .headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .is_some_and(|value| value.contains("html"));
+                let mut body = Vec::new();
+                while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+                    body.extend_from_slice(&chunk);
+                    if body.len() >= WEB_FETCH_MAX_BYTES {
+                        body.truncate(WEB_FETCH_MAX_BYTES);
+                        break;
+                    }
+                }
+                let body = String::from_utf8_lossy(&body).into_owned();
+                let text = if is_html { html_to_text(&body) } else { body };
+                return Ok(text.chars().take(WEB_FETCH_LIMIT).collect());
+            }
+            Err("too many redirects".to_string())
+        })
+        .await
+        .map_err(|_| "the page took too long to fetch".to_string())?
+    }
+}
+
+/// Send a request for a URL, without following redirects, if its host is on the public internet
+///
+/// The host is resolved first, and the request connects to the address that was checked, so a second lookup
+/// can't point it somewhere else.
+async fn fetch_public(url: &reqwest::Url) -> Result<reqwest::Response, String> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("only http and https URLs can be fetched".to_string());
+    }
+    let host = url.host_str().ok_or("the URL has no host")?;
+    let port = url.port_or_known_default().ok_or("the URL has no port")?;
+    let address = tokio::net::lookup_host((host.trim_matches(['[', ']']), port))
+        .await
+        .map_err(|e| e.to_string())?
+        .collect::<Vec<SocketAddr>>();
+    let Some(address) = address.first() else {
+        return Err(format!("unable to resolve {}", host));
+    };
+    if !is_public(address.ip()) {
+        return Err(format!("{} is not a public address", host));
+    }
+    reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .connect_timeout(WEB_FETCH_CONNECT_TIMEOUT)
+        .timeout(WEB_FETCH_TIMEOUT)
+        .resolve(host, *address)
+        .build()
+        .map_err(|e| e.to_string())?
+        .get(url.clone())
+        .header("User-Agent", format!("chaz/{}", env!("CARGO_PKG_VERSION")))
+        .send()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Check if an address is on the public internet, and not the bot's own network or the cloud metadata service
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // Shared address space, used by carrier-grade NAT
+                || (a == 100 && (64..128).contains(&b))
+                || a == 0)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = embedded_ipv4(ip) {
+                return is_public(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local
+                || (first & 0xfe00) == 0xfc00
+                // Link local
+                || (first & 0xffc0) == 0xfe80)
+        }
+    }
+}
+
+/// Get the IPv4 address an IPv6 address reaches, for the ranges that embed one
+///
+/// Covers IPv4-mapped and IPv4-compatible addresses, NAT64 with the well-known prefix, and 6to4.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let [a, b] = segments[6].to_be_bytes();
+    let [c, d] = segments[7].to_be_bytes();
+    match segments {
+        [0, 0, 0, 0, 0, 0xffff, ..] | [0, 0, 0, 0, 0, 0, ..] | [0x64, 0xff9b, 0, 0, 0, 0, ..] => {
+            Some(Ipv4Addr::new(a, b, c, d))
+        }
+        [0x2002, high, low, ..] => {
+            let [a, b] = high.to_be_bytes();
+            let [c, d] = low.to_be_bytes();
+            Some(Ipv4Addr::new(a, b, c, d))
+        }
+        _ => None,
+    }
+}
+
+/// Read the state of the things in the home
+struct HomeState;
+
+impl Tool for HomeState {
+    fn name(&self) -> &'static str {
+        "home_state"
+    }
+
+    fn description(&self) -> &'static str {
+        "Get the current state of the things in the home, e.g. doors, lights, and sensors"
+    }
+
+    fn parameters(&self) -> FunctionParameters {
+        let mut properties = HashMap::new();
+        properties.insert(
+            "entity".to_string(),
+            Box::new(JSONSchemaDefine {
+                schema_type: Some(JSONSchemaType::String),
+                description: Some(
+                    "Name of a single thing to check, all of them if left out".to_string(),
+                ),
+                ..Default::default()
+            }),
+        );
+        FunctionParameters {
+            schema_type: JSONSchemaType::Object,
+            properties: Some(properties),
+            required: None,
+        }
+    }
+
+    async fn call(&self, arguments: &serde_json::Value) -> Result<String, String> {
+        mqtt::describe_states(arguments["entity"].as_str())
+    }
+}
+
+/// Send a command to a thing in the home
+struct HomeControl;
+
+impl Tool for HomeControl {
+    fn name(&self) -> &'static str {
+        "home_control"
+    }
+
+    fn description(&self) -> &'static str {
+        "Send a command to a thing in the home, check home_state for the commands each one takes"
+    }
+
+    fn parameters(&self) -> FunctionParameters {
+        let mut parameters = string_parameter("entity", "Name of the thing to control");
+        if let Some(properties) = parameters.properties.as_mut() {
+            properties.insert(
+                "command".to_string(),
+                Box::new(JSONSchemaDefine {
+                    schema_type: Some(JSONSchemaType::String),
+                    description: Some("The command to send, e.g. ON".to_string()),
+                    ..Default::default()
+                }),
+            );
+        }
+        parameters.required = Some(vec!["entity".to_string(), "command".to_string()]);
+        parameters
+    }
+
+    async fn call(&self, arguments: &serde_json::Value) -> Result<String, String> {
+        mqtt::send_command(
+            string_argument(arguments, "entity")?,
+            string_argument(arguments, "command")?,
+        )
+    }
+}
+
+/// Roughly strip the markup from an HTML page
+fn html_to_text(html: &str) -> String {
+    let hidden = Regex::new(r"(?is)<(script|style|head)[^>]*>.*?</(script|style|head)>").unwrap();
+    let tags = Regex::new(r"(?s)<[^>]*>").unwrap();
+    let text = hidden.replace_all(html, " ");
+    let text = tags.replace_all(&text, " ");
+    text.replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(ip: &str) -> bool {
+        is_public(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn rejects_internal_ipv4() {
+        for ip in [
+            "127.0.0.1",
+            "127.255.255.254",
+            "10.0.0.1",
+            "172.16.0.1",
+            "172.31.255.255",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "0.1.2.3",
+            "255.255.255.255",
+            "224.0.0.1",
+        ] {
+            assert!(!public(ip), "{} should be rejected", ip);
+        }
+    }
+
+    #[test]
+    fn rejects_internal_ipv6() {
+        for ip in [
+            "::1",
+            "::",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "febf::1",
+            "ff02::1",
+        ] {
+            assert!(!public(ip), "{} should be rejected", ip);
+        }
+    }
+
+    #[test]
+    fn rejects_ipv6_embedding_internal_ipv4() {
+        for ip in [
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+            "::ffff:169.254.169.254",
+            "::127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b::7f00:1",
+            "2002:7f00:1::",
+            "2002:a9fe:a9fe::1",
+        ] {
+            assert!(!public(ip), "{} should be rejected", ip);
+        }
+    }
+
+    #[test]
+    fn allows_public_addresses() {
+        for ip in [
+            "1.1.1.1",
+            "8.8.8.8",
+            "172.32.0.1",
+            "100.128.0.1",
+            "2606:4700:4700::1111",
+            "::ffff:1.1.1.1",
+            "64:ff9b::808:808",
+        ] {
+            assert!(public(ip), "{} should be allowed", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn web_fetch_rejects_internal_urls() {
+        for url in [
+            "http://127.0.0.1/",
+            "http://[::1]:8080/",
+            "http://[::ffff:169.254.169.254]/latest/meta-data/",
+            "http://localhost/",
+            "file:///etc/passwd",
+        ] {
+            let arguments = serde_json::json!({ "url": url });
+            assert!(
+                WebFetch.call(&arguments).await.is_err(),
+                "{} should be rejected",
+                url
+            );
+        }
+    }
+
+    async fn calculate(expression: &str) -> Result<String, String> {
+        Calculator
+            .call(&serde_json::json!({ "expression": expression }))
+            .await
+    }
+
+    #[tokio::test]
+    async fn calculator_evaluates_expressions() {
+        assert_eq!(calculate("(2 + 3) * 4").await.unwrap(), "20");
+        assert_eq!(calculate("2 ^ 3 ^ 2").await.unwrap(), "512");
+        assert_eq!(calculate("-3 + 10 % 4").await.unwrap(), "-1");
+        assert_eq!(calculate("1.5 * 2").await.unwrap(), "3");
+    }
+
+    #[tokio::test]
+    async fn calculator_rejects_bad_expressions() {
+        for expression in ["2 +", "(1 + 2", "2 $ 3", "abc", ""] {
+            assert!(
+                calculate(expression).await.is_err(),
+                "{} should be rejected",
+                expression
+            );
+        }
+        // Deep nesting is refused before the recursive parser can overflow the stack
+        let nested = format!("{}1{}", "(".repeat(5000), ")".repeat(5000));
+        assert_eq!(
+            calculate(&nested).await,
+            Err("the expression is too long".to_string())
+        );
+    }
+}
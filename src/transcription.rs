@@ -0,0 +1,155 @@
+/// Audio transcription
+///
+/// Transcribes voice messages so they can be included in the context like any other message.
+/// Transcripts are cached for each event, so each message is only transcribed once.
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use lazy_static::lazy_static;
+use serde::Deserialize;
+
+/// The types of transcription backends
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum TranscriptionType {
+    /// A Whisper compatible `/audio/transcriptions` endpoint
+    OpenAI,
+    /// A local whisper.cpp binary
+    WhisperCpp,
+}
+
+/// Configuration for audio transcription
+#[derive(Debug, Deserialize, Clone)]
+pub struct TranscriptionConfig {
+    #[serde(rename = "type")]
+    pub transcription_type: TranscriptionType,
+    /// Base URL of the API, e.g. https://api.openai.com/v1
+    pub api_base: Option<String>,
+    pub api_key: Option<String>,
+    /// Model name for the API, or the path to the model file for whisper.cpp
+    pub model: Option<String>,
+    /// Path to the whisper.cpp binary, defaults to `whisper-cli`
+    ///
+    /// The binary needs to be able to read the audio format, e.g. built with ffmpeg support for Ogg/Opus.
+    pub binary: Option<String>,
+    /// Language of the audio, detected automatically if not set
+    pub language: Option<String>,
+}
+
+/// Counter to keep the temporary audio files unique
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// Transcripts of the messages, by event ID
+    static ref TRANSCRIPTS: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+/// Response from the transcriptions API
+#[derive(Deserialize)]
+struct TranscriptionResponse {
+    text: String,
+}
+
+impl TranscriptionConfig {
+    /// Transcribe an audio message, using the cached transcript if there is one
+    pub async fn transcribe(
+        &self,
+        event_id: Option<&str>,
+        audio: Vec<u8>,
+        filename: &str,
+    ) -> Result<String, String> {
+        if let Some(transcript) =
+            event_id.and_then(|id| TRANSCRIPTS.lock().unwrap().get(id).cloned())
+        {
+            return Ok(transcript);
+        }
+        let transcript = match self.transcription_type {
+            TranscriptionType::OpenAI => self.transcribe_api(audio, filename).await?,
+            TranscriptionType::WhisperCpp => self.transcribe_local(audio, filename).await?,
+        };
+        let transcript = transcript.trim().to_string();
+        if let Some(event_id) = event_id {
+            TRANSCRIPTS
+                .lock()
+                .unwrap()
+                .insert(event_id.to_string(), transcript.clone());
+        }
+        Ok(transcript)
+    }
+
+    /// Transcribe with a Whisper compatible API
+    async fn transcribe_api(&self, audio: Vec<u8>, filename: &str) -> Result<String, String> {
+        let api_base = self
+            .api_base
+            .clone()
+            .ok_or("Transcription API base doesn't exist".to_string())?;
+        let mut form = reqwest::multipart::Form::new()
+            .text(
+                "model",
+                self.model.clone().unwrap_or("whisper-1".to_string()),
+            )
+            .part(
+                "file",
+                reqwest::multipart::Part::bytes(audio).file_name(filename.to_string()),
+            );
+        if let Some(language) = &self.language {
+            form = form.text("language", language.clone());
+        }
+        let response = reqwest::Client::new()
+            .post(format!(
+                "{}/audio/transcriptions",
+                api_base.trim_end_matches('/')
+            ))
+            .bearer_auth(self.api_key.clone().unwrap_or_default())
+            .multipart(form)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(format!("{}: {}", status, body));
+        }
+        let response = response
+            .json::<TranscriptionResponse>()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(response.text)
+    }
+
+    /// Transcribe with a local whisper.cpp binary
+    async fn transcribe_local(&self, audio: Vec<u8>, filename: &str) -> Result<String, String> {
+        // whisper.cpp reads from a file, so write the audio out first
+        let path = std::env::temp_dir().join(format!(
+            "chaz-{}-{}-{}",
+            std::process::id(),
+            TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed),
+            filename.replace('/', "_")
+        ));
+        tokio::fs::write(&path, audio)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut command =
+            tokio::process::Command::new(self.binary.clone().unwrap_or("whisper-cli".to_string()));
+        command.arg("--no-timestamps").arg("-f").arg(&path);
+        if let Some(model) = &self.model {
+            command.arg("-m").arg(model);
+        }
+        if let Some(language) = &self.language {
+            command.arg("-l").arg(language);
+        }
+        let output = command.output().await;
+        let _ = tokio::fs::remove_file(&path).await;
+        let output = output.map_err(|e| e.to_string())?;
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).to_string())
+        } else {
+            Err(String::from_utf8_lossy(&output.stderr).to_string())
+        }
+    }
+}
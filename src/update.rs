@@ -0,0 +1,106 @@
+/// Update check
+///
+/// Compares the running version against the latest GitHub release, and lets the admin room know when there is
+/// a newer one.
+use matrix_sdk::{ruma::events::room::message::RoomMessageEventContent, Client};
+use openai_api_rs::v1::chat_completion::MessageRole;
+use serde::Deserialize;
+use tracing::{error, info};
+
+use crate::{
+    backends::{ChatContext, Message},
+    get_admin_room, get_backend, get_chat_summary_model, i18n, moderation,
+};
+
+/// GitHub API endpoint for the latest release
+const RELEASES_URL: &str = "https://api.github.com/repos/arcuru/chaz/releases/latest";
+
+/// A release on GitHub
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+    html_url: String,
+    /// The changelog
+    body: Option<String>,
+}
+
+/// Fetch the latest release from GitHub
+async fn latest_release() -> Result<Release, String> {
+    let response = reqwest::Client::new()
+        .get(RELEASES_URL)
+        // GitHub rejects requests without a user agent
+        .header("User-Agent", format!("chaz/{}", env!("CARGO_PKG_VERSION")))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(response.status().to_string());
+    }
+    response.json::<Release>().await.map_err(|e| e.to_string())
+}
+
+/// Parse a version like "v1.2.3" into its numeric parts
+fn parse_version(version: &str) -> Vec<u64> {
+    version
+        .trim_start_matches('v')
+        .split(['.', '-', '+'])
+        .map_while(|part| part.parse().ok())
+        .collect()
+}
+
+/// Check for a newer release and post it to the admin room
+///
+/// The changelog is summarized with the chat summary model.
+pub async fn check_for_update(client: &Client) {
+    let current = env!("CARGO_PKG_VERSION");
+    let release = match latest_release().await {
+        Ok(release) => release,
+        Err(e) => {
+            error!("Unable to check for updates: {}", e);
+            return;
+        }
+    };
+    if parse_version(&release.tag_name) <= parse_version(current) {
+        return;
+    }
+    info!(
+        "Update available: {} (running {}) {}",
+        release.tag_name, current, release.html_url
+    );
+    let Some(room) = get_admin_room(client) else {
+        return;
+    };
+
+    let args = [("version", release.tag_name.as_str()), ("current", current)];
+    let mut response = i18n::notice_text(&room, "update-available", &args).await;
+    if let Some(changelog) = release.body.filter(|body| !body.trim().is_empty()) {
+        let context = ChatContext {
+            messages: vec![Message::new(
+                MessageRole::user,
+                format!(
+                    "Summarize these release notes in a few short bullet points for the operator of the bot. Do not output anything except for the summary.\n\n{}",
+                    changelog
+                ),
+            )],
+            model: get_chat_summary_model(),
+            media: Vec::new(),
+            tools: Vec::new(),
+            schema: None,
+            params: Default::default(),
+            reported_cost: Default::default(),
+            role: None,
+        };
+        if let Ok(summary) = get_backend(&room, None).await.execute(&context).await {
+            if !moderation::blocks_output(&room, &summary).await {
+                response.push_str(&format!("\n\n{}", summary.trim()));
+            }
+        }
+    }
+    response.push_str(&format!("\n\n{}", release.html_url));
+    if let Err(e) = room
+        .send(RoomMessageEventContent::notice_markdown(response))
+        .await
+    {
+        error!("Unable to post the update notice: {}", e);
+    }
+}
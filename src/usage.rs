@@ -0,0 +1,230 @@
+/// Usage accounting and quotas
+///
+/// Counts the messages and estimated tokens of each user per day and per month, so quotas hold across restarts.
+/// The counts are saved in the state directory.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{
+    session,
+    util::{format_utc, read_state},
+};
+
+/// Configuration for the usage quotas
+///
+/// Each limit is per user, and unset limits aren't enforced.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct QuotaConfig {
+    pub daily_messages: Option<u64>,
+    pub monthly_messages: Option<u64>,
+    pub daily_tokens: Option<u64>,
+    pub monthly_tokens: Option<u64>,
+}
+
+/// Usage over a period
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+struct Counts {
+    messages: u64,
+    tokens: u64,
+}
+
+impl Counts {
+    fn add(&mut self, messages: u64, tokens: u64) {
+        self.messages += messages;
+        self.tokens += tokens;
+    }
+}
+
+/// Usage of a single user
+#[derive(Serialize, Deserialize, Default)]
+struct UserUsage {
+    total: Counts,
+    /// The day of the daily counts, e.g. "2024-05-01"
+    day: String,
+    daily: Counts,
+    /// The month of the monthly counts, e.g. "2024-05"
+    month: String,
+    monthly: Counts,
+}
+
+impl UserUsage {
+    /// Reset the counts of periods that are over
+    fn roll_over(&mut self, today: &str) {
+        if self.day != today {
+            self.day = today.to_string();
+            self.daily = Counts::default();
+        }
+        if self.month != today[..7] {
+            self.month = today[..7].to_string();
+            self.monthly = Counts::default();
+        }
+    }
+}
+
+struct Usage {
+    path: PathBuf,
+    users: HashMap<String, UserUsage>,
+}
+
+lazy_static! {
+    static ref USAGE: Mutex<Option<Usage>> = Mutex::new(None);
+}
+
+/// Today's date in UTC, e.g. "2024-05-01"
+fn today() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    format_utc(seconds)[..10].to_string()
+}
+
+/// Load the saved usage from the state directory
+pub fn init(state_dir: &Path) -> anyhow::Result<()> {
+    let path = state_dir.join("usage.json");
+    let users = read_state(&path)?;
+    *USAGE.lock().unwrap() = Some(Usage { path, users });
+    Ok(())
+}
+
+/// Check the user's usage against the quotas
+///
+/// Returns the reason if a quota is reached.
+pub fn check(user: &str, quotas: &QuotaConfig) -> Result<(), String> {
+    let mut usage = USAGE.lock().unwrap();
+    let Some(usage) = usage.as_mut() else {
+        return Ok(());
+    };
+    let user_usage = usage.users.entry(user.to_string()).or_default();
+    user_usage.roll_over(&today());
+    let limits = [
+        (
+            user_usage.daily.messages,
+            quotas.daily_messages,
+            "daily message quota",
+        ),
+        (
+            user_usage.monthly.messages,
+            quotas.monthly_messages,
+            "monthly message quota",
+        ),
+        (
+            user_usage.daily.tokens,
+            quotas.daily_tokens,
+            "daily token quota",
+        ),
+        (
+            user_usage.monthly.tokens,
+            quotas.monthly_tokens,
+            "monthly token quota",
+        ),
+    ];
+    for (used, limit, name) in limits {
+        if let Some(limit) = limit {
+            if used >= limit {
+                return Err(format!("you have used up your {} of {}", name, limit));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Count messages and estimated tokens for a user
+pub fn record(user: &str, messages: u64, tokens: u64) {
+    let mut usage = USAGE.lock().unwrap();
+    let Some(usage) = usage.as_mut() else {
+        return;
+    };
+    let user_usage = usage.users.entry(user.to_string()).or_default();
+    user_usage.roll_over(&today());
+    user_usage.total.add(messages, tokens);
+    user_usage.daily.add(messages, tokens);
+    user_usage.monthly.add(messages, tokens);
+    save(usage);
+}
+
+/// Describe what's left of the user's quotas, or None if no quota is set
+pub fn remaining(user: &str, quotas: &QuotaConfig) -> Option<String> {
+    let mut usage = USAGE.lock().unwrap();
+    let usage = usage.as_mut()?;
+    let user_usage = usage.users.entry(user.to_string()).or_default();
+    user_usage.roll_over(&today());
+    let limits = [
+        (
+            user_usage.daily.messages,
+            quotas.daily_messages,
+            "daily messages",
+        ),
+        (
+            user_usage.monthly.messages,
+            quotas.monthly_messages,
+            "monthly messages",
+        ),
+        (user_usage.daily.tokens, quotas.daily_tokens, "daily tokens"),
+        (
+            user_usage.monthly.tokens,
+            quotas.monthly_tokens,
+            "monthly tokens",
+        ),
+    ];
+    let remaining: Vec<String> = limits
+        .into_iter()
+        .filter_map(|(used, limit, name)| {
+            limit.map(|limit| format!("{} of {} {}", limit.saturating_sub(used), limit, name))
+        })
+        .collect();
+    (!remaining.is_empty()).then(|| remaining.join(", "))
+}
+
+/// Describe the usage of a user
+pub fn report(user: &str, quotas: &QuotaConfig) -> String {
+    let mut usage = USAGE.lock().unwrap();
+    let Some(usage) = usage.as_mut() else {
+        return "Usage isn't being tracked".to_string();
+    };
+    let user_usage = usage.users.entry(user.to_string()).or_default();
+    user_usage.roll_over(&today());
+    let limit = |limit: Option<u64>| match limit {
+        Some(limit) => format!(" of {}", limit),
+        None => String::new(),
+    };
+    [
+        format!(
+            "Today: {} messages{}, ~{} tokens{}",
+            user_usage.daily.messages,
+            limit(quotas.daily_messages),
+            user_usage.daily.tokens,
+            limit(quotas.daily_tokens)
+        ),
+        format!(
+            "This month: {} messages{}, ~{} tokens{}",
+            user_usage.monthly.messages,
+            limit(quotas.monthly_messages),
+            user_usage.monthly.tokens,
+            limit(quotas.monthly_tokens)
+        ),
+        format!(
+            "Total: {} messages, ~{} tokens",
+            user_usage.total.messages, user_usage.total.tokens
+        ),
+    ]
+    .join("\n")
+}
+
+/// Write the usage to disk
+fn save(usage: &Usage) {
+    let result = serde_json::to_string(&usage.users)
+        .map_err(anyhow::Error::from)
+        .and_then(|contents| Ok(session::write(&usage.path, &contents)?));
+    if let Err(e) = result {
+        error!("Unable to save usage: {}", e);
+    }
+}
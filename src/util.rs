@@ -0,0 +1,56 @@
+//! Helpers shared by the modules
+//!
+//! Dates are formatted by hand, so chaz doesn't need a date library for a few timestamps.
+
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+
+/// Read a JSON file from the state directory, a missing file is empty
+///
+/// A file that can't be read or parsed is an error rather than empty, so the next save doesn't overwrite what's in
+/// it.
+pub fn read_state<T: DeserializeOwned + Default>(path: &Path) -> anyhow::Result<T> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents).map_err(|e| {
+            anyhow::anyhow!(
+                "{} is corrupted, fix or remove it to start: {}",
+                path.display(),
+                e
+            )
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(anyhow::anyhow!("Unable to read {}: {}", path.display(), e)),
+    }
+}
+
+/// Convert days since the epoch to a civil date, as (year, month, day)
+///
+/// See <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Format seconds since the epoch as an ISO 8601 date and time in UTC
+pub fn format_utc(seconds: u64) -> String {
+    let time = seconds % 86400;
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
@@ -0,0 +1,264 @@
+/// Device verification and key recovery
+///
+/// Admins can verify chaz's device with interactive emoji verification, so encrypted rooms keep working.
+/// Chaz can't compare the emoji itself, so it posts them for the admin to compare, and waits for
+/// `!chaz verify confirm` before confirming its side.
+///
+/// With a recovery key in the config, the cross-signing identity and room key backup are restored from secret
+/// storage on startup, so history stays readable after losing the state directory.
+use std::{collections::HashSet, sync::Mutex};
+
+use futures_util::StreamExt;
+use lazy_static::lazy_static;
+use matrix_sdk::{
+    encryption::verification::{
+        SasState, SasVerification, Verification, VerificationRequest, VerificationRequestState,
+    },
+    ruma::{
+        events::{
+            key::verification::{
+                request::ToDeviceKeyVerificationRequestEvent,
+                start::{OriginalSyncKeyVerificationStartEvent, ToDeviceKeyVerificationStartEvent},
+            },
+            room::message::{MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent},
+        },
+        UserId,
+    },
+    Client,
+};
+use tracing::{error, info, warn};
+
+use crate::{get_admin_room, i18n, is_admin};
+
+lazy_static! {
+    /// The verification waiting for the admin to compare the emoji
+    static ref PENDING: Mutex<Option<SasVerification>> = Mutex::new(None);
+
+    /// The devices with a verification that is being handled
+    static ref ACTIVE: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// Register the handlers for verification requests
+pub fn register_handlers(client: &Client) {
+    client.add_event_handler(
+        |event: ToDeviceKeyVerificationRequestEvent, client: Client| async move {
+            handle_request(
+                &client,
+                &event.sender,
+                event.content.transaction_id.as_str(),
+            )
+            .await;
+        },
+    );
+    client.add_event_handler(
+        |event: ToDeviceKeyVerificationStartEvent, client: Client| async move {
+            handle_start(
+                &client,
+                &event.sender,
+                event.content.transaction_id.as_str(),
+            )
+            .await;
+        },
+    );
+    // Verification can also be done in a room, in which case the flow ID is the ID of the request event
+    client.add_event_handler(
+        |event: OriginalSyncRoomMessageEvent, client: Client| async move {
+            if let MessageType::VerificationRequest(_) = &event.content.msgtype {
+                handle_request(&client, &event.sender, event.event_id.as_str()).await;
+            }
+        },
+    );
+    client.add_event_handler(
+        |event: OriginalSyncKeyVerificationStartEvent, client: Client| async move {
+            handle_start(
+                &client,
+                &event.sender,
+                event.content.relates_to.event_id.as_str(),
+            )
+            .await;
+        },
+    );
+}
+
+/// Accept a verification request from an admin
+async fn handle_request(client: &Client, sender: &UserId, flow_id: &str) {
+    if !is_admin(&sender.to_owned()) {
+        warn!("Ignoring verification request from {}", sender);
+        return;
+    }
+    let Some(request) = client
+        .encryption()
+        .get_verification_request(sender, flow_id)
+        .await
+    else {
+        return;
+    };
+    if let Err(e) = request.accept().await {
+        error!("Unable to accept verification request: {}", e);
+        return;
+    }
+    tokio::spawn(wait_for_request(client.clone(), request));
+}
+
+/// Follow a verification started without a request, or after one was accepted
+async fn handle_start(client: &Client, sender: &UserId, flow_id: &str) {
+    if !is_admin(&sender.to_owned()) {
+        return;
+    }
+    if let Some(Verification::SasV1(sas)) =
+        client.encryption().get_verification(sender, flow_id).await
+    {
+        tokio::spawn(wait_for_sas(client.clone(), sas));
+    }
+}
+
+/// Wait for an accepted request to move on to emoji verification
+async fn wait_for_request(client: Client, request: VerificationRequest) {
+    let mut changes = request.changes();
+    while let Some(state) = changes.next().await {
+        match state {
+            VerificationRequestState::Transitioned { verification } => {
+                if let Some(sas) = verification.sas() {
+                    wait_for_sas(client, sas).await;
+                }
+                break;
+            }
+            VerificationRequestState::Done | VerificationRequestState::Cancelled(_) => break,
+            _ => (),
+        }
+    }
+}
+
+/// Run an emoji verification, posting the emoji for the admin to compare
+async fn wait_for_sas(client: Client, sas: SasVerification) {
+    let key = device_key(&sas);
+    if !ACTIVE.lock().unwrap().insert(key.clone()) {
+        // Already being handled
+        return;
+    }
+    let mut changes = sas.changes();
+    while let Some(state) = changes.next().await {
+        match state {
+            SasState::Started { .. } => {
+                if let Err(e) = sas.accept().await {
+                    error!("Unable to accept verification: {}", e);
+                }
+            }
+            SasState::KeysExchanged { emojis, decimals } => {
+                let code = match emojis {
+                    Some(emojis) => emojis
+                        .emojis
+                        .iter()
+                        .map(|emoji| format!("{} {}", emoji.symbol, emoji.description))
+                        .collect::<Vec<String>>()
+                        .join(", "),
+                    None => format!("{} {} {}", decimals.0, decimals.1, decimals.2),
+                };
+                *PENDING.lock().unwrap() = Some(sas.clone());
+                let user = sas.other_user_id().to_string();
+                let args = [("user", user.as_str()), ("code", code.as_str())];
+                notify(&client, &sas, "verify-request", &args).await;
+            }
+            SasState::Done { .. } => {
+                info!(
+                    "Verified with {} {}",
+                    sas.other_user_id(),
+                    sas.other_device().device_id()
+                );
+                notify(&client, &sas, "verify-done", &[]).await;
+                break;
+            }
+            SasState::Cancelled(info) => {
+                warn!("Verification cancelled: {}", info.reason());
+                break;
+            }
+            _ => (),
+        }
+    }
+    let mut pending = PENDING.lock().unwrap();
+    if pending.as_ref().is_some_and(|p| device_key(p) == key) {
+        *pending = None;
+    }
+    ACTIVE.lock().unwrap().remove(&key);
+}
+
+/// Identify the other device in a verification
+fn device_key(sas: &SasVerification) -> String {
+    format!("{} {}", sas.other_user_id(), sas.other_device().device_id())
+}
+
+/// Post a verification message to the room it happens in, or the admin room for to-device verification
+async fn notify(client: &Client, sas: &SasVerification, key: &str, args: &[(&str, &str)]) {
+    info!("{}", i18n::translate(None, key, args));
+    let room = match sas.room_id() {
+        Some(room_id) => client.get_room(room_id),
+        None => get_admin_room(client),
+    };
+    if let Some(room) = room {
+        let message = i18n::notice_text(&room, key, args).await;
+        if let Err(e) = room
+            .send(RoomMessageEventContent::notice_markdown(message))
+            .await
+        {
+            error!("Unable to post verification message: {}", e);
+        }
+    }
+}
+
+/// Confirm that the emoji of the pending verification match
+pub async fn confirm() -> Result<(), String> {
+    let sas = PENDING
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or("There is no verification waiting to be confirmed".to_string())?;
+    sas.confirm().await.map_err(|e| e.to_string())
+}
+
+/// Cancel the pending verification
+pub async fn cancel() -> Result<(), String> {
+    let sas = PENDING
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or("There is no verification waiting to be confirmed".to_string())?;
+    sas.mismatch().await.map_err(|e| e.to_string())
+}
+
+/// Describe the encryption status of this device
+pub async fn status(client: &Client) -> String {
+    let encryption = client.encryption();
+    let verified = match encryption.get_own_device().await {
+        Ok(Some(device)) => {
+            if device.is_cross_signed_by_owner() {
+                "verified"
+            } else {
+                "not verified"
+            }
+        }
+        _ => "unknown",
+    };
+    let cross_signing = match encryption.cross_signing_status().await {
+        Some(status) if status.is_complete() => "complete",
+        Some(_) => "incomplete",
+        None => "unknown",
+    };
+    let mut parts = vec![
+        format!("Device: {}", verified),
+        format!("Cross-signing: {}", cross_signing),
+        format!("Key backup: {:?}", encryption.backups().state()),
+        format!("Recovery: {:?}", encryption.recovery().state()),
+    ];
+    if PENDING.lock().unwrap().is_some() {
+        parts.push("A verification is waiting for `!chaz verify confirm`".to_string());
+    }
+    parts.join("\n")
+}
+
+/// Restore the cross-signing identity and key backup from secret storage
+pub async fn recover(client: &Client, recovery_key: &str) {
+    match client.encryption().recovery().recover(recovery_key).await {
+        Ok(()) => info!("Restored the encryption keys from secret storage"),
+        Err(e) => error!("Unable to restore the encryption keys: {}", e),
+    }
+}
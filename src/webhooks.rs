@@ -0,0 +1,242 @@
+/// Webhooks
+///
+/// With `webhooks` set, other services can post to the rooms chaz is in, e.g. alerts from monitoring or the
+/// results of a CI pipeline. A POST to `/webhook` with the token sends either a message as is, or a prompt that's
+/// answered by the model of the room like a message from a user. Prompts are sent as chaz's own user, and count
+/// against its quotas, cost budget, and rate limits.
+///
+/// The other way around, `event_webhooks` are sent a JSON event for every request to a backend, with the room,
+/// the sender, the model, the latency, and the token counts, to feed analytics or billing.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use matrix_sdk::ruma::RoomId;
+use openai_api_rs::v1::chat_completion::MessageRole;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{error, info, Instrument};
+
+use crate::{
+    accounts, backends::Message, check_limits, chunking, format, generate, get_config, get_context,
+    get_room_config, http, logging, metrics, post_response, shutdown,
+};
+
+/// Largest request accepted
+const MAX_REQUEST_SIZE: usize = 1024 * 1024;
+
+/// Configuration for the incoming webhooks
+#[derive(Debug, Deserialize, Clone)]
+pub struct WebhookConfig {
+    /// Port to listen on
+    pub port: u16,
+    /// Address to listen on, defaults to 127.0.0.1
+    ///
+    /// The requests are plain HTTP, so only listen on other addresses behind a TLS proxy.
+    pub bind: Option<String>,
+    /// Token the requests have to send as a bearer token
+    pub token: String,
+    /// Room IDs the webhooks can post to, defaults to all the rooms chaz is in
+    pub rooms: Option<Vec<String>>,
+}
+
+/// A webhook the backend requests are sent to
+#[derive(Debug, Deserialize, Clone)]
+pub struct EventWebhook {
+    pub url: String,
+    /// Sent as a bearer token, if set
+    pub token: Option<String>,
+    /// Include the prompt and the response in the events, defaults to false
+    pub include_text: Option<bool>,
+}
+
+/// How long an event webhook has to answer
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A request to a backend, as sent to the event webhooks
+#[derive(Serialize)]
+pub struct Exchange {
+    pub room_id: String,
+    pub sender: String,
+    pub backend: Option<String>,
+    /// The model set for the room, None for the default model of the backend
+    pub model: Option<String>,
+    pub latency_ms: u64,
+    /// Estimated tokens in the request
+    pub input_tokens: usize,
+    /// Estimated tokens in the response
+    pub output_tokens: usize,
+    /// Estimated cost, if the model has prices set
+    pub cost: Option<f64>,
+    /// The error from the backend, if the request failed
+    pub error: Option<String>,
+    /// The last message of the request, only sent to the webhooks with `include_text`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<String>,
+}
+
+/// Send a backend request to the event webhooks in the background
+pub fn export(exchange: Exchange) {
+    let Some(webhooks) = get_config().event_webhooks.filter(|w| !w.is_empty()) else {
+        return;
+    };
+    let mut exchange = json!(exchange);
+    exchange["timestamp"] = json!(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default());
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        for webhook in webhooks {
+            let mut event = exchange.clone();
+            if !webhook.include_text.unwrap_or(false) {
+                if let Some(event) = event.as_object_mut() {
+                    event.remove("prompt");
+                    event.remove("response");
+                }
+            }
+            let mut request = client
+                .post(&webhook.url)
+                .json(&event)
+                .timeout(EXPORT_TIMEOUT);
+            if let Some(token) = &webhook.token {
+                request = request.bearer_auth(token);
+            }
+            match request.send().await {
+                Ok(response) if !response.status().is_success() => error!(
+                    "Event webhook {} returned {}",
+                    webhook.url,
+                    response.status()
+                ),
+                Ok(_) => {}
+                Err(e) => error!("Unable to send an event to {}: {}", webhook.url, e),
+            }
+        }
+    });
+}
+
+/// The body of a request
+#[derive(Deserialize)]
+struct Payload {
+    room_id: String,
+    /// Posted as is, in the format of the room
+    message: Option<String>,
+    /// Sent to the model, and the response is posted
+    prompt: Option<String>,
+    /// Send the room history along with the prompt, defaults to false
+    #[serde(default)]
+    context: bool,
+}
+
+/// Serve the webhooks in the background
+pub fn serve(config: WebhookConfig) {
+    if config.token.is_empty() {
+        error!("Not serving webhooks, the webhooks token is empty");
+        return;
+    }
+    let bind = config.bind.unwrap_or("127.0.0.1".to_string());
+    tokio::spawn(async move {
+        let Some(listener) = http::listen("webhooks", &bind, config.port).await else {
+            return;
+        };
+        http::serve(listener, MAX_REQUEST_SIZE, |request| async {
+            match handle_request(request).await {
+                Ok((status, body)) => http::Response::json(status, body),
+                Err((status, error)) => {
+                    http::Response::json(status, json!({ "error": error }).to_string())
+                }
+            }
+        })
+        .await;
+    });
+}
+
+/// Handle a request, returning the status line and the JSON body, or the status line and the error
+async fn handle_request(
+    request: http::Request,
+) -> Result<(&'static str, String), (&'static str, String)> {
+    // The config can be reloaded, the token and the rooms are read for every request
+    let Some(config) = get_config().webhooks else {
+        return Err(("404 Not Found", "webhooks are disabled".to_string()));
+    };
+    if !request.has_token(&config.token) {
+        return Err(("401 Unauthorized", "invalid token".to_string()));
+    }
+    if request.method != "POST" || request.segments("") != ["webhook"] {
+        return Err(("404 Not Found", "use POST /webhook".to_string()));
+    }
+    if shutdown::is_draining() {
+        return Err(("503 Service Unavailable", "shutting down".to_string()));
+    }
+    let payload: Payload = serde_json::from_slice(&request.body)
+        .map_err(|e| ("400 Bad Request", format!("invalid JSON: {}", e)))?;
+    if let Some(rooms) = &config.rooms {
+        if !rooms.contains(&payload.room_id) {
+            return Err(("403 Forbidden", "room not allowed".to_string()));
+        }
+    }
+    let room = RoomId::parse(&payload.room_id)
+        .ok()
+        .and_then(|room_id| accounts::get_room(&room_id))
+        .ok_or(("404 Not Found", "chaz isn't in the room".to_string()))?;
+
+    match (payload.message, payload.prompt) {
+        (Some(message), None) => {
+            let format = format::get(&room).await;
+            let mut event_ids = Vec::new();
+            for part in chunking::split(&message, chunking::max_length()) {
+                let response = room
+                    .send(format.content(part))
+                    .await
+                    .map_err(|e| ("502 Bad Gateway", e.to_string()))?;
+                event_ids.push(response.event_id.to_string());
+            }
+            info!("Posted a webhook message to {}", room.room_id());
+            Ok(("200 OK", json!({ "event_ids": event_ids }).to_string()))
+        }
+        (None, Some(prompt)) => {
+            // Generating can take longer than the caller wants to wait
+            let in_flight = shutdown::track();
+            let Some(sender) = room.client().user_id().map(ToOwned::to_owned) else {
+                return Err(("503 Service Unavailable", "not logged in".to_string()));
+            };
+            let config = get_room_config(&room).await;
+            if let Err(reason) = check_limits(sender.as_str(), room.room_id().as_str(), config) {
+                metrics::record_rate_limited();
+                return Err(("429 Too Many Requests", reason));
+            }
+            let span = logging::request_span(&room, &sender);
+            tokio::spawn(
+                async move {
+                    let _in_flight = in_flight;
+                    info!("Answering a webhook prompt in {}", room.room_id());
+                    let mut context = match get_context(&room).await {
+                        Ok(context) => context,
+                        Err(e) => {
+                            error!("Unable to read the context for a webhook: {}", e);
+                            return;
+                        }
+                    };
+                    // The room's model and role are still used without the history
+                    if !payload.context {
+                        context.messages.clear();
+                        context.media.clear();
+                    }
+                    context
+                        .messages
+                        .push(Message::new(MessageRole::user, prompt));
+                    let (result, style) = generate(&room, &sender, context).await;
+                    if let Err(e) = post_response(&room, &sender, result, style, None).await {
+                        error!("Unable to post the webhook response: {}", e);
+                    }
+                }
+                .instrument(span),
+            );
+            Ok(("202 Accepted", "{}".to_string()))
+        }
+        _ => Err((
+            "400 Bad Request",
+            "set either message or prompt".to_string(),
+        )),
+    }
+}
@@ -0,0 +1,151 @@
+/// Conversation workspaces
+///
+/// A room can hold several independent conversations, and `!chaz workspace <name>` switches between them.
+/// Each switch posts a notice, and the notices entering and leaving each workspace are stored in the room account
+/// data under `is.chaz.workspace.<name>`, so its messages can be found in the history when building the context.
+/// The current workspace is stored in the room tags under `is.chaz.workspace`, and the settings of each workspace
+/// under `is.chaz.workspace.<name>`.
+use std::collections::HashSet;
+
+use headjack::Tags;
+use matrix_sdk::{
+    ruma::{events::room::message::RoomMessageEventContent, OwnedEventId},
+    Room,
+};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{account_data, i18n};
+
+/// Tag namespace for the current workspace
+const NAMESPACE: &str = "is.chaz.workspace";
+
+/// The workspace used before any switch
+pub const DEFAULT_WORKSPACE: &str = "default";
+
+/// The switches into and out of a workspace, stored in the room account data
+#[derive(Serialize, Deserialize, Default)]
+struct State {
+    /// The switch notices that entered the workspace
+    entered: HashSet<OwnedEventId>,
+    /// The switch notices that left the workspace
+    left: HashSet<OwnedEventId>,
+}
+
+/// The messages of a workspace, found going backwards through the history
+pub struct History {
+    state: State,
+    /// Whether the message being checked was sent in the workspace
+    inside: bool,
+}
+
+impl History {
+    /// Check if a message was sent in the workspace, going from the newest message to the oldest
+    ///
+    /// The switch notices themselves don't belong to any workspace.
+    pub fn contains(&mut self, event_id: Option<&OwnedEventId>) -> bool {
+        match event_id {
+            Some(id) if self.state.left.contains(id) => {
+                self.inside = true;
+                false
+            }
+            Some(id) if self.state.entered.contains(id) => {
+                self.inside = false;
+                false
+            }
+            _ => self.inside,
+        }
+    }
+}
+
+/// Type of the room account data the state of a workspace is stored in
+fn event_type(workspace: &str) -> String {
+    format!("{}.{}", NAMESPACE, workspace)
+}
+
+/// Load the state of a workspace
+async fn load(room: &Room, workspace: &str) -> Result<State, String> {
+    Ok(account_data::get(room, &event_type(workspace))
+        .await?
+        .unwrap_or_default())
+}
+
+/// Get the history of the current workspace, to find its messages
+///
+/// If it can't be loaded, the whole room history is treated as the workspace.
+pub async fn history(room: &Room, workspace: &str) -> History {
+    let state = match load(room, workspace).await {
+        Ok(state) => state,
+        Err(e) => {
+            error!(
+                "Unable to load the workspace {} of {}: {}",
+                workspace,
+                room.room_id(),
+                e
+            );
+            State::default()
+        }
+    };
+    History {
+        state,
+        inside: true,
+    }
+}
+
+/// Get the current workspace of the room
+pub async fn current(room: &Room) -> String {
+    Tags::new(room, NAMESPACE)
+        .await
+        .get_value("current")
+        .unwrap_or(DEFAULT_WORKSPACE.to_string())
+}
+
+/// Get the workspaces that have been used in the room
+pub async fn list(room: &Room) -> Vec<String> {
+    let tags = Tags::new(room, NAMESPACE).await;
+    let mut workspaces = vec![DEFAULT_WORKSPACE.to_string()];
+    for workspace in tags.get_value("list").unwrap_or_default().split(',') {
+        if !workspace.is_empty() && !workspaces.iter().any(|w| w == workspace) {
+            workspaces.push(workspace.to_string());
+        }
+    }
+    workspaces
+}
+
+/// Switch the room to a workspace, posting the notice that splits the history
+pub async fn switch(room: &Room, name: &str) -> Result<(), String> {
+    let previous = current(room).await;
+    let mut left = load(room, &previous).await?;
+    let mut entered = load(room, name).await?;
+    let args = [("previous", previous.as_str()), ("workspace", name)];
+    let notice = i18n::notice_text(room, "workspace-switched", &args).await;
+    let event_id = room
+        .send(RoomMessageEventContent::notice_plain(notice))
+        .await
+        .map_err(|e| e.to_string())?
+        .event_id;
+    left.left.insert(event_id.clone());
+    entered.entered.insert(event_id);
+    account_data::set(room, &event_type(&previous), &left).await?;
+    account_data::set(room, &event_type(name), &entered).await?;
+
+    let mut workspaces = list(room).await;
+    if !workspaces.iter().any(|w| w == name) {
+        workspaces.push(name.to_string());
+    }
+    let mut tags = Tags::new(room, NAMESPACE).await;
+    tags.replace_kv("current", name);
+    tags.replace_kv("list", &workspaces[1..].join(","));
+    tags.sync().await;
+    Ok(())
+}
+
+/// Get the tags holding the settings of a workspace
+///
+/// The default workspace uses the room wide settings, so it returns None.
+pub async fn settings<'a>(room: &'a Room, workspace: &str) -> Option<Tags<'a>> {
+    if workspace == DEFAULT_WORKSPACE {
+        return None;
+    }
+    Some(Tags::new(room, &format!("{}.{}", NAMESPACE, workspace)).await)
+}